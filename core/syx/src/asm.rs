@@ -0,0 +1,747 @@
+// Assembler for a textual form of Protos, and the matching disassembler
+//
+// A function is a `.function` ... `.end` block. Directives set the header
+// and debug information, everything else is an instruction or a label:
+//
+//     .function
+//     .source "@example.lua"   ; nested functions default to their parent's
+//     .linedefined 0
+//     .lastlinedefined 0
+//     .params 0
+//     .vararg
+//     .maxstack 2              ; estimated from the registers if left out
+//     .upval "_ENV" 1 0        ; name, instack, idx
+//     .const "print"           ; constants are numbered from k0
+//     .local "x" 1 4           ; name, startpc, endpc
+//     loop:
+//         [1] GetTabUp r0 u0 k0
+//         [1] Jmp 0 loop       ; jumps take a label or an offset
+//     .function                ; nested functions, numbered for Closure
+//     .end
+//     .end
+//
+// Mnemonics are OpCode variants (luac's names work too) and operands are
+// written by kind: `r1` registers, `k1` constants, `u1` upvalues, `true` and
+// `false` for booleans and plain numbers for the rest. The `[line]` prefix
+// goes into lineinfo and has to be on every instruction or none of them.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::builder::stack_top;
+use super::dump::DumpState;
+use super::errors::*;
+use super::object::{LocVar, Proto, SyxValue, Upvalue};
+use super::opcodes::*;
+use super::pretty::quote_string;
+use super::print::opname;
+
+pub fn assemble(source: &str) -> Result<Proto> {
+    let mut assembler = Assembler {
+        functions: Vec::new(),
+        main: None,
+        line: 0,
+    };
+    for (i, line) in source.lines().enumerate() {
+        assembler.line = i + 1;
+        let tokens = assembler.tokenize(line)?;
+        assembler.statement(&tokens)?;
+    }
+    if !assembler.functions.is_empty() {
+        return assembler.error("missing .end");
+    }
+    match assembler.main {
+        Some(main) => Ok(main),
+        None => assembler.error("no .function found"),
+    }
+}
+
+// Assembles and dumps `source` into a chunk LoadState can read
+pub fn assemble_chunk(source: &str, strip: bool) -> Result<Vec<u8>> {
+    DumpState::to_u8(&assemble(source)?, strip)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(Vec<u8>),
+    Line(i32),
+}
+
+struct Function {
+    proto: Proto,
+    lines: Vec<Option<i32>>,
+    labels: HashMap<String, usize>,
+    jumps: Vec<(usize, String, usize)>, // pc, label, source line
+    maxstack: bool,                     // set by .maxstack
+}
+
+struct Assembler {
+    functions: Vec<Function>,
+    main: Option<Proto>,
+    line: usize,
+}
+
+impl Assembler {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T> {
+        Err(ErrorKind::AssembleError(self.line, message.into()).into())
+    }
+
+    fn tokenize(&self, line: &str) -> Result<Vec<Token>> {
+        let bytes = line.as_bytes();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b';' => break,
+                b' ' | b'\t' | b',' => i += 1,
+                b'"' => {
+                    let (s, next) = self.string(bytes, i + 1)?;
+                    tokens.push(Token::Str(s));
+                    i = next;
+                }
+                _ => {
+                    let start = i;
+                    while i < bytes.len() && !b" \t,;\"".contains(&bytes[i]) {
+                        i += 1;
+                    }
+                    let word = &line[start..i];
+                    if word.starts_with('[') && word.ends_with(']') {
+                        match word[1..word.len() - 1].parse() {
+                            Ok(n) => tokens.push(Token::Line(n)),
+                            Err(_) => return self.error(format!("bad line number {}", word)),
+                        }
+                    } else {
+                        tokens.push(Token::Word(word.to_owned()));
+                    }
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    // reads a quoted string starting after the quote, the escapes are the
+    // ones quote_string writes
+    fn string(&self, bytes: &[u8], mut i: usize) -> Result<(Vec<u8>, usize)> {
+        let mut s = Vec::new();
+        loop {
+            match bytes.get(i) {
+                None => return self.error("unfinished string"),
+                Some(b'"') => return Ok((s, i + 1)),
+                Some(b'\\') => {
+                    i += 1;
+                    let ch = match bytes.get(i) {
+                        Some(b'n') => b'\n',
+                        Some(b'r') => b'\r',
+                        Some(b't') => b'\t',
+                        Some(b'a') => 0x07,
+                        Some(b'b') => 0x08,
+                        Some(b'v') => 0x0B,
+                        Some(b'f') => 0x0C,
+                        Some(b'\\') => b'\\',
+                        Some(b'"') => b'"',
+                        Some(ch) if ch.is_ascii_digit() => {
+                            let mut n = 0u32;
+                            let mut count = 0;
+                            while count < 3 && i < bytes.len() && bytes[i].is_ascii_digit() {
+                                n = n * 10 + u32::from(bytes[i] - b'0');
+                                i += 1;
+                                count += 1;
+                            }
+                            if n > 0xFF {
+                                return self.error("decimal escape too large");
+                            }
+                            s.push(n as u8);
+                            continue;
+                        }
+                        _ => return self.error("invalid escape sequence"),
+                    };
+                    s.push(ch);
+                    i += 1;
+                }
+                Some(&ch) => {
+                    s.push(ch);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    fn function(&mut self) -> Result<&mut Function> {
+        match self.functions.last_mut() {
+            Some(f) => Ok(f),
+            None => Err(ErrorKind::AssembleError(
+                self.line, "expected .function".to_owned()).into()),
+        }
+    }
+
+    fn integer<T: ::std::str::FromStr>(&self, token: Option<&Token>) -> Result<T> {
+        match token {
+            Some(Token::Word(word)) => match word.parse() {
+                Ok(n) => Ok(n),
+                Err(_) => self.error(format!("expected a number, got {}", word)),
+            },
+            _ => self.error("expected a number"),
+        }
+    }
+
+    fn quoted(&self, token: Option<&Token>) -> Result<Vec<u8>> {
+        match token {
+            Some(Token::Str(s)) => Ok(s.clone()),
+            _ => self.error("expected a string"),
+        }
+    }
+
+    fn statement(&mut self, tokens: &[Token]) -> Result<()> {
+        let (first, rest) = match tokens.split_first() {
+            None => return Ok(()),
+            Some(split) => split,
+        };
+        match first {
+            Token::Word(word) if word.starts_with('.') => self.directive(word, rest),
+            Token::Word(word) if word.ends_with(':') && rest.is_empty() => {
+                let name = word[..word.len() - 1].to_owned();
+                let f = self.function()?;
+                let pc = f.proto.instructions.len();
+                if f.labels.insert(name.clone(), pc).is_some() {
+                    return self.error(format!("label {} already defined", name));
+                }
+                Ok(())
+            }
+            Token::Line(line) => match rest.split_first() {
+                Some((Token::Word(mnemonic), operands)) => {
+                    self.instruction(Some(*line), mnemonic, operands)
+                }
+                _ => self.error("expected an instruction"),
+            },
+            Token::Word(mnemonic) => self.instruction(None, mnemonic, rest),
+            Token::Str(_) => self.error("unexpected string"),
+        }
+    }
+
+    fn directive(&mut self, name: &str, args: &[Token]) -> Result<()> {
+        let expected = match name {
+            ".function" | ".end" | ".vararg" => 0,
+            ".upval" | ".local" => 3,
+            _ => 1,
+        };
+        if args.len() != expected {
+            return self.error(format!("{} takes {} arguments", name, expected));
+        }
+        match name {
+            ".function" => {
+                let mut proto = Proto::new();
+                if let Some(parent) = self.functions.last() {
+                    proto.source = parent.proto.source.clone();
+                } else if self.main.is_some() {
+                    return self.error("only one main function is allowed");
+                }
+                self.functions.push(Function {
+                    proto,
+                    lines: Vec::new(),
+                    labels: HashMap::new(),
+                    jumps: Vec::new(),
+                    maxstack: false,
+                });
+            }
+            ".end" => {
+                let f = match self.functions.pop() {
+                    Some(f) => f,
+                    None => return self.error(".end without .function"),
+                };
+                let proto = self.finish(f)?;
+                match self.functions.last_mut() {
+                    Some(parent) => parent.proto.protos.push(proto),
+                    None => self.main = Some(proto),
+                }
+            }
+            ".source" => {
+                let source = self.quoted(args.first())?;
+                match String::from_utf8(source) {
+                    Ok(source) => self.function()?.proto.source = source,
+                    Err(_) => return self.error("source is not valid UTF-8"),
+                }
+            }
+            ".linedefined" => {
+                let n = self.integer(args.first())?;
+                self.function()?.proto.linedefined = n;
+            }
+            ".lastlinedefined" => {
+                let n = self.integer(args.first())?;
+                self.function()?.proto.lastlinedefined = n;
+            }
+            ".params" => {
+                let n = self.integer(args.first())?;
+                self.function()?.proto.numparams = n;
+            }
+            ".vararg" => self.function()?.proto.is_vararg = true,
+            ".maxstack" => {
+                let n = self.integer(args.first())?;
+                let f = self.function()?;
+                f.proto.maxstacksize = n;
+                f.maxstack = true;
+            }
+            ".upval" => {
+                let upvalue = Upvalue {
+                    name: self.quoted(args.first())?,
+                    instack: self.integer(args.get(1))?,
+                    idx: self.integer(args.get(2))?,
+                    kind: 0,
+                };
+                self.function()?.proto.upvalues.push(upvalue);
+            }
+            ".const" => {
+                let constant = self.constant(&args[0])?;
+                self.function()?.proto.constants.push(constant);
+            }
+            ".local" => {
+                let local = LocVar {
+                    varname: self.quoted(args.first())?,
+                    startpc: self.integer(args.get(1))?,
+                    endpc: self.integer(args.get(2))?,
+                };
+                self.function()?.proto.locvars.push(local);
+            }
+            _ => return self.error(format!("unknown directive {}", name)),
+        }
+        Ok(())
+    }
+
+    fn constant(&self, token: &Token) -> Result<SyxValue> {
+        Ok(match token {
+            Token::Str(s) => SyxValue::String(s.clone()),
+            Token::Word(word) => match word.as_str() {
+                "nil" => SyxValue::Nil,
+                "true" => SyxValue::Bool(true),
+                "false" => SyxValue::Bool(false),
+                _ => if let Ok(n) = word.parse() {
+                    SyxValue::Integer(n)
+                } else if let Ok(n) = word.parse() {
+                    SyxValue::Number(n)
+                } else {
+                    return self.error(format!("bad constant {}", word));
+                },
+            },
+            Token::Line(_) => return self.error("bad constant"),
+        })
+    }
+
+    // reads an operand, `jump` is set for the sBx operand
+    fn operand(&self, token: &Token, jump: bool) -> Result<Option<Argument>> {
+        let word = match token {
+            Token::Word(word) => word,
+            _ => return self.error("bad operand"),
+        };
+        let index = |prefix: char| -> Option<u32> {
+            if word.starts_with(prefix) { word[1..].parse().ok() } else { None }
+        };
+        Ok(Some(if jump {
+            match word.parse() {
+                Ok(n) => Argument::SInteger(n),
+                Err(_) => return Ok(None), // a label, resolved at .end
+            }
+        } else if let Some(n) = index('r') {
+            Argument::Register(n)
+        } else if let Some(n) = index('k') {
+            Argument::Constant(n)
+        } else if let Some(n) = index('u') {
+            Argument::UpValue(n)
+        } else if word == "true" || word == "false" {
+            Argument::Bool(word == "true")
+        } else if let Ok(n) = word.parse() {
+            Argument::Integer(n)
+        } else {
+            return self.error(format!("bad operand {}", word));
+        }))
+    }
+
+    fn instruction(&mut self, line: Option<i32>, mnemonic: &str, tokens: &[Token])
+        -> Result<()>
+    {
+        let op = match opcode(mnemonic) {
+            Some(op) => op,
+            None => return self.error(format!("unknown instruction {}", mnemonic)),
+        };
+        let mode = op.mode();
+        // fields written in the source, in order, and whether they are RK
+        let fields: Vec<(&str, bool)> = match mode.format {
+            OpFormat::ABC | OpFormat::AB | OpFormat::A => {
+                let rk = |m: ArgMode| m == ArgMode::RegisterConstant;
+                let mut fields = vec![("a", false)];
+                if mode.b != ArgMode::Unused {
+                    fields.push(("b", rk(mode.b)));
+                }
+                if mode.c != ArgMode::Unused {
+                    fields.push(("c", rk(mode.c)));
+                }
+                fields
+            }
+            OpFormat::ABx => vec![("a", false), ("bx", false)],
+            OpFormat::AsBx => vec![("a", false), ("sbx", false)],
+            OpFormat::Ax => vec![("ax", false)],
+        };
+        if tokens.len() != fields.len() {
+            return self.error(format!("{:?} takes {} operands, got {}",
+                                      op, fields.len(), tokens.len()));
+        }
+        let (mut a, mut b, mut c, mut bx, mut sbx, mut ax) = (0, 0, 0, 0, 0, 0);
+        let mut args = Vec::new();
+        let mut label = None;
+        for (token, &(field, rk)) in tokens.iter().zip(&fields) {
+            let arg = match self.operand(token, field == "sbx")? {
+                Some(arg) => arg,
+                None => {
+                    if let Token::Word(name) = token {
+                        label = Some(name.clone());
+                    }
+                    Argument::SInteger(0)
+                }
+            };
+            let value = match arg {
+                Argument::Constant(n) if rk => n | BITMASK_IS_RK,
+                | Argument::Register(n) | Argument::Constant(n)
+                | Argument::UpValue(n) | Argument::Integer(n) => n,
+                Argument::Bool(b) => b as u32,
+                Argument::SInteger(n) => {
+                    sbx = n;
+                    args.push(arg);
+                    continue;
+                }
+            };
+            let bitmask = match field {
+                "a" => BITMASK_A,
+                "b" => BITMASK_B,
+                "c" => BITMASK_C,
+                "bx" => BITMASK_BX,
+                _ => BITMASK_AX,
+            };
+            if value > bitmask {
+                return self.error(format!("operand {} out of range", field));
+            }
+            match field {
+                "a" => a = value,
+                "b" => b = value,
+                "c" => c = value,
+                "bx" => bx = value,
+                _ => ax = value,
+            }
+            args.push(arg);
+        }
+        let instr = match mode.format {
+            OpFormat::ABC | OpFormat::AB | OpFormat::A => Instruction::ABC {
+                instruction: op, a: a as u8, b: b as u16, c: c as u16,
+            },
+            OpFormat::ABx => Instruction::ABx { instruction: op, a: a as u8, bx },
+            OpFormat::AsBx => Instruction::AsBx { instruction: op, a: a as u8, sbx },
+            OpFormat::Ax => Instruction::Ax { instruction: op, ax },
+        };
+        // decoding the instruction has to give back what was written, which
+        // catches operands of the wrong kind
+        let decoded = instr.operands();
+        if let Some(i) = (0..args.len()).find(|&i| decoded[i] != args[i]) {
+            return self.error(format!("operand {} of {:?} should be like {}",
+                                      i + 1, op, format_argument(&decoded[i])));
+        }
+        let source_line = self.line;
+        let f = self.function()?;
+        if let Some(label) = label {
+            f.jumps.push((f.proto.instructions.len(), label, source_line));
+        }
+        f.proto.instructions.push(instr);
+        f.lines.push(line);
+        Ok(())
+    }
+
+    fn finish(&mut self, mut f: Function) -> Result<Proto> {
+        for (pc, label, line) in f.jumps.drain(..) {
+            let target = match f.labels.get(&label) {
+                Some(target) => *target as i32,
+                None => {
+                    self.line = line;
+                    return self.error(format!("no label {}", label));
+                }
+            };
+            if let Instruction::AsBx { ref mut sbx, .. } = f.proto.instructions[pc] {
+                *sbx = target - (pc as i32 + 1);
+            }
+        }
+        if f.lines.iter().all(Option::is_some) {
+            f.proto.lineinfo = f.lines.iter().map(|x| x.unwrap()).collect();
+        } else if f.lines.iter().any(Option::is_some) {
+            return self.error("line info has to be given for all instructions or none");
+        }
+        if !f.maxstack {
+            f.proto.maxstacksize = estimate_stack(&f.proto);
+        }
+        Ok(f.proto)
+    }
+}
+
+fn opcode(mnemonic: &str) -> Option<OpCode> {
+    Version::Lua53.opcodes().iter().cloned()
+        .find(|op| format!("{:?}", op) == mnemonic || opname(*op) == mnemonic)
+}
+
+// registers touched by the code, but at least 2 like lcode.c
+fn estimate_stack(proto: &Proto) -> u8 {
+    stack_top(&proto.instructions).clamp(2, 255) as u8
+}
+
+fn format_argument(arg: &Argument) -> String {
+    match *arg {
+        Argument::Register(n) => format!("r{}", n),
+        Argument::Constant(n) => format!("k{}", n),
+        Argument::UpValue(n) => format!("u{}", n),
+        Argument::Integer(n) => n.to_string(),
+        Argument::SInteger(n) => n.to_string(),
+        Argument::Bool(b) => b.to_string(),
+    }
+}
+
+fn format_constant(value: &SyxValue) -> String {
+    match value {
+        SyxValue::Nil => "nil".to_owned(),
+        SyxValue::Bool(b) => b.to_string(),
+        SyxValue::Integer(n) => n.to_string(),
+        // Debug always has a `.`, an exponent, inf or NaN, and reads back
+        // to the same float
+        SyxValue::Number(n) => format!("{:?}", n),
+        SyxValue::String(s) => quote_string(s),
+    }
+}
+
+pub fn disassemble(proto: &Proto) -> String {
+    let mut output = String::new();
+    disassemble_function(&mut output, proto, "", 0);
+    output
+}
+
+fn disassemble_function(output: &mut String, f: &Proto, parent: &str, depth: usize) {
+    let indent = "    ".repeat(depth);
+    writeln!(output, "{}.function", indent).unwrap();
+    if f.source != parent {
+        writeln!(output, "{}.source {}", indent, quote_string(f.source.as_bytes())).unwrap();
+    }
+    writeln!(output, "{}.linedefined {}", indent, f.linedefined).unwrap();
+    writeln!(output, "{}.lastlinedefined {}", indent, f.lastlinedefined).unwrap();
+    writeln!(output, "{}.params {}", indent, f.numparams).unwrap();
+    if f.is_vararg {
+        writeln!(output, "{}.vararg", indent).unwrap();
+    }
+    writeln!(output, "{}.maxstack {}", indent, f.maxstacksize).unwrap();
+    for upvalue in &f.upvalues {
+        writeln!(output, "{}.upval {} {} {}", indent, quote_string(&upvalue.name),
+                 upvalue.instack, upvalue.idx).unwrap();
+    }
+    for (i, constant) in f.constants.iter().enumerate() {
+        writeln!(output, "{}.const {} ; k{}", indent, format_constant(constant), i).unwrap();
+    }
+    for local in &f.locvars {
+        writeln!(output, "{}.local {} {} {}", indent, quote_string(&local.varname),
+                 local.startpc, local.endpc).unwrap();
+    }
+
+    // name every jump target that is inside the function
+    let code = &f.instructions;
+    let mut targets: Vec<usize> = code.iter().enumerate()
+        .filter_map(|(pc, i)| match *i {
+            Instruction::AsBx { sbx, .. } => {
+                let target = pc as i64 + 1 + i64::from(sbx);
+                if target >= 0 && target <= code.len() as i64 {
+                    Some(target as usize)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+        .collect();
+    targets.sort();
+    targets.dedup();
+    let label = |pc: usize| targets.binary_search(&pc).ok().map(|i| format!("L{}", i + 1));
+
+    let lines = f.lineinfo.len() == code.len();
+    for (pc, instr) in code.iter().enumerate() {
+        if let Some(name) = label(pc) {
+            writeln!(output, "{}{}:", indent, name).unwrap();
+        }
+        output.push_str(&indent);
+        output.push_str("    ");
+        if lines {
+            write!(output, "[{}] ", f.lineinfo[pc]).unwrap();
+        }
+        write!(output, "{:?}", instr_opcode(instr)).unwrap();
+        let mut comments = Vec::new();
+        for arg in instr.operands() {
+            output.push(' ');
+            match arg {
+                Argument::SInteger(sbx) => {
+                    let target = (pc as i64 + 1 + i64::from(sbx)) as usize;
+                    match label(target) {
+                        Some(name) => output.push_str(&name),
+                        None => write!(output, "{}", sbx).unwrap(),
+                    }
+                }
+                _ => output.push_str(&format_argument(&arg)),
+            }
+            match arg {
+                Argument::Constant(n) => if let Some(k) = f.constants.get(n as usize) {
+                    comments.push(format_constant(k));
+                },
+                Argument::UpValue(n) => if let Some(u) = f.upvalues.get(n as usize) {
+                    comments.push(String::from_utf8_lossy(&u.name).into_owned());
+                },
+                _ => {}
+            }
+        }
+        if !comments.is_empty() {
+            write!(output, " ; {}", comments.join(" ")).unwrap();
+        }
+        output.push('\n');
+    }
+    if let Some(name) = label(code.len()) {
+        writeln!(output, "{}{}:", indent, name).unwrap();
+    }
+
+    for p in &f.protos {
+        disassemble_function(output, p, &f.source, depth + 1);
+    }
+    writeln!(output, "{}.end", indent).unwrap();
+}
+
+fn instr_opcode(instr: &Instruction) -> OpCode {
+    match *instr {
+        | Instruction::ABC { instruction, .. }
+        | Instruction::ABx { instruction, .. }
+        | Instruction::AsBx { instruction, .. }
+        | Instruction::Ax { instruction, .. } => instruction,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::undump::LoadState;
+
+    macro_rules! fixture {
+        ($name:expr) => {
+            ($name, &include_bytes!(concat!("../tests/fixtures/", $name, ".luac"))[..])
+        };
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let fixtures = [
+            fixture!("expressions"),
+            fixture!("closures"),
+            fixture!("constructors"),
+            fixture!("folding"),
+            fixture!("gotos"),
+            fixture!("scopes"),
+            fixture!("strings"),
+            fixture!("loops"),
+            fixture!("closures.stripped"),
+            fixture!("loops.stripped"),
+        ];
+        for &(name, luac) in fixtures.iter() {
+            let proto = LoadState::from_u8(luac.to_vec(), name).unwrap();
+            let text = disassemble(&proto);
+            let strip = name.ends_with(".stripped");
+            let chunk = assemble_chunk(&text, strip)
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert!(chunk == luac, "{}", name);
+            let again = assemble(&text).unwrap();
+            assert_eq!(disassemble(&again), text, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = r#"
+            ; counts down from 3
+            .function
+            .source "=test"
+            .vararg
+            .upval "_ENV" 1 0
+            .const 3
+            .const "print"
+                LoadK r0 k0
+            loop:
+                GetTabUp r1 u0 k1
+                Move r2 r0
+                Call r1 2 1
+                Add r0 r0 k2
+                Lt 1 k3 r0
+                Jmp 0 loop
+                Return r0 1
+            .const -1
+            .const 0
+            .end
+        "#;
+        let proto = assemble(source).unwrap();
+        assert_eq!(proto.source, "=test");
+        assert_eq!(proto.maxstacksize, 3);
+        assert!(proto.lineinfo.is_empty());
+        assert_eq!(proto.instructions[6], Instruction::AsBx {
+            instruction: OpCode::Jmp,
+            a: 0,
+            sbx: -6,
+        });
+        assert_eq!(proto.instructions[5], Instruction::ABC {
+            instruction: OpCode::Lt,
+            a: 1,
+            b: 3 | BITMASK_IS_RK as u16,
+            c: 0,
+        });
+        let chunk = assemble_chunk(source, false).unwrap();
+        let loaded = LoadState::from_u8(chunk, "test").unwrap();
+        assert_eq!(loaded.instructions, proto.instructions);
+    }
+
+    #[test]
+    fn test_nested() {
+        let source = "
+            .function
+            .source \"@outer.lua\"
+                [1] Closure r0 0
+                [1] Closure r0 1
+                [2] Return r0 1
+            .function
+            .linedefined 1
+                [1] Return r0 1
+            .end
+            .function
+            .source \"@inner.lua\"
+                Return r0 1
+            .end
+            .end
+        ";
+        let proto = assemble(source).unwrap();
+        assert_eq!(proto.lineinfo, vec![1, 1, 2]);
+        assert_eq!(proto.protos.len(), 2);
+        assert_eq!(proto.protos[0].source, "@outer.lua");
+        assert_eq!(proto.protos[0].linedefined, 1);
+        assert_eq!(proto.protos[1].source, "@inner.lua");
+    }
+
+    #[test]
+    fn test_errors() {
+        let message = |source: &str| assemble(source).err().unwrap().to_string();
+        assert_eq!(message(".function\n  Move r0 k1\n.end"),
+                   "line 2: operand 2 of Move should be like r1");
+        assert_eq!(message(".function\n  Move r0\n.end"),
+                   "line 2: Move takes 2 operands, got 1");
+        assert_eq!(message(".function\n  Frobnicate r0\n.end"),
+                   "line 2: unknown instruction Frobnicate");
+        assert_eq!(message(".function\n  Jmp 0 nowhere\n.end"),
+                   "line 2: no label nowhere");
+        assert_eq!(message(".function\n  LoadK r256 k0\n.end"),
+                   "line 2: operand a out of range");
+        assert_eq!(message(".function\n  [1] Return r0 1\n  Return r0 1\n.end"),
+                   "line 4: line info has to be given for all instructions or none");
+        assert_eq!(message(".function\n.const \"a\\q\"\n.end"),
+                   "line 2: invalid escape sequence");
+        assert_eq!(message(".function\n"), "line 1: missing .end");
+    }
+}
@@ -0,0 +1,220 @@
+// Abstract syntax tree for Lua 5.3 source
+#![allow(dead_code)]
+
+// Byte offsets into the source, plus the lines of the first and last token.
+// `last_line` is what lparser.c calls `lastline`, and is used for lineinfo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub last_line: u32,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: u32, last_line: u32) -> Span {
+        Span { start, end, line, last_line }
+    }
+
+    // smallest span covering both `self` and `other`
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            last_line: other.last_line,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub ret: Option<Return>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Return {
+    pub exprs: Vec<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    Assign { targets: Vec<Expr>, exprs: Vec<Expr> },
+    Call(Expr), // always an ExprKind::Call or ExprKind::MethodCall
+    Label(Name),
+    Break,
+    Goto(Name),
+    Do(Block),
+    While { cond: Expr, body: Block },
+    Repeat { body: Block, cond: Expr },
+    If { clauses: Vec<(Expr, Block)>, else_block: Option<Block> },
+    NumericFor(Box<NumericFor>),
+    GenericFor { names: Vec<Name>, exprs: Vec<Expr>, body: Block },
+    Function { name: FuncName, body: FuncBody },
+    LocalFunction { name: Name, body: FuncBody },
+    Local { names: Vec<Name>, exprs: Vec<Expr> },
+    Error, // placeholder left by the parser after a syntax error
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumericFor {
+    pub var: Name,
+    pub start: Expr,
+    pub limit: Expr,
+    pub step: Option<Expr>,
+    pub body: Block,
+}
+
+// `a.b.c:d` in `function a.b.c:d() end`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    pub path: Vec<Name>,
+    pub method: Option<Name>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncBody {
+    pub params: Vec<Name>,
+    pub is_vararg: bool,
+    pub body: Block,
+    pub span: Span, // from `function` (or the name) up to `end`
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    VarArg,
+    Integer(i64),
+    Number(f64),
+    String(Vec<u8>),
+    Function(Box<FuncBody>),
+    Table(Vec<Field>),
+    Binary { op: BinOp, op_span: Span, lhs: Box<Expr>, rhs: Box<Expr> },
+    Unary { op: UnOp, op_span: Span, operand: Box<Expr> },
+    Paren(Box<Expr>),
+    Name(String),
+    Index { obj: Box<Expr>, key: Box<Expr> }, // `a.b` has a String key
+    Call { func: Box<Expr>, args: Vec<Expr> },
+    MethodCall { obj: Box<Expr>, method: Name, args: Vec<Expr> },
+    Error, // placeholder left by the parser after a syntax error
+}
+
+impl ExprKind {
+    // can appear on the left side of an assignment
+    pub fn is_assignable(&self) -> bool {
+        matches!(self, ExprKind::Name(_) | ExprKind::Index { .. })
+    }
+
+    // may produce more than one value (`f()`, `...`)
+    pub fn is_multi(&self) -> bool {
+        matches!(self, ExprKind::Call { .. }
+                     | ExprKind::MethodCall { .. }
+                     | ExprKind::VarArg)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Positional(Expr),  // { v }
+    Named(Name, Expr), // { k = v }
+    Keyed(Expr, Expr), // { [k] = v }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add, Sub, Mul, Mod, Pow, Div, IDiv,
+    BAnd, BOr, BXor, Shl, Shr,
+    Concat,
+    Eq, Lt, Le, Ne, Gt, Ge,
+    And, Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg, BNot, Not, Len,
+}
+
+// priority of unary operators, see UNARY_PRIORITY in lparser.c
+pub const UNARY_PRIORITY: u8 = 12;
+
+impl BinOp {
+    // (left, right) priority, same table as `priority` in lparser.c
+    pub fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13), // right associative
+            BinOp::Div | BinOp::IDiv => (11, 11),
+            BinOp::BAnd => (6, 6),
+            BinOp::BOr => (4, 4),
+            BinOp::BXor => (5, 5),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8), // right associative
+            | BinOp::Eq | BinOp::Lt | BinOp::Le
+            | BinOp::Ne | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::And => (2, 2),
+            BinOp::Or => (1, 1),
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Div => "/",
+            BinOp::IDiv => "//",
+            BinOp::BAnd => "&",
+            BinOp::BOr => "|",
+            BinOp::BXor => "~",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Concat => "..",
+            BinOp::Eq => "==",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Ne => "~=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+}
+
+impl UnOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnOp::Neg => "-",
+            UnOp::BNot => "~",
+            UnOp::Not => "not ",
+            UnOp::Len => "#",
+        }
+    }
+}
@@ -0,0 +1,664 @@
+// Programmatic construction of function prototypes
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use super::object::{LocVar, Proto, SyxValue, Upvalue};
+use super::opcodes::*;
+use super::errors::*;
+
+const MAXARG_C: u32 = BITMASK_C;
+const MAXARG_BX: u32 = BITMASK_BX;
+const MAXINDEXRK: u32 = BITMASK_IS_RK - 1;
+const MAXREGS: i64 = 255;
+
+// A jump target, created with `new_label` and placed with `mark`. Labels can
+// be used before they are marked; offsets are resolved by `build`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+// An RK operand: a register, or a constant that is interned on use
+pub enum Rk {
+    Register(u8),
+    Constant(SyxValue),
+}
+
+impl From<u8> for Rk {
+    fn from(register: u8) -> Rk {
+        Rk::Register(register)
+    }
+}
+
+impl From<SyxValue> for Rk {
+    fn from(value: SyxValue) -> Rk {
+        Rk::Constant(value)
+    }
+}
+
+// Constants are interned by exact value, unlike the compiler's cache floats
+// are compared by their bits so 0.0 and -0.0 stay apart.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Bool(bool),
+    Integer(i64),
+    Number(u64),
+    String(Vec<u8>),
+}
+
+impl ConstKey {
+    fn from_value(value: &SyxValue) -> ConstKey {
+        match value {
+            SyxValue::Nil => ConstKey::Nil,
+            SyxValue::Bool(b) => ConstKey::Bool(*b),
+            SyxValue::Integer(i) => ConstKey::Integer(*i),
+            SyxValue::Number(n) => ConstKey::Number(n.to_bits()),
+            SyxValue::String(s) => ConstKey::String(s.clone()),
+        }
+    }
+}
+
+pub struct ProtoBuilder {
+    proto: Proto,
+    cache: HashMap<ConstKey, u32>,
+    labels: Vec<Option<usize>>,     // label -> marked pc
+    jumps: Vec<(usize, Label)>,     // pc of a sBx instruction -> its target
+    line: Option<i32>,              // line tagged on new instructions
+    error: Option<String>,
+}
+
+impl ProtoBuilder {
+    pub fn new(source: &str) -> ProtoBuilder {
+        let mut proto = Proto::new();
+        proto.source = source.to_owned();
+        ProtoBuilder {
+            proto,
+            cache: HashMap::new(),
+            labels: Vec::new(),
+            jumps: Vec::new(),
+            line: None,
+            error: None,
+        }
+    }
+
+    // A builder for a nested function, which shares this one's source
+    pub fn function(&self) -> ProtoBuilder {
+        ProtoBuilder::new(&self.proto.source)
+    }
+
+    fn error(&mut self, message: String) {
+        if self.error.is_none() {
+            self.error = Some(message);
+        }
+    }
+
+    // Header
+
+    pub fn params(&mut self, numparams: u8) -> &mut Self {
+        self.proto.numparams = numparams;
+        self
+    }
+
+    pub fn is_vararg(&mut self) -> &mut Self {
+        self.proto.is_vararg = true;
+        self
+    }
+
+    pub fn lines(&mut self, linedefined: i32, lastlinedefined: i32) -> &mut Self {
+        self.proto.linedefined = linedefined;
+        self.proto.lastlinedefined = lastlinedefined;
+        self
+    }
+
+    // Every instruction emitted after this is tagged with `line`. Functions
+    // that never set a line are built without line info.
+    pub fn line(&mut self, line: i32) -> &mut Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn upvalue(&mut self, name: &[u8], instack: bool, idx: u8) -> u32 {
+        self.proto.upvalues.push(Upvalue {
+            name: name.to_vec(),
+            instack: instack as u8,
+            idx,
+            kind: 0,
+        });
+        self.proto.upvalues.len() as u32 - 1
+    }
+
+    pub fn local(&mut self, name: &[u8], startpc: usize, endpc: usize) -> &mut Self {
+        self.proto.locvars.push(LocVar {
+            varname: name.to_vec(),
+            startpc: startpc as i32,
+            endpc: endpc as i32,
+        });
+        self
+    }
+
+    // Index of `value` in the constant pool, adding it on first use
+    pub fn constant(&mut self, value: SyxValue) -> u32 {
+        let key = ConstKey::from_value(&value);
+        if let Some(&k) = self.cache.get(&key) {
+            return k;
+        }
+        let k = self.proto.constants.len() as u32;
+        self.proto.constants.push(value);
+        self.cache.insert(key, k);
+        k
+    }
+
+    // Code
+
+    // pc of the next instruction to be emitted
+    pub fn pc(&self) -> usize {
+        self.proto.instructions.len()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn mark(&mut self, label: Label) -> &mut Self {
+        match self.labels.get(label.0) {
+            Some(None) => self.labels[label.0] = Some(self.pc()),
+            Some(Some(_)) => self.error(format!("label {} marked twice", label.0)),
+            None => self.error(format!("unknown label {}", label.0)),
+        }
+        self
+    }
+
+    pub fn emit(&mut self, instr: Instruction) -> &mut Self {
+        self.proto.instructions.push(instr);
+        self.proto.lineinfo.push(self.line.unwrap_or(0));
+        self
+    }
+
+    fn abc(&mut self, op: OpCode, a: u8, b: u16, c: u16) -> &mut Self {
+        self.emit(Instruction::ABC { instruction: op, a, b, c })
+    }
+
+    fn abx(&mut self, op: OpCode, a: u8, bx: u32) -> &mut Self {
+        self.emit(Instruction::ABx { instruction: op, a, bx })
+    }
+
+    fn jump(&mut self, op: OpCode, a: u8, target: Label) -> &mut Self {
+        let pc = self.pc();
+        self.jumps.push((pc, target));
+        self.emit(Instruction::AsBx { instruction: op, a, sbx: 0 })
+    }
+
+    fn rk(&mut self, operand: impl Into<Rk>) -> u16 {
+        match operand.into() {
+            Rk::Register(r) => r as u16,
+            Rk::Constant(value) => {
+                let k = self.constant(value);
+                if k > MAXINDEXRK {
+                    self.error(format!(
+                        "constant {} does not fit an RK operand, load it into a register", k));
+                }
+                (k | BITMASK_IS_RK) as u16
+            }
+        }
+    }
+
+    pub fn move_(&mut self, a: u8, b: u8) -> &mut Self {
+        self.abc(OpCode::Move, a, b as u16, 0)
+    }
+
+    // LOADK, or LOADKX with an EXTRAARG once the pool outgrows Bx
+    pub fn load_k(&mut self, a: u8, value: SyxValue) -> &mut Self {
+        let k = self.constant(value);
+        if k <= MAXARG_BX {
+            self.abx(OpCode::LoadK, a, k)
+        } else {
+            self.abc(OpCode::LoadKX, a, 0, 0);
+            self.extra_arg(k)
+        }
+    }
+
+    pub fn load_bool(&mut self, a: u8, value: bool, skip: bool) -> &mut Self {
+        self.abc(OpCode::LoadBool, a, value as u16, skip as u16)
+    }
+
+    // R(a) .. R(a+b) := nil
+    pub fn load_nil(&mut self, a: u8, b: u8) -> &mut Self {
+        self.abc(OpCode::LoadNil, a, b as u16, 0)
+    }
+
+    pub fn get_upval(&mut self, a: u8, upvalue: u32) -> &mut Self {
+        self.abc(OpCode::GetUpval, a, upvalue as u16, 0)
+    }
+
+    pub fn get_tab_up(&mut self, a: u8, upvalue: u32, key: impl Into<Rk>) -> &mut Self {
+        let c = self.rk(key);
+        self.abc(OpCode::GetTabUp, a, upvalue as u16, c)
+    }
+
+    pub fn get_table(&mut self, a: u8, b: u8, key: impl Into<Rk>) -> &mut Self {
+        let c = self.rk(key);
+        self.abc(OpCode::GetTable, a, b as u16, c)
+    }
+
+    pub fn set_tab_up(&mut self, upvalue: u32, key: impl Into<Rk>,
+                      value: impl Into<Rk>) -> &mut Self {
+        let b = self.rk(key);
+        let c = self.rk(value);
+        self.abc(OpCode::SetTabUp, upvalue as u8, b, c)
+    }
+
+    pub fn set_upval(&mut self, a: u8, upvalue: u32) -> &mut Self {
+        self.abc(OpCode::SetUpval, a, upvalue as u16, 0)
+    }
+
+    pub fn set_table(&mut self, a: u8, key: impl Into<Rk>, value: impl Into<Rk>) -> &mut Self {
+        let b = self.rk(key);
+        let c = self.rk(value);
+        self.abc(OpCode::SetTable, a, b, c)
+    }
+
+    // sizes are the encoded "floating point bytes" of lobject.c, as in
+    // NEWTABLE's B and C
+    pub fn new_table(&mut self, a: u8, array: u16, hash: u16) -> &mut Self {
+        self.abc(OpCode::NewTable, a, array, hash)
+    }
+
+    pub fn self_load(&mut self, a: u8, b: u8, key: impl Into<Rk>) -> &mut Self {
+        let c = self.rk(key);
+        self.abc(OpCode::SelfLoad, a, b as u16, c)
+    }
+
+    fn arith(&mut self, op: OpCode, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        let b = self.rk(b);
+        let c = self.rk(c);
+        self.abc(op, a, b, c)
+    }
+
+    pub fn add(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Add, a, b, c)
+    }
+
+    pub fn sub(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Sub, a, b, c)
+    }
+
+    pub fn mul(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Mul, a, b, c)
+    }
+
+    pub fn mod_(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Mod, a, b, c)
+    }
+
+    pub fn pow(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Pow, a, b, c)
+    }
+
+    pub fn div(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Div, a, b, c)
+    }
+
+    pub fn idiv(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::IDiv, a, b, c)
+    }
+
+    pub fn band(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::BAnd, a, b, c)
+    }
+
+    pub fn bor(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::BOr, a, b, c)
+    }
+
+    pub fn bxor(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::BXOr, a, b, c)
+    }
+
+    pub fn shl(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Shl, a, b, c)
+    }
+
+    pub fn shr(&mut self, a: u8, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Shr, a, b, c)
+    }
+
+    pub fn unm(&mut self, a: u8, b: u8) -> &mut Self {
+        self.abc(OpCode::Unm, a, b as u16, 0)
+    }
+
+    pub fn bnot(&mut self, a: u8, b: u8) -> &mut Self {
+        self.abc(OpCode::BNot, a, b as u16, 0)
+    }
+
+    pub fn not(&mut self, a: u8, b: u8) -> &mut Self {
+        self.abc(OpCode::Not, a, b as u16, 0)
+    }
+
+    pub fn len(&mut self, a: u8, b: u8) -> &mut Self {
+        self.abc(OpCode::Len, a, b as u16, 0)
+    }
+
+    pub fn concat(&mut self, a: u8, b: u8, c: u8) -> &mut Self {
+        self.abc(OpCode::Concat, a, b as u16, c as u16)
+    }
+
+    // `close` is JMP's A: when not 0, upvalues >= R(close - 1) are closed
+    pub fn jmp(&mut self, close: u8, target: Label) -> &mut Self {
+        self.jump(OpCode::Jmp, close, target)
+    }
+
+    pub fn eq(&mut self, expect: bool, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Eq, expect as u8, b, c)
+    }
+
+    pub fn lt(&mut self, expect: bool, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Lt, expect as u8, b, c)
+    }
+
+    pub fn le(&mut self, expect: bool, b: impl Into<Rk>, c: impl Into<Rk>) -> &mut Self {
+        self.arith(OpCode::Le, expect as u8, b, c)
+    }
+
+    pub fn test(&mut self, a: u8, expect: bool) -> &mut Self {
+        self.abc(OpCode::Test, a, 0, expect as u16)
+    }
+
+    pub fn test_set(&mut self, a: u8, b: u8, expect: bool) -> &mut Self {
+        self.abc(OpCode::TestSet, a, b as u16, expect as u16)
+    }
+
+    // b and c are the argument and result counts plus one, 0 meaning "up to
+    // the top", the same as in the instruction
+    pub fn call(&mut self, a: u8, b: u16, c: u16) -> &mut Self {
+        self.abc(OpCode::Call, a, b, c)
+    }
+
+    pub fn tail_call(&mut self, a: u8, b: u16) -> &mut Self {
+        self.abc(OpCode::TailCall, a, b, 0)
+    }
+
+    pub fn return_(&mut self, a: u8, b: u16) -> &mut Self {
+        self.abc(OpCode::Return, a, b, 0)
+    }
+
+    pub fn for_loop(&mut self, a: u8, target: Label) -> &mut Self {
+        self.jump(OpCode::ForLoop, a, target)
+    }
+
+    pub fn for_prep(&mut self, a: u8, target: Label) -> &mut Self {
+        self.jump(OpCode::ForPrep, a, target)
+    }
+
+    pub fn tfor_call(&mut self, a: u8, c: u16) -> &mut Self {
+        self.abc(OpCode::TForCall, a, 0, c)
+    }
+
+    pub fn tfor_loop(&mut self, a: u8, target: Label) -> &mut Self {
+        self.jump(OpCode::TForLoop, a, target)
+    }
+
+    // c is the 1-based batch of LFIELDS_PER_FLUSH items, it moves to an
+    // EXTRAARG when it does not fit
+    pub fn set_list(&mut self, a: u8, b: u16, c: u32) -> &mut Self {
+        if c <= MAXARG_C {
+            self.abc(OpCode::SetList, a, b, c as u16)
+        } else {
+            self.abc(OpCode::SetList, a, b, 0);
+            self.extra_arg(c)
+        }
+    }
+
+    // Builds `child` and emits a CLOSURE for it into R(a)
+    pub fn closure(&mut self, a: u8, child: ProtoBuilder) -> &mut Self {
+        match child.build() {
+            Ok(proto) => self.proto.protos.push(proto),
+            Err(e) => {
+                self.error(format!("in nested function: {}", e));
+                return self;
+            }
+        }
+        let bx = self.proto.protos.len() as u32 - 1;
+        self.abx(OpCode::Closure, a, bx)
+    }
+
+    pub fn vararg(&mut self, a: u8, b: u16) -> &mut Self {
+        self.abc(OpCode::VarArg, a, b, 0)
+    }
+
+    pub fn extra_arg(&mut self, ax: u32) -> &mut Self {
+        self.emit(Instruction::Ax { instruction: OpCode::ExtraArg, ax })
+    }
+
+    pub fn build(mut self) -> Result<Proto> {
+        for &(pc, label) in &self.jumps {
+            let target = match self.labels.get(label.0) {
+                Some(&Some(target)) => target,
+                None => {
+                    self.error.get_or_insert(format!("unknown label {}", label.0));
+                    break;
+                }
+                Some(&None) => {
+                    let message = format!("label {} used at pc {} is never marked",
+                                          label.0, pc + 1);
+                    self.error.get_or_insert(message);
+                    break;
+                }
+            };
+            if let Instruction::AsBx { ref mut sbx, .. } = self.proto.instructions[pc] {
+                *sbx = target as i32 - (pc as i32 + 1);
+            }
+        }
+        if let Some(message) = self.error {
+            bail!(ErrorKind::BuildError(message));
+        }
+        // every operand, jump offsets included, has to fit its field
+        for instr in &self.proto.instructions {
+            Word::try_from(*instr)?;
+        }
+        let top = stack_top(&self.proto.instructions)
+            .max(self.proto.numparams as i64);
+        if top > MAXREGS {
+            bail!(ErrorKind::BuildError(
+                "function or expression needs too many registers".to_owned()));
+        }
+        self.proto.maxstacksize = top.max(2) as u8;
+        if self.line.is_none() {
+            self.proto.lineinfo.clear();
+        }
+        Ok(self.proto)
+    }
+}
+
+// Number of registers the instructions touch, counting the ranges implied by
+// calls, returns, loops and lists and not only the named registers.
+pub fn stack_top(instructions: &[Instruction]) -> i64 {
+    instructions.iter().map(|instr| {
+        let named = instr.operands().iter()
+            .filter_map(|arg| match *arg {
+                Argument::Register(n) => Some(n as i64 + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let implied = match *instr {
+            Instruction::ABC { instruction, a, b, c } => {
+                let (a, b, c) = (a as i64, b as i64, c as i64);
+                match instruction {
+                    OpCode::LoadNil | OpCode::SetList => a + b + 1,
+                    OpCode::Call => a + b.max(c - 1).max(1),
+                    OpCode::TailCall => a + b,
+                    OpCode::Return | OpCode::VarArg => a + b - 1,
+                    OpCode::SelfLoad => a + 2,
+                    OpCode::TForCall | OpCode::TForLoop51 => a + 3 + c,
+                    _ => 0,
+                }
+            }
+            Instruction::AsBx { instruction, a, .. } => match instruction {
+                OpCode::ForLoop | OpCode::ForPrep => a as i64 + 4,
+                OpCode::TForLoop => a as i64 + 2,
+                _ => 0,
+            },
+            _ => 0,
+        };
+        named.max(implied)
+    }).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::code::{self, get_opcode};
+    use super::super::dump::DumpState;
+
+    // local sum = 0
+    // for i = 1, 10 do
+    //   sum = sum + i
+    // end
+    // local f = function(x) return x * 2.5 end
+    // return f(sum)
+    const SOURCE: &[u8] = b"local sum = 0\nfor i = 1, 10 do\n  sum = sum + i\nend\n\
+                            local f = function(x) return x * 2.5 end\nreturn f(sum)\n";
+
+    #[test]
+    fn test_matches_compiler() {
+        let mut main = ProtoBuilder::new("@b.lua");
+        main.is_vararg();
+        main.upvalue(b"_ENV", true, 0);
+        let (body, exit) = (main.new_label(), main.new_label());
+        main.line(1).load_k(0, SyxValue::Integer(0))
+            .line(2).load_k(1, SyxValue::Integer(1))
+            .load_k(2, SyxValue::Integer(10))
+            .load_k(3, SyxValue::Integer(1))
+            .for_prep(1, exit);
+        main.mark(body).line(3).add(0, 0, 4);
+        main.mark(exit).line(2).for_loop(1, body);
+
+        let mut f = main.function();
+        f.params(1).lines(5, 5);
+        f.line(5).mul(1, 0, SyxValue::Number(2.5)).return_(1, 2).return_(0, 1);
+        f.local(b"x", 0, 3);
+        main.line(5).closure(1, f);
+        main.line(6).move_(2, 1).move_(3, 0).tail_call(2, 2).return_(2, 0).return_(0, 1);
+
+        main.local(b"sum", 1, 13)
+            .local(b"(for index)", 4, 7)
+            .local(b"(for limit)", 4, 7)
+            .local(b"(for step)", 4, 7)
+            .local(b"i", 5, 6)
+            .local(b"f", 8, 13);
+        let ours = main.build().unwrap();
+        assert_eq!(ours.constants.len(), 3);
+        assert_eq!(ours.maxstacksize, 5);
+        assert_eq!(ours.protos[0].maxstacksize, 2);
+
+        let theirs = code::compile(SOURCE, "@b.lua").unwrap();
+        assert!(DumpState::to_u8(&ours, false).unwrap()
+                == DumpState::to_u8(&theirs, false).unwrap());
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut b = ProtoBuilder::new("=test");
+        let (top, end) = (b.new_label(), b.new_label());
+        b.mark(top).test(0, false).jmp(0, end).jmp(0, top).mark(end).return_(0, 1);
+        let proto = b.build().unwrap();
+        let offsets: Vec<_> = proto.instructions.iter()
+            .filter_map(|i| match *i {
+                Instruction::AsBx { sbx, .. } => Some(sbx),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(offsets, vec![1, -3]);
+        assert!(proto.lineinfo.is_empty());
+
+        let mut b = ProtoBuilder::new("=test");
+        let missing = b.new_label();
+        b.jmp(0, missing);
+        assert_eq!(b.build().err().unwrap().to_string(),
+                   "could not build function: label 0 used at pc 1 is never marked");
+
+        let mut b = ProtoBuilder::new("=test");
+        let twice = b.new_label();
+        b.mark(twice).mark(twice);
+        assert!(b.build().is_err());
+
+        // a label of another builder
+        let mut parent = ProtoBuilder::new("=test");
+        let outer = parent.new_label();
+        let mut b = parent.function();
+        b.jmp(0, outer);
+        assert_eq!(b.build().err().unwrap().to_string(),
+                   "could not build function: unknown label 0");
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut b = ProtoBuilder::new("=test");
+        assert_eq!(b.constant(SyxValue::String(b"x".to_vec())), 0);
+        assert_eq!(b.constant(SyxValue::Integer(1)), 1);
+        assert_eq!(b.constant(SyxValue::Number(1.0)), 2);
+        assert_eq!(b.constant(SyxValue::Number(-0.0)), 3);
+        assert_eq!(b.constant(SyxValue::Number(0.0)), 4);
+        assert_eq!(b.constant(SyxValue::String(b"x".to_vec())), 0);
+        assert_eq!(b.constant(SyxValue::Integer(1)), 1);
+        b.get_tab_up(0, 0, SyxValue::String(b"x".to_vec()));
+        let proto = b.build().unwrap();
+        assert_eq!(proto.constants.len(), 5);
+        assert_eq!(proto.instructions[0].operands()[2], Argument::Constant(0));
+    }
+
+    #[test]
+    fn test_extra_arg() {
+        let mut b = ProtoBuilder::new("=test");
+        for i in 0..MAXARG_BX as i64 + 2 {
+            b.constant(SyxValue::Integer(i));
+        }
+        b.load_k(0, SyxValue::Integer(MAXARG_BX as i64))
+            .load_k(0, SyxValue::Integer(MAXARG_BX as i64 + 1))
+            .set_list(0, 1, MAXARG_C + 1);
+        let proto = b.build().unwrap();
+        let ops: Vec<_> = proto.instructions.iter().map(get_opcode).collect();
+        assert_eq!(ops, vec![OpCode::LoadK, OpCode::LoadKX, OpCode::ExtraArg,
+                             OpCode::SetList, OpCode::ExtraArg]);
+        assert_eq!(proto.instructions[1],
+                   Instruction::ABC { instruction: OpCode::LoadKX, a: 0, b: 0, c: 0 });
+        assert_eq!(proto.instructions[2],
+                   Instruction::Ax { instruction: OpCode::ExtraArg, ax: MAXARG_BX + 1 });
+
+        let mut b = ProtoBuilder::new("=test");
+        for i in 0..MAXINDEXRK as i64 + 1 {
+            b.constant(SyxValue::Integer(i));
+        }
+        b.add(0, 0, SyxValue::Integer(-1));
+        assert!(b.build().is_err());
+    }
+
+    #[test]
+    fn test_stack_size() {
+        let mut b = ProtoBuilder::new("=test");
+        b.call(3, 4, 6);
+        assert_eq!(b.build().unwrap().maxstacksize, 8);
+        let mut b = ProtoBuilder::new("=test");
+        b.params(7).tfor_call(1, 3);
+        assert_eq!(b.build().unwrap().maxstacksize, 7);
+        let mut b = ProtoBuilder::new("=test");
+        b.return_(0, 1);
+        assert_eq!(b.build().unwrap().maxstacksize, 2);
+        let mut b = ProtoBuilder::new("=test");
+        b.load_nil(250, 10);
+        assert!(b.build().is_err());
+    }
+
+    #[test]
+    fn test_nested_error() {
+        let mut main = ProtoBuilder::new("=test");
+        let mut child = main.function();
+        let label = child.new_label();
+        child.jmp(0, label);
+        main.closure(0, child);
+        let err = main.build().err().unwrap().to_string();
+        assert!(err.starts_with("could not build function: in nested function:"), "{}", err);
+    }
+}
@@ -0,0 +1,438 @@
+// Control flow graph of a function: its code split into basic blocks, with
+// the edges between them, their dominators and the natural loops they form
+#![allow(dead_code)]
+
+use super::code::get_opcode;
+use super::object::Proto;
+use super::opcodes::*;
+use super::opcodes54;
+use super::verify::opcode54;
+use super::errors::*;
+
+// Instructions `start..end`, entered only at `start` and left only after
+// `end - 1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+    // immediate dominator, None for the entry and for unreachable blocks
+    pub idom: Option<usize>,
+}
+
+// A loop found through its back edges, the edges from the latches to the
+// header, which dominates every block of the loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    pub header: usize,
+    pub latches: Vec<usize>,
+    pub blocks: Vec<usize>, // sorted, including the header
+}
+
+#[derive(Debug)]
+pub struct Graph {
+    pub blocks: Vec<Block>,
+    pub loops: Vec<Loop>,
+    reachable: Vec<bool>,
+}
+
+// Where control goes after an instruction. Only instructions that end their
+// block have anything but the next one as successor.
+struct Exit {
+    ends_block: bool,
+    successors: Vec<i64>,
+}
+
+impl Exit {
+    fn next(pc: usize) -> Exit {
+        Exit { ends_block: false, successors: vec![pc as i64 + 1] }
+    }
+
+    fn to(targets: &[i64]) -> Exit {
+        Exit { ends_block: true, successors: targets.to_vec() }
+    }
+}
+
+fn fail<T>(location: String, message: String) -> Result<T> {
+    Err(ErrorKind::FlowError(location, message).into())
+}
+
+// Test instructions skip the jump that follows them when their test fails,
+// a TailCall returns through the Return after it when it calls a C function
+fn exit(pc: usize, instr: &Instruction) -> Exit {
+    let next = pc as i64 + 1;
+    match *instr {
+        Instruction::AsBx { instruction: OpCode::Jmp, sbx, .. }
+        | Instruction::AsBx { instruction: OpCode::ForPrep, sbx, .. } => {
+            Exit::to(&[next + i64::from(sbx)])
+        }
+        Instruction::AsBx { instruction: OpCode::ForLoop, sbx, .. }
+        | Instruction::AsBx { instruction: OpCode::TForLoop, sbx, .. } => {
+            Exit::to(&[next + i64::from(sbx), next])
+        }
+        Instruction::ABC { instruction: OpCode::LoadBool, c, .. } if c != 0 => {
+            Exit::to(&[next + 1])
+        }
+        Instruction::ABC { instruction: OpCode::Return, .. } => Exit::to(&[]),
+        Instruction::ABC { instruction: OpCode::TailCall, .. } => Exit::to(&[next]),
+        _ if get_opcode(instr).mode().test => Exit::to(&[next, next + 1]),
+        _ => Exit::next(pc),
+    }
+}
+
+// 5.4 loops jump backwards with an unsigned Bx, and a ForPrep that does not
+// run its loop jumps past the ForLoop
+fn exit54(pc: usize, instr: &opcodes54::Instruction) -> Exit {
+    use self::opcodes54::{Instruction, OpCode};
+
+    let next = pc as i64 + 1;
+    match *instr {
+        Instruction::SJ { sj, .. } => Exit::to(&[next + i64::from(sj)]),
+        Instruction::ABx { instruction: OpCode::ForPrep, bx, .. } => {
+            Exit::to(&[next, next + i64::from(bx) + 1])
+        }
+        Instruction::ABx { instruction: OpCode::TForPrep, bx, .. } => {
+            Exit::to(&[next + i64::from(bx)])
+        }
+        Instruction::ABx { instruction: OpCode::ForLoop, bx, .. }
+        | Instruction::ABx { instruction: OpCode::TForLoop, bx, .. } => {
+            Exit::to(&[next - i64::from(bx), next])
+        }
+        Instruction::ABC { instruction: OpCode::LFalseSkip, .. } => Exit::to(&[next + 1]),
+        Instruction::ABC { instruction: OpCode::Return, .. }
+        | Instruction::ABC { instruction: OpCode::Return0, .. }
+        | Instruction::ABC { instruction: OpCode::Return1, .. } => Exit::to(&[]),
+        Instruction::ABC { instruction: OpCode::TailCall, .. } => Exit::to(&[next]),
+        _ if opcode54(instr).mode().test => Exit::to(&[next, next + 1]),
+        _ => Exit::next(pc),
+    }
+}
+
+// Builds the graph of a single function, its nested functions get graphs
+// of their own
+pub fn build(proto: &Proto) -> Result<Graph> {
+    let exits: Vec<Exit> = if proto.version == Version::Lua54 {
+        proto.instructions54.iter().enumerate().map(|(pc, x)| exit54(pc, x)).collect()
+    } else {
+        proto.instructions.iter().enumerate().map(|(pc, x)| exit(pc, x)).collect()
+    };
+    if exits.is_empty() {
+        return fail(proto.source.clone(), "function has no code".to_owned());
+    }
+
+    // a block starts at the entry, at every target and after every
+    // instruction that ends one
+    let mut leader = vec![false; exits.len()];
+    leader[0] = true;
+    for (pc, exit) in exits.iter().enumerate() {
+        for &target in &exit.successors {
+            if target < 0 || target >= exits.len() as i64 {
+                return fail(format!("pc {}", pc + 1), format!(
+                    "control flows to {} outside of the code", target + 1));
+            }
+            if exit.ends_block {
+                leader[target as usize] = true;
+            }
+        }
+        if exit.ends_block && pc + 1 < exits.len() {
+            leader[pc + 1] = true;
+        }
+    }
+
+    let starts: Vec<usize> = (0..exits.len()).filter(|&pc| leader[pc]).collect();
+    let mut block_of = vec![0; exits.len()];
+    let mut blocks: Vec<Block> = Vec::with_capacity(starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).cloned().unwrap_or(exits.len());
+        for block in &mut block_of[start..end] {
+            *block = n;
+        }
+        blocks.push(Block {
+            start, end, successors: Vec::new(), predecessors: Vec::new(), idom: None,
+        });
+    }
+    for n in 0..blocks.len() {
+        let last = &exits[blocks[n].end - 1];
+        for &target in &last.successors {
+            let successor = block_of[target as usize];
+            if !blocks[n].successors.contains(&successor) {
+                blocks[n].successors.push(successor);
+                blocks[successor].predecessors.push(n);
+            }
+        }
+    }
+
+    let mut graph = Graph { blocks, loops: Vec::new(), reachable: Vec::new() };
+    graph.compute_dominators();
+    graph.compute_loops();
+    Ok(graph)
+}
+
+impl Graph {
+    // Index of the block holding `pc`
+    pub fn block_of(&self, pc: usize) -> Option<usize> {
+        match self.blocks.binary_search_by(|x| x.start.cmp(&pc)) {
+            Ok(n) => Some(n),
+            Err(0) => None,
+            Err(n) if pc < self.blocks[n - 1].end => Some(n - 1),
+            Err(_) => None,
+        }
+    }
+
+    pub fn is_reachable(&self, block: usize) -> bool {
+        self.reachable[block]
+    }
+
+    // Every path from the entry to `b` goes through `a`. Unreachable blocks
+    // dominate and are dominated by nothing.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if !self.reachable[a] || !self.reachable[b] {
+            return false;
+        }
+        let mut current = Some(b);
+        while let Some(n) = current {
+            if n == a {
+                return true;
+            }
+            current = self.blocks[n].idom;
+        }
+        false
+    }
+
+    // Reachable blocks, each after all of its predecessors except the ones
+    // reached through a back edge
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        // explicit stack of (block, next successor to visit), code can nest
+        // deep enough to overflow the call stack
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some(&mut (n, ref mut next)) = stack.last_mut() {
+            if let Some(&successor) = self.blocks[n].successors.get(*next) {
+                *next += 1;
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            } else {
+                order.push(n);
+                stack.pop();
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // "A Simple, Fast Dominance Algorithm" by Cooper, Harvey and Kennedy
+    fn compute_dominators(&mut self) {
+        let order = self.reverse_postorder();
+        let mut position = vec![usize::MAX; self.blocks.len()];
+        for (i, &n) in order.iter().enumerate() {
+            position[n] = i;
+        }
+        self.reachable = position.iter().map(|&x| x != usize::MAX).collect();
+
+        let mut idom: Vec<Option<usize>> = vec![None; self.blocks.len()];
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &n in order.iter().skip(1) {
+                let mut new_idom = None;
+                for &p in &self.blocks[n].predecessors {
+                    if idom[p].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(mut other) => {
+                            let mut finger = p;
+                            while finger != other {
+                                while position[finger] > position[other] {
+                                    finger = idom[finger].unwrap();
+                                }
+                                while position[other] > position[finger] {
+                                    other = idom[other].unwrap();
+                                }
+                            }
+                            finger
+                        }
+                    });
+                }
+                if new_idom != idom[n] {
+                    idom[n] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[0] = None;
+        for (block, dominator) in self.blocks.iter_mut().zip(idom) {
+            block.idom = dominator;
+        }
+    }
+
+    // Natural loops, one per header with the back edges that reach it
+    fn compute_loops(&mut self) {
+        let mut loops: Vec<Loop> = Vec::new();
+        for n in 0..self.blocks.len() {
+            for &header in &self.blocks[n].successors {
+                if !self.dominates(header, n) {
+                    continue;
+                }
+                match loops.iter().position(|x| x.header == header) {
+                    Some(i) => loops[i].latches.push(n),
+                    None => loops.push(Loop { header, latches: vec![n], blocks: Vec::new() }),
+                }
+            }
+        }
+        // the body is everything that reaches a latch without going
+        // through the header
+        for l in &mut loops {
+            let mut body = vec![false; self.blocks.len()];
+            body[l.header] = true;
+            let mut stack = l.latches.clone();
+            while let Some(n) = stack.pop() {
+                if body[n] {
+                    continue;
+                }
+                body[n] = true;
+                stack.extend(self.blocks[n].predecessors.iter().filter(|&&x| self.reachable[x]));
+            }
+            l.blocks = (0..body.len()).filter(|&x| body[x]).collect();
+        }
+        loops.sort_by_key(|x| x.header);
+        self.loops = loops;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::code::compile;
+    use super::super::undump::LoadState;
+
+    // Instructions of each block, as ranges of pcs
+    fn ranges(graph: &Graph) -> Vec<(usize, usize)> {
+        graph.blocks.iter().map(|x| (x.start, x.end)).collect()
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let proto = compile(b"local a = 1 local b = a + 2 return b", "=test").unwrap();
+        let graph = build(&proto).unwrap();
+        // the return every function ends with is never reached after ours
+        let len = proto.instructions.len();
+        assert_eq!(ranges(&graph), vec![(0, len - 1), (len - 1, len)]);
+        assert!(graph.blocks[0].successors.is_empty());
+        assert_eq!(graph.blocks[0].idom, None);
+        assert!(!graph.is_reachable(1));
+        assert!(!graph.dominates(0, 1));
+        assert!(graph.loops.is_empty());
+    }
+
+    #[test]
+    fn test_branches() {
+        let proto = compile(b"local a, b = ... if a == b then a = 1 else a = 2 end return a",
+                            "=test").unwrap();
+        let graph = build(&proto).unwrap();
+        // the test and its jump, both arms and the return they meet at
+        let entry = &graph.blocks[0];
+        assert_eq!(get_opcode(&proto.instructions[entry.end - 1]), OpCode::Eq);
+        assert_eq!(entry.successors.len(), 2);
+        let join = graph.block_of(proto.instructions.len() - 2).unwrap();
+        assert_eq!(graph.blocks[join].predecessors.len(), 2);
+        assert_eq!(graph.blocks[join].idom, Some(0));
+        for n in (0..graph.blocks.len()).filter(|&x| graph.is_reachable(x)) {
+            assert!(graph.dominates(0, n));
+            assert_eq!(graph.dominates(n, join), n == 0 || n == join);
+        }
+        assert!(graph.loops.is_empty());
+    }
+
+    #[test]
+    fn test_loops() {
+        let source = b"local t = {} \
+                       for i = 1, 10 do t[i] = i end \
+                       for k, v in pairs(t) do \
+                           while v > 0 do v = v - 1 end \
+                       end \
+                       return t";
+        let proto = compile(source, "=test").unwrap();
+        let graph = build(&proto).unwrap();
+        let pc_of = |op| proto.instructions.iter().position(|x| get_opcode(x) == op).unwrap();
+
+        // ForPrep jumps to the ForLoop, which goes back to the body
+        let for_prep = graph.block_of(pc_of(OpCode::ForPrep)).unwrap();
+        let for_loop = graph.block_of(pc_of(OpCode::ForLoop)).unwrap();
+        let numeric = graph.loops.iter().find(|x| x.header == for_loop).unwrap();
+        assert_eq!(graph.blocks[for_prep].successors, vec![for_loop]);
+        assert_eq!(numeric.latches, vec![for_loop - 1]);
+        assert_eq!(numeric.blocks, vec![for_loop - 1, for_loop]);
+
+        // TForCall falls into TForLoop, the while loop nests in the generic one
+        assert_eq!(graph.loops.len(), 3);
+        let tfor_call = graph.block_of(pc_of(OpCode::TForCall)).unwrap();
+        assert_eq!(graph.block_of(pc_of(OpCode::TForLoop)), Some(tfor_call));
+        let generic = graph.loops.iter().find(|x| x.header == tfor_call).unwrap();
+        let inner = graph.loops.iter()
+            .find(|x| x.header != numeric.header && x.header != generic.header)
+            .unwrap();
+        assert!(graph.dominates(inner.header, inner.latches[0]));
+        assert!(inner.blocks.iter().all(|x| generic.blocks.contains(x)));
+        assert!(!numeric.blocks.iter().any(|x| generic.blocks.contains(x)));
+
+        for l in &graph.loops {
+            for &latch in &l.latches {
+                assert!(graph.blocks[latch].successors.contains(&l.header));
+            }
+        }
+    }
+
+    #[test]
+    fn test_skips() {
+        // LoadBool with C skips the next instruction
+        let proto = compile(b"local a, b = ... return a < b", "=test").unwrap();
+        let graph = build(&proto).unwrap();
+        let skip = proto.instructions.iter().position(|x| match *x {
+            Instruction::ABC { instruction: OpCode::LoadBool, c, .. } => c != 0,
+            _ => false,
+        }).unwrap();
+        let block = &graph.blocks[graph.block_of(skip).unwrap()];
+        assert_eq!(block.end, skip + 1);
+        assert_eq!(block.successors, vec![graph.block_of(skip + 2).unwrap()]);
+    }
+
+    #[test]
+    fn test_versions() {
+        let chunks: [&[u8]; 3] = [
+            include_bytes!("../tests/fixtures/loops.luac"),
+            include_bytes!("../tests/fixtures/legacy.51.luac"),
+            include_bytes!("../tests/fixtures/lua54.luac"),
+        ];
+        for chunk in &chunks {
+            let proto = LoadState::from_slice(chunk, "test").unwrap();
+            let mut stack = vec![&proto];
+            while let Some(f) = stack.pop() {
+                let graph = build(f).unwrap();
+                let len = graph.blocks.last().unwrap().end;
+                assert_eq!(len, f.instructions.len() + f.instructions54.len());
+                for (n, block) in graph.blocks.iter().enumerate() {
+                    for &s in &block.successors {
+                        assert!(graph.blocks[s].predecessors.contains(&n));
+                    }
+                    if let Some(idom) = block.idom {
+                        assert!(graph.dominates(idom, n));
+                    }
+                }
+                stack.extend(f.protos.iter());
+            }
+        }
+
+        let proto = LoadState::from_slice(include_bytes!("../tests/fixtures/lua54.luac"),
+                                          "lua54").unwrap();
+        let graph = build(&proto).unwrap();
+        assert!(!graph.loops.is_empty());
+    }
+}
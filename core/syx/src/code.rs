@@ -0,0 +1,2470 @@
+// Code generator, mirrors lcode.c and the code emitting half of lparser.c
+//
+// The AST is walked in the same order lparser.c parses the source. The token
+// stream is replayed alongside it, so that `consumed` always counts the tokens
+// lparser.c would have consumed when it emits an instruction; this is what
+// makes `lineinfo` (taken from `ls->lastline`) identical to luac's output.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+use super::ast::*;
+use super::errors::*;
+use super::lex::{tokenize, Lexeme, Token};
+use super::object::{LocVar, Proto, SyxValue, Upvalue};
+use super::opcodes::*;
+use super::parser;
+
+const NO_JUMP: i32 = -1;
+const NO_REG: i32 = BITMASK_A as i32;
+
+const MAXREGS: i32 = 255;
+const MAXVARS: usize = 200;
+const MAXUPVAL: usize = 255;
+
+const MAXARG_B: i32 = BITMASK_B as i32;
+const MAXARG_C: i32 = BITMASK_C as i32;
+const MAXARG_BX: i32 = BITMASK_BX as i32;
+const MAXARG_AX: i32 = BITMASK_AX as i32;
+const MAXINDEXRK: i32 = BITMASK_IS_RK as i32 - 1;
+
+const LFIELDS_PER_FLUSH: i32 = 50;
+const MULTRET: i32 = -1;
+
+const ENV: &[u8] = b"_ENV";
+
+fn is_k(x: i32) -> bool {
+    x & BITMASK_IS_RK as i32 != 0
+}
+
+fn rk_constant(k: i32) -> i32 {
+    k | BITMASK_IS_RK as i32
+}
+
+// Instruction helpers, every field is handled as an i32 like lcode.c does
+
+fn create_abc(op: OpCode, a: i32, b: i32, c: i32) -> Instruction {
+    Instruction::ABC { instruction: op, a: a as u8, b: b as u16, c: c as u16 }
+}
+
+fn create_abx(op: OpCode, a: i32, bx: i32) -> Instruction {
+    Instruction::ABx { instruction: op, a: a as u8, bx: bx as u32 }
+}
+
+fn create_asbx(op: OpCode, a: i32, sbx: i32) -> Instruction {
+    Instruction::AsBx { instruction: op, a: a as u8, sbx }
+}
+
+fn create_ax(op: OpCode, ax: i32) -> Instruction {
+    Instruction::Ax { instruction: op, ax: ax as u32 }
+}
+
+pub fn get_opcode(i: &Instruction) -> OpCode {
+    match *i {
+        | Instruction::ABC { instruction, .. }
+        | Instruction::ABx { instruction, .. }
+        | Instruction::AsBx { instruction, .. }
+        | Instruction::Ax { instruction, .. } => instruction,
+    }
+}
+
+fn set_opcode(i: &mut Instruction, op: OpCode) {
+    match i {
+        | Instruction::ABC { instruction, .. }
+        | Instruction::ABx { instruction, .. }
+        | Instruction::AsBx { instruction, .. }
+        | Instruction::Ax { instruction, .. } => *instruction = op,
+    }
+}
+
+fn get_arg_a(i: &Instruction) -> i32 {
+    match *i {
+        | Instruction::ABC { a, .. }
+        | Instruction::ABx { a, .. }
+        | Instruction::AsBx { a, .. } => i32::from(a),
+        Instruction::Ax { .. } => 0,
+    }
+}
+
+fn set_arg_a(i: &mut Instruction, value: i32) {
+    match i {
+        | Instruction::ABC { a, .. }
+        | Instruction::ABx { a, .. }
+        | Instruction::AsBx { a, .. } => *a = value as u8,
+        Instruction::Ax { .. } => {}
+    }
+}
+
+fn get_arg_b(i: &Instruction) -> i32 {
+    match *i {
+        Instruction::ABC { b, .. } => i32::from(b),
+        _ => 0,
+    }
+}
+
+fn set_arg_b(i: &mut Instruction, value: i32) {
+    if let Instruction::ABC { b, .. } = i {
+        *b = value as u16;
+    }
+}
+
+fn get_arg_c(i: &Instruction) -> i32 {
+    match *i {
+        Instruction::ABC { c, .. } => i32::from(c),
+        _ => 0,
+    }
+}
+
+fn set_arg_c(i: &mut Instruction, value: i32) {
+    if let Instruction::ABC { c, .. } = i {
+        *c = value as u16;
+    }
+}
+
+fn get_arg_sbx(i: &Instruction) -> i32 {
+    match *i {
+        Instruction::AsBx { sbx, .. } => sbx,
+        _ => 0,
+    }
+}
+
+fn set_arg_sbx(i: &mut Instruction, value: i32) {
+    if let Instruction::AsBx { sbx, .. } = i {
+        *sbx = value;
+    }
+}
+
+// converts an integer to a "floating point byte", luaO_int2fb in lobject.c
+fn int2fb(mut x: u32) -> i32 {
+    let mut e = 0;
+    if x < 8 {
+        return x as i32;
+    }
+    while x >= (8 << 4) {
+        x = (x + 0xf) >> 4;
+        e += 4;
+    }
+    while x >= (8 << 1) {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    ((e + 1) << 3) | (x as i32 - 8)
+}
+
+// Keys of the constant cache, `ls->h` in lparser.c. Floats are stored by
+// their bits with -0.0 folded into 0.0, which is what the table lookup does.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Bool(bool),
+    Integer(i64),
+    Number(u64),
+    String(Vec<u8>),
+}
+
+impl ConstKey {
+    fn number(n: f64) -> ConstKey {
+        ConstKey::Number(if n == 0.0 { 0 } else { n.to_bits() })
+    }
+
+    fn from_value(value: &SyxValue) -> ConstKey {
+        match value {
+            SyxValue::Nil => ConstKey::Nil,
+            SyxValue::Bool(b) => ConstKey::Bool(*b),
+            SyxValue::Integer(i) => ConstKey::Integer(*i),
+            SyxValue::Number(n) => ConstKey::number(*n),
+            SyxValue::String(s) => ConstKey::String(s.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExpKind {
+    Void, // empty expression list
+    Nil,
+    True,
+    False,
+    K(i32),        // constant index
+    KFlt(f64),
+    KInt(i64),
+    NonReloc(i32), // value in a fixed register
+    Local(i32),    // register of a local variable
+    Upval(i32),    // upvalue index
+    // `t` is a register (or upvalue, unless `vt_local`), `idx` is an RK
+    Indexed { t: i32, idx: i32, vt_local: bool },
+    Jmp(i32),       // pc of the test's jump
+    Relocable(i32), // pc of the instruction whose A is still free
+    Call(i32),      // pc of the call
+    VarArg(i32),    // pc of the vararg
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ExpDesc {
+    k: ExpKind,
+    t: i32, // patch list of 'exit when true'
+    f: i32, // patch list of 'exit when false'
+}
+
+impl ExpDesc {
+    fn new(k: ExpKind) -> ExpDesc {
+        ExpDesc { k, t: NO_JUMP, f: NO_JUMP }
+    }
+
+    fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+
+    fn info(&self) -> i32 {
+        match self.k {
+            | ExpKind::K(info)
+            | ExpKind::NonReloc(info)
+            | ExpKind::Local(info)
+            | ExpKind::Upval(info)
+            | ExpKind::Jmp(info)
+            | ExpKind::Relocable(info)
+            | ExpKind::Call(info)
+            | ExpKind::VarArg(info) => info,
+            _ => panic!("expression has no info field: {:?}", self.k),
+        }
+    }
+
+    fn has_multret(&self) -> bool {
+        matches!(self.k, ExpKind::Call(_) | ExpKind::VarArg(_))
+    }
+
+    // numeric constant without jumps, see tonumeral in lcode.c
+    fn numeral(&self) -> Option<Numeral> {
+        if self.has_jumps() {
+            return None;
+        }
+        match self.k {
+            ExpKind::KInt(i) => Some(Numeral::Integer(i)),
+            ExpKind::KFlt(n) => Some(Numeral::Number(n)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Numeral {
+    Integer(i64),
+    Number(f64),
+}
+
+impl Numeral {
+    fn to_number(self) -> f64 {
+        match self {
+            Numeral::Integer(i) => i as f64,
+            Numeral::Number(n) => n,
+        }
+    }
+
+    // only floats with an exact integer value convert
+    fn to_integer(self) -> Option<i64> {
+        match self {
+            Numeral::Integer(i) => Some(i),
+            Numeral::Number(n) => {
+                let limit = -(i64::MIN as f64);
+                if n.floor() == n && (-limit..limit).contains(&n) {
+                    Some(n as i64)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+// operators that can be folded, in LUA_OPADD order
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arith {
+    Add, Sub, Mul, Mod, Pow, Div, IDiv,
+    BAnd, BOr, BXor, Shl, Shr, Unm, BNot,
+}
+
+impl Arith {
+    fn from_binop(op: BinOp) -> Option<Arith> {
+        Some(match op {
+            BinOp::Add => Arith::Add,
+            BinOp::Sub => Arith::Sub,
+            BinOp::Mul => Arith::Mul,
+            BinOp::Mod => Arith::Mod,
+            BinOp::Pow => Arith::Pow,
+            BinOp::Div => Arith::Div,
+            BinOp::IDiv => Arith::IDiv,
+            BinOp::BAnd => Arith::BAnd,
+            BinOp::BOr => Arith::BOr,
+            BinOp::BXor => Arith::BXor,
+            BinOp::Shl => Arith::Shl,
+            BinOp::Shr => Arith::Shr,
+            _ => return None,
+        })
+    }
+
+    fn opcode(self) -> OpCode {
+        match self {
+            Arith::Add => OpCode::Add,
+            Arith::Sub => OpCode::Sub,
+            Arith::Mul => OpCode::Mul,
+            Arith::Mod => OpCode::Mod,
+            Arith::Pow => OpCode::Pow,
+            Arith::Div => OpCode::Div,
+            Arith::IDiv => OpCode::IDiv,
+            Arith::BAnd => OpCode::BAnd,
+            Arith::BOr => OpCode::BOr,
+            Arith::BXor => OpCode::BXOr,
+            Arith::Shl => OpCode::Shl,
+            Arith::Shr => OpCode::Shr,
+            Arith::Unm => OpCode::Unm,
+            Arith::BNot => OpCode::BNot,
+        }
+    }
+
+    fn is_bitwise(self) -> bool {
+        matches!(self, Arith::BAnd | Arith::BOr | Arith::BXor
+                     | Arith::Shl | Arith::Shr | Arith::BNot)
+    }
+
+    // whether folding is safe, validop in lcode.c
+    fn valid(self, v1: Numeral, v2: Numeral) -> bool {
+        match self {
+            _ if self.is_bitwise() => {
+                v1.to_integer().is_some() && v2.to_integer().is_some()
+            }
+            Arith::Div | Arith::IDiv | Arith::Mod => v2.to_number() != 0.0,
+            _ => true,
+        }
+    }
+
+    // luaO_arith in lobject.c, division by zero was ruled out by `valid`
+    fn apply(self, v1: Numeral, v2: Numeral) -> Numeral {
+        if self.is_bitwise() {
+            let (a, b) = (v1.to_integer().unwrap(), v2.to_integer().unwrap());
+            return Numeral::Integer(self.integer(a, b));
+        }
+        match (self, v1, v2) {
+            (Arith::Div, _, _) | (Arith::Pow, _, _) => {}
+            (_, Numeral::Integer(a), Numeral::Integer(b)) => {
+                return Numeral::Integer(self.integer(a, b));
+            }
+            _ => {}
+        }
+        Numeral::Number(self.number(v1.to_number(), v2.to_number()))
+    }
+
+    fn integer(self, a: i64, b: i64) -> i64 {
+        match self {
+            Arith::Add => a.wrapping_add(b),
+            Arith::Sub => a.wrapping_sub(b),
+            Arith::Mul => a.wrapping_mul(b),
+            Arith::Mod => {
+                if b == -1 {
+                    0
+                } else {
+                    let r = a % b;
+                    if r != 0 && (r ^ b) < 0 { r + b } else { r }
+                }
+            }
+            Arith::IDiv => {
+                if b == -1 {
+                    a.wrapping_neg()
+                } else {
+                    let q = a / b;
+                    if (a ^ b) < 0 && a % b != 0 { q - 1 } else { q }
+                }
+            }
+            Arith::BAnd => a & b,
+            Arith::BOr => a | b,
+            Arith::BXor => a ^ b,
+            Arith::Shl => shift_left(a, b),
+            Arith::Shr => shift_left(a, b.wrapping_neg()),
+            Arith::Unm => 0i64.wrapping_sub(a),
+            Arith::BNot => !a,
+            Arith::Pow | Arith::Div => unreachable!(),
+        }
+    }
+
+    fn number(self, a: f64, b: f64) -> f64 {
+        match self {
+            Arith::Add => a + b,
+            Arith::Sub => a - b,
+            Arith::Mul => a * b,
+            Arith::Div => a / b,
+            Arith::Pow => if b == 2.0 { a * a } else { a.powf(b) },
+            Arith::IDiv => (a / b).floor(),
+            Arith::Unm => -a,
+            Arith::Mod => {
+                let m = a % b;
+                if (m > 0.0 && b < 0.0) || (m < 0.0 && b != m && b > 0.0) {
+                    m + b
+                } else {
+                    m
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+// luaV_shiftl in lvm.c
+fn shift_left(x: i64, y: i64) -> i64 {
+    if y < 0 {
+        if y <= -64 { 0 } else { ((x as u64) >> -y) as i64 }
+    } else if y >= 64 {
+        0
+    } else {
+        ((x as u64) << y) as i64
+    }
+}
+
+struct BlockCnt {
+    firstlabel: usize, // index of first label in this block
+    firstgoto: usize,  // index of first pending goto in this block
+    nactvar: i32,      // number of active locals outside the block
+    upval: bool,       // some variable in the block is an upvalue
+    isloop: bool,
+}
+
+struct LabelDesc {
+    name: Vec<u8>,
+    pc: i32,
+    line: i32,
+    nactvar: i32, // local level where it appears in current block
+}
+
+struct FuncState {
+    f: Proto,
+    blocks: Vec<BlockCnt>,
+    lasttarget: i32,   // 'label' of last 'jump label'
+    jpc: i32,          // list of pending jumps to 'pc'
+    nactvar: i32,      // number of active local variables
+    freereg: i32,      // first free register
+    actvar: Vec<usize>, // indices into `f.locvars` of declared locals
+}
+
+impl FuncState {
+    fn pc(&self) -> i32 {
+        self.f.instructions.len() as i32
+    }
+
+    fn locvar(&mut self, i: i32) -> &mut LocVar {
+        let index = self.actvar[i as usize];
+        &mut self.f.locvars[index]
+    }
+}
+
+struct ConsControl {
+    v: ExpDesc,   // last list item read
+    t: i32,       // register of the table
+    nh: i32,      // total number of 'record' elements
+    na: i32,      // total number of array elements
+    tostore: i32, // number of array elements pending to be stored
+}
+
+struct Compiler {
+    tokens: Vec<Lexeme>,
+    consumed: usize, // tokens consumed so far, the last one gives the line
+    lookahead: usize, // token consumed while the next one was already read
+    chunkname: String,
+    fs: Vec<FuncState>,
+    labels: Vec<LabelDesc>, // active labels
+    gotos: Vec<LabelDesc>,  // pending gotos
+    cache: HashMap<ConstKey, usize>,
+    error: Option<String>,
+}
+
+// Compiles a chunk into its main function. `chunkname` is the usual Lua
+// source name, e.g. "@file.lua" or "=stdin".
+pub fn compile(source: &[u8], chunkname: &str) -> Result<Proto> {
+    let (block, diagnostics) = parser::parse(source);
+    if let Some(diagnostic) = diagnostics.first() {
+        bail!(ErrorKind::CompileError(
+            format!("{}:{}", chunk_id(chunkname), diagnostic)));
+    }
+    let (tokens, _) = tokenize(source);
+    let mut compiler = Compiler {
+        tokens,
+        consumed: 0,
+        lookahead: usize::MAX,
+        chunkname: chunkname.to_owned(),
+        fs: Vec::new(),
+        labels: Vec::new(),
+        gotos: Vec::new(),
+        cache: HashMap::new(),
+        error: None,
+    };
+    let proto = compiler.main_func(&block);
+    match compiler.error {
+        Some(message) => Err(ErrorKind::CompileError(message).into()),
+        None => Ok(proto),
+    }
+}
+
+// printable form of a chunk name, see luaO_chunkid in lobject.c
+fn chunk_id(chunkname: &str) -> String {
+    if let Some(name) = chunkname.strip_prefix('=') {
+        name.to_owned()
+    } else if let Some(name) = chunkname.strip_prefix('@') {
+        name.to_owned()
+    } else {
+        let line = chunkname.lines().next().unwrap_or("");
+        if line.len() < chunkname.len() {
+            format!("[string \"{}...\"]", line)
+        } else {
+            format!("[string \"{}\"]", line)
+        }
+    }
+}
+
+impl Compiler {
+    // Token replay
+
+    fn current(&self) -> &Token {
+        &self.tokens[self.consumed].token
+    }
+
+    // luaX_next
+    fn skip(&mut self) {
+        if self.consumed + 1 < self.tokens.len() {
+            self.consumed += 1;
+        }
+    }
+
+    // consumes every token that ends before `offset`
+    fn consume_to(&mut self, offset: usize) {
+        let last = self.tokens.len() - 1; // never consume <eof>
+        let n = self.tokens[..last].partition_point(|t| t.span.end <= offset);
+        if n > self.consumed {
+            self.consumed = n;
+        }
+    }
+
+    // `ls->lastline`, line of the last token consumed
+    fn last_line(&self) -> i32 {
+        match self.consumed {
+            0 => 1,
+            // llex.c was already past the following token
+            n if n - 1 == self.lookahead => {
+                self.tokens[n].span.last_line as i32
+            }
+            n => self.tokens[n - 1].span.last_line as i32,
+        }
+    }
+
+    // `ls->linenumber`, line of the current token
+    fn line_number(&self) -> i32 {
+        let n = if self.consumed == self.lookahead {
+            self.consumed + 1
+        } else {
+            self.consumed
+        };
+        self.tokens[n].span.last_line as i32
+    }
+
+    fn error(&mut self, message: &str) {
+        if self.error.is_none() {
+            self.error = Some(format!("{}:{}: {}", chunk_id(&self.chunkname),
+                                      self.line_number(), message));
+        }
+    }
+
+    fn error_limit(&mut self, limit: usize, what: &str) {
+        let line = self.fs().f.linedefined;
+        let location = if line == 0 {
+            "main function".to_owned()
+        } else {
+            format!("function at line {}", line)
+        };
+        self.error(&format!("too many {} (limit is {}) in {}",
+                            what, limit, location));
+    }
+
+    fn fs(&mut self) -> &mut FuncState {
+        self.fs.last_mut().unwrap()
+    }
+
+    fn pc(&self) -> i32 {
+        self.fs.last().unwrap().pc()
+    }
+
+    fn instruction(&mut self, pc: i32) -> &mut Instruction {
+        &mut self.fs().f.instructions[pc as usize]
+    }
+
+    // Code emission, lcode.c
+
+    fn code(&mut self, i: Instruction) -> i32 {
+        self.discharge_jpc();
+        let line = self.last_line();
+        let fs = self.fs();
+        fs.f.instructions.push(i);
+        fs.f.lineinfo.push(line);
+        fs.pc() - 1
+    }
+
+    fn code_abc(&mut self, op: OpCode, a: i32, b: i32, c: i32) -> i32 {
+        self.code(create_abc(op, a, b, c))
+    }
+
+    fn code_abx(&mut self, op: OpCode, a: i32, bx: i32) -> i32 {
+        self.code(create_abx(op, a, bx))
+    }
+
+    fn code_asbx(&mut self, op: OpCode, a: i32, sbx: i32) -> i32 {
+        self.code(create_asbx(op, a, sbx))
+    }
+
+    fn code_extra_arg(&mut self, a: i32) -> i32 {
+        self.code(create_ax(OpCode::ExtraArg, a))
+    }
+
+    // load constant `k` into `reg`, using LoadKX when Bx is too small
+    fn code_k(&mut self, reg: i32, k: i32) -> i32 {
+        if k <= MAXARG_BX {
+            self.code_abx(OpCode::LoadK, reg, k)
+        } else {
+            let p = self.code_abc(OpCode::LoadKX, reg, 0, 0);
+            self.code_extra_arg(k);
+            p
+        }
+    }
+
+    fn fix_line(&mut self, line: i32) {
+        let fs = self.fs();
+        *fs.f.lineinfo.last_mut().unwrap() = line;
+    }
+
+    fn code_nil(&mut self, mut from: i32, n: i32) {
+        let mut l = from + n - 1; // last register to set nil
+        let fs = self.fs();
+        if fs.pc() > fs.lasttarget && fs.pc() > 0 {
+            let previous = fs.f.instructions.last_mut().unwrap();
+            if get_opcode(previous) == OpCode::LoadNil {
+                let pfrom = get_arg_a(previous);
+                let pl = pfrom + get_arg_b(previous);
+                if (pfrom <= from && from <= pl + 1)
+                    || (from <= pfrom && pfrom <= l + 1) {
+                    // can connect both
+                    from = from.min(pfrom);
+                    l = l.max(pl);
+                    set_arg_a(previous, from);
+                    set_arg_b(previous, l - from);
+                    return;
+                }
+            }
+        }
+        self.code_abc(OpCode::LoadNil, from, n - 1, 0);
+    }
+
+    fn get_jump(&mut self, pc: i32) -> i32 {
+        let offset = get_arg_sbx(self.instruction(pc));
+        if offset == NO_JUMP {
+            NO_JUMP // end of list
+        } else {
+            pc + 1 + offset
+        }
+    }
+
+    fn fix_jump(&mut self, pc: i32, dest: i32) {
+        let offset = dest - (pc + 1);
+        if offset.abs() > MAXARG_SBX {
+            self.error("control structure too long");
+        }
+        set_arg_sbx(self.instruction(pc), offset);
+    }
+
+    // appends list `l2` to list `l1`
+    fn concat(&mut self, l1: &mut i32, l2: i32) {
+        if l2 == NO_JUMP {
+            return;
+        }
+        if *l1 == NO_JUMP {
+            *l1 = l2;
+            return;
+        }
+        let mut list = *l1;
+        loop {
+            let next = self.get_jump(list);
+            if next == NO_JUMP {
+                break;
+            }
+            list = next;
+        }
+        self.fix_jump(list, l2);
+    }
+
+    fn jump(&mut self) -> i32 {
+        let jpc = self.fs().jpc; // save list of jumps to here
+        self.fs().jpc = NO_JUMP;
+        let mut j = self.code_asbx(OpCode::Jmp, 0, NO_JUMP);
+        self.concat(&mut j, jpc);
+        j
+    }
+
+    fn jump_to(&mut self, target: i32) {
+        let j = self.jump();
+        self.patch_list(j, target);
+    }
+
+    fn ret(&mut self, first: i32, nret: i32) {
+        self.code_abc(OpCode::Return, first, nret + 1, 0);
+    }
+
+    fn cond_jump(&mut self, op: OpCode, a: i32, b: i32, c: i32) -> i32 {
+        self.code_abc(op, a, b, c);
+        self.jump()
+    }
+
+    // marks the current pc as a jump target
+    fn get_label(&mut self) -> i32 {
+        let fs = self.fs();
+        fs.lasttarget = fs.pc();
+        fs.lasttarget
+    }
+
+    // position of the instruction controlling a jump
+    fn get_jump_control(&mut self, pc: i32) -> i32 {
+        if pc >= 1 && get_opcode(self.instruction(pc - 1)).mode().test {
+            pc - 1
+        } else {
+            pc
+        }
+    }
+
+    // turns a TestSet into a Test when there is no register to set
+    fn patch_test_reg(&mut self, node: i32, reg: i32) -> bool {
+        let pc = self.get_jump_control(node);
+        let i = self.instruction(pc);
+        if get_opcode(i) != OpCode::TestSet {
+            return false;
+        }
+        if reg != NO_REG && reg != get_arg_b(i) {
+            set_arg_a(i, reg);
+        } else {
+            *i = create_abc(OpCode::Test, get_arg_b(i), 0, get_arg_c(i));
+        }
+        true
+    }
+
+    fn remove_values(&mut self, mut list: i32) {
+        while list != NO_JUMP {
+            self.patch_test_reg(list, NO_REG);
+            list = self.get_jump(list);
+        }
+    }
+
+    fn patch_list_aux(&mut self, mut list: i32, vtarget: i32, reg: i32,
+                      dtarget: i32) {
+        while list != NO_JUMP {
+            let next = self.get_jump(list);
+            if self.patch_test_reg(list, reg) {
+                self.fix_jump(list, vtarget);
+            } else {
+                self.fix_jump(list, dtarget);
+            }
+            list = next;
+        }
+    }
+
+    fn discharge_jpc(&mut self) {
+        let (jpc, pc) = (self.fs().jpc, self.pc());
+        self.patch_list_aux(jpc, pc, NO_REG, pc);
+        self.fs().jpc = NO_JUMP;
+    }
+
+    fn patch_list(&mut self, list: i32, target: i32) {
+        if target == self.pc() {
+            self.patch_to_here(list);
+        } else {
+            self.patch_list_aux(list, target, NO_REG, target);
+        }
+    }
+
+    // makes every jump in `list` close upvalues up to `level`
+    fn patch_close(&mut self, mut list: i32, level: i32) {
+        while list != NO_JUMP {
+            let next = self.get_jump(list);
+            set_arg_a(self.instruction(list), level + 1);
+            list = next;
+        }
+    }
+
+    fn patch_to_here(&mut self, list: i32) {
+        self.get_label();
+        let mut jpc = self.fs().jpc;
+        self.concat(&mut jpc, list);
+        self.fs().jpc = jpc;
+    }
+
+    // Register allocation
+
+    fn check_stack(&mut self, n: i32) {
+        let newstack = self.fs().freereg + n;
+        if newstack > i32::from(self.fs().f.maxstacksize) {
+            if newstack >= MAXREGS {
+                self.error(
+                    "function or expression needs too many registers");
+            }
+            self.fs().f.maxstacksize = newstack as u8;
+        }
+    }
+
+    fn reserve_regs(&mut self, n: i32) {
+        self.check_stack(n);
+        self.fs().freereg += n;
+    }
+
+    fn free_reg(&mut self, reg: i32) {
+        let fs = self.fs();
+        if !is_k(reg) && reg >= fs.nactvar {
+            fs.freereg -= 1;
+        }
+    }
+
+    fn free_exp(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(reg) = e.k {
+            self.free_reg(reg);
+        }
+    }
+
+    // frees registers in the proper order
+    fn free_exps(&mut self, e1: &ExpDesc, e2: &ExpDesc) {
+        let r1 = if let ExpKind::NonReloc(r) = e1.k { r } else { -1 };
+        let r2 = if let ExpKind::NonReloc(r) = e2.k { r } else { -1 };
+        if r1 > r2 {
+            self.free_reg(r1);
+            self.free_reg(r2);
+        } else {
+            self.free_reg(r2);
+            self.free_reg(r1);
+        }
+    }
+
+    // Constants
+
+    fn add_k(&mut self, key: ConstKey, value: SyxValue) -> i32 {
+        let nk = self.fs().f.constants.len();
+        if let Some(&k) = self.cache.get(&key) {
+            // the cache is shared by all functions, so check it is ours
+            if k < nk && ConstKey::from_value(&self.fs().f.constants[k]) == key {
+                return k as i32;
+            }
+        }
+        if nk > MAXARG_AX as usize {
+            self.error_limit(MAXARG_AX as usize, "constants");
+        }
+        self.cache.insert(key, nk);
+        self.fs().f.constants.push(value);
+        nk as i32
+    }
+
+    fn string_k(&mut self, s: &[u8]) -> i32 {
+        self.add_k(ConstKey::String(s.to_vec()), SyxValue::String(s.to_vec()))
+    }
+
+    fn int_k(&mut self, n: i64) -> i32 {
+        self.add_k(ConstKey::Integer(n), SyxValue::Integer(n))
+    }
+
+    fn number_k(&mut self, n: f64) -> i32 {
+        self.add_k(ConstKey::number(n), SyxValue::Number(n))
+    }
+
+    fn bool_k(&mut self, b: bool) -> i32 {
+        self.add_k(ConstKey::Bool(b), SyxValue::Bool(b))
+    }
+
+    fn nil_k(&mut self) -> i32 {
+        self.add_k(ConstKey::Nil, SyxValue::Nil)
+    }
+
+    // Expressions
+
+    fn set_returns(&mut self, e: &ExpDesc, nresults: i32) {
+        match e.k {
+            ExpKind::Call(pc) => set_arg_c(self.instruction(pc), nresults + 1),
+            ExpKind::VarArg(pc) => {
+                let freereg = self.fs().freereg;
+                let i = self.instruction(pc);
+                set_arg_b(i, nresults + 1);
+                set_arg_a(i, freereg);
+                self.reserve_regs(1);
+            }
+            _ => {}
+        }
+    }
+
+    fn set_multret(&mut self, e: &ExpDesc) {
+        self.set_returns(e, MULTRET);
+    }
+
+    fn set_one_ret(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Call(pc) => {
+                e.k = ExpKind::NonReloc(get_arg_a(self.instruction(pc)));
+            }
+            ExpKind::VarArg(pc) => {
+                set_arg_b(self.instruction(pc), 2);
+                e.k = ExpKind::Relocable(pc);
+            }
+            _ => {}
+        }
+    }
+
+    fn discharge_vars(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Local(reg) => e.k = ExpKind::NonReloc(reg),
+            ExpKind::Upval(idx) => {
+                let pc = self.code_abc(OpCode::GetUpval, 0, idx, 0);
+                e.k = ExpKind::Relocable(pc);
+            }
+            ExpKind::Indexed { t, idx, vt_local } => {
+                self.free_reg(idx);
+                let op = if vt_local {
+                    self.free_reg(t);
+                    OpCode::GetTable
+                } else {
+                    OpCode::GetTabUp
+                };
+                e.k = ExpKind::Relocable(self.code_abc(op, 0, t, idx));
+            }
+            ExpKind::Call(_) | ExpKind::VarArg(_) => self.set_one_ret(e),
+            _ => {}
+        }
+    }
+
+    fn discharge_to_reg(&mut self, e: &mut ExpDesc, reg: i32) {
+        self.discharge_vars(e);
+        match e.k {
+            ExpKind::Nil => self.code_nil(reg, 1),
+            ExpKind::False => {
+                self.code_abc(OpCode::LoadBool, reg, 0, 0);
+            }
+            ExpKind::True => {
+                self.code_abc(OpCode::LoadBool, reg, 1, 0);
+            }
+            ExpKind::K(k) => {
+                self.code_k(reg, k);
+            }
+            ExpKind::KFlt(n) => {
+                let k = self.number_k(n);
+                self.code_k(reg, k);
+            }
+            ExpKind::KInt(i) => {
+                let k = self.int_k(i);
+                self.code_k(reg, k);
+            }
+            ExpKind::Relocable(pc) => set_arg_a(self.instruction(pc), reg),
+            ExpKind::NonReloc(r) => {
+                if reg != r {
+                    self.code_abc(OpCode::Move, reg, r, 0);
+                }
+            }
+            _ => return, // ExpKind::Jmp, nothing to do
+        }
+        e.k = ExpKind::NonReloc(reg);
+    }
+
+    fn discharge_to_any_reg(&mut self, e: &mut ExpDesc) {
+        if let ExpKind::NonReloc(_) = e.k {
+            return;
+        }
+        self.reserve_regs(1);
+        let reg = self.fs().freereg - 1;
+        self.discharge_to_reg(e, reg);
+    }
+
+    fn code_load_bool(&mut self, a: i32, b: i32, jump: i32) -> i32 {
+        self.get_label(); // those instructions may be jump targets
+        self.code_abc(OpCode::LoadBool, a, b, jump)
+    }
+
+    // whether a jump list has any jump that does not produce a value
+    fn need_value(&mut self, mut list: i32) -> bool {
+        while list != NO_JUMP {
+            let pc = self.get_jump_control(list);
+            if get_opcode(self.instruction(pc)) != OpCode::TestSet {
+                return true;
+            }
+            list = self.get_jump(list);
+        }
+        false
+    }
+
+    fn exp_to_reg(&mut self, e: &mut ExpDesc, reg: i32) {
+        self.discharge_to_reg(e, reg);
+        if let ExpKind::Jmp(info) = e.k {
+            let mut t = e.t;
+            self.concat(&mut t, info);
+            e.t = t;
+        }
+        if e.has_jumps() {
+            let mut p_f = NO_JUMP; // position of an eventual LoadBool false
+            let mut p_t = NO_JUMP; // position of an eventual LoadBool true
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let fj = if let ExpKind::Jmp(_) = e.k {
+                    NO_JUMP
+                } else {
+                    self.jump()
+                };
+                p_f = self.code_load_bool(reg, 0, 1);
+                p_t = self.code_load_bool(reg, 1, 0);
+                self.patch_to_here(fj);
+            }
+            let end = self.get_label();
+            self.patch_list_aux(e.f, end, reg, p_f);
+            self.patch_list_aux(e.t, end, reg, p_t);
+        }
+        e.t = NO_JUMP;
+        e.f = NO_JUMP;
+        e.k = ExpKind::NonReloc(reg);
+    }
+
+    fn exp_to_next_reg(&mut self, e: &mut ExpDesc) {
+        self.discharge_vars(e);
+        self.free_exp(e);
+        self.reserve_regs(1);
+        let reg = self.fs().freereg - 1;
+        self.exp_to_reg(e, reg);
+    }
+
+    fn exp_to_any_reg(&mut self, e: &mut ExpDesc) -> i32 {
+        self.discharge_vars(e);
+        if let ExpKind::NonReloc(reg) = e.k {
+            if !e.has_jumps() {
+                return reg;
+            }
+            if reg >= self.fs().nactvar {
+                // not a local, put the final result in it
+                self.exp_to_reg(e, reg);
+                return reg;
+            }
+        }
+        self.exp_to_next_reg(e);
+        e.info()
+    }
+
+    // ensures the value is in a register or upvalue
+    fn exp_to_any_reg_up(&mut self, e: &mut ExpDesc) {
+        if !matches!(e.k, ExpKind::Upval(_)) || e.has_jumps() {
+            self.exp_to_any_reg(e);
+        }
+    }
+
+    fn exp_to_val(&mut self, e: &mut ExpDesc) {
+        if e.has_jumps() {
+            self.exp_to_any_reg(e);
+        } else {
+            self.discharge_vars(e);
+        }
+    }
+
+    // value as an RK operand: a register, or a constant with the RK bit set
+    fn exp_to_rk(&mut self, e: &mut ExpDesc) -> i32 {
+        self.exp_to_val(e);
+        let k = match e.k {
+            ExpKind::True => Some(self.bool_k(true)),
+            ExpKind::False => Some(self.bool_k(false)),
+            ExpKind::Nil => Some(self.nil_k()),
+            ExpKind::KInt(i) => Some(self.int_k(i)),
+            ExpKind::KFlt(n) => Some(self.number_k(n)),
+            ExpKind::K(k) => Some(k),
+            _ => None,
+        };
+        if let Some(k) = k {
+            e.k = ExpKind::K(k);
+            if k <= MAXINDEXRK {
+                return rk_constant(k);
+            }
+        }
+        self.exp_to_any_reg(e)
+    }
+
+    fn store_var(&mut self, var: &ExpDesc, ex: &mut ExpDesc) {
+        match var.k {
+            ExpKind::Local(reg) => {
+                self.free_exp(ex);
+                self.exp_to_reg(ex, reg);
+                return;
+            }
+            ExpKind::Upval(idx) => {
+                let e = self.exp_to_any_reg(ex);
+                self.code_abc(OpCode::SetUpval, e, idx, 0);
+            }
+            ExpKind::Indexed { t, idx, vt_local } => {
+                let op = if vt_local { OpCode::SetTable } else { OpCode::SetTabUp };
+                let e = self.exp_to_rk(ex);
+                self.code_abc(op, t, idx, e);
+            }
+            _ => unreachable!("invalid var kind to store"),
+        }
+        self.free_exp(ex);
+    }
+
+    // `e:key`, emits SelfLoad
+    fn code_self(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) {
+        self.exp_to_any_reg(e);
+        let ereg = e.info();
+        self.free_exp(e);
+        let base = self.fs().freereg;
+        e.k = ExpKind::NonReloc(base); // self and method
+        self.reserve_regs(2);
+        let k = self.exp_to_rk(key);
+        self.code_abc(OpCode::SelfLoad, base, ereg, k);
+        self.free_exp(key);
+    }
+
+    fn negate_condition(&mut self, e: &ExpDesc) {
+        let pc = self.get_jump_control(e.info());
+        let i = self.instruction(pc);
+        let a = get_arg_a(i);
+        set_arg_a(i, (a == 0) as i32);
+    }
+
+    fn jump_on_cond(&mut self, e: &mut ExpDesc, cond: i32) -> i32 {
+        if let ExpKind::Relocable(pc) = e.k {
+            let ie = *self.instruction(pc);
+            if get_opcode(&ie) == OpCode::Not {
+                // remove previous Not and test its operand instead
+                let fs = self.fs();
+                fs.f.instructions.pop();
+                fs.f.lineinfo.pop();
+                let cond = (cond == 0) as i32;
+                return self.cond_jump(OpCode::Test, get_arg_b(&ie), 0, cond);
+            }
+        }
+        self.discharge_to_any_reg(e);
+        self.free_exp(e);
+        self.cond_jump(OpCode::TestSet, NO_REG, e.info(), cond)
+    }
+
+    // jumps out when `e` is false
+    fn go_if_true(&mut self, e: &mut ExpDesc) {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => {
+                self.negate_condition(e);
+                pc
+            }
+            | ExpKind::K(_) | ExpKind::KFlt(_) | ExpKind::KInt(_)
+            | ExpKind::True => NO_JUMP, // always true
+            _ => self.jump_on_cond(e, 0),
+        };
+        let mut f = e.f;
+        self.concat(&mut f, pc);
+        e.f = f;
+        self.patch_to_here(e.t);
+        e.t = NO_JUMP;
+    }
+
+    // jumps out when `e` is true
+    fn go_if_false(&mut self, e: &mut ExpDesc) {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => pc,
+            ExpKind::Nil | ExpKind::False => NO_JUMP, // always false
+            _ => self.jump_on_cond(e, 1),
+        };
+        let mut t = e.t;
+        self.concat(&mut t, pc);
+        e.t = t;
+        self.patch_to_here(e.f);
+        e.f = NO_JUMP;
+    }
+
+    fn code_not(&mut self, e: &mut ExpDesc) {
+        self.discharge_vars(e);
+        match e.k {
+            ExpKind::Nil | ExpKind::False => e.k = ExpKind::True,
+            | ExpKind::K(_) | ExpKind::KFlt(_) | ExpKind::KInt(_)
+            | ExpKind::True => e.k = ExpKind::False,
+            ExpKind::Jmp(_) => self.negate_condition(e),
+            ExpKind::Relocable(_) | ExpKind::NonReloc(_) => {
+                self.discharge_to_any_reg(e);
+                self.free_exp(e);
+                let pc = self.code_abc(OpCode::Not, 0, e.info(), 0);
+                e.k = ExpKind::Relocable(pc);
+            }
+            _ => unreachable!("cannot negate {:?}", e.k),
+        }
+        // interchange true and false lists
+        ::std::mem::swap(&mut e.t, &mut e.f);
+        self.remove_values(e.f);
+        self.remove_values(e.t);
+    }
+
+    // `t[k]`, `t` must already be in a register or upvalue
+    fn indexed(&mut self, t: &mut ExpDesc, k: &mut ExpDesc) {
+        let table = t.info();
+        let idx = self.exp_to_rk(k);
+        let vt_local = !matches!(t.k, ExpKind::Upval(_));
+        t.k = ExpKind::Indexed { t: table, idx, vt_local };
+    }
+
+    fn const_folding(&mut self, op: Arith, e1: &mut ExpDesc, e2: &ExpDesc)
+                     -> bool {
+        let (v1, v2) = match (e1.numeral(), e2.numeral()) {
+            (Some(v1), Some(v2)) if op.valid(v1, v2) => (v1, v2),
+            _ => return false,
+        };
+        match op.apply(v1, v2) {
+            Numeral::Integer(i) => e1.k = ExpKind::KInt(i),
+            Numeral::Number(n) => {
+                // folds neither NaN nor 0.0, to avoid problems with -0.0
+                if n.is_nan() || n == 0.0 {
+                    return false;
+                }
+                e1.k = ExpKind::KFlt(n);
+            }
+        }
+        true
+    }
+
+    fn code_un_exp_val(&mut self, op: OpCode, e: &mut ExpDesc, line: i32) {
+        let r = self.exp_to_any_reg(e);
+        self.free_exp(e);
+        e.k = ExpKind::Relocable(self.code_abc(op, 0, r, 0));
+        self.fix_line(line);
+    }
+
+    fn code_bin_exp_val(&mut self, op: OpCode, e1: &mut ExpDesc,
+                        e2: &mut ExpDesc, line: i32) {
+        let rk2 = self.exp_to_rk(e2);
+        let rk1 = self.exp_to_rk(e1);
+        self.free_exps(e1, e2);
+        e1.k = ExpKind::Relocable(self.code_abc(op, 0, rk1, rk2));
+        self.fix_line(line);
+    }
+
+    fn code_comp(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc) {
+        let rk1 = match e1.k {
+            ExpKind::K(k) => rk_constant(k),
+            _ => e1.info(), // ExpKind::NonReloc
+        };
+        let rk2 = self.exp_to_rk(e2);
+        self.free_exps(e1, e2);
+        let pc = match op {
+            BinOp::Ne => self.cond_jump(OpCode::Eq, 0, rk1, rk2),
+            // '>' and '>=' swap their operands
+            BinOp::Gt => self.cond_jump(OpCode::Lt, 1, rk2, rk1),
+            BinOp::Ge => self.cond_jump(OpCode::Le, 1, rk2, rk1),
+            BinOp::Eq => self.cond_jump(OpCode::Eq, 1, rk1, rk2),
+            BinOp::Lt => self.cond_jump(OpCode::Lt, 1, rk1, rk2),
+            BinOp::Le => self.cond_jump(OpCode::Le, 1, rk1, rk2),
+            _ => unreachable!(),
+        };
+        e1.k = ExpKind::Jmp(pc);
+    }
+
+    fn prefix(&mut self, op: UnOp, e: &mut ExpDesc, line: i32) {
+        // fake second operand for folding
+        let ef = ExpDesc::new(ExpKind::KInt(0));
+        match op {
+            UnOp::Neg => {
+                if !self.const_folding(Arith::Unm, e, &ef) {
+                    self.code_un_exp_val(OpCode::Unm, e, line);
+                }
+            }
+            UnOp::BNot => {
+                if !self.const_folding(Arith::BNot, e, &ef) {
+                    self.code_un_exp_val(OpCode::BNot, e, line);
+                }
+            }
+            UnOp::Len => self.code_un_exp_val(OpCode::Len, e, line),
+            UnOp::Not => self.code_not(e),
+        }
+    }
+
+    // called after the first operand of a binary operator is read
+    fn infix(&mut self, op: BinOp, v: &mut ExpDesc) {
+        match op {
+            BinOp::And => self.go_if_true(v),
+            BinOp::Or => self.go_if_false(v),
+            BinOp::Concat => self.exp_to_next_reg(v), // must be on the stack
+            _ if Arith::from_binop(op).is_some() => {
+                // keep numerals, which may be folded with the 2nd operand
+                if v.numeral().is_none() {
+                    self.exp_to_rk(v);
+                }
+            }
+            _ => {
+                self.exp_to_rk(v);
+            }
+        }
+    }
+
+    fn posfix(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc,
+              line: i32) {
+        match op {
+            BinOp::And => {
+                self.discharge_vars(e2);
+                let mut f = e2.f;
+                self.concat(&mut f, e1.f);
+                e2.f = f;
+                *e1 = *e2;
+            }
+            BinOp::Or => {
+                self.discharge_vars(e2);
+                let mut t = e2.t;
+                self.concat(&mut t, e1.t);
+                e2.t = t;
+                *e1 = *e2;
+            }
+            BinOp::Concat => {
+                self.exp_to_val(e2);
+                if let ExpKind::Relocable(pc) = e2.k {
+                    if get_opcode(self.instruction(pc)) == OpCode::Concat {
+                        // merge with the Concat of the 2nd operand
+                        self.free_exp(e1);
+                        let b = e1.info();
+                        set_arg_b(self.instruction(pc), b);
+                        e1.k = ExpKind::Relocable(pc);
+                        return;
+                    }
+                }
+                self.exp_to_next_reg(e2);
+                self.code_bin_exp_val(OpCode::Concat, e1, e2, line);
+            }
+            | BinOp::Eq | BinOp::Lt | BinOp::Le
+            | BinOp::Ne | BinOp::Gt | BinOp::Ge => self.code_comp(op, e1, e2),
+            _ => {
+                let arith = Arith::from_binop(op).unwrap();
+                if !self.const_folding(arith, e1, e2) {
+                    self.code_bin_exp_val(arith.opcode(), e1, e2, line);
+                }
+            }
+        }
+    }
+
+    fn set_list(&mut self, base: i32, nelems: i32, tostore: i32) {
+        let c = (nelems - 1) / LFIELDS_PER_FLUSH + 1;
+        let b = if tostore == MULTRET { 0 } else { tostore };
+        if c <= MAXARG_C {
+            self.code_abc(OpCode::SetList, base, b, c);
+        } else if c <= MAXARG_AX {
+            self.code_abc(OpCode::SetList, base, b, 0);
+            self.code_extra_arg(c);
+        } else {
+            self.error("constructor too long");
+        }
+        self.fs().freereg = base + 1; // free registers with list values
+    }
+
+    // Variables and scopes, lparser.c
+
+    fn new_localvar(&mut self, name: &[u8]) {
+        let fs = self.fs();
+        fs.f.locvars.push(LocVar {
+            varname: name.to_vec(),
+            startpc: 0,
+            endpc: 0,
+        });
+        let index = fs.f.locvars.len() - 1;
+        fs.actvar.push(index);
+        if fs.actvar.len() > MAXVARS {
+            self.error_limit(MAXVARS, "local variables");
+        }
+    }
+
+    fn adjust_localvars(&mut self, nvars: i32) {
+        let fs = self.fs();
+        let pc = fs.pc();
+        fs.nactvar += nvars;
+        for i in (fs.nactvar - nvars)..fs.nactvar {
+            fs.locvar(i).startpc = pc;
+        }
+    }
+
+    fn remove_vars(&mut self, tolevel: i32) {
+        let fs = self.fs();
+        let pc = fs.pc();
+        while fs.nactvar > tolevel {
+            fs.nactvar -= 1;
+            let nactvar = fs.nactvar;
+            fs.locvar(nactvar).endpc = pc;
+        }
+        fs.actvar.truncate(tolevel as usize);
+    }
+
+    fn new_upvalue(&mut self, level: usize, name: &[u8], v: ExpKind) -> i32 {
+        let (instack, idx) = match v {
+            ExpKind::Local(reg) => (1, reg),
+            ExpKind::Upval(idx) => (0, idx),
+            _ => unreachable!(),
+        };
+        let nups = self.fs[level].f.upvalues.len();
+        if nups >= MAXUPVAL {
+            self.error_limit(MAXUPVAL, "upvalues");
+        }
+        self.fs[level].f.upvalues.push(Upvalue {
+            name: name.to_vec(),
+            instack,
+            idx: idx as u8,
+            kind: 0,
+        });
+        nups as i32
+    }
+
+    // marks the block where the local at `level` was defined
+    fn mark_upval(&mut self, fs: usize, level: i32) {
+        let blocks = &mut self.fs[fs].blocks;
+        if let Some(bl) = blocks.iter_mut().rev().find(|bl| bl.nactvar <= level) {
+            bl.upval = true;
+        }
+    }
+
+    // finds a variable, creating upvalues in every function between its
+    // definition and `level`; `base` is false for enclosing functions
+    fn single_var_aux(&mut self, level: usize, name: &[u8], base: bool)
+                      -> ExpKind {
+        let fs = &mut self.fs[level];
+        let local = (0..fs.nactvar).rev().find(|&i| fs.locvar(i).varname == name);
+        if let Some(v) = local {
+            if !base {
+                self.mark_upval(level, v); // local will be used as an upval
+            }
+            return ExpKind::Local(v);
+        }
+        let upvalue = fs.f.upvalues.iter().position(|up| up.name == name);
+        if let Some(idx) = upvalue {
+            return ExpKind::Upval(idx as i32);
+        }
+        if level == 0 {
+            return ExpKind::Void; // global
+        }
+        let v = self.single_var_aux(level - 1, name, false);
+        if v == ExpKind::Void {
+            return v;
+        }
+        ExpKind::Upval(self.new_upvalue(level, name, v))
+    }
+
+    fn single_var(&mut self, name: &[u8]) -> ExpDesc {
+        let level = self.fs.len() - 1;
+        let v = self.single_var_aux(level, name, true);
+        if v != ExpKind::Void {
+            return ExpDesc::new(v);
+        }
+        // global name, `_ENV[name]`
+        let mut var = ExpDesc::new(self.single_var_aux(level, ENV, true));
+        let mut key = ExpDesc::new(ExpKind::K(self.string_k(name)));
+        self.indexed(&mut var, &mut key);
+        var
+    }
+
+    fn adjust_assign(&mut self, nvars: i32, nexps: i32, e: &mut ExpDesc) {
+        let mut extra = nvars - nexps;
+        if e.has_multret() {
+            extra += 1; // includes call itself
+            if extra < 0 {
+                extra = 0;
+            }
+            self.set_returns(e, extra); // last exp. provides the difference
+            if extra > 1 {
+                self.reserve_regs(extra - 1);
+            }
+        } else {
+            if e.k != ExpKind::Void {
+                self.exp_to_next_reg(e); // close last expression
+            }
+            if extra > 0 {
+                let reg = self.fs().freereg;
+                self.reserve_regs(extra);
+                self.code_nil(reg, extra);
+            }
+        }
+        if nexps > nvars {
+            self.fs().freereg -= nexps - nvars; // remove extra values
+        }
+    }
+
+    fn enter_block(&mut self, isloop: bool) {
+        let (firstlabel, firstgoto) = (self.labels.len(), self.gotos.len());
+        let fs = self.fs();
+        fs.blocks.push(BlockCnt {
+            firstlabel,
+            firstgoto,
+            nactvar: fs.nactvar,
+            upval: false,
+            isloop,
+        });
+    }
+
+    fn leave_block(&mut self) {
+        let (nactvar, upval, isloop, firstlabel, firstgoto) = {
+            let bl = self.fs().blocks.last().unwrap();
+            (bl.nactvar, bl.upval, bl.isloop, bl.firstlabel, bl.firstgoto)
+        };
+        let previous = self.fs().blocks.len() > 1;
+        if previous && upval {
+            // create a jump to here to close upvalues
+            let j = self.jump();
+            self.patch_close(j, nactvar);
+            self.patch_to_here(j);
+        }
+        if isloop {
+            self.break_label(); // close pending breaks
+        }
+        self.fs().blocks.pop();
+        self.remove_vars(nactvar);
+        let fs = self.fs();
+        fs.freereg = fs.nactvar; // free registers
+        self.labels.truncate(firstlabel); // remove local labels
+        if previous {
+            self.move_gotos_out(firstgoto, nactvar, upval);
+        } else if firstgoto < self.gotos.len() {
+            self.undef_goto(firstgoto);
+        }
+    }
+
+    fn new_label_entry(list: &mut Vec<LabelDesc>, name: &[u8], line: i32,
+                       pc: i32, nactvar: i32) -> usize {
+        list.push(LabelDesc { name: name.to_vec(), pc, line, nactvar });
+        list.len() - 1
+    }
+
+    // solves the pending goto at `g` to `label` and removes it
+    fn close_goto(&mut self, g: usize, label: usize) {
+        let (gt_nactvar, gt_pc, gt_line) = {
+            let gt = &self.gotos[g];
+            (gt.nactvar, gt.pc, gt.line)
+        };
+        let (lb_nactvar, lb_pc) = (self.labels[label].nactvar, self.labels[label].pc);
+        if gt_nactvar < lb_nactvar {
+            let vname = self.fs().locvar(gt_nactvar).varname.clone();
+            let message = format!(
+                "<goto {}> at line {} jumps into the scope of local '{}'",
+                String::from_utf8_lossy(&self.gotos[g].name), gt_line,
+                String::from_utf8_lossy(&vname));
+            self.error(&message);
+        }
+        self.patch_list(gt_pc, lb_pc);
+        self.gotos.remove(g);
+    }
+
+    // tries to close the goto at `g` with a label of the current block
+    fn find_label(&mut self, g: usize) -> bool {
+        let (firstlabel, upval) = {
+            let bl = self.fs().blocks.last().unwrap();
+            (bl.firstlabel, bl.upval)
+        };
+        for i in firstlabel..self.labels.len() {
+            if self.labels[i].name == self.gotos[g].name {
+                let gt = &self.gotos[g];
+                let lb = &self.labels[i];
+                if gt.nactvar > lb.nactvar
+                    && (upval || self.labels.len() > firstlabel) {
+                    let (pc, level) = (gt.pc, lb.nactvar);
+                    self.patch_close(pc, level);
+                }
+                self.close_goto(g, i);
+                return true;
+            }
+        }
+        false
+    }
+
+    // checks the pending gotos of the current block against a new label
+    fn find_gotos(&mut self, label: usize) {
+        let mut i = self.fs().blocks.last().unwrap().firstgoto;
+        while i < self.gotos.len() {
+            if self.gotos[i].name == self.labels[label].name {
+                self.close_goto(i, label);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    // moves the pending gotos of a closed block to the enclosing one
+    fn move_gotos_out(&mut self, firstgoto: usize, nactvar: i32, upval: bool) {
+        let mut i = firstgoto;
+        while i < self.gotos.len() {
+            if self.gotos[i].nactvar > nactvar {
+                if upval {
+                    let pc = self.gotos[i].pc;
+                    self.patch_close(pc, nactvar);
+                }
+                self.gotos[i].nactvar = nactvar;
+            }
+            if !self.find_label(i) {
+                i += 1;
+            }
+        }
+    }
+
+    fn break_label(&mut self) {
+        let pc = self.pc();
+        let nactvar = self.fs().nactvar;
+        let l = Compiler::new_label_entry(&mut self.labels, b"break", 0, pc,
+                                          nactvar);
+        self.find_gotos(l);
+    }
+
+    fn undef_goto(&mut self, g: usize) {
+        let gt = &self.gotos[g];
+        let message = if gt.name == b"break" {
+            format!("<break> at line {} not inside a loop", gt.line)
+        } else {
+            format!("no visible label '{}' for <goto> at line {}",
+                    String::from_utf8_lossy(&gt.name), gt.line)
+        };
+        self.error(&message);
+    }
+
+    // Functions
+
+    fn open_func(&mut self, linedefined: i32) {
+        let mut f = Proto::new();
+        f.source = self.chunkname.clone();
+        f.linedefined = linedefined;
+        f.maxstacksize = 2; // registers 0/1 are always valid
+        self.fs.push(FuncState {
+            f,
+            blocks: Vec::new(),
+            lasttarget: 0,
+            jpc: NO_JUMP,
+            nactvar: 0,
+            freereg: 0,
+            actvar: Vec::new(),
+        });
+        self.enter_block(false);
+    }
+
+    fn close_func(&mut self) -> Proto {
+        self.ret(0, 0); // final return
+        self.leave_block();
+        self.fs.pop().unwrap().f
+    }
+
+    // emits the Closure for the function being closed in its parent
+    fn code_closure(&mut self) -> ExpDesc {
+        let child = self.fs.pop().unwrap();
+        let np = self.fs().f.protos.len() as i32;
+        let pc = self.code_abx(OpCode::Closure, 0, np);
+        let mut v = ExpDesc::new(ExpKind::Relocable(pc));
+        self.exp_to_next_reg(&mut v); // fix it at the last register
+        self.fs.push(child);
+        v
+    }
+
+    fn main_func(&mut self, block: &Block) -> Proto {
+        self.open_func(0);
+        self.fs().f.is_vararg = true; // main function is always vararg
+        self.new_upvalue(0, ENV, ExpKind::Local(0));
+        self.statlist(block, 0, false);
+        self.close_func()
+    }
+
+    fn body(&mut self, fb: &FuncBody, is_method: bool, line: i32) -> ExpDesc {
+        self.open_func(line);
+        self.skip(); // '('
+        let mut params = &fb.params[..];
+        if is_method {
+            self.new_localvar(b"self"); // create 'self' parameter
+            self.adjust_localvars(1);
+            params = &params[1..];
+        }
+        for param in params {
+            self.consume_to(param.span.end);
+            self.new_localvar(param.name.as_bytes());
+        }
+        while *self.current() != Token::RParen {
+            self.skip(); // ',' and '...'
+        }
+        self.fs().f.is_vararg = fb.is_vararg;
+        self.adjust_localvars(params.len() as i32);
+        let nactvar = self.fs().nactvar;
+        self.fs().f.numparams = nactvar as u8;
+        self.reserve_regs(nactvar);
+        self.skip(); // ')'
+        self.statlist(&fb.body, 0, false);
+        self.fs().f.lastlinedefined = self.line_number();
+        self.skip(); // 'end'
+        let v = self.code_closure();
+        let f = self.close_func();
+        self.fs().f.protos.push(f);
+        v
+    }
+
+    // Expressions, lparser.c
+
+    // compiles the expressions of a list into consecutive registers, except
+    // for the last one which is returned still open
+    fn explist(&mut self, exprs: &[Expr]) -> ExpDesc {
+        let mut v = self.expr(&exprs[0]);
+        for expr in &exprs[1..] {
+            self.skip(); // ','
+            self.exp_to_next_reg(&mut v);
+            v = self.expr(expr);
+        }
+        v
+    }
+
+    fn expr(&mut self, e: &Expr) -> ExpDesc {
+        let v = match &e.kind {
+            ExprKind::Nil => ExpDesc::new(ExpKind::Nil),
+            ExprKind::True => ExpDesc::new(ExpKind::True),
+            ExprKind::False => ExpDesc::new(ExpKind::False),
+            ExprKind::Integer(i) => ExpDesc::new(ExpKind::KInt(*i)),
+            ExprKind::Number(n) => ExpDesc::new(ExpKind::KFlt(*n)),
+            ExprKind::String(s) => ExpDesc::new(ExpKind::K(self.string_k(s))),
+            ExprKind::VarArg => {
+                // emitted before '...' is consumed
+                let pc = self.code_abc(OpCode::VarArg, 0, 1, 0);
+                ExpDesc::new(ExpKind::VarArg(pc))
+            }
+            ExprKind::Function(fb) => {
+                self.skip(); // 'function'
+                let line = self.line_number();
+                self.body(fb, false, line)
+            }
+            ExprKind::Table(fields) => self.constructor(fields),
+            ExprKind::Binary { op, op_span, lhs, rhs } => {
+                let mut v = self.expr(lhs);
+                self.consume_to(op_span.end);
+                self.infix(*op, &mut v);
+                let mut v2 = self.expr(rhs);
+                self.posfix(*op, &mut v, &mut v2, op_span.line as i32);
+                v
+            }
+            ExprKind::Unary { op, op_span, operand } => {
+                self.consume_to(op_span.end);
+                let mut v = self.expr(operand);
+                self.prefix(*op, &mut v, op_span.line as i32);
+                v
+            }
+            ExprKind::Paren(inner) => {
+                self.skip(); // '('
+                let mut v = self.expr(inner);
+                self.skip(); // ')'
+                self.discharge_vars(&mut v);
+                v
+            }
+            ExprKind::Name(name) => {
+                self.consume_to(e.span.end);
+                self.single_var(name.as_bytes())
+            }
+            ExprKind::Index { obj, key } => {
+                let mut v = self.expr(obj);
+                let field = *self.current() == Token::Dot;
+                self.exp_to_any_reg_up(&mut v);
+                self.skip(); // '.' or '['
+                let mut k = if field {
+                    self.expr(key)
+                } else {
+                    let mut k = self.expr(key);
+                    self.exp_to_val(&mut k);
+                    self.skip(); // ']'
+                    k
+                };
+                self.indexed(&mut v, &mut k);
+                v
+            }
+            ExprKind::Call { func, args } => {
+                let line = self.line_number();
+                let mut v = self.expr(func);
+                self.exp_to_next_reg(&mut v);
+                self.funcargs(&mut v, args, line);
+                v
+            }
+            ExprKind::MethodCall { obj, method, args } => {
+                let line = self.line_number();
+                let mut v = self.expr(obj);
+                self.skip(); // ':'
+                self.consume_to(method.span.end);
+                let mut key = ExpDesc::new(
+                    ExpKind::K(self.string_k(method.name.as_bytes())));
+                self.code_self(&mut v, &mut key);
+                self.funcargs(&mut v, args, line);
+                v
+            }
+            ExprKind::Error => unreachable!("compiling a syntax error"),
+        };
+        self.consume_to(e.span.end);
+        v
+    }
+
+    fn funcargs(&mut self, f: &mut ExpDesc, args: &[Expr], line: i32) {
+        let mut a = match self.current().clone() {
+            Token::LParen => {
+                self.skip();
+                let a = if args.is_empty() {
+                    ExpDesc::new(ExpKind::Void)
+                } else {
+                    let a = self.explist(args);
+                    self.set_multret(&a);
+                    a
+                };
+                self.skip(); // ')'
+                a
+            }
+            Token::LBrace => self.expr(&args[0]),
+            _ => self.expr(&args[0]), // string argument
+        };
+        let base = f.info(); // base register for call
+        let nparams = if a.has_multret() {
+            MULTRET // open call
+        } else {
+            if a.k != ExpKind::Void {
+                self.exp_to_next_reg(&mut a); // close last argument
+            }
+            self.fs().freereg - (base + 1)
+        };
+        f.k = ExpKind::Call(self.code_abc(OpCode::Call, base, nparams + 1, 2));
+        self.fix_line(line);
+        // call removes function and arguments and leaves one result
+        self.fs().freereg = base + 1;
+    }
+
+    fn constructor(&mut self, fields: &[Field]) -> ExpDesc {
+        let pc = self.code_abc(OpCode::NewTable, 0, 0, 0);
+        let mut t = ExpDesc::new(ExpKind::Relocable(pc));
+        self.exp_to_next_reg(&mut t); // fix it at stack top
+        let mut cc = ConsControl {
+            v: ExpDesc::new(ExpKind::Void),
+            t: t.info(),
+            nh: 0,
+            na: 0,
+            tostore: 0,
+        };
+        self.skip(); // '{'
+        for field in fields {
+            self.close_list_field(&mut cc);
+            if let Token::Name(_) = self.current() {
+                // field() in lparser.c peeks past names to look for a '='
+                self.lookahead = self.consumed;
+            }
+            match field {
+                Field::Positional(value) => {
+                    cc.v = self.expr(value);
+                    cc.na += 1;
+                    cc.tostore += 1;
+                }
+                Field::Named(name, value) => {
+                    let reg = self.fs().freereg;
+                    self.consume_to(name.span.end);
+                    let k = ExpKind::K(self.string_k(name.name.as_bytes()));
+                    self.rec_field(&mut cc, reg, ExpDesc::new(k), value);
+                }
+                Field::Keyed(key, value) => {
+                    let reg = self.fs().freereg;
+                    self.skip(); // '['
+                    let mut k = self.expr(key);
+                    self.exp_to_val(&mut k);
+                    self.skip(); // ']'
+                    self.rec_field(&mut cc, reg, k, value);
+                }
+            }
+            if matches!(self.current(), Token::Comma | Token::Semicolon) {
+                self.skip();
+            }
+        }
+        self.skip(); // '}'
+        self.last_list_field(&mut cc);
+        let i = self.instruction(pc);
+        set_arg_b(i, int2fb(cc.na as u32)); // set initial array size
+        set_arg_c(i, int2fb(cc.nh as u32)); // set initial table size
+        t
+    }
+
+    // `reg` is the first free register from before the key was read
+    fn rec_field(&mut self, cc: &mut ConsControl, reg: i32, mut key: ExpDesc,
+                 value: &Expr) {
+        cc.nh += 1;
+        self.skip(); // '='
+        let rkkey = self.exp_to_rk(&mut key);
+        let mut val = self.expr(value);
+        let rkval = self.exp_to_rk(&mut val);
+        self.code_abc(OpCode::SetTable, cc.t, rkkey, rkval);
+        self.fs().freereg = reg; // free registers
+    }
+
+    fn close_list_field(&mut self, cc: &mut ConsControl) {
+        if cc.v.k == ExpKind::Void {
+            return; // there is no list item
+        }
+        self.exp_to_next_reg(&mut cc.v);
+        cc.v.k = ExpKind::Void;
+        if cc.tostore == LFIELDS_PER_FLUSH {
+            self.set_list(cc.t, cc.na, cc.tostore); // flush
+            cc.tostore = 0;
+        }
+    }
+
+    fn last_list_field(&mut self, cc: &mut ConsControl) {
+        if cc.tostore == 0 {
+            return;
+        }
+        if cc.v.has_multret() {
+            self.set_multret(&cc.v);
+            self.set_list(cc.t, cc.na, MULTRET);
+            cc.na -= 1; // do not count last expression (unknown size)
+        } else {
+            if cc.v.k != ExpKind::Void {
+                self.exp_to_next_reg(&mut cc.v);
+            }
+            self.set_list(cc.t, cc.na, cc.tostore);
+        }
+    }
+
+    // Statements
+
+    fn block(&mut self, block: &Block) {
+        self.enter_block(false);
+        self.statlist(block, 0, false);
+        self.leave_block();
+    }
+
+    // compiles `block` from statement `start`; `until` is set for the body
+    // of a repeat, whose block is followed by `until` rather than `end`
+    fn statlist(&mut self, block: &Block, start: usize, until: bool) {
+        let mut i = start;
+        while i < block.stats.len() {
+            i = self.statement(block, i, until);
+        }
+        if let Some(ret) = &block.ret {
+            self.consume_to(ret.span.start); // skip empty statements
+            self.skip(); // 'return'
+            self.retstat(ret);
+            let fs = self.fs();
+            fs.freereg = fs.nactvar;
+        }
+        self.consume_to(block.span.end);
+    }
+
+    // compiles statement `i` of `block`, returns the index of the next one
+    fn statement(&mut self, block: &Block, i: usize, until: bool) -> usize {
+        let stat = &block.stats[i];
+        let line = stat.span.line as i32;
+        let mut next = i + 1;
+        self.consume_to(stat.span.start); // skip empty statements
+        match &stat.kind {
+            StatKind::If { clauses, else_block } => {
+                self.ifstat(clauses, else_block.as_ref());
+            }
+            StatKind::While { cond, body } => self.whilestat(cond, body),
+            StatKind::Do(body) => {
+                self.skip(); // 'do'
+                self.block(body);
+                self.skip(); // 'end'
+            }
+            StatKind::NumericFor(nf) => {
+                self.enter_block(true); // scope for loop and control vars
+                self.skip(); // 'for'
+                self.skip(); // first variable name
+                self.fornum(nf, line);
+                self.skip(); // 'end'
+                self.leave_block();
+            }
+            StatKind::GenericFor { names, exprs, body } => {
+                self.enter_block(true);
+                self.skip(); // 'for'
+                self.skip(); // first variable name
+                self.forlist(names, exprs, body);
+                self.skip(); // 'end'
+                self.leave_block();
+            }
+            StatKind::Repeat { body, cond } => self.repeatstat(body, cond),
+            StatKind::Function { name, body } => self.funcstat(name, body, line),
+            StatKind::LocalFunction { name, body } => {
+                self.skip(); // 'local'
+                self.skip(); // 'function'
+                self.localfunc(name, body);
+            }
+            StatKind::Local { names, exprs } => {
+                self.skip(); // 'local'
+                self.localstat(names, exprs);
+            }
+            StatKind::Label(name) => {
+                self.skip(); // '::'
+                self.consume_to(name.span.end);
+                next = self.labelstat(block, i, until);
+            }
+            StatKind::Break => {
+                let pc = self.jump();
+                self.gotostat(pc, b"break");
+            }
+            StatKind::Goto(name) => {
+                let pc = self.jump();
+                self.gotostat(pc, name.name.as_bytes());
+            }
+            StatKind::Assign { targets, exprs } => self.assignment(targets, exprs),
+            StatKind::Call(call) => {
+                let v = self.expr(call);
+                if let ExpKind::Call(pc) = v.k {
+                    set_arg_c(self.instruction(pc), 1); // call uses no results
+                }
+            }
+            StatKind::Error => unreachable!("compiling a syntax error"),
+        }
+        self.consume_to(stat.span.end);
+        let fs = self.fs();
+        fs.freereg = fs.nactvar; // free registers
+        next
+    }
+
+    // the statement at `i` is a label; returns the index of the next
+    // statement, as trailing labels are handled here like skipnoopstat does
+    fn labelstat(&mut self, block: &Block, i: usize, until: bool) -> usize {
+        let (name, line) = match &block.stats[i].kind {
+            StatKind::Label(name) => (name.name.as_bytes(), block.stats[i].span.line),
+            _ => unreachable!(),
+        };
+        let firstlabel = self.fs().blocks.last().unwrap().firstlabel;
+        let repeated = self.labels[firstlabel..].iter()
+            .find(|lb| lb.name == name)
+            .map(|lb| lb.line);
+        if let Some(other) = repeated {
+            self.error(&format!("label '{}' already defined on line {}",
+                                String::from_utf8_lossy(name), other));
+        }
+        self.skip(); // '::'
+        let pc = self.get_label();
+        let nactvar = self.fs().nactvar;
+        let l = Compiler::new_label_entry(&mut self.labels, name, line as i32,
+                                          pc, nactvar);
+        // skip other no-op statements
+        let mut next = i + 1;
+        while next < block.stats.len() {
+            if let StatKind::Label(_) = block.stats[next].kind {
+                next = self.statement(block, next, until);
+            } else {
+                break;
+            }
+        }
+        if next == block.stats.len() && block.ret.is_none() && !until {
+            // label is last no-op statement in the block, assume that locals
+            // are already out of scope
+            self.labels[l].nactvar = self.fs().blocks.last().unwrap().nactvar;
+        }
+        self.find_gotos(l);
+        next
+    }
+
+    fn gotostat(&mut self, pc: i32, name: &[u8]) {
+        let line = self.line_number();
+        self.skip(); // 'goto' or 'break'
+        if name != b"break" {
+            self.skip(); // label name
+        }
+        let nactvar = self.fs().nactvar;
+        let g = Compiler::new_label_entry(&mut self.gotos, name, line, pc,
+                                          nactvar);
+        self.find_label(g); // close it if label already defined
+    }
+
+    // condition of a loop, returns its false list
+    fn cond(&mut self, cond: &Expr) -> i32 {
+        let mut v = self.expr(cond);
+        if v.k == ExpKind::Nil {
+            v.k = ExpKind::False; // 'falses' are all equal here
+        }
+        self.go_if_true(&mut v);
+        v.f
+    }
+
+    fn whilestat(&mut self, cond: &Expr, body: &Block) {
+        self.skip(); // 'while'
+        let whileinit = self.get_label();
+        let condexit = self.cond(cond);
+        self.enter_block(true);
+        self.skip(); // 'do'
+        self.block(body);
+        self.jump_to(whileinit);
+        self.skip(); // 'end'
+        self.leave_block();
+        self.patch_to_here(condexit); // false conditions finish the loop
+    }
+
+    fn repeatstat(&mut self, body: &Block, cond: &Expr) {
+        let repeat_init = self.get_label();
+        self.enter_block(true); // loop block
+        self.enter_block(false); // scope block
+        self.skip(); // 'repeat'
+        self.statlist(body, 0, true);
+        self.skip(); // 'until'
+        let condexit = self.cond(cond); // read condition (inside scope block)
+        let (upval, nactvar) = {
+            let bl = self.fs().blocks.last().unwrap();
+            (bl.upval, bl.nactvar)
+        };
+        if upval {
+            self.patch_close(condexit, nactvar);
+        }
+        self.leave_block(); // finish scope
+        self.patch_list(condexit, repeat_init); // close the loop
+        self.leave_block(); // finish loop
+    }
+
+    fn exp1(&mut self, e: &Expr) {
+        let mut v = self.expr(e);
+        self.exp_to_next_reg(&mut v);
+    }
+
+    fn forbody(&mut self, base: i32, line: i32, nvars: i32, isnum: bool,
+               body: &Block) {
+        self.adjust_localvars(3); // control variables
+        self.skip(); // 'do'
+        let prep = if isnum {
+            self.code_asbx(OpCode::ForPrep, base, NO_JUMP)
+        } else {
+            self.jump()
+        };
+        self.enter_block(false); // scope for declared variables
+        self.adjust_localvars(nvars);
+        self.reserve_regs(nvars);
+        self.block(body);
+        self.leave_block();
+        self.patch_to_here(prep);
+        let endfor = if isnum {
+            self.code_asbx(OpCode::ForLoop, base, NO_JUMP)
+        } else {
+            self.code_abc(OpCode::TForCall, base, 0, nvars);
+            self.fix_line(line);
+            self.code_asbx(OpCode::TForLoop, base + 2, NO_JUMP)
+        };
+        self.patch_list(endfor, prep + 1);
+        self.fix_line(line);
+    }
+
+    fn fornum(&mut self, nf: &NumericFor, line: i32) {
+        let base = self.fs().freereg;
+        self.new_localvar(b"(for index)");
+        self.new_localvar(b"(for limit)");
+        self.new_localvar(b"(for step)");
+        self.new_localvar(nf.var.name.as_bytes());
+        self.skip(); // '='
+        self.exp1(&nf.start); // initial value
+        self.skip(); // ','
+        self.exp1(&nf.limit);
+        if let Some(step) = &nf.step {
+            self.skip(); // ','
+            self.exp1(step);
+        } else {
+            // default step = 1
+            let freereg = self.fs().freereg;
+            let k = self.int_k(1);
+            self.code_k(freereg, k);
+            self.reserve_regs(1);
+        }
+        self.forbody(base, line, 1, true, &nf.body);
+    }
+
+    fn forlist(&mut self, names: &[Name], exprs: &[Expr], body: &Block) {
+        let base = self.fs().freereg;
+        self.new_localvar(b"(for generator)");
+        self.new_localvar(b"(for state)");
+        self.new_localvar(b"(for control)");
+        for name in names {
+            self.consume_to(name.span.end);
+            self.new_localvar(name.name.as_bytes());
+        }
+        self.skip(); // 'in'
+        let line = self.line_number();
+        let mut e = self.explist(exprs);
+        self.adjust_assign(3, exprs.len() as i32, &mut e);
+        self.check_stack(3); // extra space to call generator
+        self.forbody(base, line, names.len() as i32, false, body);
+    }
+
+    fn test_then_block(&mut self, escapelist: &mut i32, cond: &Expr,
+                       block: &Block, last: bool) {
+        self.skip(); // 'if' or 'elseif'
+        let mut v = self.expr(cond);
+        self.skip(); // 'then'
+        let jf; // instruction to skip 'then' code (if condition is false)
+        let mut start = 0;
+        if matches!(self.current(), Token::Goto | Token::Break) {
+            self.go_if_false(&mut v); // will jump to label if condition is true
+            self.enter_block(false); // must enter block before 'goto'
+            let name = match &block.stats[0].kind {
+                StatKind::Goto(name) => name.name.as_bytes().to_vec(),
+                _ => b"break".to_vec(),
+            };
+            self.gotostat(v.t, &name);
+            while *self.current() == Token::Semicolon {
+                self.skip();
+            }
+            if block.stats.len() == 1 && block.ret.is_none() {
+                // 'goto' is the entire block
+                self.leave_block();
+                return;
+            }
+            // must skip over 'then' part if condition is false
+            jf = self.jump();
+            start = 1;
+        } else {
+            self.go_if_true(&mut v); // skip over block if condition is false
+            self.enter_block(false);
+            jf = v.f;
+        }
+        self.statlist(block, start, false); // 'then' part
+        self.leave_block();
+        if !last {
+            // followed by 'else' or 'elseif', must jump over it
+            let j = self.jump();
+            self.concat(escapelist, j);
+        }
+        self.patch_to_here(jf);
+    }
+
+    fn ifstat(&mut self, clauses: &[(Expr, Block)], else_block: Option<&Block>) {
+        let mut escapelist = NO_JUMP; // exit list for finished parts
+        for (i, (cond, block)) in clauses.iter().enumerate() {
+            let last = i + 1 == clauses.len() && else_block.is_none();
+            self.test_then_block(&mut escapelist, cond, block, last);
+        }
+        if let Some(block) = else_block {
+            self.skip(); // 'else'
+            self.block(block);
+        }
+        self.skip(); // 'end'
+        self.patch_to_here(escapelist); // patch escape list to 'if' end
+    }
+
+    fn localfunc(&mut self, name: &Name, body: &FuncBody) {
+        self.consume_to(name.span.end);
+        self.new_localvar(name.name.as_bytes());
+        self.adjust_localvars(1); // enter its scope
+        let line = self.line_number();
+        self.body(body, false, line);
+        // debug information will only see the variable after this point
+        let pc = self.pc();
+        let fs = self.fs();
+        let nactvar = fs.nactvar;
+        fs.locvar(nactvar - 1).startpc = pc;
+    }
+
+    fn localstat(&mut self, names: &[Name], exprs: &[Expr]) {
+        for name in names {
+            self.consume_to(name.span.end);
+            self.new_localvar(name.name.as_bytes());
+        }
+        let nvars = names.len() as i32;
+        let mut e = if exprs.is_empty() {
+            ExpDesc::new(ExpKind::Void)
+        } else {
+            self.skip(); // '='
+            self.explist(exprs)
+        };
+        self.adjust_assign(nvars, exprs.len() as i32, &mut e);
+        self.adjust_localvars(nvars);
+    }
+
+    fn funcstat(&mut self, name: &FuncName, body: &FuncBody, line: i32) {
+        self.skip(); // 'function'
+        let first = &name.path[0];
+        self.consume_to(first.span.end);
+        let mut v = self.single_var(first.name.as_bytes());
+        let fields = name.path[1..].iter().chain(name.method.iter());
+        for field in fields {
+            self.exp_to_any_reg_up(&mut v);
+            self.skip(); // '.' or ':'
+            self.consume_to(field.span.end);
+            let k = ExpKind::K(self.string_k(field.name.as_bytes()));
+            self.indexed(&mut v, &mut ExpDesc::new(k));
+        }
+        let mut b = self.body(body, name.method.is_some(), line);
+        self.store_var(&v, &mut b);
+        self.fix_line(line); // definition "happens" in the first line
+    }
+
+    // when a later target of a multiple assignment is a local or upvalue
+    // used as table or index by an earlier one, copy it to a safe register
+    fn check_conflict(&mut self, lhs: &mut [ExpDesc], v: &ExpDesc) {
+        let extra = self.fs().freereg;
+        let mut conflict = false;
+        for lh in lhs.iter_mut() {
+            if let ExpKind::Indexed { ref mut t, ref mut idx, ref mut vt_local } = lh.k {
+                let same_table = match v.k {
+                    ExpKind::Local(reg) => *vt_local && *t == reg,
+                    ExpKind::Upval(up) => !*vt_local && *t == up,
+                    _ => false,
+                };
+                if same_table {
+                    conflict = true;
+                    *vt_local = true;
+                    *t = extra; // previous assignment will use safe copy
+                }
+                if let ExpKind::Local(reg) = v.k {
+                    if *idx == reg {
+                        conflict = true;
+                        *idx = extra; // previous assignment will use safe copy
+                    }
+                }
+            }
+        }
+        if conflict {
+            let op = if let ExpKind::Local(_) = v.k {
+                OpCode::Move
+            } else {
+                OpCode::GetUpval
+            };
+            self.code_abc(op, extra, v.info(), 0);
+            self.reserve_regs(1);
+        }
+    }
+
+    fn assignment(&mut self, targets: &[Expr], exprs: &[Expr]) {
+        let mut lhs = vec![self.expr(&targets[0])];
+        for target in &targets[1..] {
+            self.skip(); // ','
+            let v = self.expr(target);
+            if !matches!(v.k, ExpKind::Indexed { .. }) {
+                self.check_conflict(&mut lhs, &v);
+            }
+            lhs.push(v);
+        }
+        self.skip(); // '='
+        let mut e = self.explist(exprs);
+        let (nvars, nexps) = (lhs.len() as i32, exprs.len() as i32);
+        let mut stored = 0;
+        if nexps != nvars {
+            self.adjust_assign(nvars, nexps, &mut e);
+        } else {
+            self.set_one_ret(&mut e); // close last expression
+            self.store_var(&lhs[lhs.len() - 1], &mut e);
+            stored = 1;
+        }
+        // default assignment, from the last target to the first one
+        for lh in lhs.iter().rev().skip(stored) {
+            let reg = self.fs().freereg - 1;
+            self.store_var(lh, &mut ExpDesc::new(ExpKind::NonReloc(reg)));
+        }
+    }
+
+    fn retstat(&mut self, ret: &Return) {
+        let (first, nret) = if ret.exprs.is_empty() {
+            (0, 0) // return no values
+        } else {
+            let mut e = self.explist(&ret.exprs);
+            let nret = ret.exprs.len() as i32;
+            if e.has_multret() {
+                self.set_multret(&e);
+                if let (ExpKind::Call(pc), 1) = (e.k, nret) {
+                    set_opcode(self.instruction(pc), OpCode::TailCall);
+                }
+                (self.fs().nactvar, MULTRET) // return all values
+            } else if nret == 1 {
+                (self.exp_to_any_reg(&mut e), 1) // only one single value
+            } else {
+                self.exp_to_next_reg(&mut e); // values must go to the stack
+                (self.fs().nactvar, nret)
+            }
+        };
+        self.ret(first, nret);
+        if *self.current() == Token::Semicolon {
+            self.skip();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::undump::LoadState;
+    use super::super::dump::DumpState;
+
+    fn constants(f: &Proto) -> Vec<ConstKey> {
+        f.constants.iter().map(ConstKey::from_value).collect()
+    }
+
+    // asserts that `ours` matches the luac output `theirs`, `path` names the
+    // function for the error message
+    fn assert_same(ours: &Proto, theirs: &Proto, path: &str) {
+        assert_eq!(ours.source, theirs.source, "{}: source", path);
+        assert_eq!(ours.numparams, theirs.numparams, "{}: numparams", path);
+        assert_eq!(ours.is_vararg, theirs.is_vararg, "{}: is_vararg", path);
+        assert_eq!(ours.maxstacksize, theirs.maxstacksize, "{}: maxstacksize", path);
+        assert_eq!(ours.linedefined, theirs.linedefined, "{}: linedefined", path);
+        assert_eq!(ours.lastlinedefined, theirs.lastlinedefined,
+                   "{}: lastlinedefined", path);
+        assert_eq!(ours.instructions, theirs.instructions, "{}: instructions", path);
+        assert_eq!(ours.lineinfo, theirs.lineinfo, "{}: lineinfo", path);
+        assert_eq!(constants(ours), constants(theirs), "{}: constants", path);
+        let upvalues = |f: &Proto| -> Vec<_> {
+            f.upvalues.iter().map(|u| (u.name.clone(), u.instack, u.idx)).collect()
+        };
+        assert_eq!(upvalues(ours), upvalues(theirs), "{}: upvalues", path);
+        let locvars = |f: &Proto| -> Vec<_> {
+            f.locvars.iter().map(|l| (l.varname.clone(), l.startpc, l.endpc)).collect()
+        };
+        assert_eq!(locvars(ours), locvars(theirs), "{}: locvars", path);
+        assert_eq!(ours.protos.len(), theirs.protos.len(), "{}: protos", path);
+        for (i, (a, b)) in ours.protos.iter().zip(&theirs.protos).enumerate() {
+            assert_same(a, b, &format!("{}.{}", path, i));
+        }
+    }
+
+    macro_rules! fixture {
+        ($name:expr) => {
+            (concat!("@", $name, ".lua"),
+             &include_bytes!(concat!("../tests/fixtures/", $name, ".lua"))[..],
+             &include_bytes!(concat!("../tests/fixtures/", $name, ".luac"))[..])
+        };
+    }
+
+    // fixtures were compiled with luac 5.3.6 from the fixtures directory
+    #[test]
+    fn test_luac_fixtures() {
+        let fixtures = [
+            fixture!("expressions"),
+            fixture!("closures"),
+            fixture!("constructors"),
+            fixture!("folding"),
+            fixture!("gotos"),
+            fixture!("scopes"),
+            fixture!("strings"),
+            fixture!("loops"),
+        ];
+        for &(name, source, luac) in fixtures.iter() {
+            let ours = compile(source, name).unwrap();
+            let theirs = LoadState::from_u8(luac.to_vec(), name).unwrap();
+            assert_same(&ours, &theirs, name);
+            let dumped = DumpState::to_u8(&ours, false).unwrap();
+            assert!(dumped == luac, "{}: dumped chunk differs", name);
+        }
+    }
+
+    #[test]
+    fn test_loadkx() {
+        let count = MAXARG_BX as usize + 2;
+        let mut source = b"local t = {".to_vec();
+        for i in 0..count {
+            source.extend(format!("{}.5,", i).bytes());
+        }
+        source.extend(b"}");
+        let f = compile(&source, "=test").unwrap();
+        assert_eq!(f.constants.len(), count);
+        let n = f.instructions.len();
+        // the last constant does not fit in Bx
+        let reg = |i: usize| 1 + (i as i32 % LFIELDS_PER_FLUSH);
+        assert_eq!(f.instructions[n - 6],
+                   create_abx(OpCode::LoadK, reg(count - 2), MAXARG_BX));
+        assert_eq!(f.instructions[n - 5],
+                   create_abc(OpCode::LoadKX, reg(count - 1), 0, 0));
+        assert_eq!(f.instructions[n - 4], create_ax(OpCode::ExtraArg, MAXARG_BX + 1));
+        let batches = (count as i32 + LFIELDS_PER_FLUSH - 1) / LFIELDS_PER_FLUSH;
+        let pending = count as i32 % LFIELDS_PER_FLUSH;
+        assert_eq!(f.instructions[n - 3],
+                   create_abc(OpCode::SetList, 0, pending, 0));
+        assert_eq!(f.instructions[n - 2], create_ax(OpCode::ExtraArg, batches));
+    }
+
+    #[test]
+    fn test_errors() {
+        let message = |source: &str| {
+            compile(source.as_bytes(), "=test").err().unwrap().to_string()
+        };
+        assert_eq!(message("x = = 1"), "test:1: unexpected symbol near '='");
+        assert_eq!(message("do\n goto l\n local x\n ::l:: print(x)\nend"),
+                   "test:4: <goto l> at line 2 jumps into the scope of local 'x'");
+        assert_eq!(message("goto nowhere"),
+                   "test:1: no visible label 'nowhere' for <goto> at line 1");
+        assert_eq!(message("::a:: ::a::"),
+                   "test:1: label 'a' already defined on line 1");
+        assert_eq!(message("if x then\n break\nend"),
+                   "test:3: <break> at line 2 not inside a loop");
+    }
+}
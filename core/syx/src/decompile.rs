@@ -0,0 +1,2236 @@
+// Decompiler from 5.2 and 5.3 bytecode back to Lua source
+//
+// Registers are replayed the way lcode.c filled them: the value of a
+// temporary waits in its register until the instruction that consumes it,
+// and becomes part of that instruction's expression. Locals are the
+// registers debug information names or, once it is stripped, those whose
+// value is still needed after being consumed, or is overwritten without
+// ever being consumed. Loops come from the back edges of the control flow
+// graph, conditions from the chains of tests and jumps lcode.c leaves for
+// `and`, `or` and comparisons, and a jump that fits no statement becomes a
+// goto.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::ast::*;
+use super::cfg;
+use super::code::get_opcode;
+use super::object::{Proto, SyxValue};
+use super::opcodes::*;
+use super::pretty::{is_identifier, print_block};
+use super::print::function_name;
+use super::errors::*;
+
+pub fn decompile(proto: &Proto) -> Result<String> {
+    if proto.version != Version::Lua52 && proto.version != Version::Lua53 {
+        return fail(function_name(proto), format!(
+            "only 5.2 and 5.3 bytecode can be decompiled, not {:?}", proto.version));
+    }
+    let mut reserved = HashSet::new();
+    reserve(proto, &mut reserved);
+    // the main function has the single upvalue _ENV
+    let upvalues = proto.upvalues.iter().enumerate().map(|(i, upvalue)| {
+        if !upvalue.name.is_empty() {
+            String::from_utf8_lossy(&upvalue.name).into_owned()
+        } else if i == 0 {
+            "_ENV".to_owned()
+        } else {
+            format!("u{}", i)
+        }
+    }).collect();
+    let mut counter = 0;
+    let body = function(proto, upvalues, &mut counter, &reserved)?;
+    let mut source = print_block(&body.body);
+    source.push('\n');
+    Ok(source)
+}
+
+fn fail<T>(location: String, message: String) -> Result<T> {
+    Err(ErrorKind::DecompileError(location, message).into())
+}
+
+// Names made up for a stripped chunk must not hide a global, or a name the
+// debug information of another function has
+fn reserve(f: &Proto, reserved: &mut HashSet<Vec<u8>>) {
+    for constant in &f.constants {
+        if let SyxValue::String(ref s) = *constant {
+            reserved.insert(s.clone());
+        }
+    }
+    reserved.extend(f.locvars.iter().map(|x| x.varname.clone()));
+    reserved.extend(f.upvalues.iter().map(|x| x.name.clone()));
+    for child in &f.protos {
+        reserve(child, reserved);
+    }
+}
+
+// Decompiles a function whose upvalues have the given names. A goto needs
+// a label at its target, which may already be behind, so the function is
+// decompiled again whenever the last pass jumped somewhere new.
+fn function(f: &Proto, upvalues: Vec<String>, counter: &mut usize,
+            reserved: &HashSet<Vec<u8>>) -> Result<FuncBody> {
+    let first = *counter;
+    let mut labels = BTreeSet::new();
+    loop {
+        let mut state = Function::new(f, upvalues.clone(), first, reserved, labels.clone())
+            .chain_err(|| format!("could not decompile {}", function_name(f)))?;
+        let body = state.body()
+            .chain_err(|| format!("could not decompile {}", function_name(f)))?;
+        *counter = state.counter;
+        if state.gotos.is_subset(&labels) {
+            return Ok(body);
+        }
+        labels.extend(state.gotos);
+    }
+}
+
+// The fields of an instruction, Bx goes to b and Ax to a
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    code: OpCode,
+    a: usize,
+    b: usize,
+    c: usize,
+    sbx: i64,
+}
+
+fn op(instr: &Instruction) -> Op {
+    let code = get_opcode(instr);
+    match *instr {
+        Instruction::ABC { a, b, c, .. } => {
+            Op { code, a: a as usize, b: b as usize, c: c as usize, sbx: 0 }
+        }
+        Instruction::ABx { a, bx, .. } => Op { code, a: a as usize, b: bx as usize, c: 0, sbx: 0 },
+        Instruction::AsBx { a, sbx, .. } => {
+            Op { code, a: a as usize, b: 0, c: 0, sbx: i64::from(sbx) }
+        }
+        Instruction::Ax { ax, .. } => Op { code, a: ax as usize, b: 0, c: 0, sbx: 0 },
+    }
+}
+
+fn is_k(x: usize) -> bool {
+    x & BITMASK_IS_RK as usize != 0
+}
+
+fn is_test(code: OpCode) -> bool {
+    matches!(code, OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet)
+}
+
+// luaO_fb2int, the table sizes of NewTable are "floating point bytes"
+fn fb2int(x: usize) -> usize {
+    if x < 8 { x } else { ((x & 7) + 8) << ((x >> 3) - 1) }
+}
+
+fn expr(kind: ExprKind) -> Expr {
+    Expr { kind, span: Span::default() }
+}
+
+fn stat(kind: StatKind) -> Stat {
+    Stat { kind, span: Span::default() }
+}
+
+fn name(name: &str) -> Name {
+    Name { name: name.to_owned(), span: Span::default() }
+}
+
+fn label(pc: usize) -> Name {
+    name(&format!("label_{}", pc + 1))
+}
+
+fn binary(op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+    expr(ExprKind::Binary {
+        op, op_span: Span::default(), lhs: Box::new(lhs), rhs: Box::new(rhs),
+    })
+}
+
+fn unary(op: UnOp, operand: Expr) -> Expr {
+    expr(ExprKind::Unary { op, op_span: Span::default(), operand: Box::new(operand) })
+}
+
+fn paren(e: Expr) -> Expr {
+    expr(ExprKind::Paren(Box::new(e)))
+}
+
+fn string_key(key: &Expr) -> Option<&[u8]> {
+    match key.kind {
+        ExprKind::String(ref s) if is_identifier(s) => Some(s),
+        _ => None,
+    }
+}
+
+// Negation that keeps the truth value of a condition, `not not x` is only
+// `x` where nobody looks further than that
+fn not(e: Expr) -> Expr {
+    match e.kind {
+        ExprKind::Unary { op: UnOp::Not, operand, .. } => *operand,
+        ExprKind::Binary { op: BinOp::Eq, op_span, lhs, rhs } => {
+            expr(ExprKind::Binary { op: BinOp::Ne, op_span, lhs, rhs })
+        }
+        ExprKind::Binary { op: BinOp::Ne, op_span, lhs, rhs } => {
+            expr(ExprKind::Binary { op: BinOp::Eq, op_span, lhs, rhs })
+        }
+        kind => unary(UnOp::Not, Expr { kind, span: e.span }),
+    }
+}
+
+// `truthy(v) == c`, what Test and TestSet jump on
+fn truth(v: Expr, c: usize) -> Expr {
+    if c != 0 { v } else { not(v) }
+}
+
+// What waits in a register for the instruction that consumes it
+#[derive(Debug, Clone)]
+enum Value {
+    Expr(Expr),
+    Multi(Expr), // every result of a call or `...`, up to the top
+    // one of the results of the call or `...` at pc `id`
+    Part { id: usize, index: usize, expr: Expr },
+    // a constructor, with room left for `array` items and `hash` fields
+    Table { fields: Vec<Field>, array: usize, hash: usize },
+    Method { obj: Expr, key: Expr }, // R(A) of SelfLoad
+    SelfArg,                         // R(A+1) of SelfLoad
+}
+
+// The last field of a constructor is the only one that keeps every value
+// of a call, so its items come wrapped in parentheses that only the last
+// single valued one keeps
+fn table(mut fields: Vec<Field>) -> Expr {
+    let last = fields.len().saturating_sub(1);
+    for field in &mut fields[..last] {
+        if let Field::Positional(ref mut item) = *field {
+            let inner = match item.kind {
+                ExprKind::Paren(ref inner) if inner.kind.is_multi() => (**inner).clone(),
+                _ => continue,
+            };
+            *item = inner;
+        }
+    }
+    expr(ExprKind::Table(fields))
+}
+
+fn value_expr(value: Value) -> Expr {
+    match value {
+        Value::Expr(e) | Value::Multi(e) | Value::Part { expr: e, .. } => e,
+        Value::Table { fields, .. } => table(fields),
+        Value::Method { obj, key } => expr(ExprKind::Index { obj: Box::new(obj), key: Box::new(key) }),
+        Value::SelfArg => expr(ExprKind::Nil),
+    }
+}
+
+// Expressions of consecutive registers, the results of one call becoming
+// that call again. A single valued call at the end of a list that would
+// take all of them gets parentheses when `fixed`.
+fn collapse(values: Vec<Value>, fixed: bool) -> Vec<Expr> {
+    let mut exprs = Vec::with_capacity(values.len());
+    let mut single = false;
+    let mut part = None;
+    for value in values {
+        single = false;
+        match value {
+            Value::Part { id, index, .. } if index > 0 && part == Some(id) => continue,
+            Value::Part { id, expr, .. } => {
+                part = Some(id);
+                exprs.push(expr);
+                continue;
+            }
+            Value::Expr(ref e) if e.kind.is_multi() => single = true,
+            _ => (),
+        }
+        part = None;
+        exprs.push(value_expr(value));
+    }
+    if fixed && single {
+        let last = exprs.pop().unwrap();
+        exprs.push(paren(last));
+    }
+    exprs
+}
+
+// `local a, b = f(), nil` is `local a, b = f()` only when no call or `...`
+// is left before the nils
+fn trim(mut exprs: Vec<Expr>) -> Vec<Expr> {
+    while let Some(last) = exprs.last().map(|x| x.kind == ExprKind::Nil) {
+        let multi = exprs.len() > 1 && exprs[exprs.len() - 2].kind.is_multi();
+        if !last || multi {
+            break;
+        }
+        exprs.pop();
+    }
+    exprs
+}
+
+// `a.b.c` in `function a.b.c() end`
+fn func_path(target: &Expr) -> Option<Vec<Name>> {
+    match target.kind {
+        ExprKind::Name(ref n) => Some(vec![name(n)]),
+        ExprKind::Index { ref obj, ref key } => {
+            let key = string_key(key)?;
+            let mut path = func_path(obj)?;
+            path.push(name(&String::from_utf8_lossy(key)));
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+fn assign(targets: Vec<Expr>, mut exprs: Vec<Expr>) -> StatKind {
+    if targets.len() == 1 && exprs.len() == 1 {
+        if let (Some(mut path), ExprKind::Function(_)) = (func_path(&targets[0]), &exprs[0].kind) {
+            let body = match exprs.pop().unwrap().kind {
+                ExprKind::Function(body) => *body,
+                _ => unreachable!(),
+            };
+            let mut method = None;
+            if path.len() > 1 && body.params.first().is_some_and(|x| x.name == "self") {
+                method = path.pop();
+            }
+            let name = FuncName { path, method, span: Span::default() };
+            return StatKind::Function { name, body };
+        }
+    }
+    StatKind::Assign { targets, exprs }
+}
+
+fn make_block(mut stats: Vec<Stat>) -> Block {
+    // returns wait in a `do` block until nothing follows them
+    let ret = match stats.last() {
+        Some(&Stat { kind: StatKind::Do(ref block), .. })
+            if block.stats.is_empty() && block.ret.is_some() => block.ret.clone(),
+        _ => None,
+    };
+    if ret.is_some() {
+        stats.pop();
+    }
+    Block { stats, ret, span: Span::default() }
+}
+
+// A test and the jump after it: when `cond` holds control goes to
+// `target`. `start` is where the code computing its operands begins.
+#[derive(Debug, Clone)]
+struct Node {
+    start: usize,
+    cond: Expr,
+    target: usize,
+    carried: Option<bool>, // truth of the value a Test(Set) carries to the join
+}
+
+// What jumping out of a condition means: jumping to one of `list` makes it
+// true or false, and a jump to `join` carries a value
+#[derive(Debug, Clone, Default)]
+struct Exits {
+    list: Vec<(usize, bool)>,
+    join: Option<usize>,
+}
+
+fn meaning(node: &Node, end: usize, cont: bool, exits: &Exits) -> Option<bool> {
+    if node.target == end {
+        Some(cont)
+    } else if Some(node.target) == exits.join {
+        node.carried
+    } else {
+        exits.list.iter().rev().find(|x| x.0 == node.target).map(|x| x.1)
+    }
+}
+
+// The meaning shared by every jump out of `nodes`, those to `split` and
+// inside the nodes aside
+fn group_meaning(nodes: &[Node], split: usize, end: usize, cont: bool,
+                 exits: &Exits) -> Option<bool> {
+    let first = nodes[0].start;
+    let mut shared = None;
+    for node in nodes {
+        if node.target == split || (node.target > first && node.target < split) {
+            continue;
+        }
+        let m = meaning(node, end, cont, exits)?;
+        if shared.is_some() && shared != Some(m) {
+            return None;
+        }
+        shared = Some(m);
+    }
+    shared
+}
+
+// The expression that is true when the code of `nodes` leaves through a
+// true exit. Falling through the last node to `end` means `cont`.
+//
+// lcode.c compiles `x and y` with every false jump of `x` going where the
+// whole expression's false jumps go, and its true ones to the code of `y`,
+// `or` the other way around, so the nodes split at the first point that
+// no earlier jump goes beyond.
+fn tree(nodes: &[Node], end: usize, cont: bool, exits: &Exits) -> Option<Expr> {
+    if nodes.len() == 1 {
+        let node = &nodes[0];
+        let m = meaning(node, end, cont, exits)?;
+        if m == cont {
+            return None;
+        }
+        return Some(if m { node.cond.clone() } else { not(node.cond.clone()) });
+    }
+    for k in 1..nodes.len() {
+        let split = nodes[k].start;
+        if nodes[..k].iter().any(|x| x.target > split && x.target < end) {
+            continue;
+        }
+        let shared = match group_meaning(&nodes[..k], split, end, cont, exits) {
+            Some(m) => m,
+            None => continue,
+        };
+        let mut inner = exits.clone();
+        inner.list.push((end, cont));
+        // the group goes on to the rest when true for `and`, false for `or`
+        let lhs = match tree(&nodes[..k], split, !shared, &inner) {
+            Some(e) => e,
+            None => continue,
+        };
+        let rhs = tree(&nodes[k..], end, cont, exits)?;
+        return Some(binary(if shared { BinOp::Or } else { BinOp::And }, lhs, rhs));
+    }
+    None
+}
+
+// The value left at a join by `nodes` and `last`, the value computed from
+// `start` on. Mixed `and` and `or` nest to the right, `a or b and c` leaves
+// its first group with `a` and the rest as a value of its own.
+fn value_tree(nodes: &[Node], start: usize, last: Expr, exits: &Exits) -> Option<Expr> {
+    if nodes.is_empty() {
+        return Some(last);
+    }
+    for k in (1..nodes.len() + 1).rev() {
+        let split = if k == nodes.len() { start } else { nodes[k].start };
+        if nodes[..k].iter().any(|x| x.target > split && x.target < start) {
+            continue;
+        }
+        // the group goes on to the rest when true for `and`, false for `or`
+        let shared = match group_meaning(&nodes[..k], split, usize::MAX, false, exits) {
+            Some(m) => m,
+            None => continue,
+        };
+        let lhs = match tree(&nodes[..k], split, !shared, exits) {
+            Some(e) => e,
+            None => continue,
+        };
+        let rhs = match value_tree(&nodes[k..], start, last.clone(), exits) {
+            Some(e) => e,
+            None => continue,
+        };
+        return Some(binary(if shared { BinOp::Or } else { BinOp::And }, lhs, rhs));
+    }
+    None
+}
+
+// How an instruction uses a register it reads. Once stripped, a local is
+// told from a temporary by being read again later, and an operand by the
+// temporaries left above it; the stores of a multiple assignment leave the
+// values that have no target there, and loops take their control values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Use {
+    Operand,
+    Store,
+    Control,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Regs {
+    values: BTreeMap<usize, Value>,
+    // register of the conditional value being replayed, it stays a
+    // temporary even when it is a local
+    target: Option<usize>,
+    // the stores of a multiple assignment, lparser.c does them last first,
+    // and whether their value was in a register
+    group: Vec<(Expr, Value, bool)>,
+    // where the locals without debug information of the block were
+    // declared, by register
+    locals: BTreeMap<usize, usize>,
+}
+
+// The state a failed attempt at a shape has to roll back
+struct Snapshot {
+    names: Vec<Option<String>>,
+    counter: usize,
+    declared: Vec<bool>,
+    gotos: BTreeSet<usize>,
+    levels: BTreeMap<usize, usize>,
+    placed: BTreeSet<usize>,
+    until: Option<Expr>,
+}
+
+// A chain of nodes and, after each of them, where it ends, the registers
+// and the state to go on with if it stopped there
+struct Chain {
+    nodes: Vec<Node>,
+    ends: Vec<(usize, Regs, Snapshot)>,
+}
+
+struct Function<'a> {
+    f: &'a Proto,
+    code: Vec<Op>,
+    upvalues: Vec<String>,
+    stripped: bool,
+    locvar_regs: Vec<usize>,
+    declared: Vec<bool>,
+    // locals without debug information, by register
+    names: Vec<Option<String>>,
+    counter: usize,
+    reserved: &'a HashSet<Vec<u8>>,
+    back_jumps: HashMap<usize, Vec<usize>>,
+    loops: Vec<(usize, usize)>, // first pc and exit of the enclosing loops
+    repeat: Option<(usize, usize)>, // header and back jump of a repeat loop
+    until: Option<Expr>,
+    labels: BTreeSet<usize>,
+    placed: BTreeSet<usize>,
+    gotos: BTreeSet<usize>,
+    // without debug information, the fewest registers named at a goto
+    // to each label
+    levels: BTreeMap<usize, usize>,
+}
+
+impl<'a> Function<'a> {
+    fn new(f: &'a Proto, upvalues: Vec<String>, counter: usize,
+           reserved: &'a HashSet<Vec<u8>>, labels: BTreeSet<usize>) -> Result<Function<'a>> {
+        let code: Vec<Op> = f.instructions.iter().map(op).collect();
+
+        // a local gets the first register free when its scope starts, after
+        // those declared with it even when their scopes are empty
+        let locvar_regs = f.locvars.iter().enumerate().map(|(i, var)| {
+            f.locvars[..i].iter()
+                .filter(|x| x.startpc == var.startpc
+                        || x.startpc < var.startpc && var.startpc < x.endpc)
+                .count()
+        }).collect();
+
+        // the jumps closing natural loops, by the first pc of the loop
+        let graph = cfg::build(f)?;
+        let mut back_jumps: HashMap<usize, Vec<usize>> = HashMap::new();
+        for lp in &graph.loops {
+            let header = graph.blocks[lp.header].start;
+            for &latch in &lp.latches {
+                let jumps_to = |pc: usize| code.get(pc).is_some_and(|x| {
+                    x.code == OpCode::Jmp && (pc as i64 + 1 + x.sbx) as usize == header
+                });
+                let mut pc = graph.blocks[latch].end - 1;
+                if jumps_to(pc) {
+                    // the jump closing the upvalues of the body comes
+                    // first, with the target of the loop's own jump
+                    while jumps_to(pc + 1) {
+                        pc += 1;
+                    }
+                    back_jumps.entry(header).or_default().push(pc);
+                }
+            }
+        }
+        // a loop left with a goto or return only jumps back in dead code
+        for (pc, x) in code.iter().enumerate() {
+            let target = (pc as i64 + 1 + x.sbx) as usize;
+            let dead = graph.block_of(pc).is_none_or(|b| !graph.is_reachable(b));
+            let last = code.get(pc + 1).is_none_or(|y| {
+                y.code != OpCode::Jmp || (pc as i64 + 2 + y.sbx) as usize != target
+            });
+            if x.code == OpCode::Jmp && target <= pc && dead && last {
+                let jumps = back_jumps.entry(target).or_default();
+                if !jumps.contains(&pc) {
+                    jumps.push(pc);
+                }
+            }
+        }
+
+        Ok(Function {
+            f,
+            code,
+            upvalues,
+            stripped: f.lineinfo.is_empty(),
+            locvar_regs,
+            declared: vec![false; f.locvars.len()],
+            names: vec![None; usize::from(f.maxstacksize) + 1],
+            counter,
+            reserved,
+            back_jumps,
+            loops: Vec::new(),
+            repeat: None,
+            until: None,
+            labels,
+            placed: BTreeSet::new(),
+            gotos: BTreeSet::new(),
+            levels: BTreeMap::new(),
+        })
+    }
+
+    fn error<T>(&self, pc: usize, message: &str) -> Result<T> {
+        fail(format!("pc {}", pc + 1), message.to_owned())
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            names: self.names.clone(),
+            counter: self.counter,
+            declared: self.declared.clone(),
+            gotos: self.gotos.clone(),
+            levels: self.levels.clone(),
+            placed: self.placed.clone(),
+            until: self.until.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.names = snapshot.names;
+        self.counter = snapshot.counter;
+        self.declared = snapshot.declared;
+        self.gotos = snapshot.gotos;
+        self.levels = snapshot.levels;
+        self.placed = snapshot.placed;
+        self.until = snapshot.until;
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        loop {
+            self.counter += 1;
+            let name = format!("{}{}", prefix, self.counter);
+            if !self.reserved.contains(name.as_bytes()) {
+                return name;
+            }
+        }
+    }
+
+    fn name_reg(&mut self, reg: usize, name: String) {
+        if reg >= self.names.len() {
+            self.names.resize(reg + 1, None);
+        }
+        self.names[reg] = Some(name);
+    }
+
+    // the registers up to the last one named
+    fn level(&self) -> usize {
+        self.names.iter().rposition(|x| x.is_some()).map_or(0, |x| x + 1)
+    }
+
+    // Without debug information the end of a `do` block only shows when
+    // its locals are closed or a goto skips them, the locals from `level`
+    // on go back into one
+    fn end_scope(&mut self, level: usize, regs: &mut Regs, out: &mut Vec<Stat>) -> Result<()> {
+        if !self.stripped {
+            return Ok(());
+        }
+        self.settle(regs, out)?;
+        if self.level() <= level {
+            return Ok(());
+        }
+        let inner: Vec<usize> = regs.locals.range(level..).map(|(&r, _)| r).collect();
+        if let Some(index) = inner.iter().map(|r| regs.locals[r]).min() {
+            let stats = out.split_off(index);
+            out.push(stat(StatKind::Do(make_block(stats))));
+        }
+        for r in inner {
+            regs.locals.remove(&r);
+        }
+        for name in &mut self.names[level..] {
+            *name = None;
+        }
+        Ok(())
+    }
+
+    fn target(&self, pc: usize) -> usize {
+        (pc as i64 + 1 + self.code[pc].sbx) as usize
+    }
+
+    fn is_jump(&self, pc: usize) -> bool {
+        self.code.get(pc).is_some_and(|x| x.code == OpCode::Jmp)
+    }
+
+    // the latest jump back to `pc` before `end`, unless it is the loop
+    // being decompiled
+    fn back_jump(&self, pc: usize, end: usize) -> Option<usize> {
+        if self.loops.iter().any(|x| x.0 == pc) {
+            return None;
+        }
+        self.back_jumps.get(&pc)?.iter().cloned().filter(|&x| x < end).max()
+    }
+
+    // A local active at pc in register `reg`, by debug information, never
+    // one of the `(for ...)` locals the compiler keeps the loop state in
+    fn locvar(&self, reg: usize, pc: usize) -> Option<usize> {
+        let pc = pc as i32;
+        self.f.locvars.iter().enumerate().position(|(i, var)| {
+            self.locvar_regs[i] == reg && var.startpc <= pc && pc < var.endpc
+                && !var.varname.starts_with(b"(")
+        })
+    }
+
+    fn local(&self, reg: usize, pc: usize) -> Option<String> {
+        if let Some(i) = self.locvar(reg, pc) {
+            return Some(String::from_utf8_lossy(&self.f.locvars[i].varname).into_owned());
+        }
+        self.names.get(reg).cloned().unwrap_or(None)
+    }
+
+    // a local whose declaration is still to be written starts at pc
+    fn starts_local(&self, pc: usize) -> bool {
+        self.f.locvars.iter().enumerate().any(|(i, var)| {
+            !self.declared[i] && var.startpc == pc as i32 && !var.varname.starts_with(b"(")
+        })
+    }
+
+    // code from pc on can't join the condition before it
+    fn boundary(&self, pc: usize, end: usize) -> bool {
+        pc >= end || self.labels.contains(&pc) || self.back_jump(pc, end).is_some()
+            || self.starts_local(pc)
+    }
+
+    fn constant(&self, k: usize) -> Expr {
+        expr(match self.f.constants.get(k) {
+            Some(&SyxValue::Bool(true)) => ExprKind::True,
+            Some(&SyxValue::Bool(false)) => ExprKind::False,
+            Some(&SyxValue::Number(n)) => ExprKind::Number(n),
+            Some(&SyxValue::Integer(n)) => ExprKind::Integer(n),
+            Some(SyxValue::String(s)) => ExprKind::String(s.clone()),
+            Some(&SyxValue::Nil) | None => ExprKind::Nil,
+        })
+    }
+
+    fn upvalue(&self, index: usize) -> String {
+        self.upvalues.get(index).cloned().unwrap_or_else(|| format!("u{}", index))
+    }
+
+    // obj[key], a global when obj is _ENV and no local hides the name
+    fn index(&self, obj: Expr, key: Expr, pc: usize) -> Expr {
+        if obj.kind == ExprKind::Name("_ENV".to_owned()) {
+            if let Some(s) = string_key(&key) {
+                let global = String::from_utf8_lossy(s).into_owned();
+                if global != "_ENV" && !self.visible(&global, pc) {
+                    return expr(ExprKind::Name(global));
+                }
+            }
+        }
+        expr(ExprKind::Index { obj: Box::new(obj), key: Box::new(key) })
+    }
+
+    fn visible(&self, n: &str, pc: usize) -> bool {
+        let pc = pc as i32;
+        self.f.locvars.iter().any(|x| x.startpc <= pc && pc < x.endpc && x.varname == n.as_bytes())
+            || self.names.iter().any(|x| x.as_ref().is_some_and(|x| x == n))
+            || self.upvalues.iter().any(|x| x == n)
+    }
+
+    fn global(&self, up: usize, key: Expr, pc: usize) -> Expr {
+        self.index(expr(ExprKind::Name(self.upvalue(up))), key, pc)
+    }
+
+    // Liveness
+
+    // the register a call or `...` left the top after, for the B or C of 0
+    // of the instruction at pc
+    fn top_at(&self, pc: usize) -> usize {
+        for q in (0..pc).rev() {
+            let Op { code, a, b, c, .. } = self.code[q];
+            if (code == OpCode::Call && c == 0) || (code == OpCode::VarArg && b == 0) {
+                return a;
+            }
+        }
+        usize::from(self.f.maxstacksize)
+    }
+
+    // registers read and written by the instruction at pc
+    fn access(&self, pc: usize) -> (Vec<usize>, Vec<usize>) {
+        use self::OpCode::*;
+
+        let Op { code, a, b, c, .. } = self.code[pc];
+        let rk = |x: usize| if is_k(x) { vec![] } else { vec![x] };
+        let range = |from: usize, to: usize| (from..to).collect::<Vec<_>>();
+        let top = || self.top_at(pc) + 1;
+        match code {
+            Move | Unm | BNot | Not | Len => (vec![b], vec![a]),
+            LoadK | LoadKX | LoadBool | GetUpval | NewTable | Closure => (vec![], vec![a]),
+            LoadNil => (vec![], range(a, a + b + 1)),
+            GetTabUp => (rk(c), vec![a]),
+            GetTable => ([vec![b], rk(c)].concat(), vec![a]),
+            SetTabUp => ([rk(b), rk(c)].concat(), vec![]),
+            SetUpval | Test => (vec![a], vec![]),
+            SetTable => ([vec![a], rk(b), rk(c)].concat(), vec![]),
+            SelfLoad => ([vec![b], rk(c)].concat(), vec![a, a + 1]),
+            Add | Sub | Mul | Mod | Pow | Div | IDiv | BAnd | BOr | BXOr | Shl | Shr => {
+                ([rk(b), rk(c)].concat(), vec![a])
+            }
+            Concat => (range(b, c + 1), vec![a]),
+            Eq | Lt | Le => ([rk(b), rk(c)].concat(), vec![]),
+            TestSet => (vec![b], vec![a]),
+            Call | TailCall => {
+                let reads = range(a, if b == 0 { top() } else { a + b });
+                (reads, if c == 0 { vec![a] } else { range(a, a + c - 1) })
+            }
+            Return => (range(a, if b == 0 { top() } else { a + b - 1 }), vec![]),
+            ForLoop => (range(a, a + 3), vec![a, a + 3]),
+            ForPrep => (range(a, a + 3), vec![a]),
+            TForCall => (range(a, a + 3), range(a + 3, a + 3 + c)),
+            TForLoop => (vec![a + 1], vec![a]),
+            SetList => (range(a, if b == 0 { top() } else { a + b + 1 }), vec![]),
+            VarArg => (vec![], if b == 0 { vec![a] } else { range(a, a + b - 1) }),
+            _ => (vec![], vec![]),
+        }
+    }
+
+    fn successors(&self, pc: usize) -> Vec<usize> {
+        let Op { code, b, c, .. } = self.code[pc];
+        match code {
+            OpCode::Jmp | OpCode::ForPrep => vec![self.target(pc)],
+            OpCode::ForLoop | OpCode::TForLoop => vec![self.target(pc), pc + 1],
+            OpCode::LoadBool if c != 0 => vec![pc + 2],
+            OpCode::LoadKX => vec![pc + 2],
+            OpCode::SetList if c == 0 => vec![pc + 2],
+            OpCode::Return | OpCode::TailCall => vec![],
+            _ if is_test(code) => vec![pc + 1, pc + 2],
+            _ => {
+                let _ = b;
+                vec![pc + 1]
+            }
+        }
+    }
+
+    // the value of `reg` is read after pc, before anything writes it
+    fn live_after(&self, pc: usize, reg: usize) -> bool {
+        if self.access(pc).1.contains(&reg) {
+            return false;
+        }
+        let mut seen = vec![false; self.code.len()];
+        let mut stack = self.successors(pc);
+        while let Some(q) = stack.pop() {
+            if q >= self.code.len() || seen[q] {
+                continue;
+            }
+            seen[q] = true;
+            let (reads, writes) = self.access(q);
+            if reads.contains(&reg) {
+                return true;
+            }
+            if !writes.contains(&reg) {
+                stack.extend(self.successors(q));
+            }
+        }
+        false
+    }
+
+    // The next instruction after pc using `reg` only takes temporaries
+    // there, lcode.c copies locals to the top for it
+    fn temp_next(&self, pc: usize, reg: usize) -> bool {
+        use self::OpCode::*;
+
+        let mut q = pc + 1;
+        while q < self.code.len() {
+            let (reads, writes) = self.access(q);
+            if reads.contains(&reg) {
+                let Op { code, a, b, .. } = self.code[q];
+                return match code {
+                    Concat | Call | TailCall | SetList | ForPrep => true,
+                    Return => b != 2,
+                    TForCall => reg >= a,
+                    _ => false,
+                };
+            }
+            if writes.contains(&reg) || self.successors(q) != [q + 1] {
+                return false;
+            }
+            q += 1;
+        }
+        false
+    }
+
+    // Registers
+
+    // Writes the values still waiting in registers up to `upto` as locals,
+    // nothing consumed them so they must have been locals
+    fn flush(&mut self, regs: &mut Regs, upto: usize, out: &mut Vec<Stat>) -> Result<()> {
+        let mut keys: Vec<usize> = regs.values.keys().cloned()
+            .filter(|&r| r <= upto && Some(r) != regs.target)
+            .collect();
+        // the results of a call go together
+        let ids: Vec<usize> = keys.iter().filter_map(|r| match regs.values[r] {
+            Value::Part { id, .. } => Some(id),
+            _ => None,
+        }).collect();
+        for (&r, value) in &regs.values {
+            if let Value::Part { id, .. } = *value {
+                if ids.contains(&id) && !keys.contains(&r) {
+                    keys.push(r);
+                }
+            }
+        }
+        keys.sort();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut names = Vec::with_capacity(keys.len());
+        let mut values = Vec::with_capacity(keys.len());
+        for r in keys {
+            let n = self.fresh("v");
+            self.name_reg(r, n.clone());
+            regs.locals.insert(r, out.len());
+            names.push(name(&n));
+            values.push(regs.values.remove(&r).unwrap());
+        }
+        out.push(stat(StatKind::Local { names, exprs: trim(collapse(values, false)) }));
+        Ok(())
+    }
+
+    fn flush_group(&mut self, regs: &mut Regs, extra: Vec<Value>, out: &mut Vec<Stat>) {
+        if regs.group.is_empty() {
+            return;
+        }
+        let mut targets = Vec::with_capacity(regs.group.len());
+        let mut values = Vec::with_capacity(regs.group.len() + extra.len());
+        let mut missing = 0;
+        for (target, value, from_reg) in regs.group.drain(..).rev() {
+            targets.push(target);
+            // `a, b = 1` gets its nil from LoadNil, `a, b = 1, nil` as a constant
+            let nil = matches!(value, Value::Expr(Expr { kind: ExprKind::Nil, .. }));
+            missing = if nil && from_reg { missing + 1 } else { 0 };
+            values.push(value);
+        }
+        values.extend(extra);
+        let mut exprs = collapse(values, false);
+        if missing > 0 && exprs.len() == targets.len() {
+            let kept = exprs.len() - missing;
+            if kept > 0 && !exprs[kept - 1].kind.is_multi() {
+                exprs.truncate(kept);
+            }
+        }
+        out.push(stat(assign(targets, exprs)));
+    }
+
+    // Ends the statement being replayed
+    fn settle(&mut self, regs: &mut Regs, out: &mut Vec<Stat>) -> Result<()> {
+        self.flush(regs, usize::MAX, out)?;
+        self.flush_group(regs, Vec::new(), out);
+        Ok(())
+    }
+
+    fn emit(&mut self, regs: &mut Regs, out: &mut Vec<Stat>, kind: StatKind) -> Result<()> {
+        self.settle(regs, out)?;
+        out.push(stat(kind));
+        Ok(())
+    }
+
+    // The value of a register, taking it when it waits there
+    fn take(&mut self, regs: &mut Regs, reg: usize, pc: usize, used: Use,
+            out: &mut Vec<Stat>) -> Result<Value> {
+        if regs.values.contains_key(&reg) {
+            let buried = match used {
+                Use::Operand if self.stripped => self.buried(regs, reg, pc),
+                _ => None,
+            };
+            let local = self.stripped && regs.target != Some(reg) && match used {
+                Use::Operand => self.live_after(pc, reg) || buried.is_some(),
+                Use::Store => self.live_after(pc, reg),
+                Use::Control => false,
+            };
+            if !local {
+                return Ok(regs.values.remove(&reg).unwrap());
+            }
+            // buried values nothing reads anymore are locals as well
+            let dead = regs.values.range(reg + 1..)
+                .map(|(&r, _)| r)
+                .rfind(|&r| buried.is_some_and(|b| r <= b) && Some(r) != regs.target
+                       && !self.live_after(pc, r));
+            self.flush(regs, dead.unwrap_or(reg), out)?;
+        }
+        match self.local(reg, pc) {
+            Some(n) => Ok(Value::Expr(expr(ExprKind::Name(n)))),
+            // a value built in a way not recognized, better no output than
+            // a name nothing assigns
+            None => self.error(pc, &format!("register {} read without a value", reg)),
+        }
+    }
+
+    // Temporaries are used last first, one that values the instruction
+    // leaves alone still wait above is a local, gives the highest of those
+    fn buried(&self, regs: &Regs, reg: usize, pc: usize) -> Option<usize> {
+        let reads = self.access(pc).0;
+        regs.values.range(reg + 1..)
+            .map(|(&r, _)| r)
+            .rfind(|&r| Some(r) != regs.target && !reads.contains(&r))
+    }
+
+    fn read(&mut self, regs: &mut Regs, reg: usize, pc: usize, out: &mut Vec<Stat>) -> Result<Expr> {
+        // a single result out of several is only reachable through a local
+        if let Some(&Value::Part { .. }) = regs.values.get(&reg) {
+            self.flush(regs, reg, out)?;
+        }
+        match self.take(regs, reg, pc, Use::Operand, out)? {
+            Value::SelfArg => self.error(pc, "self argument read on its own"),
+            value => Ok(value_expr(value)),
+        }
+    }
+
+    fn rk(&mut self, regs: &mut Regs, x: usize, pc: usize, out: &mut Vec<Stat>) -> Result<Expr> {
+        if is_k(x) {
+            Ok(self.constant(x & !(BITMASK_IS_RK as usize)))
+        } else {
+            self.read(regs, x, pc, out)
+        }
+    }
+
+    // the value of an RK operand for a store, and its register if it
+    // waited there
+    fn rk_value(&mut self, regs: &mut Regs, x: usize, pc: usize,
+                out: &mut Vec<Stat>) -> Result<(Value, Option<usize>)> {
+        if is_k(x) {
+            return Ok((Value::Expr(self.constant(x & !(BITMASK_IS_RK as usize))), None));
+        }
+        self.take_from(regs, x, pc, out)
+    }
+
+    fn take_from(&mut self, regs: &mut Regs, reg: usize, pc: usize,
+                 out: &mut Vec<Stat>) -> Result<(Value, Option<usize>)> {
+        let pending = regs.values.contains_key(&reg);
+        let value = self.take(regs, reg, pc, Use::Store, out)?;
+        let from = if pending && !regs.values.contains_key(&reg) { Some(reg) } else { None };
+        Ok((value, from))
+    }
+
+    // registers from..=to, `fixed` unless the last one goes to the top
+    fn list(&mut self, regs: &mut Regs, from: usize, to: usize, fixed: bool, pc: usize,
+            out: &mut Vec<Stat>) -> Result<Vec<Expr>> {
+        let mut values = Vec::new();
+        for reg in from..to + 1 {
+            values.push(self.take(regs, reg, pc, Use::Operand, out)?);
+        }
+        Ok(collapse(values, fixed))
+    }
+
+    // the last register of the values going to the top, from `from` on
+    fn top(&self, regs: &Regs, from: usize) -> usize {
+        regs.values.iter().rev()
+            .find(|&(&r, v)| r >= from && matches!(*v, Value::Multi(_)))
+            .map(|(&r, _)| r)
+            .unwrap_or(from.saturating_sub(1))
+    }
+
+    // An assignment, or a part of a multiple one: values waiting for an
+    // instruction while another stores mean the stores are all of them.
+    // The store of the value in the lowest register is the last, values
+    // above it were only evaluated.
+    fn store(&mut self, regs: &mut Regs, target: Expr, value: Value, from: Option<usize>,
+             pc: usize, out: &mut Vec<Stat>) -> Result<()> {
+        let mut pending: Vec<usize> = regs.values.keys().cloned()
+            .filter(|&r| Some(r) != regs.target)
+            .collect();
+        let below: Vec<usize> = pending.iter().cloned()
+            .filter(|&r| from.is_none_or(|x| r < x))
+            .collect();
+        if self.stripped && regs.group.is_empty() && !below.is_empty()
+            && !below.iter().any(|&r| self.stores(pc + 1, r)) {
+            // nothing else of the statement is stored, what waits below are locals
+            self.flush(regs, below[below.len() - 1], out)?;
+            pending.retain(|r| !below.contains(r));
+        }
+        if pending.is_empty() && regs.group.is_empty() {
+            let kind = assign(vec![target], collapse(vec![value], false));
+            return self.emit(regs, out, kind);
+        }
+        regs.group.push((target, value, from.is_some()));
+        let last = match from {
+            Some(r) => pending.iter().all(|&x| x > r),
+            None => pending.is_empty(),
+        };
+        if last {
+            let extra = pending.into_iter().map(|r| regs.values.remove(&r).unwrap()).collect();
+            self.flush_group(regs, extra, out);
+        }
+        Ok(())
+    }
+
+    // the instruction at pc stores the value in reg
+    fn stores(&self, pc: usize, reg: usize) -> bool {
+        let Op { code, a, b, c, .. } = match self.code.get(pc) {
+            Some(&x) => x,
+            None => return false,
+        };
+        match code {
+            OpCode::SetTabUp | OpCode::SetTable => !is_k(c) && c == reg,
+            OpCode::SetUpval => a == reg,
+            OpCode::Move => b == reg && self.local(a, pc + 1).is_some(),
+            _ => false,
+        }
+    }
+
+    fn write(&mut self, regs: &mut Regs, reg: usize, value: Value, pc: usize,
+             out: &mut Vec<Stat>) -> Result<()> {
+        self.write_from(regs, reg, value, None, pc, out)
+    }
+
+    fn write_from(&mut self, regs: &mut Regs, reg: usize, value: Value, from: Option<usize>,
+                  pc: usize, out: &mut Vec<Stat>) -> Result<()> {
+        if self.stripped && self.names.get(reg).is_some_and(|x| x.is_some())
+            && self.temp_next(pc, reg) {
+            // the local went out of scope, the register holds temporaries
+            for name in &mut self.names[reg..] {
+                *name = None;
+            }
+        }
+        if regs.target != Some(reg) {
+            if regs.values.contains_key(&reg) && self.local(reg, pc).is_none() {
+                // overwritten before anything consumed it
+                self.flush(regs, reg, out)?;
+            }
+            if let Some(n) = self.local(reg, pc) {
+                return self.store(regs, expr(ExprKind::Name(n)), value, from, pc, out);
+            }
+        }
+        regs.values.insert(reg, value);
+        Ok(())
+    }
+
+    fn write_results(&mut self, regs: &mut Regs, a: usize, results: usize, e: Expr, pc: usize,
+                     out: &mut Vec<Stat>) -> Result<()> {
+        match results {
+            0 => self.write(regs, a, Value::Multi(e), pc, out),
+            1 => self.write(regs, a, Value::Expr(e), pc, out),
+            count => {
+                for index in 0..count {
+                    let part = Value::Part { id: pc, index, expr: e.clone() };
+                    self.write(regs, a + index, part, pc, out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Instructions
+
+    fn call(&mut self, pc: usize, regs: &mut Regs, out: &mut Vec<Stat>) -> Result<Expr> {
+        let Op { a, b, .. } = self.code[pc];
+        let method = match regs.values.get(&a) {
+            Some(&Value::Method { .. }) => regs.values.remove(&a),
+            _ => None,
+        };
+        let func = match method {
+            Some(_) => {
+                regs.values.remove(&(a + 1));
+                None
+            }
+            None => Some(self.read(regs, a, pc, out)?),
+        };
+        let first = if func.is_some() { a + 1 } else { a + 2 };
+        let last = if b == 0 { self.top(regs, first) } else { a + b - 1 };
+        let mut args = self.list(regs, first, last, b != 0, pc, out)?;
+        Ok(expr(match (func, method) {
+            (Some(func), _) => ExprKind::Call { func: Box::new(func), args },
+            (None, Some(Value::Method { obj, key })) => match string_key(&key) {
+                Some(s) => {
+                    let method = name(&String::from_utf8_lossy(s));
+                    ExprKind::MethodCall { obj: Box::new(obj), method, args }
+                }
+                None => {
+                    args.insert(0, obj.clone());
+                    let func = expr(ExprKind::Index { obj: Box::new(obj), key: Box::new(key) });
+                    ExprKind::Call { func: Box::new(func), args }
+                }
+            },
+            _ => unreachable!(),
+        }))
+    }
+
+    // The function Closure creates and whether it is its own upvalue
+    fn closure(&mut self, pc: usize, regs: &mut Regs,
+               out: &mut Vec<Stat>) -> Result<(FuncBody, bool)> {
+        let Op { a, b, .. } = self.code[pc];
+        let child = match self.f.protos.get(b) {
+            Some(child) => child,
+            None => return self.error(pc, "closure of a function that does not exist"),
+        };
+        let mut recursive = false;
+        let mut upvalues = Vec::with_capacity(child.upvalues.len());
+        for upvalue in &child.upvalues {
+            let idx = usize::from(upvalue.idx);
+            let n = if upvalue.instack == 0 {
+                self.upvalue(idx)
+            } else if idx == a && self.local(a, pc).is_none() {
+                // `local function`, the local starts with the closure
+                recursive = true;
+                match self.locvar(a, pc + 1) {
+                    Some(i) => {
+                        self.declared[i] = true;
+                        String::from_utf8_lossy(&self.f.locvars[i].varname).into_owned()
+                    }
+                    None => {
+                        let n = self.fresh("v");
+                        self.name_reg(a, n.clone());
+                        n
+                    }
+                }
+            } else {
+                if regs.values.contains_key(&idx) {
+                    // a captured value lives in a local
+                    self.flush(regs, idx, out)?;
+                }
+                match self.local(idx, pc) {
+                    Some(n) => n,
+                    None => {
+                        let n = self.fresh("v");
+                        self.name_reg(idx, n.clone());
+                        n
+                    }
+                }
+            };
+            upvalues.push(if upvalue.name.is_empty() {
+                n
+            } else {
+                String::from_utf8_lossy(&upvalue.name).into_owned()
+            });
+        }
+        let body = function(child, upvalues, &mut self.counter, self.reserved)?;
+        Ok((body, recursive))
+    }
+
+    // Replays an instruction that only moves values around, returns the pc
+    // after it or None when it is not one of those
+    fn simple(&mut self, pc: usize, regs: &mut Regs, out: &mut Vec<Stat>) -> Result<Option<usize>> {
+        use self::OpCode::*;
+
+        let Op { code, a, b, c, .. } = self.code[pc];
+        let mut next = pc + 1;
+        match code {
+            Move => {
+                // lcode.c only copies locals and the results of calls
+                let local = match regs.values.get(&b) {
+                    Some(Value::Expr(e)) => !e.kind.is_multi(),
+                    Some(_) => true,
+                    None => false,
+                };
+                if self.stripped && local && regs.group.is_empty() && regs.target != Some(b) {
+                    self.flush(regs, b, out)?;
+                }
+                let (value, from) = match self.take_from(regs, b, pc, out)? {
+                    (Value::Part { .. }, _) => {
+                        return self.error(pc, "result of a call moved on its own");
+                    }
+                    taken => taken,
+                };
+                self.write_from(regs, a, value, from, pc, out)?;
+            }
+            LoadK => {
+                let k = self.constant(b);
+                self.write(regs, a, Value::Expr(k), pc, out)?;
+            }
+            LoadKX => {
+                let k = match self.code.get(pc + 1) {
+                    Some(x) if x.code == ExtraArg => self.constant(x.a),
+                    _ => return self.error(pc, "LoadKX without an ExtraArg"),
+                };
+                self.write(regs, a, Value::Expr(k), pc, out)?;
+                next = pc + 2;
+            }
+            LoadBool => {
+                let value = expr(if b != 0 { ExprKind::True } else { ExprKind::False });
+                self.write(regs, a, Value::Expr(value), pc, out)?;
+                if c != 0 {
+                    next = pc + 2;
+                }
+            }
+            LoadNil => {
+                for reg in a..a + b + 1 {
+                    self.write(regs, reg, Value::Expr(expr(ExprKind::Nil)), pc, out)?;
+                }
+            }
+            GetUpval => {
+                let upvalue = expr(ExprKind::Name(self.upvalue(b)));
+                self.write(regs, a, Value::Expr(upvalue), pc, out)?;
+            }
+            GetTabUp => {
+                let key = self.rk(regs, c, pc, out)?;
+                let value = self.global(b, key, pc);
+                self.write(regs, a, Value::Expr(value), pc, out)?;
+            }
+            GetTable => {
+                let obj = self.read(regs, b, pc, out)?;
+                let key = self.rk(regs, c, pc, out)?;
+                let value = self.index(obj, key, pc);
+                self.write(regs, a, Value::Expr(value), pc, out)?;
+            }
+            SetTabUp => {
+                let key = self.rk(regs, b, pc, out)?;
+                let target = self.global(a, key, pc);
+                let (value, from) = self.rk_value(regs, c, pc, out)?;
+                self.store(regs, target, value, from, pc, out)?;
+            }
+            SetUpval => {
+                let target = expr(ExprKind::Name(self.upvalue(b)));
+                let (value, from) = self.take_from(regs, a, pc, out)?;
+                self.store(regs, target, value, from, pc, out)?;
+            }
+            SetTable => self.set_table(pc, regs, out)?,
+            NewTable => {
+                let value = Value::Table { fields: Vec::new(), array: fb2int(b), hash: fb2int(c) };
+                self.write(regs, a, value, pc, out)?;
+            }
+            SelfLoad => {
+                let obj = self.read(regs, b, pc, out)?;
+                let key = self.rk(regs, c, pc, out)?;
+                regs.values.insert(a, Value::Method { obj, key });
+                regs.values.insert(a + 1, Value::SelfArg);
+            }
+            Add | Sub | Mul | Mod | Pow | Div | IDiv | BAnd | BOr | BXOr | Shl | Shr => {
+                let op = match code {
+                    Add => BinOp::Add,
+                    Sub => BinOp::Sub,
+                    Mul => BinOp::Mul,
+                    Mod => BinOp::Mod,
+                    Pow => BinOp::Pow,
+                    Div => BinOp::Div,
+                    IDiv => BinOp::IDiv,
+                    BAnd => BinOp::BAnd,
+                    BOr => BinOp::BOr,
+                    BXOr => BinOp::BXor,
+                    Shl => BinOp::Shl,
+                    _ => BinOp::Shr,
+                };
+                let lhs = self.rk(regs, b, pc, out)?;
+                let rhs = self.rk(regs, c, pc, out)?;
+                self.write(regs, a, Value::Expr(binary(op, lhs, rhs)), pc, out)?;
+            }
+            Unm | BNot | Not | Len => {
+                let op = match code {
+                    Unm => UnOp::Neg,
+                    BNot => UnOp::BNot,
+                    Not => UnOp::Not,
+                    _ => UnOp::Len,
+                };
+                let operand = self.read(regs, b, pc, out)?;
+                self.write(regs, a, Value::Expr(unary(op, operand)), pc, out)?;
+            }
+            Concat => {
+                let mut operands = Vec::with_capacity(c + 1 - b);
+                for reg in b..c + 1 {
+                    operands.push(self.read(regs, reg, pc, out)?);
+                }
+                let last = operands.pop().unwrap();
+                let value = operands.into_iter().rev()
+                    .fold(last, |rhs, lhs| binary(BinOp::Concat, lhs, rhs));
+                self.write(regs, a, Value::Expr(value), pc, out)?;
+            }
+            Call => {
+                let call = self.call(pc, regs, out)?;
+                if c == 1 {
+                    self.emit(regs, out, StatKind::Call(call))?;
+                } else {
+                    let results = if c == 0 { 0 } else { c - 1 };
+                    self.write_results(regs, a, results, call, pc, out)?;
+                }
+            }
+            Closure => {
+                let (body, recursive) = self.closure(pc, regs, out)?;
+                if recursive {
+                    let n = self.local(a, pc + 1).unwrap();
+                    let kind = StatKind::LocalFunction { name: name(&n), body };
+                    self.emit(regs, out, kind)?;
+                    if self.stripped {
+                        regs.locals.insert(a, out.len() - 1);
+                    }
+                } else {
+                    let value = expr(ExprKind::Function(Box::new(body)));
+                    self.write(regs, a, Value::Expr(value), pc, out)?;
+                }
+            }
+            VarArg => {
+                let results = if b == 0 { 0 } else { b - 1 };
+                self.write_results(regs, a, results, expr(ExprKind::VarArg), pc, out)?;
+            }
+            SetList => {
+                let last = if b == 0 { self.top(regs, a + 1) } else { a + b };
+                let mut items = Vec::new();
+                for reg in a + 1..last + 1 {
+                    if let Some(value) = regs.values.remove(&reg) {
+                        items.push(Field::Positional(item(value)));
+                    }
+                }
+                match regs.values.get_mut(&a) {
+                    Some(&mut Value::Table { ref mut fields, ref mut array, .. }) => {
+                        *array = array.saturating_sub(items.len());
+                        fields.extend(items);
+                    }
+                    _ => return self.error(pc, "SetList outside of a constructor"),
+                }
+                if c == 0 {
+                    next = pc + 2;
+                }
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(next))
+    }
+
+    fn set_table(&mut self, pc: usize, regs: &mut Regs, out: &mut Vec<Stat>) -> Result<()> {
+        let Op { a, b, c, .. } = self.code[pc];
+        let field = match regs.values.get(&a) {
+            Some(&Value::Table { hash, .. }) => hash > 0,
+            _ => false,
+        };
+        if field {
+            let key = self.rk(regs, b, pc, out)?;
+            let value = self.rk(regs, c, pc, out)?;
+            // the items waiting above the table come before this field
+            let above: Vec<usize> = regs.values.range(a + 1..).map(|(&r, _)| r).collect();
+            let mut items: Vec<Field> = above.into_iter()
+                .map(|r| Field::Positional(item(regs.values.remove(&r).unwrap())))
+                .collect();
+            items.push(match string_key(&key) {
+                Some(s) => Field::Named(name(&String::from_utf8_lossy(s)), value),
+                None => Field::Keyed(key, value),
+            });
+            if let Some(&mut Value::Table { ref mut fields, ref mut hash, .. }) = regs.values.get_mut(&a) {
+                fields.extend(items);
+                *hash -= 1;
+            }
+            return Ok(());
+        }
+        if let Some(&Value::Table { .. }) = regs.values.get(&a) {
+            // `{}.x` is not Lua, the table is a local
+            self.flush(regs, a, out)?;
+        }
+        let obj = self.read(regs, a, pc, out)?;
+        let key = self.rk(regs, b, pc, out)?;
+        let (value, from) = self.rk_value(regs, c, pc, out)?;
+        let target = self.index(obj, key, pc);
+        self.store(regs, target, value, from, pc, out)
+    }
+
+    // Conditions
+
+    // The registers and locations of `LoadBool R 0 1; LoadBool R 1 0`, when
+    // pc is one of them
+    fn loadbools(&self, pc: usize) -> Option<(usize, usize)> {
+        let pair = |lf: usize| -> Option<(usize, usize)> {
+            let f = self.code.get(lf)?;
+            let t = self.code.get(lf + 1)?;
+            if f.code == OpCode::LoadBool && f.b == 0 && f.c != 0
+                && t.code == OpCode::LoadBool && t.b != 0 && t.c == 0 && t.a == f.a {
+                Some((f.a, lf))
+            } else {
+                None
+            }
+        };
+        pair(pc).or_else(|| if pc > 0 { pair(pc - 1) } else { None })
+    }
+
+    fn node(&mut self, pc: usize, start: usize, regs: &mut Regs,
+            out: &mut Vec<Stat>) -> Result<Node> {
+        let Op { code, a, b, c, .. } = self.code[pc];
+        let target = self.target(pc + 1);
+        let (cond, carried) = match code {
+            OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                let lhs = self.rk(regs, b, pc, out)?;
+                let rhs = self.rk(regs, c, pc, out)?;
+                let op = match code {
+                    OpCode::Eq => BinOp::Eq,
+                    OpCode::Lt => BinOp::Lt,
+                    _ => BinOp::Le,
+                };
+                let comparison = binary(op, lhs, rhs);
+                (if a != 0 { comparison } else { not(comparison) }, None)
+            }
+            OpCode::Test => {
+                let carried = if regs.target == Some(a) { Some(c != 0) } else { None };
+                (truth(self.read(regs, a, pc, out)?, c), carried)
+            }
+            OpCode::TestSet => (truth(self.read(regs, b, pc, out)?, c), Some(c != 0)),
+            _ => return self.error(pc, "not a test"),
+        };
+        Ok(Node { start, cond, target, carried })
+    }
+
+    // Replays the code of a value computed with jumps, from the test at p:
+    // `and`, `or` and comparisons that end up in a register. Returns the
+    // registers after it, that register, its value and the pc after it.
+    fn value(&mut self, p: usize, regs: &Regs) -> Result<Option<(Regs, usize, Expr, usize)>> {
+        if !self.is_jump(p + 1) {
+            return Ok(None);
+        }
+        let Op { code, a, .. } = self.code[p];
+        let target = self.target(p + 1);
+        let mut candidates = Vec::new();
+        if code == OpCode::Test && regs.target != Some(a) && regs.values.contains_key(&a) {
+            candidates.push((a, target));
+        }
+        // the register and join of the value may also come from a later
+        // test, `a == b and c or d` only has its first one for `and`
+        let mut q = p;
+        while q < self.code.len() && (q == p || !self.boundary(q, self.code.len())) {
+            let op = self.code[q];
+            if is_test(op.code) && self.is_jump(q + 1) {
+                if let Some(found) = self.join_of(q) {
+                    if found.1 > q + 2 && target <= found.1 && !candidates.contains(&found) {
+                        candidates.push(found);
+                    }
+                }
+                q += 2;
+                continue;
+            }
+            match op.code {
+                OpCode::SetTable | OpCode::SetTabUp | OpCode::SetUpval | OpCode::SetList
+                | OpCode::Return | OpCode::TailCall | OpCode::ForPrep | OpCode::ForLoop
+                | OpCode::TForCall | OpCode::TForLoop => break,
+                OpCode::Call if op.c == 1 => break,
+                _ => q += 1,
+            }
+        }
+
+        for (reg, join) in candidates {
+            if join <= p + 2 || join > self.code.len() {
+                continue;
+            }
+            let lf = match self.loadbools(join - 2) {
+                Some((r, lf)) if r == reg && lf == join - 2 => Some(lf),
+                _ => None,
+            };
+            let snapshot = self.snapshot();
+            let result = self.value_at(p, reg, join, lf, regs)?;
+            if result.is_some() {
+                return Ok(result);
+            }
+            self.restore(snapshot);
+        }
+        Ok(None)
+    }
+
+    // The register and join of a value a test at q may be part of
+    fn join_of(&self, q: usize) -> Option<(usize, usize)> {
+        let Op { code, a, .. } = self.code[q];
+        let target = self.target(q + 1);
+        if code == OpCode::TestSet {
+            Some((a, target))
+        } else if let Some((reg, lf)) = self.loadbools(target) {
+            Some((reg, lf + 2))
+        } else if code == OpCode::Test && self.local(a, q).is_none() {
+            Some((a, target))
+        } else {
+            None
+        }
+    }
+
+    fn value_at(&mut self, p: usize, reg: usize, join: usize, lf: Option<usize>,
+                regs: &Regs) -> Result<Option<(Regs, usize, Expr, usize)>> {
+        let stop = lf.unwrap_or(join);
+        let mut sim = regs.clone();
+        let outer = sim.target.replace(reg);
+        let mut scratch = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut start = p;
+        let mut pc = p;
+        let mut value_end = stop;
+        while pc < stop {
+            if pc > p && (self.labels.contains(&pc) || self.starts_local(pc)) {
+                return Ok(None);
+            }
+            let op = self.code[pc];
+            if is_test(op.code) && self.is_jump(pc + 1) {
+                let t = self.target(pc + 1);
+                let exit = t == join || lf.is_some_and(|x| t == x || t == x + 1);
+                if !exit {
+                    if pc > p {
+                        let snapshot = self.snapshot();
+                        if let Some((inner, r, e, next)) = self.value(pc, &sim)? {
+                            if next > stop {
+                                return Ok(None);
+                            }
+                            // an earlier test jumping into it, as in `a and b
+                            // and c or d`, makes its tests part of this value
+                            if !nodes.iter().any(|x| x.target > pc && x.target < next) {
+                                sim = inner;
+                                self.write(&mut sim, r, Value::Expr(e), pc, &mut scratch)?;
+                                pc = next;
+                                continue;
+                            }
+                            self.restore(snapshot);
+                        }
+                    }
+                    if t <= pc || t >= stop {
+                        return Ok(None);
+                    }
+                }
+                nodes.push(self.node(pc, start, &mut sim, &mut scratch)?);
+                pc += 2;
+                start = pc;
+            } else if op.code == OpCode::Jmp {
+                // the jump over the LoadBools after the last value
+                if self.target(pc) != join || lf != Some(pc + 1) {
+                    return Ok(None);
+                }
+                value_end = pc;
+                pc += 1;
+            } else {
+                match self.simple(pc, &mut sim, &mut scratch)? {
+                    Some(next) => pc = next,
+                    None => return Ok(None),
+                }
+            }
+            if !scratch.is_empty() {
+                return Ok(None);
+            }
+        }
+        if pc != stop || nodes.is_empty() {
+            return Ok(None);
+        }
+
+        let mut exits = Exits { list: Vec::new(), join: Some(join) };
+        if let Some(lf) = lf {
+            exits.list.push((lf, false));
+            exits.list.push((lf + 1, true));
+        }
+        let e = if start < value_end {
+            // the final value, where every jump not carrying one goes on to
+            let last = match sim.values.remove(&reg) {
+                Some(value) => value_expr(value),
+                None => return Ok(None),
+            };
+            match value_tree(&nodes, start, last, &exits) {
+                Some(e) => e,
+                None => return Ok(None),
+            }
+        } else {
+            match (lf, tree(&nodes, stop, false, &exits)) {
+                (Some(_), Some(e)) => e,
+                _ => return Ok(None),
+            }
+        };
+        sim.target = outer;
+        Ok(Some((sim, reg, e, join)))
+    }
+
+    // Replays code from pc up to the next test that isn't part of a value,
+    // returns None if it leaves anything but values behind
+    fn segment(&mut self, mut pc: usize, end: usize, regs: &mut Regs) -> Result<Option<usize>> {
+        let start = pc;
+        let mut scratch = Vec::new();
+        loop {
+            if pc > start && self.boundary(pc, end) || pc >= end {
+                return Ok(None);
+            }
+            let op = self.code[pc];
+            if is_test(op.code) && self.is_jump(pc + 1) {
+                match self.value(pc, regs)? {
+                    Some((inner, r, e, next)) => {
+                        *regs = inner;
+                        self.write(regs, r, Value::Expr(e), pc, &mut scratch)?;
+                        pc = next;
+                    }
+                    None => return Ok(Some(pc)),
+                }
+            } else {
+                match self.simple(pc, regs, &mut scratch)? {
+                    Some(next) => pc = next,
+                    None => return Ok(None),
+                }
+            }
+            if !scratch.is_empty() {
+                return Ok(None);
+            }
+        }
+    }
+
+    // The tests from the one at pc on that can make a single condition
+    fn chain(&mut self, pc: usize, end: usize, regs: &mut Regs,
+             out: &mut Vec<Stat>) -> Result<Chain> {
+        let first = self.node(pc, pc, regs, out)?;
+        let mut x = pc + 2;
+        let mut chain = Chain { nodes: vec![first], ends: vec![(x, regs.clone(), self.snapshot())] };
+        while !self.boundary(x, end) {
+            let snapshot = self.snapshot();
+            let mut sim = chain.ends.last().unwrap().1.clone();
+            let mut scratch = Vec::new();
+            let node = match self.segment(x, end, &mut sim)? {
+                Some(test) => {
+                    let node = self.node(test, x, &mut sim, &mut scratch)?;
+                    x = test + 2;
+                    node
+                }
+                None => {
+                    self.restore(snapshot);
+                    break;
+                }
+            };
+            if !scratch.is_empty() {
+                self.restore(snapshot);
+                break;
+            }
+            chain.nodes.push(node);
+            chain.ends.push((x, sim, self.snapshot()));
+        }
+        Ok(chain)
+    }
+
+    // Targets of the nodes leaving them, None when one jumps inside them
+    // anywhere but to the code of a node
+    fn externals(nodes: &[Node], x: usize) -> Option<Vec<usize>> {
+        let first = nodes[0].start;
+        let mut targets = Vec::new();
+        for node in nodes {
+            let t = node.target;
+            if t == x || nodes[1..].iter().any(|n| n.start == t) {
+                continue;
+            }
+            if t > first && t < x {
+                return None;
+            }
+            if !targets.contains(&t) {
+                targets.push(t);
+            }
+        }
+        Some(targets)
+    }
+
+    fn jump(&mut self, target: usize) -> StatKind {
+        if self.loops.last().map(|x| x.1) == Some(target) {
+            StatKind::Break
+        } else {
+            self.gotos.insert(target);
+            let level = self.level();
+            let entry = self.levels.entry(target).or_insert(level);
+            *entry = level.min(*entry);
+            StatKind::Goto(label(target))
+        }
+    }
+
+    // A condition at statement level: an if, a test that breaks or jumps
+    // away, or the condition of a repeat loop
+    fn condition(&mut self, pc: usize, end: usize, regs: &mut Regs,
+                 out: &mut Vec<Stat>) -> Result<usize> {
+        let mut chain = self.chain(pc, end, regs, out)?;
+        while let Some((x, after, snapshot)) = chain.ends.pop() {
+            let nodes = &chain.nodes[..chain.ends.len() + 1];
+            let external = match Function::externals(nodes, x) {
+                Some(targets) => targets,
+                None => continue,
+            };
+
+            if let Some((header, back)) = self.repeat {
+                if x == back + 1 && external == [header] {
+                    let exits = Exits { list: vec![(header, false)], join: None };
+                    if let Some(cond) = tree(nodes, x, true, &exits) {
+                        self.restore(snapshot);
+                        *regs = after;
+                        self.settle(regs, out)?;
+                        self.until = Some(cond);
+                        return Ok(x);
+                    }
+                }
+            }
+
+            if external.len() > 1 {
+                continue;
+            }
+            let target = external.first().cloned().unwrap_or(x);
+            if target >= x && target <= end {
+                // the code up to target runs when the condition holds
+                let cond = if target == x {
+                    tree(nodes, usize::MAX, true, &Exits { list: vec![(x, false)], join: None })
+                } else {
+                    tree(nodes, x, true, &Exits { list: vec![(target, false)], join: None })
+                };
+                if let Some(cond) = cond {
+                    self.restore(snapshot);
+                    *regs = after;
+                    self.settle(regs, out)?;
+                    return self.if_stat(cond, x, target, end, out);
+                }
+            } else {
+                let exits = Exits { list: vec![(target, true)], join: None };
+                if let Some(cond) = tree(nodes, x, false, &exits) {
+                    self.restore(snapshot);
+                    *regs = after;
+                    self.settle(regs, out)?;
+                    let jump = stat(self.jump(target));
+                    let block = make_block(vec![jump]);
+                    out.push(stat(StatKind::If { clauses: vec![(cond, block)], else_block: None }));
+                    return Ok(x);
+                }
+            }
+        }
+        self.error(pc, "tests that make no condition")
+    }
+
+    fn if_stat(&mut self, cond: Expr, x: usize, f: usize, end: usize,
+               out: &mut Vec<Stat>) -> Result<usize> {
+        // a jump at the end of the block skips the else part
+        let mut then_end = f;
+        let mut next = f;
+        if f > x && self.code[f - 1].code == OpCode::Jmp {
+            let y = self.target(f - 1);
+            if y > f && y <= end {
+                then_end = f - 1;
+                next = y;
+            }
+        }
+        let then_block = self.block(x, then_end)?;
+        let mut clauses = vec![(cond, then_block)];
+        let mut else_block = None;
+        if next > f {
+            let block = self.block(f, next)?;
+            let elseif = block.ret.is_none() && block.stats.len() == 1
+                && matches!(block.stats[0].kind, StatKind::If { .. });
+            if elseif {
+                if let StatKind::If { clauses: more, else_block: last } =
+                    block.stats.into_iter().next().unwrap().kind {
+                    clauses.extend(more);
+                    else_block = last;
+                }
+            } else {
+                else_block = Some(block);
+            }
+        }
+        out.push(stat(StatKind::If { clauses, else_block }));
+        Ok(next)
+    }
+
+    // Loops
+
+    fn loop_body(&mut self, start: usize, end: usize, first: usize, exit: usize) -> Result<Block> {
+        self.loops.push((first, exit));
+        let body = self.block(start, end);
+        self.loops.pop();
+        let mut body = body?;
+        // `goto continue` goes to the end of the body, where the loop
+        // jumps back
+        if end != exit && self.labels.contains(&end) && self.placed.insert(end) {
+            body.stats.push(stat(StatKind::Label(label(end))));
+        }
+        Ok(body)
+    }
+
+    // A name for the variable of a for loop in register `reg`, whose scope
+    // starts at the body at pc and is empty with the body
+    fn loop_var(&mut self, reg: usize, pc: usize) -> Name {
+        let found = self.f.locvars.iter().enumerate().position(|(i, var)| {
+            self.locvar_regs[i] == reg && var.startpc == pc as i32 && !var.varname.starts_with(b"(")
+        });
+        if let Some(i) = found {
+            self.declared[i] = true;
+            return name(&String::from_utf8_lossy(&self.f.locvars[i].varname));
+        }
+        let n = self.fresh("v");
+        self.name_reg(reg, n.clone());
+        name(&n)
+    }
+
+    fn numeric_for(&mut self, pc: usize, regs: &mut Regs, out: &mut Vec<Stat>) -> Result<usize> {
+        let a = self.code[pc].a;
+        let forloop = self.target(pc);
+        match self.code.get(forloop) {
+            Some(x) if x.code == OpCode::ForLoop && x.a == a && forloop > pc => (),
+            _ => return self.error(pc, "ForPrep without its ForLoop"),
+        }
+        let mut control = Vec::with_capacity(3);
+        for reg in a..a + 3 {
+            control.push(value_expr(self.take(regs, reg, pc, Use::Control, out)?));
+        }
+        self.settle(regs, out)?;
+        let step = control.pop().unwrap();
+        let step = if step.kind == ExprKind::Integer(1) { None } else { Some(step) };
+        let limit = control.pop().unwrap();
+        let start = control.pop().unwrap();
+
+        let names = self.names.clone();
+        let var = self.loop_var(a + 3, pc + 1);
+        let body = self.loop_body(pc + 1, forloop, pc, forloop + 1);
+        self.names = names;
+        let body = body?;
+        out.push(stat(StatKind::NumericFor(Box::new(NumericFor { var, start, limit, step, body }))));
+        Ok(forloop + 1)
+    }
+
+    // the jump at pc goes to the TForCall of a generic for
+    fn is_generic_for(&self, pc: usize, t: usize) -> bool {
+        t > pc && self.code.get(t).is_some_and(|x| x.code == OpCode::TForCall)
+            && self.code.get(t + 1).is_some_and(|x| x.code == OpCode::TForLoop)
+            && self.target(t + 1) == pc + 1
+    }
+
+    fn generic_for(&mut self, pc: usize, t: usize, regs: &mut Regs,
+                   out: &mut Vec<Stat>) -> Result<usize> {
+        let Op { a, c, .. } = self.code[t];
+        let mut values = Vec::with_capacity(3);
+        for reg in a..a + 3 {
+            values.push(self.take(regs, reg, pc, Use::Control, out)?);
+        }
+        let exprs = trim(collapse(values, false));
+        self.settle(regs, out)?;
+
+        let names = self.names.clone();
+        let vars = (0..c).map(|i| self.loop_var(a + 3 + i, pc + 1)).collect();
+        let body = self.loop_body(pc + 1, t, pc, t + 2);
+        self.names = names;
+        let body = body?;
+        out.push(stat(StatKind::GenericFor { names: vars, exprs, body }));
+        Ok(t + 2)
+    }
+
+    // A while or repeat loop from `header` to the jump back at `back`,
+    // None when the jump back belongs to no loop statement
+    fn loop_at(&mut self, header: usize, back: usize, out: &mut Vec<Stat>) -> Result<Option<usize>> {
+        let exit = back + 1;
+        // only the jump of a repeat loop closes upvalues, and then those of
+        // locals of its body, a goto back may close others
+        let close = self.code[back].a;
+        if close > 0 && !self.captures(header, back, close - 1) {
+            return Ok(None);
+        }
+        if back > header && is_test(self.code[back - 1].code) {
+            let snapshot = self.snapshot();
+            let outer = self.repeat.replace((header, back));
+            let body = self.loop_body(header, exit, header, exit);
+            self.repeat = outer;
+            return Ok(match (body, self.until.take()) {
+                (Ok(body), Some(cond)) => {
+                    out.push(stat(StatKind::Repeat { body, cond }));
+                    Some(exit)
+                }
+                _ => {
+                    self.restore(snapshot);
+                    None
+                }
+            });
+        }
+
+        if close > 0 {
+            return Ok(None);
+        }
+        let snapshot = self.snapshot();
+        let (cond, start) = match self.loop_condition(header, back)? {
+            Some(found) => found,
+            None => {
+                self.restore(snapshot);
+                (expr(ExprKind::True), header)
+            }
+        };
+        let body = self.loop_body(start, back, header, exit)?;
+        out.push(stat(StatKind::While { cond, body }));
+        Ok(Some(exit))
+    }
+
+    // a closure between `start` and `end` captures a register from `level` on
+    fn captures(&self, start: usize, end: usize, level: usize) -> bool {
+        (start..end).any(|pc| {
+            let Op { code, b, .. } = self.code[pc];
+            code == OpCode::Closure && self.f.protos.get(b).is_some_and(|child| {
+                child.upvalues.iter().any(|x| x.instack != 0 && usize::from(x.idx) >= level)
+            })
+        })
+    }
+
+    // The condition of a while loop and where its body starts
+    fn loop_condition(&mut self, header: usize, back: usize) -> Result<Option<(Expr, usize)>> {
+        let mut regs = Regs::default();
+        let test = match self.segment(header, back, &mut regs)? {
+            Some(test) => test,
+            None => return Ok(None),
+        };
+        let mut scratch = Vec::new();
+        let mut chain = self.chain(test, back, &mut regs, &mut scratch)?;
+        if !scratch.is_empty() {
+            return Ok(None);
+        }
+        let exit = back + 1;
+        while let Some((x, after, snapshot)) = chain.ends.pop() {
+            let nodes = &chain.nodes[..chain.ends.len() + 1];
+            if !after.values.is_empty() || Function::externals(nodes, x) != Some(vec![exit]) {
+                continue;
+            }
+            let exits = Exits { list: vec![(exit, false)], join: None };
+            if let Some(cond) = tree(nodes, x, true, &exits) {
+                self.restore(snapshot);
+                return Ok(Some((cond, x)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Blocks
+
+    // Writes the `local` statement of the locals starting at pc
+    fn declare(&mut self, pc: usize, end: usize, regs: &mut Regs, out: &mut Vec<Stat>,
+               scopes: &mut Vec<(usize, usize)>) -> Result<()> {
+        let mut vars: Vec<usize> = (0..self.f.locvars.len()).filter(|&i| {
+            let var = &self.f.locvars[i];
+            !self.declared[i] && var.startpc == pc as i32 && !var.varname.starts_with(b"(")
+                && (pc < end || var.endpc as usize <= end)
+        }).collect();
+        if vars.is_empty() {
+            return Ok(());
+        }
+        vars.sort_by_key(|&i| self.locvar_regs[i]);
+        for &i in &vars {
+            self.declared[i] = true;
+        }
+        let names: Vec<Name> = vars.iter()
+            .map(|&i| name(&String::from_utf8_lossy(&self.f.locvars[i].varname)))
+            .collect();
+        let mut scope_end = vars.iter().map(|&i| self.f.locvars[i].endpc as usize).max().unwrap();
+        // the jump closing the upvalues of a block comes after its locals
+        while scope_end < end && self.code[scope_end].code == OpCode::Jmp
+            && self.code[scope_end].a > 0 && self.target(scope_end) == scope_end + 1 {
+            scope_end += 1;
+        }
+
+        let mut values: Vec<Value> = vars.iter()
+            .map(|&i| regs.values.remove(&self.locvar_regs[i])
+                 .unwrap_or_else(|| Value::Expr(expr(ExprKind::Nil))))
+            .collect();
+        let function = match values.first() {
+            Some(&Value::Expr(Expr { kind: ExprKind::Function(_), .. })) => values.len() == 1,
+            _ => false,
+        };
+        if function {
+            let body = match values.pop() {
+                Some(Value::Expr(Expr { kind: ExprKind::Function(body), .. })) => *body,
+                _ => unreachable!(),
+            };
+            let n = names.into_iter().next().unwrap();
+            self.emit(regs, out, StatKind::LocalFunction { name: n, body })?;
+        } else {
+            let exprs = trim(collapse(values, false));
+            self.emit(regs, out, StatKind::Local { names, exprs })?;
+        }
+        // locals that die before the block does were in a `do` block
+        // and those that die with the locals before them in the same one
+        if scope_end < end && scopes.last().map(|x| x.1) != Some(scope_end) {
+            scopes.push((out.len() - 1, scope_end));
+            Function::close_scopes(pc, scopes, out);
+        }
+        Ok(())
+    }
+
+    fn close_scopes(pc: usize, scopes: &mut Vec<(usize, usize)>, out: &mut Vec<Stat>) {
+        while let Some(&(index, scope_end)) = scopes.last() {
+            if pc < scope_end {
+                break;
+            }
+            scopes.pop();
+            let stats = out.split_off(index);
+            out.push(stat(StatKind::Do(make_block(stats))));
+        }
+    }
+
+    fn block(&mut self, start: usize, end: usize) -> Result<Block> {
+        let names = self.names.clone();
+        let mut regs = Regs::default();
+        let mut out = Vec::new();
+        let mut scopes = Vec::new();
+        let mut pc = start;
+        while pc < end {
+            Function::close_scopes(pc, &mut scopes, &mut out);
+            self.declare(pc, end, &mut regs, &mut out, &mut scopes)?;
+            if self.labels.contains(&pc) && self.placed.insert(pc) {
+                if let Some(&level) = self.levels.get(&pc) {
+                    self.end_scope(level, &mut regs, &mut out)?;
+                }
+                self.settle(&mut regs, &mut out)?;
+                out.push(stat(StatKind::Label(label(pc))));
+            }
+            if let Some(back) = self.back_jump(pc, end) {
+                self.settle(&mut regs, &mut out)?;
+                if let Some(next) = self.loop_at(pc, back, &mut out)? {
+                    pc = next;
+                    continue;
+                }
+            }
+            pc = self.statement(pc, end, &mut regs, &mut out)?;
+        }
+        self.declare(end, end, &mut regs, &mut out, &mut scopes)?;
+        self.settle(&mut regs, &mut out)?;
+        Function::close_scopes(usize::MAX, &mut scopes, &mut out);
+        self.names = names;
+        Ok(make_block(out))
+    }
+
+    fn statement(&mut self, pc: usize, end: usize, regs: &mut Regs,
+                 out: &mut Vec<Stat>) -> Result<usize> {
+        let Op { code, a, b, .. } = self.code[pc];
+        if is_test(code) {
+            if !self.is_jump(pc + 1) {
+                return self.error(pc, "test without a jump");
+            }
+            if let Some((inner, r, e, next)) = self.value(pc, regs)? {
+                *regs = inner;
+                self.write(regs, r, Value::Expr(e), pc, out)?;
+                return Ok(next);
+            }
+            return self.condition(pc, end, regs, out);
+        }
+        match code {
+            OpCode::ForPrep => self.numeric_for(pc, regs, out),
+            OpCode::Jmp => {
+                let t = self.target(pc);
+                let close = self.code[pc].a > 0;
+                if close && (t == pc + 1 || self.is_jump(pc + 1) && self.target(pc + 1) == t) {
+                    // only closes upvalues, see leaveblock in lparser.c
+                    if t == pc + 1 && t < end {
+                        self.end_scope(self.code[pc].a - 1, regs, out)?;
+                    }
+                    return Ok(pc + 1);
+                }
+                if self.is_generic_for(pc, t) {
+                    return self.generic_for(pc, t, regs, out);
+                }
+                // the locals waiting are in scope at the goto
+                self.settle(regs, out)?;
+                let kind = self.jump(t);
+                self.emit(regs, out, kind)?;
+                Ok(pc + 1)
+            }
+            OpCode::Return => {
+                // every function ends with a return of its own
+                if pc + 1 == self.code.len() && end == self.code.len() && b == 1 {
+                    self.settle(regs, out)?;
+                    return Ok(pc + 1);
+                }
+                let exprs = match b {
+                    0 => {
+                        let last = self.top(regs, a);
+                        self.list(regs, a, last, false, pc, out)?
+                    }
+                    1 => Vec::new(),
+                    _ => self.list(regs, a, a + b - 2, true, pc, out)?,
+                };
+                self.ret(regs, out, exprs)?;
+                Ok(pc + 1)
+            }
+            OpCode::TailCall => {
+                let call = self.call(pc, regs, out)?;
+                self.ret(regs, out, vec![call])?;
+                Ok(if self.code.get(pc + 1).is_some_and(|x| x.code == OpCode::Return) {
+                    pc + 2
+                } else {
+                    pc + 1
+                })
+            }
+            _ => match self.simple(pc, regs, out)? {
+                Some(next) => Ok(next),
+                None => self.error(pc, "instruction out of place"),
+            },
+        }
+    }
+
+    fn ret(&mut self, regs: &mut Regs, out: &mut Vec<Stat>, exprs: Vec<Expr>) -> Result<()> {
+        let ret = Return { exprs, span: Span::default() };
+        let block = Block { stats: Vec::new(), ret: Some(ret), span: Span::default() };
+        self.emit(regs, out, StatKind::Do(block))
+    }
+
+    fn body(&mut self) -> Result<FuncBody> {
+        let mut params = Vec::with_capacity(usize::from(self.f.numparams));
+        for reg in 0..usize::from(self.f.numparams) {
+            match self.locvar(reg, 0) {
+                Some(i) => {
+                    self.declared[i] = true;
+                    params.push(name(&String::from_utf8_lossy(&self.f.locvars[i].varname)));
+                }
+                None => {
+                    let n = self.fresh("p");
+                    self.name_reg(reg, n.clone());
+                    params.push(name(&n));
+                }
+            }
+        }
+        let body = self.block(0, self.code.len())?;
+        Ok(FuncBody { params, is_vararg: self.f.is_vararg, body, span: Span::default() })
+    }
+}
+
+// A positional item of a constructor, see `table`
+fn item(value: Value) -> Expr {
+    match value {
+        Value::Multi(e) => e,
+        Value::Expr(e) if e.kind.is_multi() => paren(e),
+        value => value_expr(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::code::compile;
+    use super::super::undump::LoadState;
+
+    // the same code, function by function
+    fn same_code(a: &Proto, b: &Proto) -> bool {
+        a.instructions == b.instructions && a.protos.len() == b.protos.len()
+            && a.protos.iter().zip(&b.protos).all(|(x, y)| same_code(x, y))
+    }
+
+    fn round_trip(source: &[u8]) -> String {
+        let proto = compile(source, "@test.lua").unwrap();
+        let decompiled = decompile(&proto).unwrap();
+        let recompiled = compile(decompiled.as_bytes(), "@test.lua").unwrap();
+        assert!(same_code(&proto, &recompiled), "{}", decompiled);
+        decompiled
+    }
+
+    // recompiles the output, which has to give the same code when `exact`
+    fn check(name: &str, chunk: &[u8], exact: bool) -> String {
+        let proto = LoadState::from_slice(chunk, name).unwrap();
+        let decompiled = decompile(&proto).unwrap();
+        let recompiled = compile(decompiled.as_bytes(), name).unwrap();
+        assert!(!exact || same_code(&proto, &recompiled), "{}:\n{}", name, decompiled);
+        decompiled
+    }
+
+    #[test]
+    fn test_fixtures() {
+        check("closures", include_bytes!("../tests/fixtures/closures.luac"), true);
+        check("constructors", include_bytes!("../tests/fixtures/constructors.luac"), true);
+        check("expressions", include_bytes!("../tests/fixtures/expressions.luac"), true);
+        check("folding", include_bytes!("../tests/fixtures/folding.luac"), true);
+        check("gotos", include_bytes!("../tests/fixtures/gotos.luac"), true);
+        check("loops", include_bytes!("../tests/fixtures/loops.luac"), true);
+        check("strings", include_bytes!("../tests/fixtures/strings.luac"), true);
+        check("legacy.52", include_bytes!("../tests/fixtures/legacy.52.luac"), true);
+        check("legacy.53", include_bytes!("../tests/fixtures/legacy.53.luac"), true);
+        // `repeat ... until true` with a break comes back as a plain block
+        check("scopes", include_bytes!("../tests/fixtures/scopes.luac"), false);
+    }
+
+    #[test]
+    fn test_stripped() {
+        check("closures", include_bytes!("../tests/fixtures/closures.stripped.luac"), true);
+        check("constructors", include_bytes!("../tests/fixtures/constructors.stripped.luac"), true);
+        check("folding", include_bytes!("../tests/fixtures/folding.stripped.luac"), true);
+        check("loops", include_bytes!("../tests/fixtures/loops.stripped.luac"), true);
+        check("strings", include_bytes!("../tests/fixtures/strings.stripped.luac"), true);
+        // a `do` block only shows when its locals are closed or skipped
+        check("expressions", include_bytes!("../tests/fixtures/expressions.stripped.luac"), false);
+        check("gotos", include_bytes!("../tests/fixtures/gotos.stripped.luac"), false);
+        check("scopes", include_bytes!("../tests/fixtures/scopes.stripped.luac"), false);
+
+        let source = check("gotos", include_bytes!("../tests/fixtures/gotos.stripped.luac"), false);
+        assert!(source.starts_with("goto label_3\ndo\n  local v1 = 1\nend\n::label_3::\n"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(round_trip(b"local a, b = 1\na, b = b, a\nprint(a and b or -a)"),
+                   "local a, b = 1\na, b = b, a\nprint(a and b or -a)\n");
+        assert_eq!(round_trip(b"t = {1, 2; x = f(), [3] = ...}"),
+                   "t = { 1, 2, x = f(), [3] = ... }\n");
+        assert_eq!(round_trip(b"for i = 1, 10, 2 do if i == 5 then break end end"),
+                   "for i = 1, 10, 2 do\n  if i == 5 then\n    break\n  end\nend\n");
+        assert_eq!(round_trip(b"local function f(n) return n < 2 and n or f(n - 1) end"),
+                   "local function f(n)\n  return n < 2 and n or f(n - 1)\nend\n");
+        round_trip(b"local x repeat local y = x x = y until y while x do x = x.next end");
+        round_trip(b"for k, v in pairs(t) do goto skip print(k) ::skip:: end");
+        assert_eq!(round_trip(b"local x = ... if x then return end print(1)"),
+                   "local x = ...\nif x then\n  return\nend\nprint(1)\n");
+        assert_eq!(round_trip(b"local x = a or b and c return x"),
+                   "local x = a or b and c\nreturn x\n");
+        assert_eq!(round_trip(b"local t = {a or {x=1}}"), "local t = { a or { x = 1 } }\n");
+        assert_eq!(round_trip(b"for i=1,2 do end"), "for i = 1, 2 do end\n");
+        assert_eq!(round_trip(b"for k,v in pairs(t) do end"), "for k, v in pairs(t) do end\n");
+        assert_eq!(round_trip(b"for i=1,2 do for k,v in pairs(i) do end for j=i,2 do end end"),
+                   "for i = 1, 2 do\n  for k, v in pairs(i) do end\n  for j = i, 2 do end\nend\n");
+        assert_eq!(round_trip(b"print(a or b and c)"), "print(a or b and c)\n");
+        assert_eq!(round_trip(b"f(a and b or c and d)"), "f(a and b or c and d)\n");
+        round_trip(b"x = a and b and c or d and e y = not (a or b and c)");
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let proto = LoadState::from_slice(include_bytes!("../tests/fixtures/legacy.51.luac"),
+                                          "legacy.51").unwrap();
+        assert!(decompile(&proto).is_err());
+    }
+}
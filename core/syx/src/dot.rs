@@ -0,0 +1,178 @@
+// Control flow graphs in the DOT language of Graphviz, one cluster per
+// function with its basic blocks listed like `luac -l` lists them, and an
+// edge from every Closure to the function it creates
+#![allow(dead_code)]
+
+use std::fmt::Write;
+
+use super::cfg::{self, Graph};
+use super::object::Proto;
+use super::opcodes::*;
+use super::opcodes54;
+use super::print::{function_name, print_instruction};
+use super::errors::*;
+
+pub fn dot_graph(proto: &Proto) -> Result<String> {
+    let mut state = DotState { output: String::new(), links: String::new(), functions: 0 };
+    state.output.push_str("digraph syx {\n");
+    state.output.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+    state.function(proto)?;
+    let DotState { mut output, links, .. } = state;
+    output.push_str(&links);
+    output.push_str("}\n");
+    Ok(output)
+}
+
+struct DotState {
+    output: String,
+    links: String, // edges between functions, they go outside the clusters
+    functions: usize,
+}
+
+// Escapes a label for a double quoted DOT string, tabs of the listing
+// become spaces
+fn escape(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\t' => output.push_str("  "),
+            '\n' => output.push_str("\\n"),
+            _ => output.push(ch),
+        }
+    }
+    output
+}
+
+// A back edge goes to a block dominating the one it leaves, a fallthrough
+// to the instruction right after the block, every other edge is a jump or
+// a skip that was taken
+fn edge_style(graph: &Graph, from: usize, to: usize) -> &'static str {
+    if graph.dominates(to, from) {
+        "label=\"loop-back\", color=blue, constraint=false"
+    } else if graph.blocks[to].start == graph.blocks[from].end {
+        "label=\"fallthrough\", style=dashed"
+    } else {
+        "label=\"taken\""
+    }
+}
+
+// pcs of the Closure instructions of a function with the index of the
+// function they create
+fn closures(f: &Proto) -> Vec<(usize, usize)> {
+    if f.version == Version::Lua54 {
+        f.instructions54.iter().enumerate().filter_map(|(pc, x)| match *x {
+            opcodes54::Instruction::ABx {
+                instruction: opcodes54::OpCode::Closure, bx, ..
+            } => Some((pc, bx as usize)),
+            _ => None,
+        }).collect()
+    } else {
+        f.instructions.iter().enumerate().filter_map(|(pc, x)| match *x {
+            Instruction::ABx { instruction: OpCode::Closure, bx, .. } => Some((pc, bx as usize)),
+            _ => None,
+        }).collect()
+    }
+}
+
+impl DotState {
+    // Writes the cluster of `f` and then those of its nested functions,
+    // returns the number of its cluster
+    fn function(&mut self, f: &Proto) -> Result<usize> {
+        let id = self.functions;
+        self.functions += 1;
+        let graph = cfg::build(f)
+            .chain_err(|| format!("could not build the graph of {}", function_name(f)))?;
+
+        let output = &mut self.output;
+        writeln!(output, "    subgraph cluster_{} {{", id).unwrap();
+        writeln!(output, "        label=\"{}\";", escape(&function_name(f))).unwrap();
+        for (n, block) in graph.blocks.iter().enumerate() {
+            let mut label = String::new();
+            let mut pc = block.start;
+            while pc < block.end {
+                let (text, next) = print_instruction(f, pc);
+                let line = match f.lineinfo.get(pc) {
+                    Some(line) if *line > 0 => line.to_string(),
+                    _ => "-".to_owned(),
+                };
+                // \l ends a left-justified line
+                write!(label, "{}  [{}]  {}\\l", pc + 1, line, escape(&text)).unwrap();
+                pc = next;
+            }
+            let color = if graph.is_reachable(n) { "" } else { ", color=gray" };
+            writeln!(output, "        f{}_b{} [label=\"{}\"{}];", id, n, label, color).unwrap();
+        }
+        for (n, block) in graph.blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                writeln!(output, "        f{}_b{} -> f{}_b{} [{}];",
+                         id, n, id, successor, edge_style(&graph, n, successor)).unwrap();
+            }
+        }
+        output.push_str("    }\n");
+
+        let mut children = Vec::with_capacity(f.protos.len());
+        for child in &f.protos {
+            children.push(self.function(child)?);
+        }
+        for (pc, index) in closures(f) {
+            if let (Some(block), Some(child)) = (graph.block_of(pc), children.get(index)) {
+                writeln!(self.links, "    f{}_b{} -> f{}_b0 [label=\"closure\", style=dotted];",
+                         id, block, child).unwrap();
+            }
+        }
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::code::compile;
+    use super::super::undump::LoadState;
+
+    #[test]
+    fn test_dot_graph() {
+        let source = b"local t = {} \
+                       local function f(x) return x > 1 end \
+                       for i = 1, 3 do if f(i) then t[i] = \"\\\"\" end end \
+                       return t";
+        let proto = compile(source, "@loop.lua").unwrap();
+        let dot = dot_graph(&proto).unwrap();
+        assert!(dot.starts_with("digraph syx {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert_eq!(dot.matches('{').count(), dot.matches('}').count());
+
+        assert!(dot.contains("subgraph cluster_0 {\n        label=\"main <loop.lua:0,0>\";"));
+        assert!(dot.contains("subgraph cluster_1 {\n        label=\"function <loop.lua:1,1>\";"));
+        assert!(dot.contains("f0_b0 [label=\"1  [1]  NEWTABLE   0 0 0\\l2  [1]  CLOSURE"));
+        // the string constant stays inside the label
+        assert!(dot.contains("SETTABLE   0 5 -3  ; - \\\"\\\\\\\"\\\"\\l"));
+        assert!(dot.contains("label=\"loop-back\""));
+        assert!(dot.contains("label=\"fallthrough\""));
+        assert!(dot.contains("label=\"taken\""));
+        assert!(dot.contains("    f0_b0 -> f1_b0 [label=\"closure\", style=dotted];\n"));
+    }
+
+    #[test]
+    fn test_fixtures() {
+        let chunks: [&[u8]; 3] = [
+            include_bytes!("../tests/fixtures/closures.luac"),
+            include_bytes!("../tests/fixtures/closures.stripped.luac"),
+            include_bytes!("../tests/fixtures/lua54.luac"),
+        ];
+        for chunk in &chunks {
+            let proto = LoadState::from_slice(chunk, "test").unwrap();
+            let dot = dot_graph(&proto).unwrap();
+            let mut count = 0;
+            let mut stack = vec![&proto];
+            while let Some(f) = stack.pop() {
+                count += 1;
+                stack.extend(f.protos.iter());
+            }
+            assert_eq!(dot.matches("subgraph cluster_").count(), count);
+            assert_eq!(dot.matches("label=\"closure\"").count(), count - 1);
+        }
+    }
+}
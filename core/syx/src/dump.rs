@@ -0,0 +1,326 @@
+// Bytecode dumper, mirrors ldump.c
+#![allow(dead_code)]
+
+use super::conf::{SYX_HEADER, SYX_DATA, SYX_VERSION, SYX_FORMAT, SYX_INT, SYX_NUM};
+
+use super::object::{
+    Proto, SyxInt, SyxNumber, SyxType, SyxValue,
+};
+use super::opcodes::*;
+use super::limits;
+use super::undump::{Layout, Primitives, sign_extend, uint_to_bytes};
+use super::errors::*;
+
+pub struct DumpState {
+    output: Vec<u8>,
+    strip: bool,
+    layout: Layout,
+}
+
+impl DumpState {
+    pub fn to_write(
+        proto: &Proto,
+        mut output: impl ::std::io::Write,
+        name: impl Into<String>,
+        strip: bool,
+    ) -> Result<()> {
+        let buffer = DumpState::to_u8(proto, strip)?;
+        output.write_all(&buffer)
+            .chain_err(|| ErrorKind::BufferNotWritable(name.into()))
+    }
+
+    pub fn to_u8(proto: &Proto, strip: bool) -> Result<Vec<u8>> {
+        DumpState::to_u8_with_layout(proto, strip, Layout::native())
+    }
+
+    // A chunk for a machine with other sizes or byte order. Values that do
+    // not fit its sizes are an error rather than being cut down.
+    pub fn to_u8_with_layout(proto: &Proto, strip: bool, layout: Layout)
+        -> Result<Vec<u8>>
+    {
+        if !layout.is_supported() {
+            bail!(ErrorKind::UnsupportedLayout(format!("{:?}", layout)));
+        }
+        let mut state = DumpState {
+            output: Vec::new(),
+            strip,
+            layout,
+        };
+        state.dump_chunk(proto)?;
+        Ok(state.output)
+    }
+
+    // ldump.c's DumpVar, in the layout's byte order
+    fn dump<T: Primitives>(&mut self, value: T) {
+        value.write(self.layout.endian, &mut self.output);
+    }
+
+    fn dump_signed(&mut self, value: i64, size: usize, what: &'static str) -> Result<()> {
+        if sign_extend(value as u64, size) != value {
+            bail!(ErrorKind::ValueDoesNotFit(what, value.to_string(), size));
+        }
+        uint_to_bytes(value as u64, size, self.layout.endian, &mut self.output);
+        Ok(())
+    }
+
+    fn dump_int(&mut self, value: SyxInt) -> Result<()> {
+        let size = self.layout.int;
+        self.dump_signed(value as i64, size, "int")
+    }
+
+    fn dump_number(&mut self, value: SyxNumber) -> Result<()> {
+        if self.layout.number == 4 {
+            let narrow = value as f32;
+            if narrow as SyxNumber != value && !value.is_nan() {
+                bail!(ErrorKind::ValueDoesNotFit("float", value.to_string(), 4));
+            }
+            self.dump::<f32>(narrow);
+        } else {
+            self.dump::<SyxNumber>(value);
+        }
+        Ok(())
+    }
+
+    fn dump_literal(&mut self, value: &[u8]) {
+        self.output.extend_from_slice(value);
+    }
+
+    // `None` is a NULL string in ldump.c, which is a single zero byte
+    fn dump_string(&mut self, value: Option<&[u8]>) -> Result<()> {
+        match value {
+            None => self.dump::<u8>(0),
+            Some(s) => {
+                let size = s.len() + 1; // include trailing '\0'
+                if size < 0xFF {
+                    self.dump::<u8>(size as u8);
+                } else {
+                    self.dump::<u8>(0xFF);
+                    let width = self.layout.size_t;
+                    if width < 8 && (size as u64) >> (8 * width) != 0 {
+                        bail!(ErrorKind::ValueDoesNotFit("string length",
+                                                         size.to_string(), width));
+                    }
+                    uint_to_bytes(size as u64, width, self.layout.endian,
+                                  &mut self.output);
+                }
+                self.dump_literal(s);
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_code(&mut self, proto: &Proto) -> Result<()> {
+        self.dump_int(proto.instructions.len() as SyxInt)?;
+        for instr in &proto.instructions {
+            self.dump::<Word>(encode(*instr, proto.version)?);
+        }
+        Ok(())
+    }
+
+    fn dump_constants(&mut self, proto: &Proto) -> Result<()> {
+        self.dump_int(proto.constants.len() as SyxInt)?;
+        for constant in &proto.constants {
+            match constant {
+                SyxValue::Nil => self.dump::<u8>(SyxType::TNIL.into()),
+                SyxValue::Bool(b) => {
+                    self.dump::<u8>(SyxType::TBOOLEAN.into());
+                    self.dump::<u8>(*b as u8);
+                }
+                SyxValue::Number(n) => {
+                    self.dump::<u8>(SyxType::TNUMFLT.into());
+                    self.dump_number(*n)?;
+                }
+                SyxValue::Integer(n) => {
+                    self.dump::<u8>(SyxType::TNUMINT.into());
+                    let size = self.layout.integer;
+                    self.dump_signed(*n, size, "integer")?;
+                }
+                SyxValue::String(s) => {
+                    if s.len() <= limits::SYX_MAXSHORTLEN {
+                        self.dump::<u8>(SyxType::TSHRSTR.into());
+                    } else {
+                        self.dump::<u8>(SyxType::TLNGSTR.into());
+                    }
+                    self.dump_string(Some(s))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_upvalues(&mut self, proto: &Proto) -> Result<()> {
+        self.dump_int(proto.upvalues.len() as SyxInt)?;
+        for upvalue in &proto.upvalues {
+            self.dump::<u8>(upvalue.instack);
+            self.dump::<u8>(upvalue.idx);
+        }
+        Ok(())
+    }
+
+    fn dump_protos(&mut self, proto: &Proto) -> Result<()> {
+        self.dump_int(proto.protos.len() as SyxInt)?;
+        for child in &proto.protos {
+            self.dump_function(child, &proto.source)?;
+        }
+        Ok(())
+    }
+
+    fn dump_debug(&mut self, proto: &Proto) -> Result<()> {
+        let lines = if self.strip { &[][..] } else { &proto.lineinfo[..] };
+        self.dump_int(lines.len() as SyxInt)?;
+        for line in lines {
+            self.dump_int(*line)?;
+        }
+        let locvars = if self.strip { &[][..] } else { &proto.locvars[..] };
+        self.dump_int(locvars.len() as SyxInt)?;
+        for local in locvars {
+            self.dump_string(Some(&local.varname))?;
+            self.dump_int(local.startpc)?;
+            self.dump_int(local.endpc)?;
+        }
+        let upvalues = if self.strip { &[][..] } else { &proto.upvalues[..] };
+        self.dump_int(upvalues.len() as SyxInt)?;
+        for upvalue in upvalues {
+            self.dump_string(Some(&upvalue.name))?;
+        }
+        Ok(())
+    }
+
+    // Nested functions share their parent's source, which is only written
+    // once; the loader fills it back in from the parent.
+    fn dump_function(&mut self, proto: &Proto, source: &str) -> Result<()> {
+        // older opcodes have no number in 5.3
+        if proto.version != Version::Lua53 {
+            bail!(ErrorKind::UnsupportedVersion(proto.version.to_string()));
+        }
+        if self.strip || proto.source == source {
+            self.dump_string(None)?;
+        } else {
+            self.dump_string(Some(proto.source.as_bytes()))?;
+        }
+        self.dump_int(proto.linedefined)?;
+        self.dump_int(proto.lastlinedefined)?;
+        self.dump::<u8>(proto.numparams);
+        self.dump::<u8>(proto.is_vararg as u8);
+        self.dump::<u8>(proto.maxstacksize);
+        self.dump_code(proto)?;
+        self.dump_constants(proto)?;
+        self.dump_upvalues(proto)?;
+        self.dump_protos(proto)?;
+        self.dump_debug(proto)?;
+        Ok(())
+    }
+
+    fn dump_header(&mut self) -> Result<()> {
+        self.dump_literal(SYX_HEADER);
+        self.dump::<u8>(SYX_VERSION);
+        self.dump::<u8>(SYX_FORMAT);
+        self.dump_literal(SYX_DATA);
+        let layout = self.layout;
+        for size in &[layout.int, layout.size_t, layout.instruction,
+                      layout.integer, layout.number] {
+            self.dump::<u8>(*size as u8);
+        }
+        self.dump_signed(SYX_INT, layout.integer, "integer")?;
+        self.dump_number(SYX_NUM)
+    }
+
+    fn dump_chunk(&mut self, proto: &Proto) -> Result<()> {
+        self.dump_header()?;
+        self.dump::<u8>(proto.upvalues.len() as u8);
+        // the main function has no parent, its source is only omitted when
+        // the loader had none to give it either
+        self.dump_function(proto, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::undump::LoadState;
+
+    macro_rules! fixture {
+        ($name:expr) => {
+            (
+                $name,
+                &include_bytes!(concat!("../tests/fixtures/", $name, ".luac"))[..],
+                &include_bytes!(concat!("../tests/fixtures/", $name,
+                                        ".stripped.luac"))[..],
+            )
+        };
+    }
+
+    fn fixtures() -> Vec<(&'static str, &'static [u8], &'static [u8])> {
+        vec![
+            fixture!("expressions"),
+            fixture!("closures"),
+            fixture!("constructors"),
+            fixture!("folding"),
+            fixture!("gotos"),
+            fixture!("scopes"),
+            fixture!("strings"),
+            fixture!("loops"),
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (name, luac, stripped) in fixtures() {
+            let proto = LoadState::from_u8(luac.to_vec(), name).unwrap();
+            assert!(DumpState::to_u8(&proto, false).unwrap() == luac, "{}", name);
+            let proto = LoadState::from_u8(stripped.to_vec(), name).unwrap();
+            assert!(DumpState::to_u8(&proto, true).unwrap() == stripped, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_strip() {
+        for (name, luac, stripped) in fixtures() {
+            let proto = LoadState::from_u8(luac.to_vec(), name).unwrap();
+            assert!(DumpState::to_u8(&proto, true).unwrap() == stripped, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_to_write() {
+        let (name, luac, _) = fixture!("closures");
+        let proto = LoadState::from_u8(luac.to_vec(), name).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        DumpState::to_write(&proto, &mut output, name, false).unwrap();
+        assert!(output == luac);
+    }
+
+    #[test]
+    fn test_invalid_operand() {
+        let mut proto = Proto::new();
+        proto.instructions.push(Instruction::ABC {
+            instruction: OpCode::Call,
+            a: 0,
+            b: 1 << SIZE_B,
+            c: 1,
+        });
+        let err = DumpState::to_u8(&proto, false).err().unwrap();
+        assert_eq!(err.to_string(), "operand b out of range: 512");
+
+        // 5.1 only, it has no number in 5.3
+        proto.instructions[0] = Instruction::ABx { instruction: OpCode::GetGlobal, a: 0, bx: 0 };
+        assert!(DumpState::to_u8(&proto, false).is_err());
+    }
+
+    #[test]
+    fn test_layout() {
+        let native = Layout::native();
+        let small = Layout { integer: 4, number: 4, ..native };
+        let mut proto = Proto::new();
+        proto.constants.push(SyxValue::Integer(1 << 40));
+        let err = DumpState::to_u8_with_layout(&proto, false, small).err().unwrap();
+        assert_eq!(err.to_string(), "integer 1099511627776 does not fit in 4 bytes");
+        proto.constants[0] = SyxValue::Number(0.1);
+        let err = DumpState::to_u8_with_layout(&proto, false, small).err().unwrap();
+        assert_eq!(err.to_string(), "float 0.1 does not fit in 4 bytes");
+
+        let wide = Layout { instruction: 8, ..native };
+        let err = DumpState::to_u8_with_layout(&proto, false, wide).err().unwrap();
+        assert!(err.to_string().starts_with("can not dump for an unsupported layout"));
+    }
+}
@@ -28,12 +28,102 @@ error_chain! {
             display("could not match source name from UTF8"),
         }
 
+        LoadError(name: String, offset: usize, path: String) {
+            display("could not load {} at byte {} ({})", name, offset, path),
+        }
+
+        NegativeCount(what: &'static str, count: i32) {
+            display("negative number of {}: {}", what, count),
+        }
+
+        ChunkTooLarge(size: usize, limit: usize) {
+            display("chunk of at least {} bytes is over the limit of {}", size, limit),
+        }
+
+        TooManyInstructions(count: usize, limit: usize) {
+            display("{} instructions are over the limit of {}", count, limit),
+        }
+
+        TooManyConstants(count: usize, limit: usize) {
+            display("{} constants are over the limit of {}", count, limit),
+        }
+
+        StringTooLong(length: usize, limit: usize) {
+            display("string of {} bytes is over the limit of {}", length, limit),
+        }
+
+        NestingTooDeep(limit: usize) {
+            display("functions nested deeper than the limit of {}", limit),
+        }
+
+        LineInfoTooLarge(count: usize, limit: usize) {
+            display("{} lines are over the limit of {}", count, limit),
+        }
+
+        // dump.rs
+
+        BufferNotWritable(t: String) {
+            display("could not write dumped chunk: {}", t),
+        }
+
+        UnsupportedLayout(layout: String) {
+            display("can not dump for an unsupported layout: {}", layout),
+        }
+
+        UnsupportedVersion(version: String) {
+            display("can not dump {} bytecode", version),
+        }
+
+        ValueDoesNotFit(what: &'static str, value: String, size: usize) {
+            display("{} {} does not fit in {} bytes", what, value, size),
+        }
+
+        // asm.rs
+
+        AssembleError(line: usize, message: String) {
+            display("line {}: {}", line, message),
+        }
+
+        // builder.rs
+
+        BuildError(message: String) {
+            display("could not build function: {}", message),
+        }
+
+        // code.rs
+
+        CompileError(message: String) {
+            display("{}", message),
+        }
+
         // opcodes.rs
 
         InvalidOpCode {
             display("opcode is not valid"),
         }
 
+        InvalidOperand(field: &'static str, value: i64) {
+            display("operand {} out of range: {}", field, value),
+        }
+
+        // verify.rs
+
+        VerifyError(location: String, message: String) {
+            display("invalid bytecode at {}: {}", location, message),
+        }
+
+        // cfg.rs
+
+        FlowError(location: String, message: String) {
+            display("invalid control flow at {}: {}", location, message),
+        }
+
+        // decompile.rs
+
+        DecompileError(location: String, message: String) {
+            display("could not decompile at {}: {}", location, message),
+        }
+
         // objects.rs
 
         InvalidType(t: u8) {
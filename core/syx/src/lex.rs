@@ -0,0 +1,674 @@
+// Lexical analyzer, mirrors llex.c
+#![allow(dead_code)]
+
+use std::fmt;
+
+use super::ast::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    // reserved words
+    And, Break, Do, Else, ElseIf, End, False, For, Function, Goto, If, In,
+    Local, Nil, Not, Or, Repeat, Return, Then, True, Until, While,
+
+    // multi-character symbols
+    IDiv,     // //
+    Concat,   // ..
+    Dots,     // ...
+    Eq,       // ==
+    Ge,       // >=
+    Le,       // <=
+    Ne,       // ~=
+    Shl,      // <<
+    Shr,      // >>
+    DbColon,  // ::
+
+    // single character symbols
+    Plus, Minus, Star, Slash, Percent, Caret, Hash, Amp, Tilde, Pipe,
+    Lt, Gt, Assign, LParen, RParen, LBrace, RBrace, LBracket, RBracket,
+    Semicolon, Colon, Comma, Dot,
+
+    // literals
+    Number(f64),
+    Integer(i64),
+    Name(String),
+    String(Vec<u8>),
+
+    Eof,
+}
+
+const RESERVED: [(&str, Token); 22] = [
+    ("and", Token::And), ("break", Token::Break), ("do", Token::Do),
+    ("else", Token::Else), ("elseif", Token::ElseIf), ("end", Token::End),
+    ("false", Token::False), ("for", Token::For),
+    ("function", Token::Function), ("goto", Token::Goto), ("if", Token::If),
+    ("in", Token::In), ("local", Token::Local), ("nil", Token::Nil),
+    ("not", Token::Not), ("or", Token::Or), ("repeat", Token::Repeat),
+    ("return", Token::Return), ("then", Token::Then), ("true", Token::True),
+    ("until", Token::Until), ("while", Token::While),
+];
+
+pub fn is_reserved(name: &str) -> bool {
+    RESERVED.iter().any(|&(word, _)| word == name)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Token::Number(n) => return write!(f, "{}", n),
+            Token::Integer(n) => return write!(f, "{}", n),
+            Token::Name(name) => return write!(f, "{}", name),
+            Token::String(s) => {
+                return write!(f, "{}", String::from_utf8_lossy(s));
+            }
+            Token::Eof => "<eof>",
+            Token::IDiv => "//",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Eq => "==",
+            Token::Ge => ">=",
+            Token::Le => "<=",
+            Token::Ne => "~=",
+            Token::Shl => "<<",
+            Token::Shr => ">>",
+            Token::DbColon => "::",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::Hash => "#",
+            Token::Amp => "&",
+            Token::Tilde => "~",
+            Token::Pipe => "|",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Assign => "=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::Semicolon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            reserved => RESERVED
+                .iter()
+                .find(|(_, token)| token == reserved)
+                .map(|&(word, _)| word)
+                .unwrap_or("?"),
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Span,
+}
+
+pub struct Lexer<'a> {
+    source: &'a [u8],
+    pos: usize,
+    line: u32,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a [u8]) -> Lexer<'a> {
+        let mut lexer = Lexer {
+            source,
+            pos: 0,
+            line: 1,
+            diagnostics: Vec::new(),
+        };
+        // skip the first line if it starts with '#', like luaL_loadfilex
+        if source.starts_with(b"#") {
+            while let Some(ch) = lexer.current() {
+                if ch == b'\n' || ch == b'\r' {
+                    break;
+                }
+                lexer.pos += 1;
+            }
+        }
+        lexer
+    }
+
+    fn current(&self) -> Option<u8> {
+        self.source.get(self.pos).cloned()
+    }
+
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.get(self.pos + offset).cloned()
+    }
+
+    fn check_next(&mut self, ch: u8) -> bool {
+        if self.current() == Some(ch) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&mut self, start: usize, line: u32, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            span: Span::new(start, self.pos, line, self.line),
+            message: message.into(),
+        });
+    }
+
+    // skips '\n', '\r', '\n\r' or '\r\n' and bumps the line counter
+    fn newline(&mut self) {
+        let old = self.current();
+        self.pos += 1;
+        match (old, self.current()) {
+            (Some(b'\n'), Some(b'\r')) | (Some(b'\r'), Some(b'\n')) => {
+                self.pos += 1
+            }
+            _ => (),
+        }
+        self.line += 1;
+    }
+
+    // counts the '=' of a long bracket; returns the level if the bracket is
+    // well formed, otherwise None (and the position is left after the '='s)
+    fn skip_sep(&mut self) -> (Option<usize>, usize) {
+        let open = self.current();
+        self.pos += 1;
+        let mut count = 0;
+        while self.current() == Some(b'=') {
+            self.pos += 1;
+            count += 1;
+        }
+        if self.current() == open {
+            (Some(count), count)
+        } else {
+            (None, count)
+        }
+    }
+
+    fn read_long_string(&mut self, start: usize, line: u32, sep: usize)
+        -> Vec<u8>
+    {
+        let mut buffer = Vec::new();
+        self.pos += 1; // skip second '['
+        if let Some(b'\n') | Some(b'\r') = self.current() {
+            self.newline(); // first newline is skipped
+        }
+        loop {
+            match self.current() {
+                None => {
+                    self.error(start, line, "unfinished long string/comment");
+                    return buffer;
+                }
+                Some(b']') => {
+                    let save = self.pos;
+                    if let (Some(level), _) = self.skip_sep() {
+                        if level == sep {
+                            self.pos += 1; // skip second ']'
+                            return buffer;
+                        }
+                    }
+                    buffer.extend_from_slice(&self.source[save..self.pos]);
+                }
+                Some(b'\n') | Some(b'\r') => {
+                    buffer.push(b'\n');
+                    self.newline();
+                }
+                Some(ch) => {
+                    buffer.push(ch);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn read_hex_digit(&mut self, start: usize, line: u32) -> Option<u32> {
+        let digit = self.current().and_then(|ch| (ch as char).to_digit(16));
+        if digit.is_some() {
+            self.pos += 1;
+        } else {
+            self.error(start, line, "hexadecimal digit expected");
+        }
+        digit
+    }
+
+    fn read_utf8_escape(&mut self, start: usize, line: u32,
+                        buffer: &mut Vec<u8>)
+    {
+        if !self.check_next(b'{') {
+            self.error(start, line, "missing '{' in \\u{xxxx}");
+            return;
+        }
+        let mut value: u32 = match self.read_hex_digit(start, line) {
+            Some(digit) => digit,
+            None => return,
+        };
+        while let Some(digit) = self.current()
+            .and_then(|ch| (ch as char).to_digit(16))
+        {
+            self.pos += 1;
+            value = value.saturating_mul(16).saturating_add(digit);
+            if value > 0x7FFF_FFFF {
+                self.error(start, line, "UTF-8 value too large");
+                return;
+            }
+        }
+        if !self.check_next(b'}') {
+            self.error(start, line, "missing '}' in \\u{xxxx}");
+            return;
+        }
+        utf8_encode(value, buffer);
+    }
+
+    fn read_string(&mut self, delimiter: u8, start: usize, line: u32)
+        -> Vec<u8>
+    {
+        let mut buffer = Vec::new();
+        self.pos += 1; // skip delimiter
+        loop {
+            match self.current() {
+                None => {
+                    self.error(start, line, "unfinished string");
+                    return buffer;
+                }
+                Some(b'\n') | Some(b'\r') => {
+                    self.error(start, line, "unfinished string");
+                    return buffer;
+                }
+                Some(ch) if ch == delimiter => {
+                    self.pos += 1;
+                    return buffer;
+                }
+                Some(b'\\') => {
+                    let escape = self.pos;
+                    self.pos += 1;
+                    let ch = match self.current() {
+                        None => continue, // reported as unfinished string
+                        Some(ch) => ch,
+                    };
+                    match ch {
+                        b'a' => buffer.push(0x07),
+                        b'b' => buffer.push(0x08),
+                        b'f' => buffer.push(0x0C),
+                        b'n' => buffer.push(b'\n'),
+                        b'r' => buffer.push(b'\r'),
+                        b't' => buffer.push(b'\t'),
+                        b'v' => buffer.push(0x0B),
+                        b'\\' | b'"' | b'\'' => buffer.push(ch),
+                        b'\n' | b'\r' => {
+                            buffer.push(b'\n');
+                            self.newline();
+                            continue;
+                        }
+                        b'x' => {
+                            self.pos += 1;
+                            let high = self.read_hex_digit(escape, line);
+                            let low = high.and_then(
+                                |_| self.read_hex_digit(escape, line));
+                            if let (Some(high), Some(low)) = (high, low) {
+                                buffer.push((high * 16 + low) as u8);
+                            }
+                            continue;
+                        }
+                        b'u' => {
+                            self.pos += 1;
+                            self.read_utf8_escape(escape, line, &mut buffer);
+                            continue;
+                        }
+                        b'z' => {
+                            self.pos += 1;
+                            while let Some(ch) = self.current() {
+                                match ch {
+                                    b'\n' | b'\r' => self.newline(),
+                                    b' ' | b'\t' | 0x0B | 0x0C => self.pos += 1,
+                                    _ => break,
+                                }
+                            }
+                            continue;
+                        }
+                        b'0'..=b'9' => {
+                            let mut value: u32 = 0;
+                            let mut digits = 0;
+                            while let Some(digit @ b'0'..=b'9') = self.current() {
+                                if digits == 3 {
+                                    break;
+                                }
+                                value = value * 10 + (digit - b'0') as u32;
+                                digits += 1;
+                                self.pos += 1;
+                            }
+                            if value > 0xFF {
+                                self.error(escape, line,
+                                           "decimal escape too large");
+                            } else {
+                                buffer.push(value as u8);
+                            }
+                            continue;
+                        }
+                        _ => {
+                            self.pos += 1;
+                            self.error(escape, line, "invalid escape sequence");
+                            continue;
+                        }
+                    }
+                    self.pos += 1;
+                }
+                Some(ch) => {
+                    buffer.push(ch);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn read_numeral(&mut self, start: usize, line: u32) -> Token {
+        let mut exponent: &[u8] = b"Ee";
+        if self.current() == Some(b'0') {
+            if let Some(b'x') | Some(b'X') = self.peek(1) {
+                exponent = b"Pp";
+                self.pos += 2;
+            }
+        }
+        loop {
+            match self.current() {
+                Some(ch) if exponent.contains(&ch) => {
+                    self.pos += 1;
+                    if let Some(b'+') | Some(b'-') = self.current() {
+                        self.pos += 1;
+                    }
+                }
+                Some(ch) if ch.is_ascii_alphanumeric() || ch == b'.' => {
+                    self.pos += 1
+                }
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&self.source[start..self.pos])
+            .into_owned();
+        match str_to_number(&text) {
+            Some(token) => token,
+            None => {
+                self.error(start, line, format!("malformed number near '{}'",
+                                                text));
+                Token::Integer(0)
+            }
+        }
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        while let Some(ch) = self.current() {
+            match ch {
+                b'\n' | b'\r' => self.newline(),
+                b' ' | b'\t' | 0x0B | 0x0C => self.pos += 1,
+                b'-' if self.peek(1) == Some(b'-') => {
+                    let start = self.pos;
+                    let line = self.line;
+                    self.pos += 2;
+                    if self.current() == Some(b'[') {
+                        let save = self.pos;
+                        if let (Some(sep), _) = self.skip_sep() {
+                            self.read_long_string(start, line, sep);
+                            continue;
+                        }
+                        self.pos = save;
+                    }
+                    while let Some(ch) = self.current() {
+                        if ch == b'\n' || ch == b'\r' {
+                            break;
+                        }
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    pub fn next_token(&mut self) -> Lexeme {
+        // a loop rather than recursion, a binary file is a long run of
+        // unexpected symbols
+        loop {
+            self.skip_whitespace_and_comments();
+            let start = self.pos;
+            let line = self.line;
+            if let Some(token) = self.read_token(start, line) {
+                return Lexeme {
+                    token,
+                    span: Span::new(start, self.pos, line, self.line),
+                };
+            }
+        }
+    }
+
+    // None after reporting a byte that starts no token
+    fn read_token(&mut self, start: usize, line: u32) -> Option<Token> {
+        let ch = match self.current() {
+            None => return Some(Token::Eof),
+            Some(ch) => ch,
+        };
+        macro_rules! choose {
+            ($($next:expr => $token:expr),* ; $default:expr) => {{
+                self.pos += 1;
+                $(
+                    if self.check_next($next) {
+                        return Some($token);
+                    }
+                )*
+                $default
+            }};
+        }
+        Some(match ch {
+            b'[' => {
+                let save = self.pos;
+                match self.skip_sep() {
+                    (Some(sep), _) => {
+                        Token::String(self.read_long_string(start, line, sep))
+                    }
+                    (None, 0) => {
+                        self.pos = save + 1;
+                        Token::LBracket
+                    }
+                    (None, _) => {
+                        self.error(start, line, "invalid long string delimiter");
+                        Token::String(vec![])
+                    }
+                }
+            }
+            b'=' => choose!(b'=' => Token::Eq; Token::Assign),
+            b'<' => choose!(b'=' => Token::Le, b'<' => Token::Shl; Token::Lt),
+            b'>' => choose!(b'=' => Token::Ge, b'>' => Token::Shr; Token::Gt),
+            b'/' => choose!(b'/' => Token::IDiv; Token::Slash),
+            b'~' => choose!(b'=' => Token::Ne; Token::Tilde),
+            b':' => choose!(b':' => Token::DbColon; Token::Colon),
+            b'"' | b'\'' => Token::String(self.read_string(ch, start, line)),
+            b'.' => {
+                match self.peek(1) {
+                    Some(b'.') => {
+                        self.pos += 2;
+                        if self.check_next(b'.') {
+                            Token::Dots
+                        } else {
+                            Token::Concat
+                        }
+                    }
+                    Some(b'0'..=b'9') => self.read_numeral(start, line),
+                    _ => {
+                        self.pos += 1;
+                        Token::Dot
+                    }
+                }
+            }
+            b'0'..=b'9' => self.read_numeral(start, line),
+            ch if ch.is_ascii_alphabetic() || ch == b'_' => {
+                while let Some(ch) = self.current() {
+                    if !(ch.is_ascii_alphanumeric() || ch == b'_') {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let word = String::from_utf8_lossy(&self.source[start..self.pos])
+                    .into_owned();
+                RESERVED
+                    .iter()
+                    .find(|(reserved, _)| *reserved == word)
+                    .map(|(_, token)| token.clone())
+                    .unwrap_or(Token::Name(word))
+            }
+            _ => {
+                self.pos += 1;
+                match ch {
+                    b'+' => Token::Plus,
+                    b'-' => Token::Minus,
+                    b'*' => Token::Star,
+                    b'%' => Token::Percent,
+                    b'^' => Token::Caret,
+                    b'#' => Token::Hash,
+                    b'&' => Token::Amp,
+                    b'|' => Token::Pipe,
+                    b'(' => Token::LParen,
+                    b')' => Token::RParen,
+                    b'{' => Token::LBrace,
+                    b'}' => Token::RBrace,
+                    b']' => Token::RBracket,
+                    b';' => Token::Semicolon,
+                    b',' => Token::Comma,
+                    _ => {
+                        self.error(start, line, format!(
+                            "unexpected symbol near '<\\{}>'", ch));
+                        return None;
+                    }
+                }
+            }
+        })
+    }
+}
+
+// Lexes the whole source; the last token is always `Token::Eof`.
+pub fn tokenize(source: &[u8]) -> (Vec<Lexeme>, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = Vec::new();
+    loop {
+        let lexeme = lexer.next_token();
+        let eof = lexeme.token == Token::Eof;
+        tokens.push(lexeme);
+        if eof {
+            break;
+        }
+    }
+    (tokens, lexer.diagnostics)
+}
+
+pub fn utf8_encode(value: u32, buffer: &mut Vec<u8>) {
+    // same scheme as luaO_utf8esc, which allows values up to 2^31
+    if value < 0x80 {
+        buffer.push(value as u8);
+        return;
+    }
+    let mut bytes = Vec::new();
+    let mut rest = value;
+    let mut mfb: u32 = 0x3F; // maximum that fits in first byte
+    loop {
+        bytes.push((0x80 | (rest & 0x3F)) as u8);
+        rest >>= 6;
+        mfb >>= 1;
+        if rest <= mfb {
+            break;
+        }
+    }
+    bytes.push(((!mfb << 1) | rest) as u8);
+    buffer.extend(bytes.iter().rev());
+}
+
+fn str_to_integer(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    let mut value: u64 = 0;
+    if let Some(digits) = lower.strip_prefix("0x") {
+        if digits.is_empty() {
+            return None;
+        }
+        for ch in digits.chars() {
+            // hexadecimal integers wrap around, like l_str2int
+            value = value.wrapping_mul(16)
+                .wrapping_add(ch.to_digit(16)? as u64);
+        }
+    } else {
+        if lower.is_empty() {
+            return None;
+        }
+        for ch in lower.chars() {
+            value = value.checked_mul(10)?
+                .checked_add(ch.to_digit(10)? as u64)?;
+            if value > i64::MAX as u64 {
+                return None;
+            }
+        }
+    }
+    Some(value as i64)
+}
+
+fn str_to_hex_float(text: &str) -> Option<f64> {
+    let text = &text[2..];
+    let (mantissa, exponent) = match text.find(['p', 'P']) {
+        Some(index) => (&text[..index], Some(&text[index + 1..])),
+        None => (text, None),
+    };
+    let mut value = 0f64;
+    let mut scale: i64 = 0;
+    let mut seen_dot = false;
+    let mut any_digit = false;
+    for ch in mantissa.chars() {
+        if ch == '.' {
+            if seen_dot {
+                return None;
+            }
+            seen_dot = true;
+        } else {
+            value = value * 16.0 + ch.to_digit(16)? as f64;
+            any_digit = true;
+            if seen_dot {
+                scale -= 4;
+            }
+        }
+    }
+    if !any_digit {
+        return None;
+    }
+    if let Some(exponent) = exponent {
+        scale += exponent.parse::<i64>().ok()?;
+    }
+    Some(value * 2f64.powi(scale.clamp(-2000, 2000) as i32))
+}
+
+// converts a numeral the same way luaO_str2num does
+pub fn str_to_number(text: &str) -> Option<Token> {
+    if let Some(integer) = str_to_integer(text) {
+        return Some(Token::Integer(integer));
+    }
+    let lower = text.to_ascii_lowercase();
+    if lower.contains('n') {
+        return None; // reject 'inf' and 'nan'
+    }
+    if lower.starts_with("0x") {
+        str_to_hex_float(&lower).map(Token::Number)
+    } else {
+        lower.parse::<f64>().ok().map(Token::Number)
+    }
+}
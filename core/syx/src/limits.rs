@@ -1 +1,10 @@
 pub const SYX_MAXSHORTLEN: usize = 40;
+
+// Defaults for undump::LoadLimits. Real chunks stay far below these, they only
+// keep a hostile chunk from making the loader allocate without bound.
+pub const SYX_MAXCHUNK: usize = 1 << 28; // bytes in a chunk
+pub const SYX_MAXCODE: usize = 1 << 24; // instructions in a function
+pub const SYX_MAXCONSTANTS: usize = 1 << 26; // constants in a function (MAXARG_Ax)
+pub const SYX_MAXSTRLEN: usize = 1 << 26; // bytes in a string
+pub const SYX_MAXNESTING: usize = 200; // nested functions, LUAI_MAXCCALLS
+pub const SYX_MAXLINEINFO: usize = 1 << 24; // line info entries in a function
@@ -6,16 +6,27 @@ extern crate syx_codegen;
 mod errors;
 mod conf;
 mod opcodes;
+mod opcodes54;
 mod limits;
 mod object;
 mod state;
 mod undump;
+mod dump;
+mod ast;
+mod lex;
+mod parser;
+mod pretty;
+mod print;
+mod asm;
+mod builder;
+mod code;
+mod verify;
+mod cfg;
+mod dot;
+mod decompile;
 
-#[macro_use]
-mod macros;
-
-use object::SyxValue;
 use std::fs::File;
+use std::io::Read;
 
 fn main() {
     if let Err(e) = run() {
@@ -33,64 +44,37 @@ fn main() {
 
 fn run() -> errors::Result<()> {
     let args: Vec<_> = ::std::env::args().collect();
-    let main_chunk = match args.get(1) {
+    // like luac, a single `-l` lists the code and `-l -l` everything,
+    // `--dot` writes the control flow graphs for Graphviz instead, and
+    // `--decompile` Lua source
+    let flags: Vec<_> = args.iter().skip(1)
+        .take_while(|x| *x == "-l" || *x == "--dot" || *x == "--decompile")
+        .collect();
+    let listing = flags.iter().filter(|x| **x == "-l").count();
+    let dot = flags.iter().any(|x| *x == "--dot");
+    let decompile = flags.iter().any(|x| *x == "--decompile");
+    let main_chunk = match args.get(1 + flags.len()) {
         None => {
-            println!("test");
-            panic!("Usage: {} [filename]", args[0]);
+            panic!("Usage: {} [-l [-l] | --dot | --decompile] [filename]", args[0]);
         }
         Some(file) => {
-            let handle = File::open(file).unwrap();
-            undump::LoadState::from_read(handle, file.clone())?
-        }
-    };
-    if !main_chunk.constants.is_empty() {
-        println!();
-        println!("constants:");
-        for constant in main_chunk.constants {
-            match constant {
-                SyxValue::Bool(boolean) => {
-                    println!("bool: {}", boolean);
-                }
-                SyxValue::Number(n) => {
-                    println!("number: {}", n);
-                }
-                SyxValue::Integer(n) => {
-                    println!("integer: {}", n);
-                }
-                SyxValue::String(s) => match String::from_utf8(s.clone()) {
-                    Ok(string) => println!("string: {}", string),
-                    Err(_) => println!("vec<u8>: {:?}", s),
-                },
-                _ => (),
-            }
-        }
-    }
-    if !main_chunk.locvars.is_empty() {
-        println!();
-        println!("locals:");
-        for local in main_chunk.locvars {
-            if let Ok(name) = String::from_utf8(local.varname) {
-                println!("local: {}", name)
+            let mut buffer = Vec::new();
+            File::open(file).unwrap().read_to_end(&mut buffer).unwrap();
+            if buffer.starts_with(conf::SYX_HEADER) {
+                let proto = undump::LoadState::from_u8(buffer, file.clone())?;
+                verify::verify(&proto)?;
+                proto
+            } else {
+                code::compile(&buffer, &format!("@{}", file))?
             }
         }
-    }
-    if !main_chunk.upvalues.is_empty() {
-        println!();
-        println!("upvalues:");
-        for upval in main_chunk.upvalues {
-            if upval.name == vec![] {
-                println!("instack: {}, idx: {}", upval.instack, upval.idx);
-            } else if let Ok(string) = String::from_utf8(upval.name) {
-                println!("{} [{}, {}]", string, upval.instack, upval.idx);
-            }
-        }
-    }
-    if !main_chunk.instructions.is_empty() {
-        println!();
-        println!("instructions:");
-        for instr in main_chunk.instructions {
-            println!("{:?}", instr);
-        }
+    };
+    if dot {
+        print!("{}", dot::dot_graph(&main_chunk)?);
+    } else if decompile {
+        print!("{}", decompile::decompile(&main_chunk)?);
+    } else {
+        print!("{}", print::print_function(&main_chunk, listing != 1));
     }
     Ok(())
 }
@@ -1,13 +1,18 @@
 use super::errors::*;
+use syx_codegen::TagEnum;
 
-use super::opcodes::{Instruction, Word};
+use super::opcodes::{Instruction, Version, Word};
+use super::opcodes54;
 
 pub type SyxInt = i32; // because Lua hates me
 pub type SyxInteger = i64;
 pub type SyxNumber = f64;
 pub type SyxString = Vec<u8>;
 
-#[derive(Debug)]
+// Type tags of lua.h, with the variants of numbers and strings that 5.3
+// tags constants with
+#[derive(Debug, Clone, Copy, PartialEq, TagEnum)]
+#[tag(error = Error, invalid = ErrorKind::InvalidType)]
 pub enum SyxType {
     TNIL,
     TBOOLEAN,
@@ -18,41 +23,16 @@ pub enum SyxType {
     TFUNCTION,
     TUSERDATA,
     TTHREAD,
+    #[tag(TNUMBER, 0)]
     TNUMFLT,
+    #[tag(TNUMBER, 1)]
     TNUMINT,
+    #[tag(TSTRING, 0)]
     TSHRSTR,
+    #[tag(TSTRING, 1)]
     TLNGSTR,
 }
 
-pub const SYX_TNUMFLT: u8 = (SyxType::TNUMBER as u8) | (0 << 4);
-pub const SYX_TNUMINT: u8 = (SyxType::TNUMBER as u8) | (1 << 4);
-
-pub const SYX_TSHRSTR: u8 = (SyxType::TSTRING as u8) | (0 << 4);
-pub const SYX_TLNGSTR: u8 = (SyxType::TSTRING as u8) | (1 << 4);
-
-impl std::convert::TryFrom<u8> for SyxType {
-    type Error = Error;
-
-    fn try_from(value: u8) -> Result<SyxType> {
-        match value {
-            SYX_TNUMFLT => Ok(SyxType::TNUMFLT),
-            SYX_TNUMINT => Ok(SyxType::TNUMINT),
-            SYX_TSHRSTR => Ok(SyxType::TSHRSTR),
-            SYX_TLNGSTR => Ok(SyxType::TLNGSTR),
-            0 => Ok(SyxType::TNIL),
-            1 => Ok(SyxType::TBOOLEAN),
-            2 => Ok(SyxType::TLIGHTUSERDATA),
-            // 3 => Ok(SyxType::TNUMBER),
-            // 4 => Ok(SyxType::TSTRING),
-            5 => Ok(SyxType::TTABLE),
-            6 => Ok(SyxType::TFUNCTION),
-            7 => Ok(SyxType::TUSERDATA),
-            8 => Ok(SyxType::TTHREAD),
-            _ => Err(ErrorKind::InvalidType(value).into()),
-        }
-    }
-}
-
 pub enum SyxValue {
     Bool(bool),
     Number(SyxNumber),
@@ -65,6 +45,7 @@ pub struct Upvalue {
     pub name: SyxString,
     pub instack: u8, // ::TODO:: bool?
     pub idx: u8,
+    pub kind: u8, // 5.4 only, regular, constant or to-be-closed variable
 }
 
 pub struct LocVar {
@@ -83,11 +64,13 @@ pub struct Proto {
     pub constants: Vec<SyxValue>, // constants used by the function
     pub ip: i32,             // instruction pointer, used for instruction index
     pub instructions: Vec<Instruction>, // function opcodes
+    pub instructions54: Vec<opcodes54::Instruction>, // opcodes of a 5.4 function
     pub protos: Vec<Proto>,  // functions defined in this function
     pub lineinfo: Vec<i32>,  // map from opcode to source lines ::TODO:: what?
     pub upvalues: Vec<Upvalue>, // upvalue information
     pub locvars: Vec<LocVar>, // local variables
     pub source: String,
+    pub version: Version,    // bytecode the function was loaded from
 }
 
 impl Proto {
@@ -101,11 +84,13 @@ impl Proto {
             constants: Vec::new(),
             ip: 0,
             instructions: Vec::new(),
+            instructions54: Vec::new(),
             protos: Vec::new(),
             lineinfo: Vec::new(),
             upvalues: Vec::new(),
             locvars: Vec::new(),
             source: "".to_owned(),
+            version: Version::Lua53,
         }
     }
 }
@@ -133,3 +118,39 @@ impl Proto {
 //   TString  *source;  /* used for debug information */
 //   GCObject *gclist;
 // } Proto;
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use super::*;
+
+    #[test]
+    fn test_type_tags() {
+        assert_eq!(u8::from(SyxType::TTHREAD), 8);
+        assert_eq!(u8::from(SyxType::TNUMINT), 3 | (1 << 4));
+        assert_eq!(u8::from(SyxType::TLNGSTR), 4 | (1 << 4));
+        assert_eq!(SyxType::TNUMINT.base(), SyxType::TNUMBER);
+        assert_eq!(SyxType::TTABLE.base(), SyxType::TTABLE);
+        assert_eq!(SyxType::TSHRSTR.name(), "TSHRSTR");
+
+        // the tag of a float is the tag of a number, constants are decoded
+        // to the exact variant
+        assert_eq!(u8::from(SyxType::TNUMBER), u8::from(SyxType::TNUMFLT));
+        assert_eq!(SyxType::try_from(3).unwrap(), SyxType::TNUMFLT);
+        assert_eq!(SyxType::try_from(4).unwrap(), SyxType::TSHRSTR);
+        for &tag in SyxType::variants().filter(|x| x.base() == **x) {
+            if tag != SyxType::TNUMBER && tag != SyxType::TSTRING {
+                assert_eq!(SyxType::try_from(u8::from(tag)).unwrap(), tag);
+            }
+        }
+        for &tag in SyxType::variants().filter(|x| x.base() != **x) {
+            assert_eq!(SyxType::try_from(u8::from(tag)).unwrap(), tag);
+        }
+        assert_eq!(SyxType::variants().count(), 13);
+
+        match SyxType::try_from(9) {
+            Err(Error(ErrorKind::InvalidType(9), _)) => (),
+            x => panic!("expected an invalid type, got {:?}", x),
+        }
+    }
+}
@@ -21,89 +21,186 @@ use super::object;
  * more values than could be used normally. The value sBx is used regardless of
  * whether the space is needed for B, and the result is a signed integer.
  *
+ * The layout given to bytecode! lists the fields from the lowest bit up, Bx
+ * takes every bit above A and Ax every bit above the opcode.
+ *
  * Consult the versioned lopcodes.h for more information.
  */
 
 use super::errors::*;
 
-#[macro_use]
-use super::try_from_enum;
-
-bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into() =>
-    Move: AB = Register, Register; // R(A) := R(B)
-    LoadK: ABx = Register, Constant; // R(A) = Kst(Bx)
-    LoadKX: A = Register; // R(A) = Kst(extra arg); see ExtraArg
-    LoadBool: ABC = Register, Bool, Integer; // R(A) := (Bool)B; if C pc++
-    LoadNil: AB = Register, Integer; // R(A .. A+B) := nil
+bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into(),
+            ErrorKind::InvalidOperand,
+            layout = { op: 6, a: 8, c: 9, b: 9 } =>
+    Move: AB = Register, Register [sets_a]; // R(A) := R(B)
+    LoadK: ABx = Register, Constant [sets_a]; // R(A) = Kst(Bx)
+    LoadKX: A = Register [sets_a, extra_arg]; // R(A) = Kst(extra arg); see ExtraArg
+    LoadBool: ABC = Register, Bool, Integer [sets_a]; // R(A) := (Bool)B; if C pc++
+    LoadNil: AB = Register, Integer [sets_a]; // R(A .. A+B) := nil
 
-    GetUpval: AB = Register, UpValue; // R(A) = UpValue[B]
-    GetTabUp: ABC = Register, UpValue, RegisterConstant; // R(A) := UpValue[B][RK(C)]
-    GetTable: ABC = Register, Register, RegisterConstant; // R(A) := R(B)[RK(C)]
+    GetUpval: AB = Register, UpValue [sets_a]; // R(A) = UpValue[B]
+    GetTabUp: ABC = Register, UpValue, RegisterConstant [sets_a]; // R(A) := UpValue[B][RK(C)]
+    GetTable: ABC = Register, Register, RegisterConstant [sets_a]; // R(A) := R(B)[RK(C)]
 
     SetTabUp: ABC = UpValue, RegisterConstant, RegisterConstant; // UpValue[A][RK(B)] = RK(C)
-    SetUpval: AB = UpValue, Register; // UpValue[B] := R(A)
+    SetUpval: AB = Register, UpValue; // UpValue[B] := R(A)
     SetTable: ABC = Register, RegisterConstant, RegisterConstant; // R(A)[RK(B)] := RK(C)
 
-    NewTable: ABC = Register, Integer, Integer; // R(A) := {} (size: array = B, hash = C)
+    NewTable: ABC = Register, Integer, Integer [sets_a]; // R(A) := {} (size: array = B, hash = C)
 
     // OP_SELF
     // move the table to the next item of the registers, assign A to the "method"
-    SelfLoad: ABC = Register, Register, RegisterConstant; // R(A+1) := R(B); R(A) = R(B)[RK(C)]
-
-    Add: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) + RK(C)
-    Sub: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) - RK(C)
-    Mul: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) * RK(C)
-    Mod: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) % RK(C)
-    Pow: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) ^ RK(C)
-    Div: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) / RK(C)
-    IDiv: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) // RK(C)
-    BAnd: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) & RK(C)
-    BOr: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) | RK(C)
-    BXOr: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) ~ RK(C)
-    Shl: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) << RK(C)
-    Shr: ABC = Register, RegisterConstant, RegisterConstant; // R(A) = RK(B) >> RK(C)
-    Unm: AB = Register, RegisterConstant; // R(A) = RK(B)  RK(C)
-    BNot: AB = Register, RegisterConstant; // R(A) = RK(B) + RK(C)
-    Not: AB = Register, RegisterConstant; // R(A) = RK(B) + RK(C)
-    Len: AB = Register, RegisterConstant; // R(A) = RK(B) + RK(C)
-
-    Concat: ABC = Register, Register, Register; // R(A) := R(B).. ... ..R(C)
+    SelfLoad: ABC = Register, Register, RegisterConstant [sets_a]; // R(A+1) := R(B); R(A) = R(B)[RK(C)]
+
+    Add: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) + RK(C)
+    Sub: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) - RK(C)
+    Mul: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) * RK(C)
+    Mod: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) % RK(C)
+    Pow: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) ^ RK(C)
+    Div: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) / RK(C)
+    IDiv: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) // RK(C)
+    BAnd: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) & RK(C)
+    BOr: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) | RK(C)
+    BXOr: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) ~ RK(C)
+    Shl: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) << RK(C)
+    Shr: ABC = Register, RegisterConstant, RegisterConstant [sets_a]; // R(A) = RK(B) >> RK(C)
+    Unm: AB = Register, Register [sets_a]; // R(A) = -R(B)
+    BNot: AB = Register, Register [sets_a]; // R(A) = ~R(B)
+    Not: AB = Register, Register [sets_a]; // R(A) = not R(B)
+    Len: AB = Register, Register [sets_a]; // R(A) = length of R(B)
+
+    Concat: ABC = Register, Register, Register [sets_a]; // R(A) := R(B).. ... ..R(C)
 
     Jmp: AsBx = Integer, SInteger; // pc += sBx; if A != 0 close upvalues >= R(A - 1)
-    Eq: ABC = Integer, RegisterConstant, RegisterConstant; // if ((RK(B) == RK(C)) ~= A) pc++
-    Lt: ABC = Integer, RegisterConstant, RegisterConstant; // if ((RK(B) <  RK(C)) ~= A) pc++
-    Le: ABC = Integer, RegisterConstant, RegisterConstant; // if ((RK(B) <= RK(C)) ~= A) pc++
+    Eq: ABC = Integer, RegisterConstant, RegisterConstant [test]; // if ((RK(B) == RK(C)) ~= A) pc++
+    Lt: ABC = Integer, RegisterConstant, RegisterConstant [test]; // if ((RK(B) <  RK(C)) ~= A) pc++
+    Le: ABC = Integer, RegisterConstant, RegisterConstant [test]; // if ((RK(B) <= RK(C)) ~= A) pc++
 
-    Test: ABC = Register, Register, Integer; // if !(R(A) != C) pc++
-    TestSet: ABC = Register, Register, Integer; // !(R(B) != C) ? (R(A) := R(B)) : pc++
+    Test: ABC = Register, Unused, Integer [test]; // if !(R(A) != C) pc++
+    TestSet: ABC = Register, Register, Integer [sets_a, test]; // !(R(B) != C) ? (R(A) := R(B)) : pc++
 
     // R(A), ... R(A+C-2) := R(A)(R(A+1), ..., R(A+B-1))
     // Set register A through A+C-2 to return values of calling A with the
     // values of A+1 until A+b-1
-    Call: ABC = Register, Integer, Integer; 
+    Call: ABC = Register, Integer, Integer [sets_a, uses_top, sets_top]; 
     // return R(A)(R(A+1), ..., R(A+B-1))
     // Call the function A with values A+1 until A+B-1, and leave the return
     // values on top of the stack(?)
     // ::TODO:: is C used?
-    TailCall: ABC = Register, Integer, Integer;
-    Return: AB = Register, Integer; // return R(A), ... ,R(A+B-2) (see note)
+    TailCall: ABC = Register, Integer, Integer [sets_a, uses_top, sets_top];
+    Return: AB = Register, Integer [uses_top]; // return R(A), ... ,R(A+B-2) (see note)
 
-    ForLoop: AsBx = Register, SInteger; // R(A)+=R(A+2); if R(A) <?= R(A+1) { pc += sBx; R(A+3)=R(A) }
-    ForPrep: AsBx = Register, SInteger; // R(A)-=R(A+2); pc += sBx
+    ForLoop: AsBx = Register, SInteger [sets_a]; // R(A)+=R(A+2); if R(A) <?= R(A+1) { pc += sBx; R(A+3)=R(A) }
+    ForPrep: AsBx = Register, SInteger [sets_a]; // R(A)-=R(A+2); pc += sBx
 
     // set register A+3 through A+2+C to return values of call R(A) for R(A+1) and R(A+2)
     // i think this means you can only have two arguments to an iterator?
-    TForCall: ABC = Register, Integer, Integer; // R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2))
-    TForLoop: AsBx = Register, SInteger; // if R(A+1) ~= nil then { R(A)=R(A+!); pc += sBx }
+    TForCall: ABC = Register, Unused, Integer; // R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2))
+    TForLoop: AsBx = Register, SInteger [sets_a]; // if R(A+1) ~= nil then { R(A)=R(A+!); pc += sBx }
 
     // ::TODO:: ask mailing list, what is this??
-    SetList: ABC = Register, Integer, Integer; // R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
+    SetList: ABC = Register, Integer, Integer [uses_top, extra_arg]; // R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
 
-    Closure: ABx = Register, Integer; // R(A) := closure(prototypes[Bx])
+    Closure: ABx = Register, Integer [sets_a]; // R(A) := closure(prototypes[Bx])
     
-    VarArg: AB = Register, Integer; // R(A+1), ..., R(A+B-2) = vararg
+    VarArg: AB = Register, Integer [sets_a, sets_top]; // R(A+1), ..., R(A+B-2) = vararg
 
     ExtraArg: Ax = Integer; // ExtraArg = Ax
+
+    // Lua 5.1 only, see Version
+    GetGlobal: ABx = Register, Constant [sets_a]; // R(A) := Gbl[Kst(Bx)]
+    SetGlobal: ABx = Register, Constant; // Gbl[Kst(Bx)] := R(A)
+    Close: A = Register; // close all variables in the stack up to (>=) R(A)
+    // R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2));
+    // if R(A+3) ~= nil then R(A+2) = R(A+3) else pc++
+    TForLoop51: ABC = Register, Unused, Integer [test];
+}
+
+// Bytecode versions that can be loaded. Each numbers its opcodes in its own
+// way, 5.2 dropped the 5.1 opcodes at the end of OpCode and 5.3 added the
+// integer division and bitwise ones. 5.4 changed the instruction format as
+// well, its instructions are the ones of opcodes54.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Version {
+    Lua51,
+    Lua52,
+    Lua53,
+    Lua54,
+}
+
+impl Version {
+    // the version byte of a chunk's header
+    pub fn number(self) -> u8 {
+        match self {
+            Version::Lua51 => 0x51,
+            Version::Lua52 => 0x52,
+            Version::Lua53 => 0x53,
+            Version::Lua54 => 0x54,
+        }
+    }
+
+    // OpCodes in the order of the version's lopcodes.h, none for 5.4
+    pub fn opcodes(self) -> &'static [OpCode] {
+        use self::OpCode::*;
+        static LUA51: [OpCode; 38] = [
+            Move, LoadK, LoadBool, LoadNil, GetUpval, GetGlobal, GetTable,
+            SetGlobal, SetUpval, SetTable, NewTable, SelfLoad, Add, Sub, Mul,
+            Div, Mod, Pow, Unm, Not, Len, Concat, Jmp, Eq, Lt, Le, Test,
+            TestSet, Call, TailCall, Return, ForLoop, ForPrep, TForLoop51,
+            SetList, Close, Closure, VarArg,
+        ];
+        static LUA52: [OpCode; 40] = [
+            Move, LoadK, LoadKX, LoadBool, LoadNil, GetUpval, GetTabUp,
+            GetTable, SetTabUp, SetUpval, SetTable, NewTable, SelfLoad, Add,
+            Sub, Mul, Div, Mod, Pow, Unm, Not, Len, Concat, Jmp, Eq, Lt, Le,
+            Test, TestSet, Call, TailCall, Return, ForLoop, ForPrep, TForCall,
+            TForLoop, SetList, Closure, VarArg, ExtraArg,
+        ];
+        static LUA53: [OpCode; 47] = [
+            Move, LoadK, LoadKX, LoadBool, LoadNil, GetUpval, GetTabUp,
+            GetTable, SetTabUp, SetUpval, SetTable, NewTable, SelfLoad, Add,
+            Sub, Mul, Mod, Pow, Div, IDiv, BAnd, BOr, BXOr, Shl, Shr, Unm,
+            BNot, Not, Len, Concat, Jmp, Eq, Lt, Le, Test, TestSet, Call,
+            TailCall, Return, ForLoop, ForPrep, TForCall, TForLoop, SetList,
+            Closure, VarArg, ExtraArg,
+        ];
+        match self {
+            Version::Lua51 => &LUA51,
+            Version::Lua52 => &LUA52,
+            Version::Lua53 => &LUA53,
+            Version::Lua54 => &[],
+        }
+    }
+}
+
+impl ::std::fmt::Display for Version {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let number = self.number();
+        write!(f, "Lua {}.{}", number >> 4, number & 0xF)
+    }
+}
+
+// Decodes a word of `version`'s bytecode. Only the opcode is numbered
+// differently, the arguments are laid out the same in all of them.
+pub fn decode(word: Word, version: Version) -> Result<Instruction> {
+    let number = (word >> OFFSET_OP) & BITMASK_OP;
+    match version.opcodes().get(number as usize) {
+        Some(&op) => {
+            let word = word & !(BITMASK_OP << OFFSET_OP) | (op as Word) << OFFSET_OP;
+            Instruction::try_from(word)
+        }
+        None => Err(ErrorKind::InvalidOpCode.into()),
+    }
+}
+
+// Encodes an instruction as `version`'s bytecode, the inverse of decode
+pub fn encode(instr: Instruction, version: Version) -> Result<Word> {
+    let word = Word::try_from(instr)?;
+    let op = (word >> OFFSET_OP) & BITMASK_OP;
+    match version.opcodes().iter().position(|&x| x as Word == op) {
+        Some(number) => Ok(word & !(BITMASK_OP << OFFSET_OP) | (number as Word) << OFFSET_OP),
+        None => Err(ErrorKind::InvalidOpCode.into()),
+    }
 }
 
 /*===========================================================================
@@ -176,4 +273,316 @@ mod tests {
             assert_eq!(instr, instr_comp);
         }
     }
+
+    // xorshift32, enough to spread fields over their whole range
+    fn next_word(state: &mut u32) -> Word {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut state = 0x5678u32;
+        let mut opcodes = 0;
+        for op in 0..=BITMASK_OP {
+            if OpCode::try_from(op as u8).is_err() {
+                continue;
+            }
+            opcodes += 1;
+            for _ in 0..1000 {
+                let word = (next_word(&mut state) & !BITMASK_OP) | op;
+                let instr: Instruction = word.try_into().unwrap();
+                let encoded: Word = instr.try_into().unwrap();
+                assert_eq!(encoded, word);
+                assert_eq!(Instruction::try_from(encoded).unwrap(), instr);
+            }
+        }
+        assert_eq!(opcodes, 51);
+    }
+
+    // a fork with more registers, at the cost of B and C, that keeps ExtraArg
+    // at the top of the opcode space
+    mod wide {
+        use syx_codegen::bytecode;
+        use errors::*;
+
+        bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into(),
+                    ErrorKind::InvalidOperand,
+                    layout = { op: 7, a: 9, c: 8, b: 8 } =>
+            Move: AB = Register, Register [sets_a];
+            LoadK: ABx = Register, Constant [sets_a];
+            Jmp: AsBx = Integer, SInteger;
+            Add: ABC = Register, RegisterConstant, RegisterConstant [sets_a];
+            BXOr: ABC = Register, RegisterConstant, RegisterConstant [sets_a];
+            ExtraArg = 127: Ax = Integer;
+        }
+    }
+
+    #[test]
+    fn test_declared_layout() {
+        assert_eq!((wide::OFFSET_A, wide::OFFSET_C, wide::OFFSET_B), (7, 16, 24));
+        assert_eq!((wide::SIZE_BX, wide::OFFSET_BX, wide::SIZE_AX), (16, 16, 25));
+        assert_eq!(wide::BITMASK_IS_RK, 1 << 7);
+
+        let instr = wide::Instruction::ABC {
+            instruction: wide::OpCode::Add, a: 300, b: 2, c: 0x81,
+        };
+        let word = wide::Word::try_from(instr).unwrap();
+        assert_eq!(word, 3 | 300 << 7 | 0x81 << 16 | 2 << 24);
+        assert_eq!(wide::Instruction::try_from(word).unwrap(), instr);
+        assert_eq!(instr.operands(), vec![wide::Argument::Register(300),
+                                          wide::Argument::Register(2),
+                                          wide::Argument::Constant(1)]);
+
+        let jmp = wide::Instruction::AsBx { instruction: wide::OpCode::Jmp, a: 0, sbx: -5 };
+        let word = wide::Word::try_from(jmp).unwrap();
+        assert_eq!(wide::Instruction::try_from(word).unwrap(), jmp);
+        assert!(wide::Word::try_from(wide::Instruction::ABx {
+            instruction: wide::OpCode::LoadK, a: 512, bx: 0,
+        }).is_err());
+    }
+
+    #[test]
+    fn test_explicit_numbers() {
+        assert_eq!(wide::OpCode::ExtraArg as u8, 127);
+        assert_eq!(wide::OpCode::try_from(127).unwrap(), wide::OpCode::ExtraArg);
+        assert!(wide::OpCode::try_from(5).is_err());
+        assert_eq!(wide::OpCode::ExtraArg.mode().format, wide::OpFormat::Ax);
+        assert!(wide::OpCode::Add.mode().sets_a);
+
+        let word = wide::Word::try_from(wide::Instruction::Ax {
+            instruction: wide::OpCode::ExtraArg, ax: 9,
+        }).unwrap();
+        assert_eq!(word, 127 | 9 << 7);
+    }
+
+    // lists instructions of the wide fork, like print.rs does
+    struct Listing;
+
+    impl wide::InstructionVisitor for Listing {
+        type Output = String;
+
+        fn visit_move(&mut self, a: wide::Reg, b: wide::Reg) -> String {
+            format!("MOVE {} {}", a.0, b.0)
+        }
+
+        fn visit_load_k(&mut self, a: wide::Reg, bx: wide::Const) -> String {
+            format!("LOADK {} K{}", a.0, bx.0)
+        }
+
+        fn visit_jmp(&mut self, a: u32, sbx: i32) -> String {
+            format!("JMP {} {}", a, sbx)
+        }
+
+        fn visit_add(&mut self, a: wide::Reg, b: wide::RK, c: wide::RK) -> String {
+            format!("ADD {} {:?} {:?}", a.0, b, c)
+        }
+
+        fn visit_bx_or(&mut self, a: wide::Reg, b: wide::RK, c: wide::RK) -> String {
+            format!("BXOR {} {:?} {:?}", a.0, b, c)
+        }
+
+        fn visit_extra_arg(&mut self, ax: u32) -> String {
+            format!("EXTRAARG {}", ax)
+        }
+    }
+
+    #[test]
+    fn test_visitor() {
+        use self::wide::{Instruction, OpCode, Word};
+
+        let words = [1 << 7 | 2 << 24, 1 | 3 << 7 | 7 << 16, 2 | 0x7ffc << 16,
+                     3 | 300 << 7 | 0x81 << 16 | 2 << 24, 127 | 9 << 7];
+        let listing: Vec<_> = words.iter()
+            .map(|&x| Instruction::try_from(x).unwrap().visit(&mut Listing).unwrap())
+            .collect();
+        assert_eq!(listing, vec![
+            "MOVE 1 2",
+            "LOADK 3 K7",
+            "JMP 0 -3",
+            "ADD 300 Register(Reg(2)) Constant(Const(1))",
+            "EXTRAARG 9",
+        ]);
+
+        // the variant has to match the format of the opcode
+        let instr = Instruction::ABx { instruction: OpCode::Move, a: 0, bx: 0 };
+        assert!(instr.visit(&mut Listing).is_err());
+        let instr = Instruction::ABC { instruction: OpCode::BXOr, a: 0, b: 0x80, c: 0 };
+        assert_eq!(instr.visit(&mut Listing).unwrap(),
+                   "BXOR 0 Constant(Const(0)) Register(Reg(0))");
+        assert!(Word::try_from(instr).is_ok());
+    }
+
+    #[test]
+    fn test_versions() {
+        let sizes = [(Version::Lua51, 38), (Version::Lua52, 40), (Version::Lua53, 47)];
+        for &(version, size) in sizes.iter() {
+            let opcodes = version.opcodes();
+            assert_eq!(opcodes.len(), size);
+            for (i, op) in opcodes.iter().enumerate() {
+                assert!(!opcodes[..i].contains(op), "{} {:?}", version, op);
+            }
+        }
+        // 5.3 numbers them the way OpCode does
+        for (i, op) in Version::Lua53.opcodes().iter().enumerate() {
+            assert_eq!(*op as usize, i);
+        }
+
+        // GETGLOBAL 1 Kst(2) in 5.1, GETUPVAL 1 0 after that
+        let word = 5 | 1 << OFFSET_A | 2 << OFFSET_BX;
+        assert_eq!(decode(word, Version::Lua51).unwrap(),
+                   Instruction::ABx { instruction: OpCode::GetGlobal, a: 1, bx: 2 });
+        assert_eq!(decode(word, Version::Lua52).unwrap(), Instruction::ABC {
+            instruction: OpCode::GetUpval, a: 1, b: 0, c: 2,
+        });
+        assert_eq!(decode(word, Version::Lua53).unwrap(), Instruction::ABC {
+            instruction: OpCode::GetUpval, a: 1, b: 0, c: 2,
+        });
+        assert!(decode(OpCode::GetGlobal as Word, Version::Lua53).is_err());
+        assert!(decode(38, Version::Lua51).is_err());
+        let instr = Instruction::ABx { instruction: OpCode::GetGlobal, a: 1, bx: 2 };
+        assert_eq!(encode(instr, Version::Lua51).unwrap(), word);
+        assert!(encode(instr, Version::Lua53).is_err());
+        assert_eq!(Version::Lua52.to_string(), "Lua 5.2");
+    }
+
+    #[test]
+    fn test_encode_range() {
+        let bad = [
+            Instruction::ABC { instruction: OpCode::Add, a: 0, b: 1 << SIZE_B, c: 0 },
+            Instruction::ABC { instruction: OpCode::Add, a: 0, b: 0, c: 1 << SIZE_C },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 0, bx: 1 << SIZE_BX },
+            Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: -MAXARG_SBX - 1 },
+            Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: MAXARG_SBX + 2 },
+            Instruction::Ax { instruction: OpCode::ExtraArg, ax: 1 << SIZE_AX },
+        ];
+        for instr in bad.iter() {
+            assert!(Word::try_from(*instr).is_err(), "{:?}", instr);
+        }
+        let max = Instruction::ABx {
+            instruction: OpCode::LoadK,
+            a: BITMASK_A as u8,
+            bx: BITMASK_BX,
+        };
+        assert_eq!(Word::try_from(max).unwrap(), !BITMASK_OP | OpCode::LoadK as Word);
+    }
+
+    // every jump in loops.lua, decoded from luac 5.3.6 output
+    #[test]
+    fn test_sbx_bias() {
+        use super::super::undump::LoadState;
+        let luac = include_bytes!("../tests/fixtures/loops.luac");
+        let proto = LoadState::from_u8(luac.to_vec(), "loops").unwrap();
+        let jumps: Vec<_> = proto.instructions.iter().enumerate()
+            .filter_map(|(pc, instr)| match *instr {
+                Instruction::AsBx { instruction, sbx, .. } => Some((pc, instruction, sbx)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(jumps, vec![
+            (4, OpCode::ForPrep, 1),
+            (6, OpCode::ForLoop, -2),
+            (14, OpCode::Jmp, 2),
+            (18, OpCode::TForLoop, -4),
+            (20, OpCode::Jmp, 4),
+            (23, OpCode::Jmp, 1),
+            (24, OpCode::Jmp, -6),
+            (27, OpCode::Jmp, -3),
+            (30, OpCode::Jmp, -3),
+        ]);
+        let zero = Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: 0 };
+        assert_eq!(Word::try_from(zero).unwrap() >> OFFSET_BX, MAXARG_SBX as Word);
+        let min: Instruction = (OpCode::Jmp as Word).try_into().unwrap();
+        assert_eq!(min, Instruction::AsBx {
+            instruction: OpCode::Jmp,
+            a: 0,
+            sbx: -MAXARG_SBX,
+        });
+    }
+
+    #[test]
+    fn test_operands() {
+        let abc = |instruction, a, b, c| Instruction::ABC { instruction, a, b, c };
+        assert_eq!(abc(OpCode::GetTabUp, 1, 0, 259).operands(), vec![
+            Argument::Register(1),
+            Argument::UpValue(0),
+            Argument::Constant(3),
+        ]);
+        assert_eq!(abc(OpCode::Add, 0, 0, 4).operands(), vec![
+            Argument::Register(0),
+            Argument::Register(0),
+            Argument::Register(4),
+        ]);
+        assert_eq!(abc(OpCode::LoadBool, 2, 1, 0).operands(), vec![
+            Argument::Register(2),
+            Argument::Bool(true),
+            Argument::Integer(0),
+        ]);
+        // AB and A formats ignore the fields they do not use
+        assert_eq!(abc(OpCode::Move, 1, 2, 7).operands(), vec![
+            Argument::Register(1),
+            Argument::Register(2),
+        ]);
+        assert_eq!(abc(OpCode::LoadKX, 4, 1, 1).operands(), vec![
+            Argument::Register(4),
+        ]);
+        let jmp = Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: -6 };
+        assert_eq!(jmp.operands(), vec![
+            Argument::Integer(0),
+            Argument::SInteger(-6),
+        ]);
+        let loadk = Instruction::ABx { instruction: OpCode::LoadK, a: 3, bx: 1 };
+        assert_eq!(loadk.operands(), vec![
+            Argument::Register(3),
+            Argument::Constant(1),
+        ]);
+        let extra = Instruction::Ax { instruction: OpCode::ExtraArg, ax: 1 << 20 };
+        assert_eq!(extra.operands(), vec![Argument::Integer(1 << 20)]);
+        // the variant has to match the format of the opcode
+        let bad = Instruction::ABx { instruction: OpCode::Add, a: 0, bx: 0 };
+        assert_eq!(bad.operands(), vec![]);
+    }
+
+    // test mode, sets A, B and C modes of luaP_opmodes in Lua 5.3's lopcodes.c
+    #[test]
+    fn test_opmodes() {
+        use self::ArgMode::{Unused as N, Used as U, Register as R, RegisterConstant as K};
+        let arith = (false, true, K, K);
+        let unary = (false, true, R, N);
+        let expected = [
+            (false, true, R, N), (false, true, K, N), (false, true, N, N),
+            (false, true, U, U), (false, true, U, N), (false, true, U, N),
+            (false, true, U, K), (false, true, R, K), (false, false, K, K),
+            (false, false, U, N), (false, false, K, K), (false, true, U, U),
+            (false, true, R, K),
+            arith, arith, arith, arith, arith, arith, arith, arith, arith,
+            arith, arith, arith,
+            unary, unary, unary, unary,
+            (false, true, R, R), (false, false, R, N),
+            (true, false, K, K), (true, false, K, K), (true, false, K, K),
+            (true, false, N, U), (true, true, R, U),
+            (false, true, U, U), (false, true, U, U), (false, false, U, N),
+            (false, true, R, N), (false, true, R, N), (false, false, N, U),
+            (false, true, R, N), (false, false, U, U), (false, true, U, N),
+            (false, true, U, N), (false, false, U, U),
+            // 5.1's
+            (false, true, K, N), (false, false, K, N), (false, false, N, N),
+            (true, false, N, U),
+        ];
+        assert_eq!(OPMODES.len(), expected.len());
+        for (i, &(test, sets_a, b, c)) in expected.iter().enumerate() {
+            let op = OpCode::try_from(i as u8).unwrap();
+            let mode = op.mode();
+            assert_eq!((mode.test, mode.sets_a, mode.b, mode.c),
+                       (test, sets_a, b, c), "{:?}", op);
+        }
+        assert_eq!(OpCode::Jmp.mode().format, OpFormat::AsBx);
+        assert_eq!(OpCode::Move.mode().format, OpFormat::AB);
+        assert!(OpCode::SetList.mode().extra_arg);
+        assert!(OpCode::LoadKX.mode().extra_arg);
+        assert!(OpCode::Return.mode().uses_top && !OpCode::Return.mode().sets_top);
+        assert!(OpCode::VarArg.mode().sets_top && !OpCode::VarArg.mode().uses_top);
+    }
 }
@@ -0,0 +1,222 @@
+// Lua 5.4 VM instructions
+#![allow(dead_code)]
+
+use syx_codegen::bytecode;
+
+/* Word Format:
+ * |0bCCCCCCCC_BBBBBBBB_k_AAAAAAAA_IIIIIII| -> C, B, k, A, Instruction
+ * |0bBBBBBBBB_BBBBBBBB_B_AAAAAAAA_IIIIIII| -> Bx, A, Instruction
+ * |0bSBBBBBBB_BBBBBBBB_B_AAAAAAAA_IIIIIII| -> sBx, A, Instruction
+ * |0bAAAAAAAA_AAAAAAAA_A_AAAAAAAA_IIIIIII| -> Ax, Instruction
+ * |0bSJJJJJJJ_JJJJJJJJ_J_JJJJJJJJ_IIIIIII| -> sJ, Instruction
+ *
+ * 5.4 took a bit from the opcode's neighbours for more opcodes, and one from
+ * B and C for the k flag, which is what marks C as a constant now that there
+ * are no RK operands. Jumps got their own format with a wider offset.
+ *
+ * Signed B and C (sB, sC) are excess-K like sBx, with MAXARG_SC as K.
+ *
+ * Consult lopcodes.h of Lua 5.4 for more information.
+ */
+
+use super::errors::*;
+
+bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into(),
+            ErrorKind::InvalidOperand,
+            layout = { op: 7, a: 8, k: 1, b: 8, c: 8, sj } =>
+    Move: AB = Register, Register [sets_a]; // R[A] := R[B]
+    LoadI: AsBx = Register, SInteger [sets_a]; // R[A] := sBx
+    LoadF: AsBx = Register, SInteger [sets_a]; // R[A] := (lua_Number)sBx
+    LoadK: ABx = Register, Constant [sets_a]; // R[A] := K[Bx]
+    LoadKX: ABx = Register, Unused [sets_a, extra_arg]; // R[A] := K[extra arg]
+    LoadFalse: A = Register [sets_a]; // R[A] := false
+    LFalseSkip: A = Register [sets_a]; // R[A] := false; pc++
+    LoadTrue: A = Register [sets_a]; // R[A] := true
+    LoadNil: AB = Register, Integer [sets_a]; // R[A], R[A+1], ..., R[A+B] := nil
+    GetUpval: AB = Register, UpValue [sets_a]; // R[A] := UpValue[B]
+    SetUpval: AB = Register, UpValue; // UpValue[B] := R[A]
+
+    GetTabUp: ABC = Register, UpValue, Constant [sets_a]; // R[A] := UpValue[B][K[C]:shortstring]
+    GetTable: ABC = Register, Register, Register [sets_a]; // R[A] := R[B][R[C]]
+    GetI: ABC = Register, Register, Integer [sets_a]; // R[A] := R[B][C]
+    GetField: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B][K[C]:shortstring]
+
+    SetTabUp: ABC = UpValue, Constant, RegisterConstant; // UpValue[A][K[B]:shortstring] := RK(C)
+    SetTable: ABC = Register, Register, RegisterConstant; // R[A][R[B]] := RK(C)
+    SetI: ABC = Register, Integer, RegisterConstant; // R[A][B] := RK(C)
+    SetField: ABC = Register, Constant, RegisterConstant; // R[A][K[B]:shortstring] := RK(C)
+
+    // B is the log2 of the hash size plus one, C the array size; with k the
+    // array size continues in an ExtraArg
+    NewTable: ABC = Register, Integer, Integer [sets_a, extra_arg]; // R[A] := {}
+
+    SelfLoad: ABC = Register, Register, RegisterConstant [sets_a]; // R[A+1] := R[B]; R[A] := R[B][RK(C):string]
+
+    AddI: ABC = Register, Register, SInteger [sets_a]; // R[A] := R[B] + sC
+
+    AddK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] + K[C]:number
+    SubK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] - K[C]:number
+    MulK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] * K[C]:number
+    ModK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] % K[C]:number
+    PowK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] ^ K[C]:number
+    DivK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] / K[C]:number
+    IDivK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] // K[C]:number
+
+    BAndK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] & K[C]:integer
+    BOrK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] | K[C]:integer
+    BXOrK: ABC = Register, Register, Constant [sets_a]; // R[A] := R[B] ~ K[C]:integer
+
+    ShrI: ABC = Register, Register, SInteger [sets_a]; // R[A] := R[B] >> sC
+    ShlI: ABC = Register, Register, SInteger [sets_a]; // R[A] := sC << R[B]
+
+    Add: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] + R[C]
+    Sub: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] - R[C]
+    Mul: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] * R[C]
+    Mod: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] % R[C]
+    Pow: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] ^ R[C]
+    Div: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] / R[C]
+    IDiv: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] // R[C]
+
+    BAnd: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] & R[C]
+    BOr: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] | R[C]
+    BXOr: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] ~ R[C]
+    Shl: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] << R[C]
+    Shr: ABC = Register, Register, Register [sets_a]; // R[A] := R[B] >> R[C]
+
+    // the arithmetic above falls through to these when an operand is not a
+    // number, C is the metamethod's event
+    MmBin: ABC = Register, Register, Integer; // call C metamethod over R[A] and R[B]
+    MmBinI: ABC = Register, SInteger, Integer; // call C metamethod over R[A] and sB
+    MmBinK: ABC = Register, Constant, Integer; // call C metamethod over R[A] and K[B]
+
+    Unm: AB = Register, Register [sets_a]; // R[A] := -R[B]
+    BNot: AB = Register, Register [sets_a]; // R[A] := ~R[B]
+    Not: AB = Register, Register [sets_a]; // R[A] := not R[B]
+    Len: AB = Register, Register [sets_a]; // R[A] := #R[B] (length operator)
+
+    Concat: AB = Register, Integer [sets_a]; // R[A] := R[A].. ... ..R[A + B - 1]
+
+    Close: A = Register; // close all upvalues >= R[A]
+    Tbc: A = Register; // mark variable A "to be closed"
+    Jmp: sJ = SInteger; // pc += sJ
+
+    // k is the result the comparison has to give for the jump to be taken
+    Eq: AB = Register, Register [test]; // if ((R[A] == R[B]) ~= k) then pc++
+    Lt: AB = Register, Register [test]; // if ((R[A] <  R[B]) ~= k) then pc++
+    Le: AB = Register, Register [test]; // if ((R[A] <= R[B]) ~= k) then pc++
+
+    EqK: AB = Register, Constant [test]; // if ((R[A] == K[B]) ~= k) then pc++
+    EqI: AB = Register, SInteger [test]; // if ((R[A] == sB) ~= k) then pc++
+    LtI: AB = Register, SInteger [test]; // if ((R[A] < sB) ~= k) then pc++
+    LeI: AB = Register, SInteger [test]; // if ((R[A] <= sB) ~= k) then pc++
+    GtI: AB = Register, SInteger [test]; // if ((R[A] > sB) ~= k) then pc++
+    GeI: AB = Register, SInteger [test]; // if ((R[A] >= sB) ~= k) then pc++
+
+    Test: A = Register [test]; // if (not R[A] == k) then pc++
+    TestSet: AB = Register, Register [sets_a, test]; // if (not R[B] == k) then pc++ else R[A] := R[B]
+
+    Call: ABC = Register, Integer, Integer [sets_a, uses_top, sets_top]; // R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
+    TailCall: ABC = Register, Integer, Integer [sets_a, uses_top, sets_top]; // return R[A](R[A+1], ... ,R[A+B-1])
+
+    Return: ABC = Register, Integer, Integer [uses_top]; // return R[A], ... ,R[A+B-2]
+    Return0: A = Unused; // return
+    Return1: A = Register; // return R[A]
+
+    // the loops jump by an unsigned Bx, backwards for the ones ending a loop
+    ForLoop: ABx = Register, Integer [sets_a]; // update counters; if loop continues then pc-=Bx;
+    ForPrep: ABx = Register, Integer [sets_a]; // <check values and prepare counters>; if not to run then pc+=Bx+1;
+
+    TForPrep: ABx = Register, Integer; // create upvalue for R[A + 3]; pc+=Bx
+    TForCall: ABC = Register, Unused, Integer; // R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2]);
+    TForLoop: ABx = Register, Integer [sets_a]; // if R[A+2] ~= nil then { R[A]=R[A+2]; pc -= Bx }
+
+    SetList: ABC = Register, Integer, Integer [uses_top, extra_arg]; // R[A][C+i] := R[A+i], 1 <= i <= B
+
+    Closure: ABx = Register, Integer [sets_a]; // R[A] := closure(KPROTO[Bx])
+
+    VarArg: ABC = Register, Unused, Integer [sets_a, sets_top]; // R[A], R[A+1], ..., R[A+C-2] = vararg
+
+    VarArgPrep: A = Integer [sets_a, uses_top]; // (adjust vararg parameters)
+
+    ExtraArg: Ax = Integer; // extra (larger) argument for previous opcode
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!((SIZE_OP, SIZE_A, SIZE_K, SIZE_B, SIZE_C), (7, 8, 1, 8, 8));
+        assert_eq!((OFFSET_A, OFFSET_K, OFFSET_B, OFFSET_C), (7, 15, 16, 24));
+        assert_eq!((SIZE_BX, SIZE_AX, SIZE_SJ), (17, 25, 25));
+        assert_eq!((MAXARG_SBX, MAXARG_SJ, MAXARG_SC), (65535, 16777215, 127));
+        assert_eq!(OPMODES.len(), 83);
+        assert_eq!(OpCode::try_from(82).unwrap(), OpCode::ExtraArg);
+        assert!(OpCode::try_from(83).is_err());
+    }
+
+    // words from tests/fixtures/lua54.luac
+    #[test]
+    fn test_decode() {
+        let cases = [
+            (0x0200_040e, Instruction::ABC {
+                instruction: OpCode::GetField, a: 8, b: 0, c: 2, k: false,
+            }),
+            (0x0100_8012, Instruction::ABC {
+                instruction: OpCode::SetField, a: 0, b: 0, c: 1, k: true,
+            }),
+            (0x8000_0015, Instruction::ABC {
+                instruction: OpCode::AddI, a: 0, b: 0, c: 128, k: false,
+            }),
+            (0x7fff_fbb8, Instruction::SJ { instruction: OpCode::Jmp, sj: -8 }),
+            (0x0006_8149, Instruction::ABx { instruction: OpCode::ForLoop, a: 2, bx: 13 }),
+            (0x8004_8181, Instruction::AsBx { instruction: OpCode::LoadI, a: 3, sbx: 10 }),
+        ];
+        for &(word, instr) in cases.iter() {
+            assert_eq!(Instruction::try_from(word).unwrap(), instr, "{:#010x}", word);
+            assert_eq!(Word::try_from(instr).unwrap(), word);
+        }
+        assert!(Word::try_from(Instruction::SJ { instruction: OpCode::Jmp, sj: MAXARG_SJ + 2 })
+                .is_err());
+    }
+
+    #[test]
+    fn test_operands() {
+        let set = Instruction::ABC { instruction: OpCode::SetField, a: 1, b: 2, c: 3, k: true };
+        assert_eq!(set.operands(), vec![Argument::Register(1), Argument::Constant(2),
+                                        Argument::Constant(3)]);
+        let set = Instruction::ABC { instruction: OpCode::SetField, a: 1, b: 2, c: 3, k: false };
+        assert_eq!(set.operands()[2], Argument::Register(3));
+        let add = Instruction::ABC { instruction: OpCode::AddI, a: 0, b: 1, c: 126, k: false };
+        assert_eq!(add.operands()[2], Argument::SInteger(-1));
+        let jmp = Instruction::SJ { instruction: OpCode::Jmp, sj: -5 };
+        assert_eq!(jmp.operands(), vec![Argument::SInteger(-5)]);
+        assert_eq!(Instruction::ABC { instruction: OpCode::Return0, a: 0, b: 1, c: 1, k: false }
+                   .operands(), vec![]);
+    }
+
+    // luaP_opmodes in lopcodes.c of 5.4: test, sets_a, uses_top, sets_top
+    #[test]
+    fn test_opmodes() {
+        let flags = |op: OpCode| {
+            let mode = op.mode();
+            (mode.test, mode.sets_a, mode.uses_top, mode.sets_top)
+        };
+        assert_eq!(flags(OpCode::Move), (false, true, false, false));
+        assert_eq!(flags(OpCode::SetUpval), (false, false, false, false));
+        assert_eq!(flags(OpCode::MmBin), (false, false, false, false));
+        assert_eq!(flags(OpCode::EqK), (true, false, false, false));
+        assert_eq!(flags(OpCode::TestSet), (true, true, false, false));
+        assert_eq!(flags(OpCode::Call), (false, true, true, true));
+        assert_eq!(flags(OpCode::Return), (false, false, true, false));
+        assert_eq!(flags(OpCode::TForPrep), (false, false, false, false));
+        assert_eq!(flags(OpCode::VarArg), (false, true, false, true));
+        assert_eq!(flags(OpCode::VarArgPrep), (false, true, true, false));
+        assert_eq!(OpCode::Jmp.mode().format, OpFormat::SJ);
+        assert_eq!(OpCode::LoadKX.mode().format, OpFormat::ABx);
+        assert!(OpCode::NewTable.mode().extra_arg);
+    }
+}
@@ -0,0 +1,819 @@
+// Lua 5.3 parser, mirrors the grammar in lparser.c
+//
+// Unlike lparser.c this does not stop at the first syntax error. Missing
+// closing tokens (`end`, `)`, `then`, ...) are reported and assumed to be
+// present, while unexpected tokens abandon the current statement, which is
+// replaced by `StatKind::Error`, and parsing resumes at the next token that
+// looks like the start of a statement.
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+
+use super::ast::*;
+use super::limits;
+use super::lex::{tokenize, Diagnostic, Lexeme, Token};
+
+// marker returned when a statement has to be abandoned; the diagnostic has
+// already been recorded by then
+struct Unwind;
+
+type PResult<T> = ::std::result::Result<T, Unwind>;
+
+pub struct Parser<'a> {
+    source: &'a [u8],
+    tokens: Vec<Lexeme>,
+    pos: usize,
+    vararg: Vec<bool>, // is_vararg of every enclosing function
+    level: usize,      // nested statements and expressions, L->nCcalls
+    diagnostics: Vec<Diagnostic>,
+    reported: HashSet<usize>, // starts of the tokens with a diagnostic
+}
+
+// Parses a whole chunk. The returned block is always usable; any syntax
+// errors are listed in the diagnostics, in source order.
+pub fn parse(source: &[u8]) -> (Block, Vec<Diagnostic>) {
+    let (tokens, diagnostics) = tokenize(source);
+    let mut parser = Parser {
+        source,
+        tokens,
+        pos: 0,
+        vararg: vec![true], // main function is always vararg
+        level: 0,
+        reported: diagnostics.iter().map(|d| d.span.start).collect(),
+        diagnostics,
+    };
+    let block = parser.chunk();
+    let mut diagnostics = parser.diagnostics;
+    diagnostics.sort_by_key(|d| d.span.start);
+    (block, diagnostics)
+}
+
+impl<'a> Parser<'a> {
+    fn token(&self) -> &Token {
+        &self.tokens[self.pos].token
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.pos].span
+    }
+
+    fn lookahead(&self) -> &Token {
+        let index = (self.pos + 1).min(self.tokens.len() - 1);
+        &self.tokens[index].token
+    }
+
+    // span of the last consumed token
+    fn prev_span(&self) -> Span {
+        if self.pos == 0 {
+            Span::new(0, 0, 1, 1)
+        } else {
+            self.tokens[self.pos - 1].span
+        }
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        let end = self.prev_span();
+        if end.end < start.start {
+            // nothing consumed, e.g. after a missing token
+            Span::new(start.start, start.start, start.line, start.line)
+        } else {
+            start.to(end)
+        }
+    }
+
+    fn next(&mut self) {
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+    }
+
+    fn test_next(&mut self, token: &Token) -> bool {
+        if self.token() == token {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    // text of the current token, as shown by luaX_token2str
+    fn near(&self) -> String {
+        let lexeme = &self.tokens[self.pos];
+        match lexeme.token {
+            Token::Eof => "<eof>".to_owned(),
+            | Token::Name(_)
+            | Token::String(_)
+            | Token::Number(_)
+            | Token::Integer(_) => String::from_utf8_lossy(
+                &self.source[lexeme.span.start..lexeme.span.end]).into_owned(),
+            ref token => token.to_string(),
+        }
+    }
+
+    fn error(&mut self, message: impl Into<String>) {
+        let message = format!("{} near '{}'", message.into(), self.near());
+        let span = self.span();
+        // only keep the first error reported at a given token
+        if !self.reported.insert(span.start) {
+            return;
+        }
+        self.diagnostics.push(Diagnostic { span, message });
+    }
+
+    fn error_at(&mut self, span: Span, message: impl Into<String>) {
+        self.reported.insert(span.start);
+        self.diagnostics.push(Diagnostic { span, message: message.into() });
+    }
+
+    fn unwind<T>(&mut self, message: impl Into<String>) -> PResult<T> {
+        self.error(message);
+        Err(Unwind)
+    }
+
+    // enterlevel in lparser.c, nesting too deep is an error rather than a
+    // stack overflow
+    fn enter_level(&mut self) -> PResult<()> {
+        if self.level >= limits::SYX_MAXNESTING {
+            return self.unwind(format!("too many C levels (limit is {})",
+                                       limits::SYX_MAXNESTING));
+        }
+        self.level += 1;
+        Ok(())
+    }
+
+    // reports a missing token and carries on as if it was there
+    fn check_next(&mut self, token: Token) {
+        if !self.test_next(&token) {
+            self.error(format!("'{}' expected", token));
+        }
+    }
+
+    fn check_match(&mut self, what: Token, who: Token, line: u32) {
+        if !self.test_next(&what) {
+            if line == self.span().line {
+                self.error(format!("'{}' expected", what));
+            } else {
+                self.error(format!("'{}' expected (to close '{}' at line {})",
+                                   what, who, line));
+            }
+        }
+    }
+
+    fn check_name(&mut self) -> PResult<Name> {
+        let span = self.span();
+        if let Token::Name(ref name) = *self.token() {
+            let name = name.clone();
+            self.next();
+            Ok(Name { name, span })
+        } else {
+            self.unwind("<name> expected")
+        }
+    }
+
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.token() {
+            Token::Else | Token::ElseIf | Token::End | Token::Eof => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    // skips tokens after an error until something that can start a
+    // statement or end a block
+    fn synchronize(&mut self, error_pos: usize) {
+        let error_line = self.tokens[error_pos].span.line;
+        if self.pos == error_pos && !self.block_follow(true) {
+            self.next();
+        }
+        loop {
+            match self.token() {
+                | Token::Eof | Token::End | Token::Else | Token::ElseIf
+                | Token::Until | Token::Semicolon | Token::If | Token::While
+                | Token::Do | Token::For | Token::Repeat | Token::Function
+                | Token::Local | Token::DbColon | Token::Return
+                | Token::Break | Token::Goto => return,
+                Token::Name(_) if self.span().line > error_line => return,
+                _ => self.next(),
+            }
+        }
+    }
+
+    fn chunk(&mut self) -> Block {
+        let start = self.span();
+        let mut block = self.block(false);
+        while *self.token() != Token::Eof {
+            // stray `end`, `until`, ... at the top level
+            self.error("'<eof>' expected");
+            self.next();
+            let rest = self.block(false);
+            block.stats.extend(rest.stats);
+            if rest.ret.is_some() {
+                block.ret = rest.ret;
+            }
+        }
+        block.span = self.span_from(start);
+        block
+    }
+
+    fn block(&mut self, with_until: bool) -> Block {
+        let start = self.span();
+        let mut stats = Vec::new();
+        let mut ret = None;
+        while !self.block_follow(with_until) {
+            if *self.token() == Token::Until {
+                // `until` outside of a `repeat`, which synchronize stops at
+                self.error("'end' expected");
+                self.next();
+                continue;
+            }
+            if *self.token() == Token::Return {
+                ret = Some(self.retstat());
+                if !self.block_follow(with_until) {
+                    self.error("'end' expected");
+                    let error_pos = self.pos;
+                    self.synchronize(error_pos);
+                    continue;
+                }
+                break;
+            }
+            if let Some(stat) = self.statement() {
+                stats.push(stat);
+            }
+        }
+        Block { stats, ret, span: self.span_from(start) }
+    }
+
+    fn retstat(&mut self) -> Return {
+        let start = self.span();
+        self.next(); // skip `return`
+        let mut exprs = Vec::new();
+        if !self.block_follow(true) && *self.token() != Token::Semicolon {
+            match self.exprlist() {
+                Ok(list) => exprs = list,
+                Err(Unwind) => {
+                    let error_pos = self.pos;
+                    self.synchronize(error_pos);
+                }
+            }
+        }
+        self.test_next(&Token::Semicolon);
+        Return { exprs, span: self.span_from(start) }
+    }
+
+    fn statement(&mut self) -> Option<Stat> {
+        let start = self.span();
+        let start_pos = self.pos;
+        if self.test_next(&Token::Semicolon) {
+            return None;
+        }
+        let kind = self.enter_level().and_then(|()| {
+            let kind = self.statement_kind(start.line);
+            self.level -= 1;
+            kind
+        });
+        let kind = match kind {
+            Ok(kind) => kind,
+            Err(Unwind) => {
+                let error_pos = self.pos.max(start_pos);
+                self.synchronize(error_pos);
+                StatKind::Error
+            }
+        };
+        Some(Stat { kind, span: self.span_from(start) })
+    }
+
+    fn statement_kind(&mut self, line: u32) -> PResult<StatKind> {
+        match self.token().clone() {
+            Token::If => self.ifstat(line),
+            Token::While => {
+                self.next();
+                let cond = self.expr()?;
+                self.check_next(Token::Do);
+                let body = self.block(false);
+                self.check_match(Token::End, Token::While, line);
+                Ok(StatKind::While { cond, body })
+            }
+            Token::Do => {
+                self.next();
+                let body = self.block(false);
+                self.check_match(Token::End, Token::Do, line);
+                Ok(StatKind::Do(body))
+            }
+            Token::For => self.forstat(line),
+            Token::Repeat => {
+                self.next();
+                let body = self.block(true);
+                self.check_match(Token::Until, Token::Repeat, line);
+                let cond = self.expr()?;
+                Ok(StatKind::Repeat { body, cond })
+            }
+            Token::Function => self.funcstat(line),
+            Token::Local => {
+                self.next();
+                if self.test_next(&Token::Function) {
+                    let name = self.check_name()?;
+                    let body = self.body(false, line, name.span)?;
+                    Ok(StatKind::LocalFunction { name, body })
+                } else {
+                    self.localstat()
+                }
+            }
+            Token::DbColon => {
+                self.next();
+                let name = self.check_name()?;
+                self.check_next(Token::DbColon);
+                Ok(StatKind::Label(name))
+            }
+            Token::Break => {
+                self.next();
+                Ok(StatKind::Break)
+            }
+            Token::Goto => {
+                self.next();
+                Ok(StatKind::Goto(self.check_name()?))
+            }
+            _ => self.exprstat(),
+        }
+    }
+
+    fn ifstat(&mut self, line: u32) -> PResult<StatKind> {
+        let mut clauses = Vec::new();
+        let mut else_block = None;
+        loop {
+            // skip `if` or `elseif`
+            self.next();
+            let cond = self.expr()?;
+            self.check_next(Token::Then);
+            let block = self.block(false);
+            clauses.push((cond, block));
+            if *self.token() != Token::ElseIf {
+                break;
+            }
+        }
+        if self.test_next(&Token::Else) {
+            else_block = Some(self.block(false));
+        }
+        self.check_match(Token::End, Token::If, line);
+        Ok(StatKind::If { clauses, else_block })
+    }
+
+    fn forstat(&mut self, line: u32) -> PResult<StatKind> {
+        self.next(); // skip `for`
+        let first = self.check_name()?;
+        match self.token() {
+            Token::Assign => {
+                self.next();
+                let start = self.expr()?;
+                self.check_next(Token::Comma);
+                let limit = self.expr()?;
+                let step = if self.test_next(&Token::Comma) {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                self.check_next(Token::Do);
+                let body = self.block(false);
+                self.check_match(Token::End, Token::For, line);
+                Ok(StatKind::NumericFor(Box::new(NumericFor {
+                    var: first, start, limit, step, body,
+                })))
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.test_next(&Token::Comma) {
+                    names.push(self.check_name()?);
+                }
+                self.check_next(Token::In);
+                let exprs = self.exprlist()?;
+                self.check_next(Token::Do);
+                let body = self.block(false);
+                self.check_match(Token::End, Token::For, line);
+                Ok(StatKind::GenericFor { names, exprs, body })
+            }
+            _ => self.unwind("'=' or 'in' expected"),
+        }
+    }
+
+    fn funcstat(&mut self, line: u32) -> PResult<StatKind> {
+        self.next(); // skip `function`
+        let start = self.span();
+        let mut path = vec![self.check_name()?];
+        let mut method = None;
+        while self.test_next(&Token::Dot) {
+            path.push(self.check_name()?);
+        }
+        if self.test_next(&Token::Colon) {
+            method = Some(self.check_name()?);
+        }
+        let name = FuncName { path, method, span: self.span_from(start) };
+        let body = self.body(name.method.is_some(), line, start)?;
+        Ok(StatKind::Function { name, body })
+    }
+
+    fn localstat(&mut self) -> PResult<StatKind> {
+        let mut names = vec![self.check_name()?];
+        while self.test_next(&Token::Comma) {
+            names.push(self.check_name()?);
+        }
+        let exprs = if self.test_next(&Token::Assign) {
+            self.exprlist()?
+        } else {
+            Vec::new()
+        };
+        Ok(StatKind::Local { names, exprs })
+    }
+
+    fn exprstat(&mut self) -> PResult<StatKind> {
+        let first = self.suffixedexp()?;
+        if let Token::Assign | Token::Comma = *self.token() {
+            let mut targets = vec![first];
+            while self.test_next(&Token::Comma) {
+                targets.push(self.suffixedexp()?);
+            }
+            self.check_next(Token::Assign);
+            for target in &targets {
+                if !target.kind.is_assignable() {
+                    self.error_at(target.span, "syntax error (cannot assign \
+                                                to this expression)");
+                }
+            }
+            let exprs = self.exprlist()?;
+            Ok(StatKind::Assign { targets, exprs })
+        } else {
+            match first.kind {
+                ExprKind::Call { .. } | ExprKind::MethodCall { .. } => {
+                    Ok(StatKind::Call(first))
+                }
+                _ => self.unwind("syntax error"),
+            }
+        }
+    }
+
+    // `function` body: parameters, block and `end`
+    fn body(&mut self, is_method: bool, line: u32, start: Span)
+        -> PResult<FuncBody>
+    {
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if is_method {
+            params.push(Name { name: "self".to_owned(), span: start });
+        }
+        self.check_next(Token::LParen);
+        if *self.token() != Token::RParen {
+            loop {
+                match self.token().clone() {
+                    Token::Name(name) => {
+                        params.push(Name { name, span: self.span() });
+                        self.next();
+                    }
+                    Token::Dots => {
+                        self.next();
+                        is_vararg = true;
+                    }
+                    _ => return self.unwind("<name> expected"),
+                }
+                if is_vararg || !self.test_next(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.check_next(Token::RParen);
+        self.vararg.push(is_vararg);
+        let body = self.block(false);
+        self.vararg.pop();
+        self.check_match(Token::End, Token::Function, line);
+        Ok(FuncBody { params, is_vararg, body, span: self.span_from(start) })
+    }
+
+    fn exprlist(&mut self) -> PResult<Vec<Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.test_next(&Token::Comma) {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn primaryexp(&mut self) -> PResult<Expr> {
+        let start = self.span();
+        match self.token().clone() {
+            Token::Name(name) => {
+                self.next();
+                Ok(Expr { kind: ExprKind::Name(name), span: start })
+            }
+            Token::LParen => {
+                self.next();
+                let inner = self.expr()?;
+                self.check_match(Token::RParen, Token::LParen, start.line);
+                Ok(Expr {
+                    kind: ExprKind::Paren(Box::new(inner)),
+                    span: self.span_from(start),
+                })
+            }
+            _ => self.unwind("unexpected symbol"),
+        }
+    }
+
+    fn suffixedexp(&mut self) -> PResult<Expr> {
+        let start = self.span();
+        let mut expr = self.primaryexp()?;
+        loop {
+            let kind = match self.token().clone() {
+                Token::Dot => {
+                    self.next();
+                    let name = self.check_name()?;
+                    let key = Expr {
+                        kind: ExprKind::String(name.name.into_bytes()),
+                        span: name.span,
+                    };
+                    ExprKind::Index { obj: Box::new(expr), key: Box::new(key) }
+                }
+                Token::LBracket => {
+                    self.next();
+                    let key = self.expr()?;
+                    self.check_next(Token::RBracket);
+                    ExprKind::Index { obj: Box::new(expr), key: Box::new(key) }
+                }
+                Token::Colon => {
+                    self.next();
+                    let method = self.check_name()?;
+                    let args = self.funcargs(start.line)?;
+                    ExprKind::MethodCall { obj: Box::new(expr), method, args }
+                }
+                Token::LParen | Token::String(_) | Token::LBrace => {
+                    let args = self.funcargs(start.line)?;
+                    ExprKind::Call { func: Box::new(expr), args }
+                }
+                _ => return Ok(expr),
+            };
+            expr = Expr { kind, span: self.span_from(start) };
+        }
+    }
+
+    fn funcargs(&mut self, line: u32) -> PResult<Vec<Expr>> {
+        let start = self.span();
+        match self.token().clone() {
+            Token::LParen => {
+                self.next();
+                let args = if *self.token() == Token::RParen {
+                    Vec::new()
+                } else {
+                    self.exprlist()?
+                };
+                self.check_match(Token::RParen, Token::LParen, line);
+                Ok(args)
+            }
+            Token::LBrace => Ok(vec![self.constructor()?]),
+            Token::String(s) => {
+                self.next();
+                Ok(vec![Expr { kind: ExprKind::String(s), span: start }])
+            }
+            _ => self.unwind("function arguments expected"),
+        }
+    }
+
+    fn constructor(&mut self) -> PResult<Expr> {
+        let start = self.span();
+        let mut fields = Vec::new();
+        self.check_next(Token::LBrace);
+        while *self.token() != Token::RBrace {
+            let field = match self.token().clone() {
+                Token::Name(name) => {
+                    if *self.lookahead() == Token::Assign {
+                        let name = Name { name, span: self.span() };
+                        self.next();
+                        self.next();
+                        Field::Named(name, self.expr()?)
+                    } else {
+                        Field::Positional(self.expr()?)
+                    }
+                }
+                Token::LBracket => {
+                    self.next();
+                    let key = self.expr()?;
+                    self.check_next(Token::RBracket);
+                    self.check_next(Token::Assign);
+                    Field::Keyed(key, self.expr()?)
+                }
+                _ => Field::Positional(self.expr()?),
+            };
+            fields.push(field);
+            if !(self.test_next(&Token::Comma)
+                 || self.test_next(&Token::Semicolon))
+            {
+                break;
+            }
+        }
+        self.check_match(Token::RBrace, Token::LBrace, start.line);
+        Ok(Expr { kind: ExprKind::Table(fields), span: self.span_from(start) })
+    }
+
+    fn simpleexp(&mut self) -> PResult<Expr> {
+        let start = self.span();
+        let kind = match self.token().clone() {
+            Token::Number(n) => ExprKind::Number(n),
+            Token::Integer(n) => ExprKind::Integer(n),
+            Token::String(s) => ExprKind::String(s),
+            Token::Nil => ExprKind::Nil,
+            Token::True => ExprKind::True,
+            Token::False => ExprKind::False,
+            Token::Dots => {
+                if !self.vararg.last().cloned().unwrap_or(true) {
+                    self.error("cannot use '...' outside a vararg function");
+                }
+                ExprKind::VarArg
+            }
+            Token::LBrace => return self.constructor(),
+            Token::Function => {
+                self.next();
+                let body = self.body(false, start.line, start)?;
+                return Ok(Expr {
+                    kind: ExprKind::Function(Box::new(body)),
+                    span: self.span_from(start),
+                });
+            }
+            _ => return self.suffixedexp(),
+        };
+        self.next();
+        Ok(Expr { kind, span: start })
+    }
+
+    fn unary_op(&self) -> Option<UnOp> {
+        match self.token() {
+            Token::Not => Some(UnOp::Not),
+            Token::Minus => Some(UnOp::Neg),
+            Token::Tilde => Some(UnOp::BNot),
+            Token::Hash => Some(UnOp::Len),
+            _ => None,
+        }
+    }
+
+    fn binary_op(&self) -> Option<BinOp> {
+        match self.token() {
+            Token::Plus => Some(BinOp::Add),
+            Token::Minus => Some(BinOp::Sub),
+            Token::Star => Some(BinOp::Mul),
+            Token::Percent => Some(BinOp::Mod),
+            Token::Caret => Some(BinOp::Pow),
+            Token::Slash => Some(BinOp::Div),
+            Token::IDiv => Some(BinOp::IDiv),
+            Token::Amp => Some(BinOp::BAnd),
+            Token::Pipe => Some(BinOp::BOr),
+            Token::Tilde => Some(BinOp::BXor),
+            Token::Shl => Some(BinOp::Shl),
+            Token::Shr => Some(BinOp::Shr),
+            Token::Concat => Some(BinOp::Concat),
+            Token::Ne => Some(BinOp::Ne),
+            Token::Eq => Some(BinOp::Eq),
+            Token::Lt => Some(BinOp::Lt),
+            Token::Le => Some(BinOp::Le),
+            Token::Gt => Some(BinOp::Gt),
+            Token::Ge => Some(BinOp::Ge),
+            Token::And => Some(BinOp::And),
+            Token::Or => Some(BinOp::Or),
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self) -> PResult<Expr> {
+        self.subexpr(0)
+    }
+
+    // subexpr -> (simpleexp | unop subexpr) { binop subexpr }
+    // where `binop` is any binary operator with a priority above `limit`
+    fn subexpr(&mut self, limit: u8) -> PResult<Expr> {
+        self.enter_level()?;
+        let expr = self.subexpr_inner(limit);
+        self.level -= 1;
+        expr
+    }
+
+    fn subexpr_inner(&mut self, limit: u8) -> PResult<Expr> {
+        let start = self.span();
+        let mut expr = match self.unary_op() {
+            Some(op) => {
+                let op_span = self.span();
+                self.next();
+                let operand = self.subexpr(UNARY_PRIORITY)?;
+                Expr {
+                    kind: ExprKind::Unary { op, op_span,
+                                            operand: Box::new(operand) },
+                    span: self.span_from(start),
+                }
+            }
+            None => self.simpleexp()?,
+        };
+        while let Some(op) = self.binary_op() {
+            let (left, right) = op.priority();
+            if left <= limit {
+                break;
+            }
+            let op_span = self.span();
+            self.next();
+            let rhs = self.subexpr(right)?;
+            expr = Expr {
+                kind: ExprKind::Binary { op, op_span, lhs: Box::new(expr),
+                                         rhs: Box::new(rhs) },
+                span: self.span_from(start),
+            };
+        }
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::pretty::print_block;
+
+    fn round_trip(source: &str) {
+        let (block, diagnostics) = parse(source.as_bytes());
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let printed = print_block(&block);
+        let (reparsed, diagnostics) = parse(printed.as_bytes());
+        assert!(diagnostics.is_empty(), "{}\n{:?}", printed, diagnostics);
+        assert_eq!(printed, print_block(&reparsed));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip("local a, b = 1, 2.5 print(a // b, a & b | ~a ~ b << 2 >> 1)");
+        round_trip("goto skip ::skip:: for i = 1, 10, 2 do break end");
+        round_trip("local t = { 1, x = 2, [3] = 'four'; f() } t.x.y = t[1]");
+        round_trip("function a.b:c(x, ...) return x, ... end");
+        round_trip("local function f() return (f()) end f {} f 'str' f()");
+        round_trip("x = (a + b) * c ^ -d ^ e .. f .. g < h and not i or j");
+        round_trip("repeat local x = 1 until x if a then elseif b then else end");
+        round_trip("while true do for k, v in pairs(t) do end end");
+        round_trip("f() (g)() local s = \"\\0\\1\\255\\n\" x = 0xffffffffffffffff");
+    }
+
+    #[test]
+    fn test_spans() {
+        let (block, _) = parse(b"local x =\n  1 +\n  2\nprint(x)");
+        assert_eq!(block.stats.len(), 2);
+        let span = block.stats[0].span;
+        assert_eq!((span.start, span.line, span.last_line), (0, 1, 3));
+        assert_eq!(block.stats[1].span.line, 4);
+        match block.stats[0].kind {
+            StatKind::Local { ref exprs, .. } => match exprs[0].kind {
+                ExprKind::Binary { op, op_span, .. } => {
+                    assert_eq!(op, BinOp::Add);
+                    assert_eq!(op_span.line, 2);
+                }
+                ref kind => panic!("unexpected expression: {:?}", kind),
+            },
+            ref kind => panic!("unexpected statement: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let source = "local = 1\nif x then y = end\nprint('ok')\nfunction f() return ... end\nx = 1 +";
+        let (block, diagnostics) = parse(source.as_bytes());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(messages, vec![
+            "1: <name> expected near '='",
+            "2: unexpected symbol near 'end'",
+            "4: cannot use '...' outside a vararg function near '...'",
+            "5: unexpected symbol near '<eof>'",
+        ]);
+        // the statements in between still make it into the tree
+        assert!(block.stats.iter()
+                .any(|stat| matches!(stat.kind, StatKind::Call(_))));
+    }
+
+    #[test]
+    fn test_missing_end() {
+        let (_, diagnostics) = parse(b"while x do\n  y()\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message,
+                   "'end' expected (to close 'while' at line 1) near '<eof>'");
+    }
+
+    #[test]
+    fn test_stray_bytes() {
+        // a binary file is one unexpected symbol after another
+        let (_, diagnostics) = parse(&vec![0x01; 500_000]);
+        assert_eq!(diagnostics.len(), 500_000);
+        assert_eq!(diagnostics[0].to_string(), "1: unexpected symbol near '<\\1>'");
+    }
+
+    #[test]
+    fn test_nesting() {
+        let source = format!("x = {}1{}\nprint(x)", "(".repeat(200_000), ")".repeat(200_000));
+        let (block, diagnostics) = parse(source.as_bytes());
+        assert_eq!(diagnostics[0].to_string(), "1: too many C levels (limit is 200) near '('");
+        assert!(block.stats.iter()
+                .any(|stat| matches!(stat.kind, StatKind::Call(_))));
+        let (_, diagnostics) = parse("do ".repeat(100_000).as_bytes());
+        assert!(!diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_stray_until() {
+        let (_, diagnostics) = parse(b"x = 1 until y");
+        assert_eq!(diagnostics[0].to_string(), "1: 'end' expected near 'until'");
+        let (_, diagnostics) = parse(b"while x do until end");
+        assert_eq!(diagnostics[0].to_string(), "1: 'end' expected near 'until'");
+    }
+}
@@ -0,0 +1,410 @@
+// Pretty printer, turns an AST back into formatted Lua source
+//
+// The output parses back into the same tree (ignoring spans): parentheses
+// are only added where the tree needs them, and `;` is inserted before
+// statements that would otherwise continue the previous call.
+#![allow(dead_code)]
+
+use std::fmt::Write;
+
+use super::ast::*;
+use super::lex;
+
+const INDENT: &str = "  ";
+
+pub fn print_block(block: &Block) -> String {
+    let mut printer = Printer { output: String::new(), level: 0 };
+    printer.block(block);
+    printer.output
+}
+
+pub fn print_expr(expr: &Expr) -> String {
+    let mut printer = Printer { output: String::new(), level: 0 };
+    printer.expr(expr, 0);
+    printer.output
+}
+
+struct Printer {
+    output: String,
+    level: usize,
+}
+
+// `name` can be written as an identifier (used for `t.name` and `{name = v}`)
+pub fn is_identifier(name: &[u8]) -> bool {
+    match name.first() {
+        Some(ch) if ch.is_ascii_alphabetic() || *ch == b'_' => (),
+        _ => return false,
+    }
+    name.iter().all(|ch| ch.is_ascii_alphanumeric() || *ch == b'_')
+        && !lex::is_reserved(&String::from_utf8_lossy(name))
+}
+
+pub fn quote_string(s: &[u8]) -> String {
+    let mut output = String::with_capacity(s.len() + 2);
+    output.push('"');
+    for &ch in s {
+        match ch {
+            b'"' => output.push_str("\\\""),
+            b'\\' => output.push_str("\\\\"),
+            b'\n' => output.push_str("\\n"),
+            b'\r' => output.push_str("\\r"),
+            b'\t' => output.push_str("\\t"),
+            0x07 => output.push_str("\\a"),
+            0x08 => output.push_str("\\b"),
+            0x0B => output.push_str("\\v"),
+            0x0C => output.push_str("\\f"),
+            0x20..=0x7E => output.push(ch as char),
+            // three digits so that a following digit can't extend it
+            _ => write!(output, "\\{:03}", ch).unwrap(),
+        }
+    }
+    output.push('"');
+    output
+}
+
+pub fn format_number(n: f64) -> String {
+    if n.is_infinite() {
+        if n > 0.0 { "1e9999".to_owned() } else { "-1e9999".to_owned() }
+    } else if n.is_nan() {
+        "(0/0)".to_owned()
+    } else {
+        // Debug keeps the `.0`, so it reads back as a float
+        format!("{:?}", n)
+    }
+}
+
+pub fn format_integer(n: i64) -> String {
+    if n < 0 {
+        // a negative literal would read back as a unary minus; hexadecimal
+        // integers wrap around instead
+        format!("{:#x}", n as u64)
+    } else {
+        n.to_string()
+    }
+}
+
+impl Printer {
+    fn newline(&mut self) {
+        self.output.push('\n');
+        for _ in 0..self.level {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        let mut first = true;
+        for stat in &block.stats {
+            if !first {
+                self.newline();
+            }
+            first = false;
+            self.stat(stat);
+        }
+        if let Some(ref ret) = block.ret {
+            if !first {
+                self.newline();
+            }
+            self.output.push_str("return");
+            if !ret.exprs.is_empty() {
+                self.output.push(' ');
+                self.exprlist(&ret.exprs);
+            }
+        }
+    }
+
+    fn indented(&mut self, block: &Block) {
+        if block.stats.is_empty() && block.ret.is_none() {
+            self.output.push(' ');
+            return;
+        }
+        self.level += 1;
+        self.newline();
+        self.block(block);
+        self.level -= 1;
+        self.newline();
+    }
+
+    fn names(&mut self, names: &[Name]) {
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                self.output.push_str(", ");
+            }
+            self.output.push_str(&name.name);
+        }
+    }
+
+    fn exprlist(&mut self, exprs: &[Expr]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.output.push_str(", ");
+            }
+            self.expr(expr, 0);
+        }
+    }
+
+    // statements starting with `(` would continue a previous call
+    fn starts_with_paren(expr: &Expr) -> bool {
+        match expr.kind {
+            ExprKind::Paren(_) => true,
+            ExprKind::Index { ref obj, .. } => Printer::starts_with_paren(obj),
+            ExprKind::Call { ref func, .. } => Printer::starts_with_paren(func),
+            ExprKind::MethodCall { ref obj, .. } => {
+                Printer::starts_with_paren(obj)
+            }
+            _ => false,
+        }
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match stat.kind {
+            StatKind::Assign { ref targets, ref exprs } => {
+                if Printer::starts_with_paren(&targets[0]) {
+                    self.output.push(';');
+                }
+                self.exprlist(targets);
+                self.output.push_str(" = ");
+                self.exprlist(exprs);
+            }
+            StatKind::Call(ref call) => {
+                if Printer::starts_with_paren(call) {
+                    self.output.push(';');
+                }
+                self.expr(call, 0);
+            }
+            StatKind::Label(ref name) => {
+                write!(self.output, "::{}::", name.name).unwrap();
+            }
+            StatKind::Break => self.output.push_str("break"),
+            StatKind::Goto(ref name) => {
+                write!(self.output, "goto {}", name.name).unwrap();
+            }
+            StatKind::Do(ref body) => {
+                self.output.push_str("do");
+                self.indented(body);
+                self.output.push_str("end");
+            }
+            StatKind::While { ref cond, ref body } => {
+                self.output.push_str("while ");
+                self.expr(cond, 0);
+                self.output.push_str(" do");
+                self.indented(body);
+                self.output.push_str("end");
+            }
+            StatKind::Repeat { ref body, ref cond } => {
+                self.output.push_str("repeat");
+                self.indented(body);
+                self.output.push_str("until ");
+                self.expr(cond, 0);
+            }
+            StatKind::If { ref clauses, ref else_block } => {
+                for (i, (cond, block)) in clauses.iter().enumerate() {
+                    self.output.push_str(if i == 0 { "if " } else { "elseif " });
+                    self.expr(cond, 0);
+                    self.output.push_str(" then");
+                    self.indented(block);
+                }
+                if let Some(ref block) = *else_block {
+                    self.output.push_str("else");
+                    self.indented(block);
+                }
+                self.output.push_str("end");
+            }
+            StatKind::NumericFor(ref numeric) => {
+                write!(self.output, "for {} = ", numeric.var.name).unwrap();
+                self.expr(&numeric.start, 0);
+                self.output.push_str(", ");
+                self.expr(&numeric.limit, 0);
+                if let Some(ref step) = numeric.step {
+                    self.output.push_str(", ");
+                    self.expr(step, 0);
+                }
+                self.output.push_str(" do");
+                self.indented(&numeric.body);
+                self.output.push_str("end");
+            }
+            StatKind::GenericFor { ref names, ref exprs, ref body } => {
+                self.output.push_str("for ");
+                self.names(names);
+                self.output.push_str(" in ");
+                self.exprlist(exprs);
+                self.output.push_str(" do");
+                self.indented(body);
+                self.output.push_str("end");
+            }
+            StatKind::Function { ref name, ref body } => {
+                self.output.push_str("function ");
+                for (i, part) in name.path.iter().enumerate() {
+                    if i > 0 {
+                        self.output.push('.');
+                    }
+                    self.output.push_str(&part.name);
+                }
+                let mut params = &body.params[..];
+                if let Some(ref method) = name.method {
+                    write!(self.output, ":{}", method.name).unwrap();
+                    params = &params[1..]; // implicit `self`
+                }
+                self.funcbody(params, body);
+            }
+            StatKind::LocalFunction { ref name, ref body } => {
+                write!(self.output, "local function {}", name.name).unwrap();
+                self.funcbody(&body.params, body);
+            }
+            StatKind::Local { ref names, ref exprs } => {
+                self.output.push_str("local ");
+                self.names(names);
+                if !exprs.is_empty() {
+                    self.output.push_str(" = ");
+                    self.exprlist(exprs);
+                }
+            }
+            StatKind::Error => self.output.push_str("--[[ error ]]"),
+        }
+    }
+
+    fn funcbody(&mut self, params: &[Name], body: &FuncBody) {
+        self.output.push('(');
+        self.names(params);
+        if body.is_vararg {
+            if !params.is_empty() {
+                self.output.push_str(", ");
+            }
+            self.output.push_str("...");
+        }
+        self.output.push(')');
+        self.indented(&body.body);
+        self.output.push_str("end");
+    }
+
+    fn args(&mut self, args: &[Expr]) {
+        self.output.push('(');
+        self.exprlist(args);
+        self.output.push(')');
+    }
+
+    // prints a prefix expression, wrapping anything else in parentheses
+    fn prefix(&mut self, expr: &Expr) {
+        match expr.kind {
+            | ExprKind::Name(_)
+            | ExprKind::Paren(_)
+            | ExprKind::Index { .. }
+            | ExprKind::Call { .. }
+            | ExprKind::MethodCall { .. } => self.expr(expr, 0),
+            _ => {
+                self.output.push('(');
+                self.expr(expr, 0);
+                self.output.push(')');
+            }
+        }
+    }
+
+    // `limit` is the priority of the surrounding operator, as in subexpr
+    fn expr(&mut self, expr: &Expr, limit: u8) {
+        match expr.kind {
+            ExprKind::Nil => self.output.push_str("nil"),
+            ExprKind::True => self.output.push_str("true"),
+            ExprKind::False => self.output.push_str("false"),
+            ExprKind::VarArg => self.output.push_str("..."),
+            ExprKind::Integer(n) => self.output.push_str(&format_integer(n)),
+            ExprKind::Number(n) => self.output.push_str(&format_number(n)),
+            ExprKind::String(ref s) => self.output.push_str(&quote_string(s)),
+            ExprKind::Function(ref body) => {
+                self.output.push_str("function");
+                self.funcbody(&body.params, body);
+            }
+            ExprKind::Table(ref fields) => self.table(fields),
+            ExprKind::Binary { op, ref lhs, ref rhs, .. } => {
+                let (left, right) = op.priority();
+                let wrap = left <= limit;
+                if wrap {
+                    self.output.push('(');
+                }
+                // a left operand binding looser than `op` needs parentheses,
+                // and so does a right one unless `op` is right associative
+                self.expr(lhs, if left > right { left } else { left - 1 });
+                write!(self.output, " {} ", op.symbol()).unwrap();
+                self.expr(rhs, right);
+                if wrap {
+                    self.output.push(')');
+                }
+            }
+            ExprKind::Unary { op, ref operand, .. } => {
+                let wrap = UNARY_PRIORITY <= limit;
+                if wrap {
+                    self.output.push('(');
+                }
+                self.output.push_str(op.symbol());
+                // avoid `- -x` turning into a comment, and `~ ~x`
+                if let ExprKind::Unary { op: inner, .. } = operand.kind {
+                    if inner == op && op != UnOp::Not {
+                        self.output.push(' ');
+                    }
+                }
+                if let (UnOp::Neg, &ExprKind::Integer(n)) = (op, &operand.kind) {
+                    if n < 0 {
+                        self.output.push(' ');
+                    }
+                }
+                self.expr(operand, UNARY_PRIORITY);
+                if wrap {
+                    self.output.push(')');
+                }
+            }
+            ExprKind::Paren(ref inner) => {
+                self.output.push('(');
+                self.expr(inner, 0);
+                self.output.push(')');
+            }
+            ExprKind::Name(ref name) => self.output.push_str(name),
+            ExprKind::Index { ref obj, ref key } => {
+                self.prefix(obj);
+                match key.kind {
+                    ExprKind::String(ref s) if is_identifier(s) => {
+                        write!(self.output, ".{}",
+                               String::from_utf8_lossy(s)).unwrap();
+                    }
+                    _ => {
+                        self.output.push('[');
+                        self.expr(key, 0);
+                        self.output.push(']');
+                    }
+                }
+            }
+            ExprKind::Call { ref func, ref args } => {
+                self.prefix(func);
+                self.args(args);
+            }
+            ExprKind::MethodCall { ref obj, ref method, ref args } => {
+                self.prefix(obj);
+                write!(self.output, ":{}", method.name).unwrap();
+                self.args(args);
+            }
+            ExprKind::Error => self.output.push_str("--[[ error ]]nil"),
+        }
+    }
+
+    fn table(&mut self, fields: &[Field]) {
+        if fields.is_empty() {
+            self.output.push_str("{}");
+            return;
+        }
+        self.output.push('{');
+        for (i, field) in fields.iter().enumerate() {
+            self.output.push_str(if i == 0 { " " } else { ", " });
+            match *field {
+                Field::Positional(ref value) => self.expr(value, 0),
+                Field::Named(ref name, ref value) => {
+                    write!(self.output, "{} = ", name.name).unwrap();
+                    self.expr(value, 0);
+                }
+                Field::Keyed(ref key, ref value) => {
+                    self.output.push('[');
+                    self.expr(key, 0);
+                    self.output.push_str("] = ");
+                    self.expr(value, 0);
+                }
+            }
+        }
+        self.output.push_str(" }");
+    }
+}
@@ -0,0 +1,496 @@
+// Bytecode listing, mirrors the print functions of luac.c
+//
+// `print_function(f, false)` is the output of `luac -l` and
+// `print_function(f, true)` the output of `luac -l -l`, down to the `%p`
+// addresses, which are the addresses of our Protos instead.
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use super::object::{Proto, SyxValue};
+use super::opcodes::*;
+use super::opcodes54;
+use super::pretty::quote_string;
+
+// luaP_opnames in lopcodes.c, indexed by OpCode, then the ones only 5.1 has
+const OPNAMES: [&str; 51] = [
+    "MOVE", "LOADK", "LOADKX", "LOADBOOL", "LOADNIL", "GETUPVAL", "GETTABUP",
+    "GETTABLE", "SETTABUP", "SETUPVAL", "SETTABLE", "NEWTABLE", "SELF", "ADD",
+    "SUB", "MUL", "MOD", "POW", "DIV", "IDIV", "BAND", "BOR", "BXOR", "SHL",
+    "SHR", "UNM", "BNOT", "NOT", "LEN", "CONCAT", "JMP", "EQ", "LT", "LE",
+    "TEST", "TESTSET", "CALL", "TAILCALL", "RETURN", "FORLOOP", "FORPREP",
+    "TFORCALL", "TFORLOOP", "SETLIST", "CLOSURE", "VARARG", "EXTRAARG",
+    "GETGLOBAL", "SETGLOBAL", "CLOSE", "TFORLOOP",
+];
+
+// opnames of lopcodes.c in 5.4, indexed by opcodes54::OpCode
+const OPNAMES54: [&str; 83] = [
+    "MOVE", "LOADI", "LOADF", "LOADK", "LOADKX", "LOADFALSE", "LFALSESKIP",
+    "LOADTRUE", "LOADNIL", "GETUPVAL", "SETUPVAL", "GETTABUP", "GETTABLE",
+    "GETI", "GETFIELD", "SETTABUP", "SETTABLE", "SETI", "SETFIELD", "NEWTABLE",
+    "SELF", "ADDI", "ADDK", "SUBK", "MULK", "MODK", "POWK", "DIVK", "IDIVK",
+    "BANDK", "BORK", "BXORK", "SHRI", "SHLI", "ADD", "SUB", "MUL", "MOD", "POW",
+    "DIV", "IDIV", "BAND", "BOR", "BXOR", "SHL", "SHR", "MMBIN", "MMBINI",
+    "MMBINK", "UNM", "BNOT", "NOT", "LEN", "CONCAT", "CLOSE", "TBC", "JMP", "EQ",
+    "LT", "LE", "EQK", "EQI", "LTI", "LEI", "GTI", "GEI", "TEST", "TESTSET",
+    "CALL", "TAILCALL", "RETURN", "RETURN0", "RETURN1", "FORLOOP", "FORPREP",
+    "TFORPREP", "TFORCALL", "TFORLOOP", "SETLIST", "CLOSURE", "VARARG",
+    "VARARGPREP", "EXTRAARG",
+];
+
+pub fn opname(op: OpCode) -> &'static str {
+    OPNAMES[op as usize]
+}
+
+pub fn opname54(op: opcodes54::OpCode) -> &'static str {
+    OPNAMES54[op as usize]
+}
+
+pub fn print_function(proto: &Proto, full: bool) -> String {
+    let mut output = String::new();
+    function(&mut output, proto, full);
+    output
+}
+
+// Formats a float like the "%.14g" of LUA_NUMBER_FMT
+pub fn format_float(n: f64) -> String {
+    const PRECISION: usize = 14;
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_owned();
+    } else if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_owned();
+    }
+    // the exponent after rounding decides between fixed and exponent form
+    let scientific = format!("{:.*e}", PRECISION - 1, n);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    let trim = |s: &str| -> String {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_owned()
+        } else {
+            s.to_owned()
+        }
+    };
+    if exponent < -4 || exponent >= PRECISION as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (PRECISION as i32 - 1 - exponent) as usize;
+        trim(&format!("{:.*}", decimals, n))
+    }
+}
+
+fn constant(output: &mut String, f: &Proto, i: usize) {
+    match f.constants.get(i) {
+        Some(SyxValue::Nil) => output.push_str("nil"),
+        Some(SyxValue::Bool(b)) => output.push_str(if *b { "true" } else { "false" }),
+        Some(SyxValue::Number(n)) => {
+            let s = format_float(*n);
+            // make sure it still reads as a float
+            let float = s.bytes().any(|ch| !(ch == b'-' || ch.is_ascii_digit()));
+            output.push_str(&s);
+            if !float {
+                output.push_str(".0");
+            }
+        }
+        Some(SyxValue::Integer(n)) => write!(output, "{}", n).unwrap(),
+        Some(SyxValue::String(s)) => output.push_str(&quote_string(s)),
+        None => write!(output, "? index={}", i).unwrap(),
+    }
+}
+
+fn upvalue_name(f: &Proto, i: usize) -> String {
+    match f.upvalues.get(i) {
+        Some(upvalue) if !upvalue.name.is_empty() => {
+            String::from_utf8_lossy(&upvalue.name).into_owned()
+        }
+        _ => "-".to_owned(),
+    }
+}
+
+fn is_k(x: u32) -> bool {
+    x & BITMASK_IS_RK != 0
+}
+
+fn index_k(x: u32) -> usize {
+    (x & !BITMASK_IS_RK) as usize
+}
+
+// constants are listed as negative numbers, MYK in luac.c
+fn my_k(x: usize) -> i64 {
+    -1 - x as i64
+}
+
+fn rk(x: u32) -> i64 {
+    if is_k(x) { my_k(index_k(x)) } else { i64::from(x) }
+}
+
+// An instruction as its line of the listing shows it, without the pc and
+// source line, and the pc of the instruction after it
+pub fn print_instruction(f: &Proto, pc: usize) -> (String, usize) {
+    let mut output = String::new();
+    let next = if f.version == Version::Lua54 {
+        instruction54(&mut output, f, pc)
+    } else {
+        instruction(&mut output, f, pc)
+    };
+    (output, next)
+}
+
+// The pc and source line columns of a line of the listing
+fn location(output: &mut String, f: &Proto, pc: usize) {
+    write!(output, "\t{}\t", pc + 1).unwrap();
+    match f.lineinfo.get(pc) {
+        Some(line) if *line > 0 => write!(output, "[{}]\t", line).unwrap(),
+        _ => output.push_str("[-]\t"),
+    }
+}
+
+fn code(output: &mut String, f: &Proto) {
+    let mut pc = 0;
+    while pc < f.instructions.len() {
+        location(output, f, pc);
+        pc = instruction(output, f, pc);
+        output.push('\n');
+    }
+}
+
+// Writes the instruction at `pc` and returns the pc after it, which skips
+// the raw argument of a SetList
+fn instruction(output: &mut String, f: &Proto, mut pc: usize) -> usize {
+    let code = &f.instructions;
+    let (op, a, b, c, bx, sbx, ax) = match code[pc] {
+        Instruction::ABC { instruction, a, b, c } => {
+            (instruction, a, u32::from(b), u32::from(c), 0, 0, 0)
+        }
+        Instruction::ABx { instruction, a, bx } => (instruction, a, 0, 0, bx, 0, 0),
+        Instruction::AsBx { instruction, a, sbx } => (instruction, a, 0, 0, 0, sbx, 0),
+        Instruction::Ax { instruction, ax } => (instruction, 0, 0, 0, 0, 0, ax),
+    };
+    let mode = op.mode();
+    write!(output, "{:<9}\t", opname(op)).unwrap();
+    match mode.format {
+        OpFormat::ABC | OpFormat::AB | OpFormat::A => {
+            write!(output, "{}", a).unwrap();
+            if mode.b != ArgMode::Unused {
+                write!(output, " {}", rk(b)).unwrap();
+            }
+            if mode.c != ArgMode::Unused {
+                write!(output, " {}", rk(c)).unwrap();
+            }
+        }
+        OpFormat::ABx => {
+            write!(output, "{}", a).unwrap();
+            match mode.b {
+                ArgMode::RegisterConstant => write!(output, " {}", my_k(bx as usize)).unwrap(),
+                ArgMode::Used => write!(output, " {}", bx).unwrap(),
+                _ => {}
+            }
+        }
+        OpFormat::AsBx => write!(output, "{} {}", a, sbx).unwrap(),
+        OpFormat::Ax => write!(output, "{}", my_k(ax as usize)).unwrap(),
+    }
+    match op {
+        OpCode::LoadK | OpCode::GetGlobal | OpCode::SetGlobal => {
+            output.push_str("\t; ");
+            constant(output, f, bx as usize);
+        }
+        OpCode::GetUpval | OpCode::SetUpval => {
+            write!(output, "\t; {}", upvalue_name(f, b as usize)).unwrap();
+        }
+        OpCode::GetTabUp => {
+            write!(output, "\t; {}", upvalue_name(f, b as usize)).unwrap();
+            if is_k(c) {
+                output.push(' ');
+                constant(output, f, index_k(c));
+            }
+        }
+        OpCode::SetTabUp => {
+            write!(output, "\t; {}", upvalue_name(f, a as usize)).unwrap();
+            if is_k(b) {
+                output.push(' ');
+                constant(output, f, index_k(b));
+            }
+            if is_k(c) {
+                output.push(' ');
+                constant(output, f, index_k(c));
+            }
+        }
+        OpCode::GetTable | OpCode::SelfLoad if is_k(c) => {
+            output.push_str("\t; ");
+            constant(output, f, index_k(c));
+        }
+        | OpCode::SetTable | OpCode::Add | OpCode::Sub | OpCode::Mul
+        | OpCode::Mod | OpCode::Pow | OpCode::Div | OpCode::IDiv
+        | OpCode::BAnd | OpCode::BOr | OpCode::BXOr | OpCode::Shl
+        | OpCode::Shr | OpCode::Eq | OpCode::Lt | OpCode::Le if is_k(b) || is_k(c) => {
+            output.push_str("\t; ");
+            if is_k(b) { constant(output, f, index_k(b)) } else { output.push('-') }
+            output.push(' ');
+            if is_k(c) { constant(output, f, index_k(c)) } else { output.push('-') }
+        }
+        OpCode::Jmp | OpCode::ForLoop | OpCode::ForPrep | OpCode::TForLoop => {
+            write!(output, "\t; to {}", sbx + pc as i32 + 2).unwrap();
+        }
+        OpCode::Closure => match f.protos.get(bx as usize) {
+            Some(p) => write!(output, "\t; {:p}", p).unwrap(),
+            None => output.push_str("\t; (nil)"),
+        },
+        OpCode::SetList => {
+            if c == 0 {
+                // luac prints the raw word of the ExtraArg and skips it
+                pc += 1;
+                let word = code.get(pc).and_then(|i| Word::try_from(*i).ok());
+                write!(output, "\t; {}", word.unwrap_or(0) as i32).unwrap();
+            } else {
+                write!(output, "\t; {}", c).unwrap();
+            }
+        }
+        OpCode::ExtraArg => {
+            output.push_str("\t; ");
+            constant(output, f, ax as usize);
+        }
+        _ => {}
+    }
+    pc + 1
+}
+
+// 5.4 code is listed by its operands as bytecode! declares them rather than
+// in the per-opcode format of luac 5.4, constants again as negative numbers
+fn code54(output: &mut String, f: &Proto) {
+    for pc in 0..f.instructions54.len() {
+        location(output, f, pc);
+        instruction54(output, f, pc);
+        output.push('\n');
+    }
+}
+
+fn instruction54(output: &mut String, f: &Proto, pc: usize) -> usize {
+    use self::opcodes54::{Argument, Instruction, OpCode};
+
+    let instr = &f.instructions54[pc];
+    let (op, k) = match *instr {
+        Instruction::ABC { instruction, k, .. } => (instruction, k),
+        Instruction::ABx { instruction, .. } | Instruction::AsBx { instruction, .. }
+        | Instruction::Ax { instruction, .. } | Instruction::SJ { instruction, .. } => {
+            (instruction, false)
+        }
+    };
+    write!(output, "{:<9}\t", opname54(op)).unwrap();
+    let mut constants = Vec::new();
+    let operands: Vec<_> = instr.operands().iter().map(|arg| match *arg {
+        Argument::Register(n) | Argument::Integer(n) | Argument::UpValue(n) => i64::from(n),
+        Argument::Constant(n) => {
+            constants.push(n as usize);
+            my_k(n as usize)
+        }
+        Argument::SInteger(n) => i64::from(n),
+        Argument::Bool(b) => i64::from(b),
+    }).map(|n| n.to_string()).collect();
+    output.push_str(&operands.join(" "));
+    if k {
+        output.push_str(" k");
+    }
+    let target = match *instr {
+        Instruction::SJ { sj, .. } => Some(pc as i64 + 2 + i64::from(sj)),
+        Instruction::ABx { instruction: OpCode::ForPrep, bx, .. } => {
+            Some(pc as i64 + 3 + i64::from(bx))
+        }
+        Instruction::ABx { instruction: OpCode::TForPrep, bx, .. } => {
+            Some(pc as i64 + 2 + i64::from(bx))
+        }
+        Instruction::ABx { instruction: OpCode::ForLoop, bx, .. }
+        | Instruction::ABx { instruction: OpCode::TForLoop, bx, .. } => {
+            Some(pc as i64 + 2 - i64::from(bx))
+        }
+        _ => None,
+    };
+    if let Some(target) = target {
+        write!(output, "\t; to {}", target).unwrap();
+    } else if !constants.is_empty() {
+        output.push_str("\t;");
+        for i in constants {
+            output.push(' ');
+            constant(output, f, i);
+        }
+    }
+    pc + 1
+}
+
+fn plural(x: usize) -> &'static str {
+    if x == 1 { "" } else { "s" }
+}
+
+// The start of the header of a function, like `main <test.lua:0,0>`
+pub fn function_name(f: &Proto) -> String {
+    let source = if f.source.is_empty() { "=?" } else { f.source.as_str() };
+    let name = if source.starts_with('@') || source.starts_with('=') {
+        &source[1..]
+    } else if source.starts_with('\x1b') {
+        "(bstring)"
+    } else {
+        "(string)"
+    };
+    let kind = if f.linedefined == 0 { "main" } else { "function" };
+    format!("{} <{}:{},{}>", kind, name, f.linedefined, f.lastlinedefined)
+}
+
+fn header(output: &mut String, f: &Proto) {
+    let count = if f.version == Version::Lua54 {
+        f.instructions54.len()
+    } else {
+        f.instructions.len()
+    };
+    writeln!(output, "\n{} ({} instruction{} at {:p})",
+           function_name(f), count, plural(count), f).unwrap();
+    let params = usize::from(f.numparams);
+    let slots = usize::from(f.maxstacksize);
+    write!(output, "{}{} param{}, {} slot{}, {} upvalue{}, ",
+           params, if f.is_vararg { "+" } else { "" }, plural(params),
+           slots, plural(slots),
+           f.upvalues.len(), plural(f.upvalues.len())).unwrap();
+    writeln!(output, "{} local{}, {} constant{}, {} function{}",
+           f.locvars.len(), plural(f.locvars.len()),
+           f.constants.len(), plural(f.constants.len()),
+           f.protos.len(), plural(f.protos.len())).unwrap();
+}
+
+fn debug(output: &mut String, f: &Proto) {
+    writeln!(output, "constants ({}) for {:p}:", f.constants.len(), f).unwrap();
+    for i in 0..f.constants.len() {
+        write!(output, "\t{}\t", i + 1).unwrap();
+        constant(output, f, i);
+        output.push('\n');
+    }
+    writeln!(output, "locals ({}) for {:p}:", f.locvars.len(), f).unwrap();
+    for (i, local) in f.locvars.iter().enumerate() {
+        writeln!(output, "\t{}\t{}\t{}\t{}", i,
+               String::from_utf8_lossy(&local.varname),
+               local.startpc + 1, local.endpc + 1).unwrap();
+    }
+    writeln!(output, "upvalues ({}) for {:p}:", f.upvalues.len(), f).unwrap();
+    for (i, upvalue) in f.upvalues.iter().enumerate() {
+        writeln!(output, "\t{}\t{}\t{}\t{}", i, upvalue_name(f, i),
+               upvalue.instack, upvalue.idx).unwrap();
+    }
+}
+
+fn function(output: &mut String, f: &Proto, full: bool) {
+    header(output, f);
+    if f.version == Version::Lua54 {
+        code54(output, f);
+    } else {
+        code(output, f);
+    }
+    if full {
+        debug(output, f);
+    }
+    for p in &f.protos {
+        function(output, p, full);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::undump::LoadState;
+
+    // replaces every "0x..." address with "0x0", they differ between runs
+    fn strip_addresses(s: &str) -> String {
+        let mut output = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(i) = rest.find("0x") {
+            output.push_str(&rest[..i]);
+            output.push_str("0x0");
+            rest = rest[i + 2..].trim_start_matches(|ch: char| ch.is_ascii_hexdigit());
+        }
+        output.push_str(rest);
+        output
+    }
+
+    macro_rules! fixture {
+        ($name:expr) => {
+            (
+                $name,
+                &include_bytes!(concat!("../tests/fixtures/", $name, ".luac"))[..],
+                include_str!(concat!("../tests/fixtures/", $name, ".listing")),
+            )
+        };
+    }
+
+    // listings were made by the print functions of luac 5.3.6
+    #[test]
+    fn test_luac_listing() {
+        let fixtures = [
+            fixture!("expressions"),
+            fixture!("closures"),
+            fixture!("constructors"),
+            fixture!("folding"),
+            fixture!("gotos"),
+            fixture!("scopes"),
+            fixture!("strings"),
+            fixture!("loops"),
+            fixture!("closures.stripped"),
+            fixture!("strings.stripped"),
+            fixture!("loops.stripped"),
+        ];
+        for &(name, luac, listing) in fixtures.iter() {
+            let proto = LoadState::from_u8(luac.to_vec(), name).unwrap();
+            let ours = strip_addresses(&print_function(&proto, true));
+            let theirs = strip_addresses(listing);
+            for (i, (a, b)) in ours.lines().zip(theirs.lines()).enumerate() {
+                assert_eq!(a, b, "{}:{}", name, i + 1);
+            }
+            assert_eq!(ours.lines().count(), theirs.lines().count(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_short_listing() {
+        let (name, luac, listing) = fixture!("loops");
+        let proto = LoadState::from_u8(luac.to_vec(), name).unwrap();
+        let ours = strip_addresses(&print_function(&proto, false));
+        let full = strip_addresses(listing);
+        assert!(full.starts_with(&ours));
+        assert!(full[ours.len()..].starts_with("constants (7)"));
+    }
+
+    #[test]
+    fn test_listing54() {
+        let luac = include_bytes!("../tests/fixtures/lua54.luac");
+        let proto = LoadState::from_slice(luac, "lua54").unwrap();
+        let listing = print_function(&proto, false);
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(strip_addresses(lines[1]),
+                   "main <lua54.lua:0,0> (92 instructions at 0x0)");
+        assert_eq!(lines[6], "\t4\t[4]\tSETFIELD \t0 -1 -2 k\t; \"x\" 1");
+        assert_eq!(lines[18], "\t16\t[6]\tFORPREP  \t2 12\t; to 30");
+        assert_eq!(lines[23], "\t21\t[7]\tADDI     \t1 6 -1");
+        assert_eq!(lines[72], "\t70\t[24]\tJMP      \t-8\t; to 63");
+        assert_eq!(lines[73], "\t71\t[326]\tGETTABUP \t4 0 -12\t; \"print\"");
+    }
+
+    #[test]
+    fn test_format_float() {
+        let cases: [(f64, &str); 14] = [
+            (0.5, "0.5"),
+            (100.0, "100"),
+            (-0.0, "-0"),
+            (0.1, "0.1"),
+            (1.0 / 3.0, "0.33333333333333"),
+            (1e15, "1e+15"),
+            (123456789012345.0, "1.2345678901234e+14"),
+            (99999999999999.5, "1e+14"),
+            (0.0001, "0.0001"),
+            (0.00001, "1e-05"),
+            (1e-300, "1e-300"),
+            (12345678901234.5, "12345678901234"),
+            (0.000123456789012345, "0.00012345678901234"),
+            (f64::INFINITY, "inf"),
+        ];
+        for &(n, expected) in cases.iter() {
+            assert_eq!(format_float(n), expected, "{:?}", n);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 
 use super::conf::{SYX_HEADER, SYX_DATA, SYX_VERSION, SYX_FORMAT, SYX_INT, SYX_NUM};
 
@@ -6,20 +6,234 @@ use super::object::{
     LocVar, Proto, SyxInt, SyxInteger, SyxNumber, SyxString,
     SyxType, SyxValue, Upvalue
 };
-use super::opcodes::{Instruction, Word};
+use super::opcodes::{self, Instruction, OpCode, Version, Word, BITMASK_AX};
+use super::opcodes54;
 use super::{limits, state};
 use super::errors::*;
 
-pub struct LoadState {
-    input: Box<Iterator<Item = u8>>,
-    name: Box<::std::fmt::Display>,
+pub struct LoadState<S: Source> {
+    input: S,
+    name: String,
     state: Option<state::SyxState>,
+    limits: LoadLimits,
+    layout: Layout,   // sizes and byte order the chunk declares
+    version: Version, // bytecode format the chunk declares
+    offset: usize,    // where the value being loaded starts
+    path: Vec<Field>, // fields leading to the value being loaded
+    depth: usize,     // nesting of the function being loaded
 }
 
-trait Primitives {}
+// Bounds on what a chunk may ask the loader to allocate, checked before
+// anything is read or reserved
+#[derive(Debug, Clone, Copy)]
+pub struct LoadLimits {
+    pub max_bytes: usize,
+    pub max_instructions: usize,
+    pub max_constants: usize,
+    pub max_string_length: usize,
+    pub max_depth: usize,
+    pub max_lineinfo: usize,
+}
+
+impl Default for LoadLimits {
+    fn default() -> LoadLimits {
+        LoadLimits {
+            max_bytes: limits::SYX_MAXCHUNK,
+            max_instructions: limits::SYX_MAXCODE,
+            max_constants: limits::SYX_MAXCONSTANTS,
+            max_string_length: limits::SYX_MAXSTRLEN,
+            max_depth: limits::SYX_MAXNESTING,
+            max_lineinfo: limits::SYX_MAXLINEINFO,
+        }
+    }
+}
+
+// A step of the path to the value being loaded. They are only turned into
+// text when loading fails, so keeping track costs no allocations.
+#[derive(Clone, Copy)]
+enum Field {
+    Name(&'static str),
+    Index(&'static str, usize),
+}
+
+impl ::std::fmt::Display for Field {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Field::Name(name) => write!(f, "{}", name),
+            Field::Index(name, i) => write!(f, "{}[{}]", name, i),
+        }
+    }
+}
+
+// Where the loader takes its bytes from
+pub trait Source {
+    // The next `n` bytes, or UnexpectedEof if the input ends before that.
+    // The slice is only good until the next read.
+    fn read(&mut self, n: usize) -> ::std::io::Result<&[u8]>;
+    // bytes consumed so far
+    fn position(&self) -> usize;
+    // bytes left, if the source knows
+    fn remaining(&self) -> Option<usize>;
+}
+
+fn end_of_input() -> ::std::io::Error {
+    ::std::io::ErrorKind::UnexpectedEof.into()
+}
+
+// A cursor over a borrowed chunk, reads hand out slices of the chunk itself
+pub struct SliceSource<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SliceSource<'a> {
+    pub fn new(input: &'a [u8]) -> SliceSource<'a> {
+        SliceSource { input, position: 0 }
+    }
+}
+
+impl<'a> Source for SliceSource<'a> {
+    fn read(&mut self, n: usize) -> ::std::io::Result<&[u8]> {
+        let end = self.position.checked_add(n).ok_or_else(end_of_input)?;
+        let bytes = self.input.get(self.position..end).ok_or_else(end_of_input)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        Some(self.input.len() - self.position)
+    }
+}
+
+// Reads through any io::Read, one field at a time. The bytes of the field
+// being loaded are kept in a buffer that is reused for every read.
+pub struct ReadSource<R: ::std::io::Read> {
+    input: R,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<R: ::std::io::Read> ReadSource<R> {
+    pub fn new(input: R) -> ReadSource<R> {
+        ReadSource {
+            input,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<R: ::std::io::Read> Source for ReadSource<R> {
+    fn read(&mut self, n: usize) -> ::std::io::Result<&[u8]> {
+        use std::io::Read;
+        self.buffer.clear();
+        // grow with the data that actually arrives rather than trusting `n`
+        let read = (&mut self.input).take(n as u64).read_to_end(&mut self.buffer);
+        self.position += self.buffer.len();
+        read?;
+        if self.buffer.len() == n {
+            Ok(&self.buffer)
+        } else {
+            Err(end_of_input())
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn native() -> Endian {
+        if cfg!(target_endian = "big") {
+            Endian::Big
+        } else {
+            Endian::Little
+        }
+    }
+}
+
+// How the machine that dumped a chunk lays out its numbers, as declared in
+// the chunk's header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub endian: Endian,
+    pub int: usize,         // counts and line numbers
+    pub size_t: usize,      // lengths of long strings
+    pub instruction: usize,
+    pub integer: usize,     // integer constants
+    pub number: usize,      // float constants
+}
+
+// the widths the loader can read
+const INT_SIZES: &[usize] = &[4, 8];
+const WORD_SIZES: &[usize] = &[4];
+
+impl Layout {
+    pub fn native() -> Layout {
+        Layout {
+            endian: Endian::native(),
+            int: ::std::mem::size_of::<SyxInt>(),
+            size_t: ::std::mem::size_of::<usize>(),
+            instruction: ::std::mem::size_of::<Word>(),
+            integer: ::std::mem::size_of::<SyxInteger>(),
+            number: ::std::mem::size_of::<SyxNumber>(),
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        [self.int, self.size_t, self.integer, self.number]
+            .iter().all(|size| INT_SIZES.contains(size))
+            && WORD_SIZES.contains(&self.instruction)
+    }
+}
+
+// Numbers that are read from and written to chunks
+pub trait Primitives: Copy {
+    const SIZE: usize;
+
+    // `bytes` is exactly SIZE long
+    fn from_bytes(bytes: &[u8], endian: Endian) -> Self;
+
+    fn write(self, endian: Endian, output: &mut Vec<u8>);
+}
 
 macro_rules! primitive {
-    ($($item:ty),*) => { $(impl Primitives for $item {})* }
+    ($($item:ty),*) => { $(
+        impl Primitives for $item {
+            const SIZE: usize = ::std::mem::size_of::<$item>();
+
+            fn from_bytes(bytes: &[u8], endian: Endian) -> $item {
+                let mut raw = [0u8; ::std::mem::size_of::<$item>()];
+                raw.copy_from_slice(bytes);
+                match endian {
+                    Endian::Little => <$item>::from_le_bytes(raw),
+                    Endian::Big => <$item>::from_be_bytes(raw),
+                }
+            }
+
+            fn write(self, endian: Endian, output: &mut Vec<u8>) {
+                match endian {
+                    Endian::Little => output.extend_from_slice(&self.to_le_bytes()),
+                    Endian::Big => output.extend_from_slice(&self.to_be_bytes()),
+                }
+            }
+        }
+    )* }
 }
 
 primitive!(u8, u16, u32, u64);
@@ -27,42 +241,145 @@ primitive!(i8, i16, i32, i64);
 primitive!(usize, isize);
 primitive!(f32, f64);
 
-macro_rules! expand {
-    ($item:ty) => {{
-        (::std::mem::size_of::<$item>(), stringify!($item))
-    }};
+// Integers whose width is only known once the header is read, from 1 to 8
+// bytes
+pub fn uint_from_bytes(bytes: &[u8], endian: Endian) -> u64 {
+    let mut raw = [0u8; 8];
+    match endian {
+        Endian::Little => {
+            raw[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(raw)
+        }
+        Endian::Big => {
+            raw[8 - bytes.len()..].copy_from_slice(bytes);
+            u64::from_be_bytes(raw)
+        }
+    }
+}
+
+pub fn uint_to_bytes(value: u64, size: usize, endian: Endian, output: &mut Vec<u8>) {
+    match endian {
+        Endian::Little => output.extend_from_slice(&value.to_le_bytes()[..size]),
+        Endian::Big => output.extend_from_slice(&value.to_be_bytes()[8 - size..]),
+    }
+}
+
+// the low `size` bytes of `value` as a signed number
+pub fn sign_extend(value: u64, size: usize) -> i64 {
+    let shift = 64 - 8 * size as u32;
+    (value << shift) as i64 >> shift
+}
+
+#[allow(dead_code)]
+impl<R: ::std::io::Read> LoadState<ReadSource<::std::io::BufReader<R>>> {
+    // Parses while reading, the chunk is never held in memory as a whole
+    pub fn from_read(input: R, name: impl Into<String>) -> Result<Proto> {
+        LoadState::from_read_with_limits(input, name, LoadLimits::default())
+    }
+
+    pub fn from_read_with_limits(input: R, name: impl Into<String>, limits: LoadLimits)
+        -> Result<Proto>
+    {
+        let input = ReadSource::new(::std::io::BufReader::new(input));
+        LoadState::from_source(input, name, limits)
+    }
+}
+
+#[allow(dead_code)]
+impl<R: ::std::io::BufRead> LoadState<ReadSource<R>> {
+    // like from_read, for input that is buffered already
+    pub fn from_buf_read(input: R, name: impl Into<String>, limits: LoadLimits)
+        -> Result<Proto>
+    {
+        LoadState::from_source(ReadSource::new(input), name, limits)
+    }
 }
 
 #[allow(dead_code)]
-impl LoadState {
-    pub fn from_read(
-        mut input: impl ::std::io::Read,
+impl<'a> LoadState<SliceSource<'a>> {
+    pub fn from_u8(buffer: Vec<u8>, name: impl Into<String>)
+        -> Result<Proto>
+    {
+        LoadState::from_slice(&buffer, name)
+    }
+
+    pub fn from_slice(buffer: &[u8], name: impl Into<String>)
+        -> Result<Proto>
+    {
+        LoadState::from_slice_with_limits(buffer, name, LoadLimits::default())
+    }
+
+    pub fn from_slice_with_limits(
+        buffer: &[u8],
         name: impl Into<String>,
+        limits: LoadLimits,
     ) -> Result<Proto> {
-        let mut buffer: Vec<u8> = Vec::new();
-        let into_name = name.into();
-        if input.read_to_end(&mut buffer).is_ok() {
-            LoadState::from_u8(buffer, into_name.clone())
-        } else {
-            Err(ErrorKind::BufferNotReadable(into_name).into())
+        if buffer.len() > limits.max_bytes {
+            // report the first byte past the limit
+            let err = ErrorKind::ChunkTooLarge(buffer.len(), limits.max_bytes);
+            let location = ErrorKind::LoadError(name.into(), limits.max_bytes,
+                                                "chunk".to_owned());
+            return Err(Error::from(err)).chain_err(|| location);
         }
+        LoadState::from_source(SliceSource::new(buffer), name, limits)
     }
+}
 
-    pub fn from_u8(buffer: Vec<u8>, name: impl Into<String>)
+#[allow(dead_code)]
+impl<S: Source> LoadState<S> {
+    pub fn from_source(input: S, name: impl Into<String>, limits: LoadLimits)
         -> Result<Proto>
     {
         let mut state = LoadState {
-            input: Box::new(buffer.into_iter()),
-            name: Box::new(name.into()),
+            input,
+            name: name.into(),
             state: None,
+            limits,
+            layout: Layout::native(),
+            version: Version::Lua53,
+            offset: 0,
+            path: Vec::new(),
+            depth: 0,
         };
-        let proto = state.load_chunk(state::SyxState::new())?;
-        match state.load::<u8>() {
-            Err(_) => Ok(proto),
+        let proto = state.load_all();
+        // the path is left pointing at whatever failed to load
+        proto.chain_err(|| ErrorKind::LoadError(state.name.clone(),
+                                                state.offset,
+                                                state.path_string()))
+    }
+
+    fn load_all(&mut self) -> Result<Proto> {
+        let proto = self.load_chunk(state::SyxState::new())?;
+        self.enter("end");
+        self.offset = self.input.position();
+        // one byte more is enough to tell, a stream is not read to its end
+        match self.input.read(1) {
             Ok(_) => Err(ErrorKind::BufferNotEmpty.into()),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => Ok(proto),
+            Err(e) => {
+                let err = ErrorKind::BufferNotReadable(self.name.clone());
+                Err(e).chain_err(|| err)
+            }
         }
     }
 
+    fn enter(&mut self, name: &'static str) {
+        self.path.push(Field::Name(name));
+    }
+
+    fn enter_index(&mut self, name: &'static str, i: usize) {
+        self.path.push(Field::Index(name, i));
+    }
+
+    fn path_string(&self) -> String {
+        let fields: Vec<_> = self.path.iter().map(|f| f.to_string()).collect();
+        fields.join(".")
+    }
+
+    fn leave(&mut self) {
+        self.path.pop();
+    }
+
     fn assert_verification(&mut self, val: bool, err: impl ::std::fmt::Display)
         -> Result<()>
     {
@@ -80,51 +397,149 @@ impl LoadState {
                                            err.to_string()).into())
     }
 
-    fn load_range(&mut self, range: usize) -> Result<Vec<u8>> {
-        let v: Vec<u8> = self.input.by_ref().take(range).collect();
-        self.assert_verification(v.len() == range,
-                                 format!("Not enough bytes: {}", range))?;
-        Ok(v)
-        // made redundant by the above
-        /*
-        let mut ret: Vec<u8> = Vec::with_capacity(range);
-        for i in 0..range {
-            if let Some(mut ch) = self.input.next() {
-                ret.push(ch);
-            } else {
-                self.raise_from_verification(
-                    format!("Missing byte at pos: {}", i))?;
+    fn load_range(&mut self, range: usize) -> Result<&[u8]> {
+        self.offset = self.input.position();
+        // sources that know their size were checked up front
+        let end = self.offset.saturating_add(range);
+        if end > self.limits.max_bytes {
+            bail!(ErrorKind::ChunkTooLarge(end, self.limits.max_bytes));
+        }
+        match self.input.read(range) {
+            Ok(bytes) => Ok(bytes),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => {
+                Err(ErrorKind::InvalidVerification(
+                    self.name.clone(), format!("Not enough bytes: {}", range)).into())
             }
-        };
-        Ok(ret)
-        */
-    }
-
-    fn load<T: Copy + Primitives>(&mut self) -> Result<T> {
-        /*
-         * Safety of this method
-         * ---
-         * I had to mark unsafe because of the transmutation, but this is why
-         * it will alwasy pass:
-         *
-         * 1. It will always transmute bytes directly to the size of T
-         * 2. The size of T is loaded from self.load_range, which either grabs
-         *    the whole thing or fails to load
-         * 3. All values of type `Primitives` are defined at the top of this
-         *    file and will always be Rust primitives.
-         */
-        // ::TODO:: optimize for <u8> when specializations lands:
-        // https://github.com/rust-lang/rust/issues/31844
-        // https://github.com/rust-lang/rfcs/blob/master/text/1210-impl-specialization.md
-        let size = ::std::mem::size_of::<T>();
+            Err(e) => {
+                let err = ErrorKind::BufferNotReadable(self.name.clone());
+                Err(e).chain_err(|| err)
+            }
+        }
+    }
+
+    // How much room to make for `count` items up front. A stream can not be
+    // checked for enough bytes ahead of time, so it only gets a little.
+    fn reservation(&self, count: usize) -> usize {
+        match self.input.remaining() {
+            Some(_) => count,
+            None => count.min(1024),
+        }
+    }
+
+    fn load<T: Primitives>(&mut self) -> Result<T> {
+        let endian = self.layout.endian;
+        let bytes = self.load_range(T::SIZE)?;
+        Ok(T::from_bytes(bytes, endian))
+    }
+
+    fn load_field<T: Primitives>(&mut self, field: &'static str) -> Result<T> {
+        self.enter(field);
+        let value = self.load::<T>()?;
+        self.leave();
+        Ok(value)
+    }
+
+    fn load_sized(&mut self, size: usize) -> Result<u64> {
+        let endian = self.layout.endian;
         let bytes = self.load_range(size)?;
-        Ok(unsafe { *(&bytes[0] as *const u8 as *const T) })
+        Ok(uint_from_bytes(bytes, endian))
+    }
+
+    // 5.4 writes ints and sizes as groups of seven bits, most significant
+    // first, with the high bit set on the last one
+    fn load_varint(&mut self, limit: u64) -> Result<u64> {
+        let mut value: u64 = 0;
+        loop {
+            if value >= limit >> 7 {
+                return Err(ErrorKind::InvalidVerification(
+                    self.name.clone(), format!("varint over {}", limit)).into());
+            }
+            let byte = self.load::<u8>()?;
+            value = value << 7 | u64::from(byte & 0x7F);
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    // An int as wide as the dumping machine's, which has to fit in ours
+    fn load_int(&mut self) -> Result<SyxInt> {
+        if self.version == Version::Lua54 {
+            return Ok(self.load_varint(SyxInt::MAX as u64)? as SyxInt);
+        }
+        let size = self.layout.int;
+        let value = sign_extend(self.load_sized(size)?, size);
+        match SyxInt::try_from(value) {
+            Ok(int) => Ok(int),
+            Err(_) => Err(ErrorKind::InvalidVerification(
+                self.name.clone(), format!("int out of range: {}", value)).into()),
+        }
+    }
+
+    fn load_int_field(&mut self, field: &'static str) -> Result<SyxInt> {
+        self.enter(field);
+        let value = self.load_int()?;
+        self.leave();
+        Ok(value)
+    }
+
+    fn load_size_t(&mut self) -> Result<usize> {
+        let value = if self.version == Version::Lua54 {
+            self.load_varint(u64::MAX)?
+        } else {
+            let size = self.layout.size_t;
+            self.load_sized(size)?
+        };
+        // anything this large is over the string limit anyway
+        Ok(usize::try_from(value).unwrap_or(usize::MAX))
+    }
+
+    fn load_integer(&mut self) -> Result<SyxInteger> {
+        let size = self.layout.integer;
+        Ok(sign_extend(self.load_sized(size)?, size))
+    }
+
+    fn load_number(&mut self) -> Result<SyxNumber> {
+        let size = self.layout.number;
+        let bits = self.load_sized(size)?;
+        Ok(if size == 4 {
+            f32::from_bits(bits as u32) as SyxNumber
+        } else {
+            SyxNumber::from_bits(bits)
+        })
+    }
+
+    // A count of items that follow, each at least a byte long, so anything
+    // past the end of the input is rejected before it is used to reserve
+    fn load_count(&mut self, what: &'static str) -> Result<usize> {
+        let count = self.load_int()?;
+        if count < 0 {
+            bail!(ErrorKind::NegativeCount(what, count));
+        }
+        let count = count as usize;
+        match self.input.remaining() {
+            Some(remaining) if count > remaining => {
+                self.raise_from_verification(
+                    format!("Not enough bytes for {} {}", count, what))?;
+            }
+            _ => {}
+        }
+        Ok(count)
     }
 
     fn load_string(&mut self) -> Result<SyxString> {
-        let mut size: usize = self.load::<u8>()? as usize;
+        let mut size: usize = if self.version == Version::Lua53 {
+            self.load::<u8>()? as usize
+        } else {
+            // before 5.3 every length is a size_t
+            0xFF
+        };
         if size == 0xFF {
-            size = self.load::<usize>()?;
+            size = self.load_size_t()?;
+        }
+        // size includes the trailing '\0' that is never written
+        if size > self.limits.max_string_length.saturating_add(1) {
+            bail!(ErrorKind::StringTooLong(size - 1, self.limits.max_string_length));
         }
         if size == 0 {
             // Turns out it can happen with stripped debug info. We'll just
@@ -136,97 +551,277 @@ impl LoadState {
             // optimized later in the future, as well as the SyxString type, to
             // include a hash field.
             size -= 1;
-            if size < limits::SYX_MAXSHORTLEN {
-                self.load_range(size)
-            } else {
-                self.load_range(size)
+            let string = self.load_range(size).map(<[u8]>::to_vec);
+            if let Version::Lua51 | Version::Lua52 = self.version {
+                // and the '\0' is written too
+                self.load::<u8>()?;
             }
+            string
         }
     }
 
     fn load_constants(&mut self, proto: &mut Proto) -> Result<()> {
-        let constant_count: isize = self.load::<i32>()? as isize;
+        self.enter("constants");
+        let constant_count = self.load_count("constants")?;
+        if constant_count > self.limits.max_constants {
+            bail!(ErrorKind::TooManyConstants(constant_count,
+                                              self.limits.max_constants));
+        }
+        self.leave();
         proto.constants.clear();
-        for _ in 0..constant_count {
+        proto.constants.reserve(self.reservation(constant_count));
+        for i in 0..constant_count {
+            self.enter_index("constants", i);
             // get type from byte
-            proto.constants.push(match SyxType::try_from(self.load::<u8>()?)? {
+            let tag = self.load::<u8>()?;
+            if self.version == Version::Lua54 {
+                let constant = self.load_constant54(tag)?;
+                proto.constants.push(constant);
+                self.leave();
+                continue;
+            }
+            proto.constants.push(match SyxType::try_from(tag)? {
                 SyxType::TNIL => SyxValue::Nil,
                 SyxType::TBOOLEAN => SyxValue::Bool(self.load::<u8>()? == 1),
                 // these lines represent everything wrong with the world
                 // they take up more than 80 characters
-                SyxType::TNUMFLT => SyxValue::Number(self.load::<SyxNumber>()?),
-                SyxType::TNUMINT => SyxValue::Integer(self.load::<SyxInteger>()?),
-                | SyxType::TSHRSTR
-                | SyxType::TLNGSTR => SyxValue::String(self.load_string()?),
+                SyxType::TNUMFLT => SyxValue::Number(self.load_number()?),
+                SyxType::TNUMINT if self.version == Version::Lua53 => {
+                    SyxValue::Integer(self.load_integer()?)
+                }
+                SyxType::TSHRSTR => SyxValue::String(self.load_string()?),
+                SyxType::TLNGSTR if self.version == Version::Lua53 => {
+                    SyxValue::String(self.load_string()?)
+                }
                 x => {
                     return Err(ErrorKind::InvalidConstantType(x).into());
                 }
             });
+            self.leave();
         }
         Ok(())
     }
 
+    // 5.4 tags booleans by their value and swapped the tags of integers and
+    // floats, so they do not go through SyxType
+    fn load_constant54(&mut self, tag: u8) -> Result<SyxValue> {
+        Ok(match tag {
+            0 => SyxValue::Nil,
+            1 => SyxValue::Bool(false),
+            17 => SyxValue::Bool(true),
+            3 => SyxValue::Integer(self.load_integer()?),
+            19 => SyxValue::Number(self.load_number()?),
+            4 | 20 => SyxValue::String(self.load_string()?),
+            _ => bail!(ErrorKind::InvalidType(tag)),
+        })
+    }
+
     fn load_code(&mut self, proto: &mut Proto) -> Result<()> {
-        let count = self.load::<SyxInt>()?;
+        self.enter("instructions");
+        let count = self.load_count("instructions")?;
+        if count > self.limits.max_instructions {
+            bail!(ErrorKind::TooManyInstructions(count,
+                                                 self.limits.max_instructions));
+        }
+        self.leave();
+        if self.version == Version::Lua54 {
+            return self.load_code54(proto, count);
+        }
         proto.instructions.clear();
-        proto.instructions.reserve(count as usize);
-        for _ in 0..(count) {
-            proto.instructions.push(self.load::<Word>()?.try_into()?);
+        proto.instructions.reserve(self.reservation(count));
+        // a 5.1 SetList with a C of 0 has the real C as the next word
+        let mut raw_c = false;
+        for i in 0..(count) {
+            self.enter_index("instructions", i);
+            let word = self.load::<Word>()?;
+            let instr = if raw_c {
+                if word > BITMASK_AX {
+                    bail!(ErrorKind::InvalidOperand("c", word as i64));
+                }
+                Instruction::Ax { instruction: OpCode::ExtraArg, ax: word }
+            } else {
+                self.decode(word)?
+            };
+            raw_c = self.version == Version::Lua51 && match instr {
+                Instruction::ABC { instruction: OpCode::SetList, c, .. } => c == 0,
+                _ => false,
+            };
+            proto.instructions.push(instr);
+            self.leave();
+        }
+        Ok(())
+    }
+
+    fn load_code54(&mut self, proto: &mut Proto, count: usize) -> Result<()> {
+        proto.instructions54.clear();
+        proto.instructions54.reserve(self.reservation(count));
+        for i in 0..count {
+            self.enter_index("instructions", i);
+            let word = self.load::<Word>()?;
+            proto.instructions54.push(opcodes54::Instruction::try_from(word)?);
+            self.leave();
         }
         Ok(())
     }
 
+    // The arguments of 5.1's LoadNil are the first and last register, they
+    // become a first register and a count like later versions have
+    fn decode(&mut self, word: Word) -> Result<Instruction> {
+        let instr = opcodes::decode(word, self.version)?;
+        Ok(match instr {
+            Instruction::ABC { instruction: OpCode::LoadNil, a, b, c }
+                if self.version == Version::Lua51 => {
+                if b < a as u16 {
+                    bail!(ErrorKind::InvalidOperand("b", b as i64));
+                }
+                Instruction::ABC { instruction: OpCode::LoadNil, a, b: b - a as u16, c }
+            }
+            _ => instr,
+        })
+    }
+
     fn load_protos(&mut self, proto: &mut Proto) -> Result<()> {
-        let count = self.load::<SyxInt>()?;
+        self.enter("protos");
+        let count = self.load_count("functions")?;
+        self.leave();
         proto.protos.clear();
-        proto.protos.reserve(count as usize);
-        for _ in 0..(count) {
+        proto.protos.reserve(self.reservation(count));
+        for i in 0..(count) {
+            self.enter_index("protos", i);
             let mut new_proto = Proto::new();
-            self.load_function(&mut new_proto, vec![])?;
+            // nested functions inherit the source when it was not dumped
+            let source = proto.source.as_bytes().to_vec();
+            self.depth += 1;
+            if self.depth > self.limits.max_depth {
+                bail!(ErrorKind::NestingTooDeep(self.limits.max_depth));
+            }
+            self.load_function(&mut new_proto, source)?;
+            self.depth -= 1;
             proto.protos.push(new_proto);
+            self.leave();
         }
         Ok(())
     }
 
     fn load_upvalues(&mut self, proto: &mut Proto) -> Result<()> {
-        let upvalues_count = self.load::<SyxInt>()?;
+        self.enter("upvalues");
+        let upvalues_count = self.load_count("upvalues")?;
+        self.leave();
         proto.upvalues.clear();
-        proto.upvalues.reserve(upvalues_count as usize);
-        for _ in 0..upvalues_count {
-            proto.upvalues.push(Upvalue {
-                name: vec![],
-                instack: self.load::<u8>()?,
-                idx: self.load::<u8>()?,
-            })
+        proto.upvalues.reserve(self.reservation(upvalues_count));
+        for i in 0..upvalues_count {
+            self.enter_index("upvalues", i);
+            let instack = self.load::<u8>()?;
+            let idx = self.load::<u8>()?;
+            let kind = if self.version == Version::Lua54 { self.load::<u8>()? } else { 0 };
+            proto.upvalues.push(Upvalue { name: vec![], instack, idx, kind });
+            self.leave();
         }
         Ok(())
     }
 
     fn load_debug(&mut self, proto: &mut Proto) -> Result<()> {
-        let lines = self.load::<SyxInt>()? as usize;
+        if self.version == Version::Lua54 {
+            self.load_lineinfo54(proto)?;
+        } else {
+            self.load_lineinfo(proto)?;
+        }
+        self.load_names(proto)
+    }
+
+    fn load_lineinfo(&mut self, proto: &mut Proto) -> Result<()> {
+        self.enter("lineinfo");
+        let lines = self.load_count("lines")?;
+        if lines > self.limits.max_lineinfo {
+            bail!(ErrorKind::LineInfoTooLarge(lines, self.limits.max_lineinfo));
+        }
+        self.leave();
+        proto.lineinfo.clear();
+        proto.lineinfo.reserve(self.reservation(lines));
+        for i in 0..lines {
+            self.enter_index("lineinfo", i);
+            proto.lineinfo.push(self.load_int()?);
+            self.leave();
+        }
+        Ok(())
+    }
+
+    // 5.4 keeps each line as a signed byte difference to the previous one,
+    // with absolute lines where that would not fit or every so often. They
+    // are turned back into a line per instruction the way luaG_getfuncline
+    // finds them.
+    fn load_lineinfo54(&mut self, proto: &mut Proto) -> Result<()> {
+        self.enter("lineinfo");
+        let lines = self.load_count("lines")?;
+        if lines > self.limits.max_lineinfo {
+            bail!(ErrorKind::LineInfoTooLarge(lines, self.limits.max_lineinfo));
+        }
+        let deltas = self.load_range(lines)?.to_vec();
+        self.leave();
+        self.enter("abslineinfo");
+        let count = self.load_count("absolute lines")?;
+        if count > self.limits.max_lineinfo {
+            bail!(ErrorKind::LineInfoTooLarge(count, self.limits.max_lineinfo));
+        }
+        self.leave();
+        let mut absolute = Vec::with_capacity(self.reservation(count));
+        for i in 0..count {
+            self.enter_index("abslineinfo", i);
+            let pc = self.load_int()?;
+            let line = self.load_int()?;
+            absolute.push((pc as usize, line));
+            self.leave();
+        }
         proto.lineinfo.clear();
         proto.lineinfo.reserve(lines);
-        for _ in 0..lines {
-            proto.lineinfo.push(self.load::<SyxInt>()?);
+        let mut line = proto.linedefined;
+        let mut next = absolute.iter().peekable();
+        for (pc, &delta) in deltas.iter().enumerate() {
+            match next.peek() {
+                Some(&&(abs_pc, abs_line)) if abs_pc == pc => {
+                    line = abs_line;
+                    next.next();
+                }
+                _ => line = line.wrapping_add(SyxInt::from(delta as i8)),
+            }
+            proto.lineinfo.push(line);
         }
-        let size = self.load::<SyxInt>()? as usize;
+        if next.peek().is_some() {
+            self.enter("abslineinfo");
+            self.raise_from_verification("absolute lines out of order")?;
+        }
+        Ok(())
+    }
+
+    fn load_names(&mut self, proto: &mut Proto) -> Result<()> {
+        self.enter("locvars");
+        let size = self.load_count("locals")?;
+        self.leave();
         proto.locvars.clear();
-        proto.locvars.reserve(size);
+        proto.locvars.reserve(self.reservation(size));
         // load locvars
-        for _ in 0..size {
+        for i in 0..size {
+            self.enter_index("locvars", i);
             proto.locvars.push(LocVar {
                 varname: self.load_string()?,
-                startpc: self.load::<SyxInt>()?,
-                endpc: self.load::<SyxInt>()?,
+                startpc: self.load_int()?,
+                endpc: self.load_int()?,
             });
+            self.leave();
         }
         // end trash
-        let upvalue_count = self.load::<SyxInt>()? as usize;
+        self.enter("upvalues");
+        let upvalue_count = self.load_count("upvalue names")?;
+        self.leave();
         for i in 0..upvalue_count {
+            self.enter_index("upvalues", i);
+            self.enter("name");
             match proto.upvalues.get_mut(i) {
                 Some(value) => value.name = self.load_string()?,
                 None => return Err(ErrorKind::InvalidUpvalueIndex(i).into()),
             }
+            self.leave();
+            self.leave();
         }
         Ok(())
     }
@@ -234,6 +829,13 @@ impl LoadState {
     fn load_function(&mut self, proto: &mut Proto, source: SyxString)
         -> Result<()>
     {
+        proto.version = self.version;
+        match self.version {
+            Version::Lua51 => return self.load_function51(proto, source),
+            Version::Lua52 => return self.load_function52(proto),
+            Version::Lua53 | Version::Lua54 => {}
+        }
+        self.enter("source");
         let loaded_source = self.load_string()?;
         proto.source = String::from_utf8({
             if !loaded_source.is_empty() {
@@ -242,11 +844,12 @@ impl LoadState {
                 source
             }
         }).chain_err(|| ErrorKind::InvalidSourceName)?;
-        proto.linedefined = self.load::<SyxInt>()?;
-        proto.lastlinedefined = self.load::<SyxInt>()?;
-        proto.numparams = self.load::<u8>()?;
-        proto.is_vararg = self.load::<u8>()? != 0;
-        proto.maxstacksize = self.load::<u8>()?;
+        self.leave();
+        proto.linedefined = self.load_int_field("linedefined")?;
+        proto.lastlinedefined = self.load_int_field("lastlinedefined")?;
+        proto.numparams = self.load_field::<u8>("numparams")?;
+        proto.is_vararg = self.load_field::<u8>("is_vararg")? != 0;
+        proto.maxstacksize = self.load_field::<u8>("maxstacksize")?;
         self.load_code(proto)?;
         self.load_constants(proto)?;
         self.load_upvalues(proto)?;
@@ -255,47 +858,219 @@ impl LoadState {
         Ok(())
     }
 
-    fn check_size(&mut self, size: (usize, &'static str)) -> Result<()> {
-        if let Ok(bytecode_size) = self.load::<u8>() {
-            self.assert_verification(
-                bytecode_size == (size.0 as u8),
-                format!("size mismatch: {}", size.1),
-            )
-        } else {
-            Ok(())
+    // 5.1 has no upvalue descriptions, only a count. They are rebuilt from
+    // the Move or GetUpval that follow each Closure for every upvalue.
+    fn load_function51(&mut self, proto: &mut Proto, source: SyxString)
+        -> Result<()>
+    {
+        self.enter("source");
+        let loaded_source = self.load_string()?;
+        proto.source = String::from_utf8({
+            if !loaded_source.is_empty() {
+                loaded_source
+            } else {
+                source
+            }
+        }).chain_err(|| ErrorKind::InvalidSourceName)?;
+        self.leave();
+        proto.linedefined = self.load_int_field("linedefined")?;
+        proto.lastlinedefined = self.load_int_field("lastlinedefined")?;
+        let upvalues = self.load_field::<u8>("nups")?;
+        proto.numparams = self.load_field::<u8>("numparams")?;
+        // VARARG_ISVARARG, next to the flags for the `arg` table
+        proto.is_vararg = self.load_field::<u8>("is_vararg")? & 2 != 0;
+        proto.maxstacksize = self.load_field::<u8>("maxstacksize")?;
+        proto.upvalues.clear();
+        for _ in 0..upvalues {
+            proto.upvalues.push(Upvalue { name: vec![], instack: 0, idx: 0, kind: 0 });
         }
+        self.load_code(proto)?;
+        self.load_constants(proto)?;
+        self.load_protos(proto)?;
+        self.enter("instructions");
+        self.link_upvalues51(proto)?;
+        self.leave();
+        self.load_debug(proto)?;
+        Ok(())
+    }
+
+    fn link_upvalues51(&mut self, proto: &mut Proto) -> Result<()> {
+        for pc in 0..proto.instructions.len() {
+            let child = match proto.instructions[pc] {
+                Instruction::ABx { instruction: OpCode::Closure, bx, .. } => bx as usize,
+                _ => continue,
+            };
+            let upvalues = match proto.protos.get_mut(child) {
+                Some(child) => &mut child.upvalues,
+                None => continue, // left to the verifier
+            };
+            for (i, upvalue) in upvalues.iter_mut().enumerate() {
+                let (instack, idx) = match proto.instructions.get(pc + 1 + i) {
+                    Some(&Instruction::ABC { instruction: OpCode::Move, b, .. }) => (1, b),
+                    Some(&Instruction::ABC { instruction: OpCode::GetUpval, b, .. }) => (0, b),
+                    _ => {
+                        return Err(ErrorKind::InvalidVerification(self.name.clone(), format!(
+                            "closure at {} is missing upvalue {}", pc + 1, i)).into());
+                    }
+                };
+                if idx > 0xFF {
+                    bail!(ErrorKind::InvalidUpvalueIndex(idx as usize));
+                }
+                upvalue.instack = instack;
+                upvalue.idx = idx as u8;
+            }
+        }
+        Ok(())
+    }
+
+    // 5.2 keeps the source with the debug information, after the nested
+    // functions, so they do not get their parent's
+    fn load_function52(&mut self, proto: &mut Proto) -> Result<()> {
+        proto.linedefined = self.load_int_field("linedefined")?;
+        proto.lastlinedefined = self.load_int_field("lastlinedefined")?;
+        proto.numparams = self.load_field::<u8>("numparams")?;
+        proto.is_vararg = self.load_field::<u8>("is_vararg")? != 0;
+        proto.maxstacksize = self.load_field::<u8>("maxstacksize")?;
+        self.load_code(proto)?;
+        self.load_constants(proto)?;
+        self.load_protos(proto)?;
+        self.load_upvalues(proto)?;
+        self.enter("source");
+        proto.source = String::from_utf8(self.load_string()?)
+            .chain_err(|| ErrorKind::InvalidSourceName)?;
+        self.leave();
+        self.load_debug(proto)?;
+        Ok(())
+    }
+
+    fn load_size(&mut self, field: &'static str, allowed: &[usize]) -> Result<usize> {
+        self.enter(field);
+        let size = self.load::<u8>()? as usize;
+        if !allowed.contains(&size) {
+            let expected: Vec<_> = allowed.iter().map(|n| n.to_string()).collect();
+            self.raise_from_verification(format!("size mismatch: expected {}, got {}",
+                                                 expected.join(" or "), size))?;
+        }
+        self.leave();
+        Ok(size)
     }
 
     fn check_literal(
         &mut self,
         value_impl: impl Into<Vec<u8>>,
-        err: impl ::std::fmt::Display,
+        field: &'static str,
     ) -> Result<()> {
         let value = value_impl.into();
-        if let Ok(literal) = self.load_range(value.len()) {
-            self.assert_verification(literal == value,
-                                     format!("literal mismatch: {}", err))
+        self.enter(field);
+        let literal = self.load_range(value.len())?.to_vec();
+        self.assert_verification(
+            literal == value,
+            format!("literal mismatch: expected {:?}, got {:?}",
+                    String::from_utf8_lossy(&value),
+                    String::from_utf8_lossy(&literal)),
+        )?;
+        self.leave();
+        Ok(())
+    }
+
+    fn check_byte(&mut self, expected: u8, field: &'static str) -> Result<()> {
+        self.enter(field);
+        let bt = self.load::<u8>()?;
+        self.assert_verification(
+            bt == expected,
+            format!("{} mismatch: expected {:#04x}, got {:#04x}", field, expected, bt),
+        )?;
+        self.leave();
+        Ok(())
+    }
+
+    fn check_header(&mut self) -> Result<()> {
+        self.enter("header");
+        self.check_literal(SYX_HEADER, "signature")?;
+        self.enter("version");
+        let number = self.load::<u8>()?;
+        self.version = match number {
+            0x51 => Version::Lua51,
+            0x52 => Version::Lua52,
+            SYX_VERSION => Version::Lua53,
+            0x54 => Version::Lua54,
+            _ => {
+                return Err(ErrorKind::InvalidVerification(self.name.clone(), format!(
+                    "version mismatch: expected 0x51, 0x52, {:#04x} or 0x54, got {:#04x}",
+                    SYX_VERSION, number)).into());
+            }
+        };
+        self.leave();
+        self.check_byte(SYX_FORMAT, "format")?;
+        if let Version::Lua53 | Version::Lua54 = self.version {
+            self.check_header53()?;
         } else {
-            Ok(())
+            self.check_header_legacy()?;
         }
+        self.leave();
+        Ok(())
     }
 
-    fn check_header(&mut self) -> Result<()> {
-        self.check_literal(SYX_HEADER, "header")?;
-        let bt = self.load::<u8>()?;
-        self.assert_verification(bt == SYX_VERSION, "version mismatch")?;
-        let bt = self.load::<u8>()?;
-        self.assert_verification(bt == SYX_FORMAT, "format mismatch")?;
-        self.check_literal(SYX_DATA, "load order verification")?;
-        self.check_size(expand!(i32))?;
-        self.check_size(expand!(usize))?;
-        self.check_size(expand!(Word))?;
-        self.check_size(expand!(SyxInteger))?;
-        self.check_size(expand!(SyxNumber))?;
-        let int: SyxInteger = self.load::<SyxInteger>()?;
-        self.assert_verification(int == SYX_INT, "endianness mismatch")?;
-        let float: SyxNumber = self.load::<SyxNumber>()?;
-        self.assert_verification(float == SYX_NUM, "float format mismatch")?;
+    // 5.4 leaves out the sizes of int and size_t, they are varints there
+    fn check_header53(&mut self) -> Result<()> {
+        self.check_literal(SYX_DATA, "data")?;
+        // the chunk's own sizes are used for everything after this
+        if self.version == Version::Lua53 {
+            self.layout.int = self.load_size("sizeof(i32)", INT_SIZES)?;
+            self.layout.size_t = self.load_size("sizeof(usize)", INT_SIZES)?;
+        }
+        self.layout.instruction = self.load_size("sizeof(Word)", WORD_SIZES)?;
+        self.layout.integer = self.load_size("sizeof(SyxInteger)", INT_SIZES)?;
+        self.layout.number = self.load_size("sizeof(SyxNumber)", INT_SIZES)?;
+        // and the byte order is whichever one reads back SYX_INT
+        self.enter("int");
+        let size = self.layout.integer;
+        let (little, big) = {
+            let bytes = self.load_range(size)?;
+            (sign_extend(uint_from_bytes(bytes, Endian::Little), size),
+             sign_extend(uint_from_bytes(bytes, Endian::Big), size))
+        };
+        if little == SYX_INT {
+            self.layout.endian = Endian::Little;
+        } else if big == SYX_INT {
+            self.layout.endian = Endian::Big;
+        } else {
+            self.raise_from_verification(format!(
+                "endianness mismatch: expected {:#x}, got {:#x}", SYX_INT, little))?;
+        }
+        self.leave();
+        self.enter("float");
+        let float = self.load_number()?;
+        self.assert_verification(
+            float == SYX_NUM,
+            format!("float format mismatch: expected {}, got {}", SYX_NUM, float),
+        )?;
+        self.leave();
+        Ok(())
+    }
+
+    // 5.1 and 5.2 state their byte order instead of giving a number to check,
+    // and have no integers
+    fn check_header_legacy(&mut self) -> Result<()> {
+        self.enter("endianness");
+        self.layout.endian = match self.load::<u8>()? {
+            0 => Endian::Big,
+            1 => Endian::Little,
+            n => {
+                return Err(ErrorKind::InvalidVerification(self.name.clone(), format!(
+                    "endianness mismatch: expected 0x00 or 0x01, got {:#04x}", n)).into());
+            }
+        };
+        self.leave();
+        self.layout.int = self.load_size("sizeof(i32)", INT_SIZES)?;
+        self.layout.size_t = self.load_size("sizeof(usize)", INT_SIZES)?;
+        self.layout.instruction = self.load_size("sizeof(Word)", WORD_SIZES)?;
+        self.layout.number = self.load_size("sizeof(SyxNumber)", INT_SIZES)?;
+        // lua_Number built as an integer type
+        self.check_byte(0, "integral")?;
+        if self.version == Version::Lua52 {
+            self.check_literal(SYX_DATA, "tail")?;
+        }
         Ok(())
     }
 
@@ -305,8 +1080,528 @@ impl LoadState {
         // cl->p
         self.check_header()?;
         let mut proto = Proto::new();
-        let _upvals = self.load::<u8>()?;
+        self.enter("main");
+        if let Version::Lua53 | Version::Lua54 = self.version {
+            let _upvals = self.load::<u8>()?;
+        }
         self.load_function(&mut proto, vec![])?;
+        self.leave();
         Ok(proto)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::builder::ProtoBuilder;
+    use super::super::dump::DumpState;
+
+    // offset of the instruction count of a stripped main function: the
+    // header, the upvalue count, a NULL source, two line numbers and three
+    // bytes of parameters, vararg flag and stack size
+    const CODE_COUNT: usize = 33 + 1 + 1 + 8 + 3;
+
+    macro_rules! fixture {
+        ($name:expr) => {
+            ($name, &include_bytes!(concat!("../tests/fixtures/", $name, ".luac"))[..])
+        };
+    }
+
+    fn fixtures() -> Vec<(&'static str, &'static [u8])> {
+        vec![
+            fixture!("expressions"),
+            fixture!("closures"),
+            fixture!("constructors"),
+            fixture!("folding"),
+            fixture!("gotos"),
+            fixture!("scopes"),
+            fixture!("strings"),
+            fixture!("loops"),
+        ]
+    }
+
+    fn chunk() -> Vec<u8> {
+        let mut b = ProtoBuilder::new("=test");
+        b.line(1).load_k(0, SyxValue::String(b"hello".to_vec()))
+            .load_k(1, SyxValue::Integer(2))
+            .return_(0, 3);
+        DumpState::to_u8(&b.build().unwrap(), false).unwrap()
+    }
+
+    fn nested(depth: usize) -> Vec<u8> {
+        let mut b = ProtoBuilder::new("=test");
+        b.return_(0, 1);
+        for _ in 0..depth {
+            let mut parent = ProtoBuilder::new("=test");
+            parent.closure(0, b).return_(0, 1);
+            b = parent;
+        }
+        DumpState::to_u8(&b.build().unwrap(), true).unwrap()
+    }
+
+    // the location and then its cause
+    fn load_from(result: Result<Proto>) -> String {
+        let err = result.err().expect("chunk should not load");
+        err.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")
+    }
+
+    fn load(chunk: Vec<u8>, limits: LoadLimits) -> String {
+        load_from(LoadState::from_slice_with_limits(&chunk, "test", limits))
+    }
+
+    #[test]
+    fn test_limits() {
+        let defaults = LoadLimits::default();
+        LoadState::from_slice_with_limits(&chunk(), "test", defaults).unwrap();
+
+        let size = chunk().len();
+        assert_eq!(load(chunk(), LoadLimits { max_bytes: size - 1, ..defaults }),
+                   format!("could not load test at byte {} (chunk): \
+                            chunk of at least {} bytes is over the limit of {}",
+                           size - 1, size, size - 1));
+        let limits = LoadLimits { max_bytes: 10, ..defaults };
+        assert_eq!(LoadState::from_read_with_limits(&chunk()[..], "test", limits)
+                       .err().unwrap().iter().nth(1).unwrap().to_string(),
+                   "chunk of at least 12 bytes is over the limit of 10");
+        assert_eq!(load(chunk(), LoadLimits { max_instructions: 2, ..defaults }),
+                   "could not load test at byte 51 (main.instructions): \
+                    3 instructions are over the limit of 2");
+        assert_eq!(load(chunk(), LoadLimits { max_constants: 1, ..defaults }),
+                   "could not load test at byte 67 (main.constants): \
+                    2 constants are over the limit of 1");
+        assert_eq!(load(chunk(), LoadLimits { max_string_length: 4, ..defaults }),
+                   "could not load test at byte 34 (main.source): \
+                    string of 5 bytes is over the limit of 4");
+        assert_eq!(load(chunk(), LoadLimits { max_lineinfo: 0, ..defaults }),
+                   "could not load test at byte 95 (main.lineinfo): \
+                    3 lines are over the limit of 0");
+    }
+
+    #[test]
+    fn test_nesting() {
+        let defaults = LoadLimits::default();
+        LoadState::from_slice_with_limits(&nested(3), "test",
+                                       LoadLimits { max_depth: 3, ..defaults }).unwrap();
+        assert_eq!(load(nested(4), LoadLimits { max_depth: 3, ..defaults }),
+                   "could not load test at byte 174 \
+                    (main.protos[0].protos[0].protos[0].protos[0]): \
+                    functions nested deeper than the limit of 3");
+        assert!(load(nested(300), defaults)
+                .ends_with("functions nested deeper than the limit of 200"));
+    }
+
+    #[test]
+    fn test_counts() {
+        let patch = |count: i32| {
+            let mut chunk = nested(0);
+            chunk[CODE_COUNT..CODE_COUNT + 4].copy_from_slice(&count.to_ne_bytes());
+            chunk
+        };
+        LoadState::from_u8(patch(1), "test").unwrap();
+        assert_eq!(load(patch(-1), LoadLimits::default()),
+                   "could not load test at byte 46 (main.instructions): \
+                    negative number of instructions: -1");
+        // would reserve gigabytes if the count was trusted
+        assert_eq!(load(patch(0x7FFF_FFFF), LoadLimits {
+                       max_instructions: usize::MAX, ..LoadLimits::default() }),
+                   "could not load test at byte 46 (main.instructions): \
+                    error verifying test: Not enough bytes for 2147483647 instructions");
+    }
+
+    #[test]
+    fn test_locations() {
+        let defaults = LoadLimits::default();
+        let patch = |offset: usize, bytes: &[u8]| {
+            let mut chunk = chunk();
+            chunk[offset..offset + bytes.len()].copy_from_slice(bytes);
+            chunk
+        };
+        assert_eq!(load(patch(1, b"Lub"), defaults),
+                   "could not load test at byte 0 (header.signature): \
+                    error verifying test: literal mismatch: expected \"\\u{1b}Lua\", \
+                    got \"\\u{1b}Lub\"");
+        assert_eq!(load(patch(4, &[0x55]), defaults),
+                   "could not load test at byte 4 (header.version): \
+                    error verifying test: version mismatch: \
+                    expected 0x51, 0x52, 0x53 or 0x54, got 0x55");
+        assert_eq!(load(patch(13, &[3]), defaults),
+                   "could not load test at byte 13 (header.sizeof(usize)): \
+                    error verifying test: size mismatch: expected 4 or 8, got 3");
+        assert_eq!(load(patch(14, &[8]), defaults),
+                   "could not load test at byte 14 (header.sizeof(Word)): \
+                    error verifying test: size mismatch: expected 4, got 8");
+        assert_eq!(load(patch(17, &0x1234i64.to_le_bytes()), defaults),
+                   "could not load test at byte 17 (header.int): \
+                    error verifying test: endianness mismatch: \
+                    expected 0x5678, got 0x1234");
+        // taken for a big endian chunk, so the float is read the wrong way
+        let swapped = SYX_INT.swap_bytes().to_ne_bytes();
+        assert!(load(patch(17, &swapped), defaults)
+                .starts_with("could not load test at byte 25 (header.float): \
+                              error verifying test: float format mismatch"));
+
+        // the count at 67 and "hello" as a tag, a size and 5 bytes put the
+        // second constant's tag at 78
+        assert_eq!(load(patch(78, &[9]), defaults),
+                   "could not load test at byte 78 (main.constants[1]): \
+                    invalid type parameter loaded: 9");
+
+        let mut trailing = chunk();
+        trailing.push(0);
+        assert_eq!(load(trailing, defaults),
+                   format!("could not load test at byte {} (end): \
+                            bytes left over from buffer", chunk().len()));
+    }
+
+    // legacy.lua compiled by luac 5.1.5, 5.2.4, 5.3.6 and 5.4
+    const LEGACY: [&[u8]; 4] = [
+        include_bytes!("../tests/fixtures/legacy.51.luac"),
+        include_bytes!("../tests/fixtures/legacy.52.luac"),
+        include_bytes!("../tests/fixtures/legacy.53.luac"),
+        include_bytes!("../tests/fixtures/legacy.54.luac"),
+    ];
+
+    fn functions(proto: &Proto) -> Vec<&Proto> {
+        let mut all = vec![proto];
+        for child in &proto.protos {
+            all.extend(functions(child));
+        }
+        all
+    }
+
+    fn upvalues(proto: &Proto) -> Vec<(String, u8, u8)> {
+        proto.upvalues.iter()
+            .map(|u| (String::from_utf8_lossy(&u.name).into_owned(), u.instack, u.idx))
+            .collect()
+    }
+
+    #[test]
+    fn test_versions() {
+        use super::super::verify::verify;
+        let versions = [Version::Lua51, Version::Lua52, Version::Lua53, Version::Lua54];
+        for (chunk, version) in LEGACY.iter().zip(versions.iter()) {
+            for proto in [LoadState::from_slice(chunk, "legacy").unwrap(),
+                          LoadState::from_read(*chunk, "legacy").unwrap()] {
+                verify(&proto).unwrap_or_else(|e| panic!("{}: {}", version, e));
+                assert!(functions(&proto).iter().all(|f| f.version == *version));
+                assert_eq!(proto.source, "@legacy.lua");
+            }
+        }
+
+        let proto = LoadState::from_slice(LEGACY[0], "legacy").unwrap();
+        assert_eq!(DumpState::to_u8(&proto, false).unwrap_err().to_string(),
+                   "can not dump Lua 5.1 bytecode");
+        let mut chunk = LEGACY[1].to_vec();
+        chunk[4] = 0x50;
+        assert_eq!(load_from(LoadState::from_slice(&chunk, "legacy")),
+                   "could not load legacy at byte 4 (header.version): \
+                    error verifying legacy: version mismatch: \
+                    expected 0x51, 0x52, 0x53 or 0x54, got 0x50");
+    }
+
+    // 5.2 only numbers its opcodes differently and has no integer constants
+    #[test]
+    fn test_lua52() {
+        let lua52 = LoadState::from_slice(LEGACY[1], "legacy").unwrap();
+        let lua53 = LoadState::from_slice(LEGACY[2], "legacy").unwrap();
+        let (lua52, lua53) = (functions(&lua52), functions(&lua53));
+        assert_eq!(lua52.len(), lua53.len());
+        for (old, new) in lua52.iter().zip(lua53.iter()) {
+            assert_eq!(old.instructions, new.instructions);
+            assert_eq!(upvalues(old), upvalues(new));
+            assert_eq!(old.lineinfo, new.lineinfo);
+            for (k, l) in old.constants.iter().zip(new.constants.iter()) {
+                match (k, l) {
+                    (SyxValue::Number(n), SyxValue::Integer(i)) => assert_eq!(*n, *i as f64),
+                    (SyxValue::Number(n), SyxValue::Number(m)) => assert_eq!(n, m),
+                    (SyxValue::String(s), SyxValue::String(t)) => assert_eq!(s, t),
+                    _ => panic!("constants do not match"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lua51() {
+        let lua51 = LoadState::from_slice(LEGACY[0], "legacy").unwrap();
+        let lua52 = LoadState::from_slice(LEGACY[1], "legacy").unwrap();
+        // upvalues come from the instructions after each closure, and only
+        // _ENV is new in 5.2
+        for (old, new) in functions(&lua51).iter().zip(functions(&lua52).iter()) {
+            let mut expected = upvalues(new);
+            expected.retain(|u| u.0 != "_ENV");
+            assert_eq!(upvalues(old), expected);
+        }
+        assert_eq!(upvalues(&lua51.protos[0].protos[0]), vec![
+            ("n".to_owned(), 1, 1),
+            ("step".to_owned(), 1, 0),
+        ]);
+
+        // local a, b, c, d
+        let code = &lua51.instructions;
+        assert_eq!(code[24], Instruction::ABC { instruction: OpCode::LoadNil, a: 2, b: 3, c: 0 });
+        assert_eq!(code[1], Instruction::ABx { instruction: OpCode::SetGlobal, a: 0, bx: 0 });
+        assert_eq!(code[40], Instruction::ABC {
+            instruction: OpCode::TForLoop51, a: 7, b: 0, c: 2,
+        });
+
+        // a SetList with a C of 0 takes the next word as it is
+        let setlist: Word = 34 | 6 << 6 | 1 << 14 | 3 << 23;
+        let mut chunk = LEGACY[0].to_vec();
+        let at = chunk.windows(4).position(|w| w == setlist.to_le_bytes()).unwrap();
+        chunk[at..at + 4].copy_from_slice(&(setlist & !(0x1FF << 14)).to_le_bytes());
+        chunk[at + 4..at + 8].copy_from_slice(&63u32.to_le_bytes());
+        let proto = LoadState::from_slice(&chunk, "legacy").unwrap();
+        assert_eq!(proto.instructions[31], Instruction::ABC {
+            instruction: OpCode::SetList, a: 6, b: 3, c: 0,
+        });
+        assert_eq!(proto.instructions[32], Instruction::Ax {
+            instruction: OpCode::ExtraArg, ax: 63,
+        });
+    }
+
+    #[test]
+    fn test_lua54() {
+        use super::super::opcodes54;
+
+        let proto = LoadState::from_slice(include_bytes!("../tests/fixtures/lua54.luac"),
+                                          "lua54").unwrap();
+        assert!(proto.instructions.is_empty());
+        let code = &proto.instructions54;
+        assert_eq!(code.len(), 92);
+        assert_eq!(code[20], opcodes54::Instruction::ABC {
+            instruction: opcodes54::OpCode::AddI, a: 1, b: 6, c: 126, k: false,
+        });
+        assert_eq!(code[69], opcodes54::Instruction::SJ {
+            instruction: opcodes54::OpCode::Jmp, sj: -8,
+        });
+        // the line after the gap only fits as an absolute one
+        assert_eq!(proto.lineinfo.len(), 92);
+        assert_eq!(&proto.lineinfo[68..72], &[24, 24, 326, 326]);
+        match (&proto.constants[12], &proto.constants[14]) {
+            (SyxValue::Number(n), SyxValue::Integer(i)) => assert_eq!((*n, *i), (2.5, 7)),
+            _ => panic!("constants do not match"),
+        }
+        assert_eq!(upvalues(&proto.protos[0]), vec![("sum".to_owned(), 1, 1)]);
+
+        // the same upvalues as 5.3 makes
+        let lua53 = LoadState::from_slice(LEGACY[2], "legacy").unwrap();
+        let lua54 = LoadState::from_slice(LEGACY[3], "legacy").unwrap();
+        for (old, new) in functions(&lua53).iter().zip(functions(&lua54).iter()) {
+            assert_eq!(upvalues(old), upvalues(new));
+            assert_eq!((old.linedefined, old.lastlinedefined),
+                       (new.linedefined, new.lastlinedefined));
+        }
+
+        let mut chunk = include_bytes!("../tests/fixtures/lua54.stripped.luac").to_vec();
+        let stripped = LoadState::from_slice(&chunk, "lua54").unwrap();
+        assert_eq!(&stripped.instructions54, code);
+        assert!(stripped.lineinfo.is_empty());
+        // the instruction count, after the header, the upvalue count, a NULL
+        // source, two line numbers and three bytes
+        let at = 31 + 1 + 1 + 2 + 3;
+        chunk[at..at + 5].copy_from_slice(&[0x7F; 5]);
+        assert_eq!(load_from(LoadState::from_slice(&chunk, "lua54")),
+                   "could not load lua54 at byte 41 (main.instructions): \
+                    error verifying lua54: varint over 2147483647");
+    }
+
+    #[test]
+    fn test_stream() {
+        for (name, luac) in fixtures() {
+            let proto = LoadState::from_read(luac, name).unwrap();
+            assert!(DumpState::to_u8(&proto, false).unwrap() == luac, "{}", name);
+        }
+
+        // a truncated stream fails at the field that was cut off
+        let chunk = chunk();
+        for n in 0..chunk.len() {
+            let err = LoadState::from_read(&chunk[..n], "test").err().unwrap();
+            match *err.kind() {
+                ErrorKind::LoadError(_, offset, _) => assert!(offset <= n, "{}", err),
+                _ => panic!("{}", err),
+            }
+            assert!(load_from(Err(err)).contains("Not enough bytes"));
+        }
+        assert_eq!(load_from(LoadState::from_read(&chunk[..57], "test")),
+                   "could not load test at byte 55 (main.instructions[0]): \
+                    error verifying test: Not enough bytes: 4");
+
+        // only a byte past the end is read, or this would never finish
+        let endless = ::std::io::Read::chain(&chunk[..], ::std::io::repeat(0));
+        assert_eq!(load_from(LoadState::from_read(endless, "test")),
+                   format!("could not load test at byte {} (end): \
+                            bytes left over from buffer", chunk.len()));
+
+        // a count no stream could back is not reserved up front
+        let mut huge = nested(0);
+        huge[CODE_COUNT..CODE_COUNT + 4].copy_from_slice(&0x7FFF_FFFFi32.to_ne_bytes());
+        let limits = LoadLimits { max_instructions: usize::MAX, ..LoadLimits::default() };
+        let err = load_from(LoadState::from_read_with_limits(&huge[..], "test", limits));
+        assert!(err.ends_with("Not enough bytes: 4"), "{}", err);
+    }
+
+    #[test]
+    fn test_stream_error() {
+        struct Failing(usize);
+        impl ::std::io::Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(::std::io::Error::other("gone"));
+                }
+                self.0 -= 1;
+                buf[0] = 0x1b;
+                Ok(1)
+            }
+        }
+        let limits = LoadLimits::default();
+        assert_eq!(load_from(LoadState::from_buf_read(
+                       ::std::io::BufReader::new(Failing(2)), "test", limits)),
+                   "could not load test at byte 0 (header.signature): \
+                    no values read from buffer: test: gone");
+    }
+
+    #[test]
+    fn test_primitives() {
+        let bytes = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(u32::from_bytes(&bytes, Endian::Little), 0x7856_3412);
+        assert_eq!(u32::from_bytes(&bytes, Endian::Big), 0x1234_5678);
+        let float = 370.5f64.to_be_bytes();
+        assert_eq!(f64::from_bytes(&float, Endian::Big), 370.5);
+        let mut output = Vec::new();
+        0x5678i64.write(Endian::Big, &mut output);
+        assert_eq!(i64::from_bytes(&output, Endian::Big), 0x5678);
+
+        assert_eq!(uint_from_bytes(&bytes[..3], Endian::Little), 0x56_3412);
+        assert_eq!(uint_from_bytes(&bytes[..3], Endian::Big), 0x12_3456);
+        assert_eq!(sign_extend(0xFFFF_FFFE, 4), -2);
+        assert_eq!(sign_extend(0xFFFF_FFFE, 8), 0xFFFF_FFFE);
+        let mut output = Vec::new();
+        uint_to_bytes(-2i64 as u64, 4, Endian::Big, &mut output);
+        assert_eq!(output, [0xFF, 0xFF, 0xFF, 0xFE]);
+    }
+
+    fn layout(endian: Endian, int: usize, size_t: usize, integer: usize, number: usize)
+        -> Layout
+    {
+        Layout { endian, int, size_t, instruction: 4, integer, number }
+    }
+
+    #[test]
+    fn test_layouts() {
+        // chunks from big endian and 32 bit machines
+        let layouts = [
+            layout(Endian::Big, 4, 8, 8, 8),
+            layout(Endian::Little, 4, 4, 8, 8),
+            layout(Endian::Big, 8, 4, 8, 8),
+        ];
+        for layout in layouts.iter() {
+            for (name, luac) in fixtures() {
+                let proto = LoadState::from_u8(luac.to_vec(), name).unwrap();
+                let foreign = DumpState::to_u8_with_layout(&proto, false, *layout).unwrap();
+                for proto in [LoadState::from_slice(&foreign, name).unwrap(),
+                              LoadState::from_read(&foreign[..], name).unwrap()] {
+                    assert!(DumpState::to_u8(&proto, false).unwrap() == luac,
+                            "{} {:?}", name, layout);
+                }
+            }
+        }
+
+        let big = DumpState::to_u8_with_layout(
+            &LoadState::from_u8(chunk(), "test").unwrap(), false, layouts[0]).unwrap();
+        assert_eq!(big[17..25], 0x5678i64.to_be_bytes());
+        assert_eq!(big[25..33], 370.5f64.to_be_bytes());
+
+        // LUA_32BITS, 4 byte integers and floats
+        let small = layout(Endian::Little, 4, 4, 4, 4);
+        let proto = LoadState::from_u8(chunk(), "test").unwrap();
+        let chunk32 = DumpState::to_u8_with_layout(&proto, false, small).unwrap();
+        assert_eq!(chunk32[12..17], [4, 4, 4, 4, 4]);
+        let proto = LoadState::from_u8(chunk32, "test").unwrap();
+        assert!(DumpState::to_u8(&proto, false).unwrap() == chunk());
+
+        let mut b = ProtoBuilder::new("=test");
+        b.load_k(0, SyxValue::Integer(-5)).load_k(1, SyxValue::Number(0.25)).return_(0, 1);
+        let chunk32 = DumpState::to_u8_with_layout(&b.build().unwrap(), true, small).unwrap();
+        let proto = LoadState::from_u8(chunk32, "test").unwrap();
+        match proto.constants[..] {
+            [SyxValue::Integer(-5), SyxValue::Number(n)] => assert_eq!(n, 0.25),
+            _ => panic!("constants did not survive 4 byte numbers"),
+        }
+    }
+
+    #[test]
+    fn test_wide_int() {
+        // an 8 byte int only loads if its value fits in ours
+        let wide = layout(Endian::Little, 8, 8, 8, 8);
+        let proto = LoadState::from_u8(nested(0), "test").unwrap();
+        let mut chunk = DumpState::to_u8_with_layout(&proto, true, wide).unwrap();
+        LoadState::from_slice(&chunk, "test").unwrap();
+        // linedefined follows the header, the upvalue count and a NULL source
+        chunk[35..43].copy_from_slice(&(1i64 << 40).to_le_bytes());
+        assert_eq!(load(chunk, LoadLimits::default()),
+                   "could not load test at byte 35 (main.linedefined): \
+                    error verifying test: int out of range: 1099511627776");
+    }
+
+    // The loader used to pull bytes through a boxed iterator, collecting a
+    // Vec for every read; this source does the same to compare against.
+    struct IterSource {
+        input: Box<dyn Iterator<Item = u8>>,
+        position: usize,
+        last: Vec<u8>,
+    }
+
+    impl Source for IterSource {
+        fn read(&mut self, n: usize) -> ::std::io::Result<&[u8]> {
+            self.last = self.input.by_ref().take(n).collect();
+            self.position += self.last.len();
+            if self.last.len() == n { Ok(&self.last) } else { Err(end_of_input()) }
+        }
+
+        fn position(&self) -> usize {
+            self.position
+        }
+
+        fn remaining(&self) -> Option<usize> {
+            None
+        }
+    }
+
+    // cargo test --release bench_load -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_load() {
+        use std::time::Instant;
+        use super::super::code;
+
+        let mut source = String::new();
+        for i in 0..2000 {
+            source += &format!("function f{0}(a, b)\n  local s = 'string {0}' .. a\n  \
+                                return s, {0} + 0.5, b * {0}, {{a, b, x = {0}}}\nend\n", i);
+        }
+        let proto = code::compile(source.as_bytes(), "=bench").unwrap();
+        let chunk = DumpState::to_u8(&proto, false).unwrap();
+        let rounds = 20;
+
+        let time = |load: &dyn Fn() -> Result<Proto>| {
+            let start = Instant::now();
+            for _ in 0..rounds {
+                load().unwrap();
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            (chunk.len() * rounds) as f64 / elapsed / (1024.0 * 1024.0)
+        };
+        let slice = time(&|| LoadState::from_slice(&chunk, "bench"));
+        let iter = time(&|| {
+            let input = IterSource {
+                input: Box::new(chunk.clone().into_iter()),
+                position: 0,
+                last: Vec::new(),
+            };
+            LoadState::from_source(input, "bench", LoadLimits::default())
+        });
+        println!("{} byte chunk: slice {:.1} MiB/s, iterator {:.1} MiB/s ({:.1}x)",
+                 chunk.len(), slice, iter, slice / iter);
+    }
+}
@@ -0,0 +1,403 @@
+// Static checks for loaded chunks, so that code from an untrusted chunk can
+// not reach outside of its registers, constants, upvalues or code
+#![allow(dead_code)]
+
+use std::slice;
+
+use super::builder::stack_top;
+use super::code::get_opcode;
+use super::object::Proto;
+use super::opcodes::*;
+use super::opcodes54;
+use super::errors::*;
+
+pub fn verify(proto: &Proto) -> Result<()> {
+    verify_function(proto, None, "main")
+}
+
+fn fail<T>(location: String, message: String) -> Result<T> {
+    Err(ErrorKind::VerifyError(location, message).into())
+}
+
+// Checks that do not depend on the instruction set
+fn verify_header(proto: &Proto, parent: Option<&Proto>, path: &str, code_len: usize)
+    -> Result<()>
+{
+    let maxstack = proto.maxstacksize as u32;
+    if proto.numparams as u32 > maxstack {
+        return fail(path.to_owned(), format!(
+            "{} parameters do not fit in {} registers", proto.numparams, maxstack));
+    }
+    // stripped chunks have no line info at all
+    if !proto.lineinfo.is_empty() && proto.lineinfo.len() != code_len {
+        return fail(path.to_owned(), format!(
+            "{} lines for {} instructions", proto.lineinfo.len(), code_len));
+    }
+
+    // a closure's upvalues are taken from the registers or upvalues of the
+    // function creating it
+    if let Some(parent) = parent {
+        for (n, upvalue) in proto.upvalues.iter().enumerate() {
+            let (what, size) = if upvalue.instack != 0 {
+                ("register", parent.maxstacksize as usize)
+            } else {
+                ("upvalue", parent.upvalues.len())
+            };
+            if upvalue.idx as usize >= size {
+                return fail(format!("{} upvalue {}", path, n), format!(
+                    "enclosing {} {} out of range", what, upvalue.idx));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify_function(proto: &Proto, parent: Option<&Proto>, path: &str) -> Result<()> {
+    if proto.version == Version::Lua54 {
+        return verify_function54(proto, parent, path);
+    }
+    let code = &proto.instructions;
+    let maxstack = proto.maxstacksize as u32;
+
+    verify_header(proto, parent, path, code.len())?;
+    match code.last().map(get_opcode) {
+        Some(OpCode::Return) => {}
+        _ => return fail(path.to_owned(), "code does not end with a return".to_owned()),
+    }
+
+    for (pc, instr) in code.iter().enumerate() {
+        let location = || format!("{} pc {}", path, pc + 1);
+        let op = get_opcode(instr);
+        if !proto.version.opcodes().contains(&op) {
+            return fail(location(), format!("{:?} is not an opcode of {}", op, proto.version));
+        }
+
+        for arg in instr.operands() {
+            let (what, n, size) = match arg {
+                Argument::Register(n) => ("register", n, maxstack),
+                Argument::Constant(n) => ("constant", n, proto.constants.len() as u32),
+                Argument::UpValue(n) => ("upvalue", n, proto.upvalues.len() as u32),
+                _ => continue,
+            };
+            if n >= size {
+                return fail(location(), format!("{} {} out of range", what, n));
+            }
+        }
+        // ranges like a call's arguments or a loop's control registers
+        if stack_top(slice::from_ref(instr)) > maxstack as i64 {
+            return fail(location(), format!(
+                "{:?} uses registers past the stack size {}", op, maxstack));
+        }
+
+        let next = code.get(pc + 1).map(get_opcode);
+        match *instr {
+            Instruction::AsBx { sbx, .. } => {
+                let target = pc as i64 + 1 + sbx as i64;
+                if target < 0 || target >= code.len() as i64 {
+                    return fail(location(), format!("jump to {} outside of the code", target + 1));
+                }
+                if get_opcode(&code[target as usize]) == OpCode::ExtraArg {
+                    return fail(location(), "jump into an extra argument".to_owned());
+                }
+            }
+            Instruction::ABx { instruction: OpCode::Closure, bx, .. }
+                if bx as usize >= proto.protos.len() => {
+                return fail(location(), format!("function {} out of range", bx));
+            }
+            Instruction::ABC { instruction: OpCode::LoadKX, .. } => match code.get(pc + 1) {
+                Some(&Instruction::Ax { instruction: OpCode::ExtraArg, ax }) => {
+                    if ax as usize >= proto.constants.len() {
+                        return fail(location(), format!("constant {} out of range", ax));
+                    }
+                }
+                _ => return fail(location(), "LoadKX without an ExtraArg".to_owned()),
+            },
+            Instruction::ABC { instruction: OpCode::SetList, c: 0, .. }
+                if next != Some(OpCode::ExtraArg) => {
+                return fail(location(), "SetList without an ExtraArg".to_owned());
+            }
+            Instruction::ABC { instruction: OpCode::TForCall, .. }
+                if next != Some(OpCode::TForLoop) => {
+                return fail(location(), "TForCall not followed by TForLoop".to_owned());
+            }
+            _ => {}
+        }
+        if op.mode().test && next != Some(OpCode::Jmp) {
+            return fail(location(), format!("{:?} not followed by Jmp", op));
+        }
+    }
+
+    for (n, child) in proto.protos.iter().enumerate() {
+        verify_function(child, Some(proto), &format!("{}.protos[{}]", path, n))?;
+    }
+    Ok(())
+}
+
+pub fn opcode54(instr: &opcodes54::Instruction) -> opcodes54::OpCode {
+    use self::opcodes54::Instruction::*;
+    match *instr {
+        ABC { instruction, .. } | ABx { instruction, .. } | AsBx { instruction, .. }
+        | Ax { instruction, .. } | SJ { instruction, .. } => instruction,
+    }
+}
+
+// 5.4 gets the checks that keep its operands and jumps in range, the ranges
+// of registers an instruction uses are not checked
+fn verify_function54(proto: &Proto, parent: Option<&Proto>, path: &str) -> Result<()> {
+    use self::opcodes54::{Argument, Instruction, OpCode};
+
+    let code = &proto.instructions54;
+    let maxstack = proto.maxstacksize as u32;
+
+    verify_header(proto, parent, path, code.len())?;
+    // a regular, constant, to-be-closed or compile-time constant variable
+    for (n, upvalue) in proto.upvalues.iter().enumerate() {
+        if upvalue.kind > 3 {
+            return fail(format!("{} upvalue {}", path, n), format!(
+                "unknown kind {}", upvalue.kind));
+        }
+    }
+    match code.last().map(opcode54) {
+        Some(OpCode::Return) | Some(OpCode::Return0) | Some(OpCode::Return1) => {}
+        _ => return fail(path.to_owned(), "code does not end with a return".to_owned()),
+    }
+
+    for (pc, instr) in code.iter().enumerate() {
+        let location = || format!("{} pc {}", path, pc + 1);
+        let op = opcode54(instr);
+
+        // returning nothing can name the register past the last one
+        let operands = match *instr {
+            Instruction::ABC { instruction: OpCode::Return, b: 1, .. } => Vec::new(),
+            _ => instr.operands(),
+        };
+        for arg in operands {
+            let (what, n, size) = match arg {
+                Argument::Register(n) => ("register", n, maxstack),
+                Argument::Constant(n) => ("constant", n, proto.constants.len() as u32),
+                Argument::UpValue(n) => ("upvalue", n, proto.upvalues.len() as u32),
+                _ => continue,
+            };
+            if n >= size {
+                return fail(location(), format!("{} {} out of range", what, n));
+            }
+        }
+
+        let next = code.get(pc + 1).map(opcode54);
+        // where the instruction can jump to, relative to the next one
+        let offset = match *instr {
+            Instruction::SJ { sj, .. } => Some(i64::from(sj)),
+            Instruction::ABx { instruction: OpCode::ForPrep, bx, .. } => Some(i64::from(bx) + 1),
+            Instruction::ABx { instruction: OpCode::TForPrep, bx, .. } => Some(i64::from(bx)),
+            Instruction::ABx { instruction: OpCode::ForLoop, bx, .. }
+            | Instruction::ABx { instruction: OpCode::TForLoop, bx, .. } => Some(-i64::from(bx)),
+            _ => None,
+        };
+        if let Some(offset) = offset {
+            let target = pc as i64 + 1 + offset;
+            if target < 0 || target >= code.len() as i64 {
+                return fail(location(), format!("jump to {} outside of the code", target + 1));
+            }
+            if opcode54(&code[target as usize]) == OpCode::ExtraArg {
+                return fail(location(), "jump into an extra argument".to_owned());
+            }
+        }
+        match *instr {
+            Instruction::ABx { instruction: OpCode::Closure, bx, .. }
+                if bx as usize >= proto.protos.len() => {
+                return fail(location(), format!("function {} out of range", bx));
+            }
+            Instruction::ABx { instruction: OpCode::LoadKX, .. } => match code.get(pc + 1) {
+                Some(&Instruction::Ax { instruction: OpCode::ExtraArg, ax }) => {
+                    if ax as usize >= proto.constants.len() {
+                        return fail(location(), format!("constant {} out of range", ax));
+                    }
+                }
+                _ => return fail(location(), "LoadKX without an ExtraArg".to_owned()),
+            },
+            Instruction::ABC { instruction: OpCode::TForCall, .. }
+                if next != Some(OpCode::TForLoop) => {
+                return fail(location(), "TForCall not followed by TForLoop".to_owned());
+            }
+            _ => {}
+        }
+        if op.mode().test && next != Some(OpCode::Jmp) {
+            return fail(location(), format!("{:?} not followed by Jmp", op));
+        }
+    }
+
+    for (n, child) in proto.protos.iter().enumerate() {
+        verify_function(child, Some(proto), &format!("{}.protos[{}]", path, n))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::builder::ProtoBuilder;
+    use super::super::dump::DumpState;
+    use super::super::object::SyxValue;
+    use super::super::undump::LoadState;
+
+    macro_rules! fixture {
+        ($name:expr) => {
+            (
+                $name,
+                &include_bytes!(concat!("../tests/fixtures/", $name, ".luac"))[..],
+                &include_bytes!(concat!("../tests/fixtures/", $name,
+                                        ".stripped.luac"))[..],
+            )
+        };
+    }
+
+    #[test]
+    fn test_fixtures() {
+        let fixtures = [
+            fixture!("expressions"),
+            fixture!("closures"),
+            fixture!("constructors"),
+            fixture!("folding"),
+            fixture!("gotos"),
+            fixture!("scopes"),
+            fixture!("strings"),
+            fixture!("loops"),
+        ];
+        for &(name, luac, stripped) in fixtures.iter() {
+            for chunk in &[luac, stripped] {
+                let proto = LoadState::from_u8(chunk.to_vec(), name).unwrap();
+                verify(&proto).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            }
+        }
+    }
+
+    fn error(proto: &Proto) -> String {
+        verify(proto).expect_err("chunk should not verify").to_string()
+    }
+
+    fn simple() -> Proto {
+        let mut b = ProtoBuilder::new("=test");
+        b.line(1).upvalue(b"_ENV", true, 0);
+        b.get_tab_up(0, 0, SyxValue::String(b"x".to_vec())).return_(0, 2);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_indices() {
+        verify(&simple()).unwrap();
+
+        let mut proto = simple();
+        proto.maxstacksize = 0;
+        assert_eq!(error(&proto), "invalid bytecode at main pc 1: register 0 out of range");
+
+        let mut proto = simple();
+        proto.constants.clear();
+        assert_eq!(error(&proto), "invalid bytecode at main pc 1: constant 0 out of range");
+
+        let mut proto = simple();
+        proto.upvalues.clear();
+        assert_eq!(error(&proto), "invalid bytecode at main pc 1: upvalue 0 out of range");
+
+        let mut b = ProtoBuilder::new("=test");
+        b.call(0, 3, 2).return_(0, 1);
+        let mut proto = b.build().unwrap();
+        proto.maxstacksize = 2;
+        assert_eq!(error(&proto), "invalid bytecode at main pc 1: \
+                                   Call uses registers past the stack size 2");
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let mut proto = simple();
+        proto.instructions.pop();
+        proto.lineinfo.pop();
+        assert_eq!(error(&proto), "invalid bytecode at main: code does not end with a return");
+
+        let mut proto = simple();
+        proto.lineinfo.push(1);
+        assert_eq!(error(&proto), "invalid bytecode at main: 3 lines for 2 instructions");
+
+        let mut b = ProtoBuilder::new("=test");
+        let label = b.new_label();
+        b.mark(label).jmp(0, label).return_(0, 1);
+        let mut proto = b.build().unwrap();
+        proto.instructions[0] = Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: 5 };
+        assert_eq!(error(&proto), "invalid bytecode at main pc 1: jump to 7 outside of the code");
+
+        let mut b = ProtoBuilder::new("=test");
+        b.test(0, true).return_(0, 1);
+        assert_eq!(error(&b.build().unwrap()),
+                   "invalid bytecode at main pc 1: Test not followed by Jmp");
+
+        let mut proto = simple();
+        proto.instructions[0] = Instruction::ABx { instruction: OpCode::GetGlobal, a: 0, bx: 0 };
+        assert_eq!(error(&proto),
+                   "invalid bytecode at main pc 1: GetGlobal is not an opcode of Lua 5.3");
+
+        // LoadKX the way the loader reads it back
+        let reload = |proto: &Proto| {
+            let chunk = DumpState::to_u8(proto, true).unwrap();
+            LoadState::from_u8(chunk, "=test".to_owned()).unwrap()
+        };
+        let mut b = ProtoBuilder::new("=test");
+        b.load_k(0, SyxValue::Integer(1)).return_(0, 1);
+        let mut proto = b.build().unwrap();
+        proto.instructions[0] = Instruction::ABC { instruction: OpCode::LoadKX, a: 0, b: 0, c: 0 };
+        assert_eq!(error(&reload(&proto)),
+                   "invalid bytecode at main pc 1: LoadKX without an ExtraArg");
+        proto.instructions.insert(1, Instruction::Ax { instruction: OpCode::ExtraArg, ax: 1 });
+        assert_eq!(error(&reload(&proto)),
+                   "invalid bytecode at main pc 1: constant 1 out of range");
+    }
+
+    #[test]
+    fn test_lua54() {
+        use super::opcodes54::{Instruction, OpCode};
+
+        for chunk in &[&include_bytes!("../tests/fixtures/lua54.luac")[..],
+                       &include_bytes!("../tests/fixtures/lua54.stripped.luac")[..]] {
+            verify(&LoadState::from_slice(chunk, "lua54").unwrap()).unwrap();
+        }
+        let load = || LoadState::from_slice(include_bytes!("../tests/fixtures/lua54.luac"),
+                                            "lua54").unwrap();
+
+        let mut proto = load();
+        proto.instructions54[69] = Instruction::SJ { instruction: OpCode::Jmp, sj: -80 };
+        assert_eq!(error(&proto), "invalid bytecode at main pc 70: jump to -9 outside of the code");
+
+        // the ForLoop of `for i = 1, limit do` jumps back past its ForPrep
+        let mut proto = load();
+        proto.instructions54[28] = Instruction::ABx { instruction: OpCode::ForLoop, a: 2, bx: 30 };
+        assert_eq!(error(&proto), "invalid bytecode at main pc 29: jump to 0 outside of the code");
+
+        let mut proto = load();
+        proto.instructions54[33] = Instruction::ABC {
+            instruction: OpCode::GetField, a: 8, b: 0, c: 15, k: false,
+        };
+        assert_eq!(error(&proto), "invalid bytecode at main pc 34: constant 15 out of range");
+
+        let mut proto = load();
+        proto.protos[0].instructions54.pop();
+        proto.protos[0].lineinfo.pop();
+        assert_eq!(error(&proto),
+                   "invalid bytecode at main.protos[0]: code does not end with a return");
+    }
+
+    #[test]
+    fn test_nested() {
+        let mut main = ProtoBuilder::new("=test");
+        let mut child = main.function();
+        child.upvalue(b"x", true, 0);
+        child.get_upval(0, 0).return_(0, 2);
+        main.closure(0, child).return_(0, 1);
+        let mut proto = main.build().unwrap();
+        verify(&proto).unwrap();
+
+        proto.protos[0].upvalues[0].idx = 7;
+        assert_eq!(error(&proto), "invalid bytecode at main.protos[0] upvalue 0: \
+                                   enclosing register 7 out of range");
+
+        proto.instructions[0] = Instruction::ABx { instruction: OpCode::Closure, a: 0, bx: 1 };
+        assert_eq!(error(&proto), "invalid bytecode at main pc 1: function 1 out of range");
+    }
+}
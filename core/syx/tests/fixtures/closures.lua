@@ -0,0 +1,43 @@
+-- upvalues and closures
+local x = 0
+local function counter()
+  local n = 0
+  return function()
+    n = n + 1
+    x = x + n
+    return n, x
+  end
+end
+for i = 1, 3 do
+  local j = i
+  local f = function() return j end
+  if j == 2 then goto continue end
+  print(f())
+  ::continue::
+end
+while true do
+  local k = 1
+  local g = function() return k end
+  if g() then break end
+end
+repeat
+  local m = 2
+  local g = function() return m end
+until g() == 2
+local a = {}
+a.b, a.c = 1, 2
+local i = 1
+i, a[i] = i+1, 20
+local t = setmetatable({}, {__index = function(t, k) return k * 2 end})
+print(t[21], 2^53, 1e300 * 1e10, 0x10, 0xff // 2, 7 // 2.0, 3 % -2, -3 % 2, 5.5 % 2)
+print(1 << 63, 1 >> 1, 3 & 5, 3 | 5, 3 ~ 5, ~0, 2^-1, 10 / 2, 1/0, -1/0, 0/0)
+print(-0.0, 0.0, 1 - 1.0, "a" .. 1, 2 .. "")
+local big = 9223372036854775807
+local neg = -9223372036854775808
+print(big + 1, neg - 1, math.maxinteger)
+local nested = {{{{}}}, {a = {b = {c = 1}}}}
+if a and b or c and not d then print(1) end
+if (a or b) and (c or d) then print(2) end
+local z = a == b and c or d
+local w = x > 1 and x < 10
+print(x and 1 or 2, nil and 1, false or nil)
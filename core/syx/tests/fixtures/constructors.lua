@@ -0,0 +1,36 @@
+local t = {
+  1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+  21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40,
+  41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60,
+  61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80,
+  81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, 96, 97, 98, 99, 100,
+  101, 102, 103, 104, 105, 106, 107, 108, 109, 110, ...
+}
+local u = {
+  a = 1, b = 2, c = 3, d = 4, e = 5, f = 6, g = 7, h = 8, i = 9, j = 10,
+  k = 11, l = 12, m = 13, n = 14, o = 15, p = 16, q = 17, r = 18, s = 19, t = 20,
+  1, 2, 3,
+}
+print(
+  t,
+  u
+)
+local function f(...)
+  local a, b = ...
+  return ..., a
+end
+local x = f(1,
+  2,
+  3)
+x = f
+(
+1
+)
+local s = [[
+long
+string]] .. "x"
+a = {f = function(self) return self end}
+a:f():f()
+a.b.c.d = 1
+a[1][2][3] = a[3][2][1]
+local e = a.x.y.z
@@ -0,0 +1,32 @@
+local a, b, c = 1, 2.5, "x"
+local d
+x = a + b * c - (a // 2) % 3 ^ 2
+y = -a, not b, #c, ~a
+local t = {1, 2, 3, x = 4, ["y"] = 5, [a] = 6; f(), ...}
+local u = {...}
+local v = {f()}
+function g(p, q, ...)
+  local r = p and q or nil
+  if p then return q elseif q then return p else return ... end
+end
+function t.a.b:c(z) return self, z end
+local function h(n) if n <= 1 then return 1 end return n * h(n - 1) end
+for i = 1, 10 do print(i) end
+for i = 10, 1, -1 do print(i) end
+for k, v in pairs(t) do print(k, v) end
+while a < 10 do a = a + 1 if a == 5 then break end end
+repeat local z = a; a = a - 1 until z < 0
+do local q = function() return a end end
+goto skip
+print("unreachable")
+::skip::
+a, b = b, a
+t[a], t[b] = t[b], t[a]
+print(1 == 2, 1 ~= 2, a > b, a >= b, a < b, a <= b)
+print(a .. b .. c)
+print(("x"):rep(3))
+print 'hello'
+print { 1 }
+local s = a and b
+local s2 = not (a == b)
+return f(a)
@@ -0,0 +1,38 @@
+local a = 1 + 2 * 3 - 4 / 2
+local b = 2^3^2
+local c = "a" .. "b" .. "c" .. (1 .. 2)
+local d = 1 // 0
+local e = 1 % 0
+local f = 1.0 // 0
+local g = 3 & 1.5
+local h = 3 & 2.0
+local i = -(-9223372036854775807 - 1)
+local j = 5 // -2
+local k = -5 % 3
+local l = 5.0 % -3
+local m = 1 << 64
+local n = 1 >> -1
+local o = ~5
+local p = ~5.0
+local q = -0
+local r = 0.0 * -1
+local s = 1e308 * 10
+local t = 2^0.5
+local u = 7 // 0.0
+local v = 0/0
+local w = -(1 - 1)
+local x = "10" + 1
+local y = 1 == 1
+local z = 1 < 2
+local aa = not nil
+local ab = not 1
+local ac = not not x
+local ad = nil == false
+local ae = #"abc"
+local af = 3 - -3
+local ag = - - 3
+local ah = 10 // 3 * 3 + 10 % 3
+local ai = 0x7fffffffffffffff + 1
+local aj = math.pi * 2
+local ak = 1e15 + 0.5
+local al = -1 ^ 2
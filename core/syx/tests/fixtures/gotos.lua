@@ -0,0 +1,38 @@
+#!/usr/bin/env lua
+-- gotos and labels
+do
+  goto a
+  local x = 1
+  ::a::
+end
+for i = 1, 3 do
+  for j = 1, 3 do
+    if j == 2 then goto next_i end
+    local c = function() return i + j end
+  end
+  ::next_i::
+end
+do
+  local k = 0
+  ::top::
+  k = k + 1
+  local f = function() return k end
+  if k < 3 then goto top end
+end
+repeat
+  local x = 1
+  if x then goto cont end
+  ::cont::
+until true
+while false do ::l1:: ::l2:: ; ; end
+if a then goto out elseif b then break_ = 1 else goto out end
+::out::
+if a then break_ = 2; goto out2; end
+::out2::
+for i = 1, 2 do
+  if i then break end
+  if i then break; end
+  if i then break; print(i) end
+end
+local t = {}
+function t:m(a, b, ...) local x = 1 end
@@ -0,0 +1,39 @@
+-- bytecode that Lua 5.1 and 5.2 compile differently from 5.3
+count = 0
+local function counter(step)
+  local n = 0
+  return function()
+    n = n + step
+    count = count + n
+    return n, count
+  end
+end
+local next_value = counter(2)
+for i = 1, 3 do
+  local j = i
+  local f = function() return j + next_value() end
+  if j == 2 then break end
+  print(f())
+end
+local a, b, c, d
+local t = {1, 2, 3, x = "y", [10] = 1.5}
+for k, v in pairs(t) do
+  print(k, v)
+end
+local obj = {name = "obj"}
+function obj:greet(...)
+  local args = {...}
+  return self.name .. #args, select("#", ...)
+end
+print(obj:greet(1, 2, 3))
+local long = "a string that is long enough to need more than a single size byte in Lua 5.3 and that keeps going so it passes two hundred and fifty five bytes, which takes a while to type out, so here is some more text to get it there"
+print(#long, 2^10, 10 % 3, -count, not a, 1e300 * 1e10)
+while count < 100 do
+  local x = count
+  local g = function() return x end
+  count = count + g()
+end
+repeat
+  count = count - 1
+until count < 50
+return t, long
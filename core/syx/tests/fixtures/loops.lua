@@ -0,0 +1,25 @@
+-- every backward jump luac emits: numeric and generic for, while, repeat
+-- and goto
+local sum = 0
+for i = 1, 10 do
+  sum = sum + i
+end
+
+for k, v in pairs({1, 2, 3}) do
+  sum = sum + k * v
+end
+
+while sum > 0 do
+  sum = sum - 7
+  if sum == 3 then break end
+end
+
+repeat
+  sum = sum + 1
+until sum >= 10
+
+::top::
+sum = sum - 1
+if sum > 0 then goto top end
+
+return sum
@@ -0,0 +1,327 @@
+-- bytecode only Lua 5.4 has: immediates, k operands, sJ jumps, to-be-closed
+-- variables and loops with unsigned offsets
+local limit <const> = 10
+local t = {x = 1, y = {z = 2}, 3, 4}
+local sum = 0
+for i = 1, limit do
+  sum = sum + i * 2 - 1
+  if sum >= 20 and i ~= 3 then
+    sum = sum >> 1
+  end
+end
+for k, v in pairs(t) do
+  t.y.z = t.y.z + (type(v) == "number" and v or 0)
+end
+do
+  local h <close> = setmetatable({}, {__close = function() sum = sum + 1 end})
+end
+local function f(a, ...)
+  local n = select("#", ...)
+  return a.x + n, ...
+end
+local s = "0"
+while #s < 3 do
+  s = s .. 0
+end
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+print(f(t, 1, 2.5, "three"), sum, t[1] == 3.0, -sum // 7)
+return t.y.z, s
@@ -0,0 +1,33 @@
+local fns = {}
+for i = 1, 3 do
+  repeat
+    local j = i
+    fns[#fns + 1] = function() return j end
+    if j == 2 then break end
+    local k = j
+    fns[#fns + 1] = function() return k end
+  until true
+end
+do
+  local a = 1
+  goto l
+  ::l::
+  local f = function() return a end
+end
+while true do
+  local x
+  local g = function() x = 1 end
+  if x then break end
+  do local y; local h = function() return y end; goto done end
+end
+::done::
+for k, v in next, {} do local c = function() return k, v end end
+local function outer()
+  local u1, u2 = 1, 2
+  return function()
+    return function() return u1 + u2, fns end
+  end
+end
+local _ENV = {print = print}
+print(x)
+function _ENV.f() return y end
@@ -0,0 +1,11 @@
+-- short, long and very long string constants
+local short = "short"
+local long = "a string that is longer than the forty byte short string limit"
+local huge = "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"
+local empty = ""
+
+local function concat(...)
+  return short .. long .. huge .. empty .. select("#", ...)
+end
+
+return concat(1, 2.5, -0.0, true, nil, 0x7fffffffffffffff)
@@ -6,12 +6,14 @@ use proc_macro::TokenStream;
 extern crate syn;
 use syn::parse::{Parse, ParseStream, Result, Error};
 use syn::punctuated::{Punctuated, Pair, IntoIter};
-use syn::{parse_macro_input, Expr, Ident, Token, Type, Visibility};
+use syn::{parse_macro_input, Expr, Ident, LitInt, Path, Token, Type, Visibility};
 use syn::spanned::Spanned;
-use syn::export::Span;
+extern crate proc_macro2;
+use proc_macro2::{Literal, Span};
+use proc_macro2::TokenStream as TokenStream2;
 
 extern crate quote;
-use quote::quote;
+use quote::{format_ident, quote};
 
 #[derive(Clone)]
 enum OpCodeType {
@@ -21,16 +23,19 @@ enum OpCodeType {
     ABx(Ident, Ident),
     AsBx(Ident, Ident),
     Ax(Ident),
+    SJ(Ident),
 }
 
 #[derive(Clone)]
-struct OpCodeContainer(Ident, OpCodeType);
+struct OpCodeContainer(Ident, OpCodeType, Vec<Ident>, u8); // name, format, flags, number
 
 struct OpCodeParse {
     instruction_name: Ident, // Name of Instruction type
     opcode_name: Ident, // Name of the OpCode variants, usually OpCodes
     error_name: Ident, // Name of the error type to use
     error_expr: Expr, // Name of the expression used to generate errors
+    operand_error: Path, // Error constructor taking (field, value)
+    layout: Layout, // Where the fields sit in a Word
 
     // Containers used for matching
     abc: Vec<Ident>,
@@ -39,16 +44,118 @@ struct OpCodeParse {
     abx: Vec<Ident>,
     asbx: Vec<Ident>,
     ax: Vec<Ident>,
+    sj: Vec<Ident>,
 
     // Containers used for TryFrom
     list: Vec<OpCodeContainer>,
 }
 
-const INVALID_FORMAT: &str = "expected one of `ABC`, `AB`, `A`, `ABx`, `AsBx`, `Ax`";
-const ALLOWED_RHS: [&str; 7] = ["Register", "Constant", "RegisterConstant",
+const INVALID_FORMAT: &str = "expected one of `ABC`, `AB`, `A`, `ABx`, `AsBx`, `Ax`, `sJ`";
+const ALLOWED_RHS: [&str; 8] = ["Register", "Constant", "RegisterConstant", "Unused",
                                 "Integer", "SInteger", "Bool", "UpValue"];
 const INVALID_RHS: &str = "expected one of `Register`, `Constant`, \
-                           `RegisterConstant`, `Integer`, `SInteger`, `Bool`, `UpValue`";
+                           `RegisterConstant`, `Unused`, `Integer`, `SInteger`, `Bool`, `UpValue`";
+
+const ALLOWED_FLAGS: [&str; 5] = ["sets_a", "test", "uses_top", "sets_top",
+                                  "extra_arg"];
+const INVALID_FLAG: &str = "expected one of `sets_a`, `test`, `uses_top`, \
+                            `sets_top`, `extra_arg`";
+
+const LAYOUT_FIELDS: [&str; 6] = ["op", "a", "b", "c", "k", "sj"];
+const INVALID_LAYOUT_FIELD: &str = "expected one of `op`, `a`, `b`, `c`, `k`, `sj`";
+
+// Bits in a Word
+const WORD_BITS: u32 = 32;
+
+// Size and position of a field of a Word, in bits
+#[derive(Clone, Copy)]
+struct Field {
+    size: u32,
+    offset: u32,
+}
+
+impl Field {
+    fn new(size: u32, offset: u32) -> Field {
+        Field { size, offset }
+    }
+}
+
+// Where the fields of an instruction live, the SIZE_* and POS_* macros of a
+// version's lopcodes.h
+struct Layout {
+    op: Field,
+    a: Field,
+    b: Field,
+    c: Field,
+    bx: Field,
+    ax: Field,
+    // 5.4 replaced the RK bit of B and C with a flag next to A that makes C a
+    // constant, and added a signed jump spanning everything past the opcode
+    k: Option<Field>,
+    sj: Option<Field>,
+}
+
+impl Layout {
+    // Lays out `fields` from the lowest bit up. Bx takes every bit above A and
+    // Ax every bit above the opcode, as does sJ if there is one.
+    fn new(fields: &[(&str, u32)], sj: bool) -> ::std::result::Result<Layout, String> {
+        let names: Vec<_> = fields.iter().map(|x| x.0).collect();
+        if !names.starts_with(&["op", "a"]) {
+            return Err("`op` and `a` have to be the lowest fields, \
+                        Bx and Ax are the bits above them".to_owned());
+        }
+        let mut offset = 0;
+        let mut placed = Vec::new();
+        for &(name, size) in fields {
+            placed.push((name, Field::new(size, offset)));
+            offset += size;
+        }
+        if offset > WORD_BITS {
+            return Err(format!("the fields take {} bits, more than the {} of a Word",
+                               offset, WORD_BITS));
+        }
+        let field = |name: &str| placed.iter().find(|x| x.0 == name).map(|x| x.1);
+        let (op, a, k) = (field("op").unwrap(), field("a").unwrap(), field("k"));
+        if op.size > 8 {
+            // opcodes are decoded through a u8
+            return Err(format!("`op` takes {} bits, more than the 8 of an opcode", op.size));
+        }
+        let (b, c) = match (field("b"), field("c")) {
+            (Some(b), Some(c)) => (b, c),
+            (None, _) => return Err("missing `b`".to_owned()),
+            (_, None) => return Err("missing `c`".to_owned()),
+        };
+        if k.is_none() && b.size != c.size {
+            return Err("`b` and `c` have to be the same size to share the RK bit, \
+                        or the layout needs a `k` flag".to_owned());
+        }
+        let ax = Field::new(offset - a.offset, a.offset);
+        Ok(Layout {
+            op,
+            a,
+            b,
+            c,
+            bx: Field::new(offset - (a.offset + a.size), a.offset + a.size),
+            ax,
+            k,
+            sj: if sj { Some(ax) } else { None },
+        })
+    }
+
+    // |B:9|C:9|A:8|Op:6|, for input that does not declare a layout
+    fn lua53() -> Layout {
+        Layout::new(&[("op", 6), ("a", 8), ("c", 9), ("b", 9)], false).unwrap()
+    }
+
+    // Fields with their names in the generated constants
+    fn fields(&self) -> Vec<(&'static str, Field)> {
+        let mut fields = vec![("OP", self.op), ("A", self.a), ("B", self.b), ("C", self.c),
+                              ("BX", self.bx), ("AX", self.ax)];
+        fields.extend(self.k.map(|k| ("K", k)));
+        fields.extend(self.sj.map(|sj| ("SJ", sj)));
+        fields
+    }
+}
 
 macro_rules! bad_count_rhs {
     () => {"expected {} arguments, got {}"}
@@ -65,6 +172,60 @@ fn get_arg(args: &mut IntoIter<Ident>, count: usize, max: u8, error_span: Span)
     Ok(item)
 }
 
+// A field of the layout, like `a: 8`, or `sj` which takes no size
+struct LayoutEntry {
+    name: Ident,
+    size: Option<LitInt>,
+}
+
+impl Parse for LayoutEntry {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse::<Ident>()?;
+        let size = if input.peek(Token![:]) {
+            input.parse::<Token![:]>()?;
+            Some(input.parse::<LitInt>()?)
+        } else {
+            None
+        };
+        Ok(LayoutEntry { name, size })
+    }
+}
+
+fn parse_layout(input: ParseStream) -> Result<Layout> {
+    let content;
+    let braces = syn::braced!(content in input);
+    let mut fields: Vec<(String, u32)> = Vec::new();
+    let mut sj = false;
+    for entry in Punctuated::<LayoutEntry, Token![,]>::parse_terminated(&content)? {
+        let name = entry.name.to_string();
+        if !LAYOUT_FIELDS.contains(&name.as_str()) {
+            return Err(Error::new(entry.name.span(), INVALID_LAYOUT_FIELD));
+        }
+        if fields.iter().any(|x| x.0 == name) || (sj && name == "sj") {
+            return Err(Error::new(entry.name.span(), format!("`{}` is laid out twice", name)));
+        }
+        let size = match entry.size {
+            Some(ref size) if name == "sj" => {
+                return Err(Error::new(size.span(), "`sj` takes no size, it spans the bits of Ax"));
+            }
+            None if name == "sj" => {
+                sj = true;
+                continue;
+            }
+            Some(size) => size,
+            None => return Err(Error::new(entry.name.span(), "expected a size in bits")),
+        };
+        let bits = size.base10_parse::<u32>()?;
+        if bits == 0 || (name == "k" && bits != 1) {
+            let message = if bits == 0 { "a field needs at least one bit" } else { "`k` is a single bit" };
+            return Err(Error::new(size.span(), message));
+        }
+        fields.push((name, bits));
+    }
+    let fields: Vec<_> = fields.iter().map(|x| (x.0.as_str(), x.1)).collect();
+    Layout::new(&fields, sj).map_err(|message| Error::new(braces.span, message))
+}
+
 impl Parse for OpCodeParse {
     fn parse(input: ParseStream) -> Result<Self> {
         // Parse names
@@ -75,6 +236,20 @@ impl Parse for OpCodeParse {
         let error_name = input.parse::<Ident>()?;
         input.parse::<Token![=]>()?;
         let error_expr = input.parse::<Expr>()?;
+        input.parse::<Token![,]>()?;
+        let operand_error = input.parse::<Path>()?;
+        // optional layout of the Word, like: `, layout = { op: 6, a: 8, c: 9, b: 9 }`
+        let layout = if input.peek(Token![,]) {
+            input.parse::<Token![,]>()?;
+            let key = input.parse::<Ident>()?;
+            if key != "layout" {
+                return Err(Error::new(key.span(), "expected `layout`"));
+            }
+            input.parse::<Token![=]>()?;
+            parse_layout(input)?
+        } else {
+            Layout::lua53()
+        };
         input.parse::<Token![=]>()?;
         input.parse::<Token![>]>()?;
         
@@ -85,22 +260,64 @@ impl Parse for OpCodeParse {
         let mut abx = Vec::new();
         let mut asbx = Vec::new();
         let mut ax = Vec::new();
+        let mut sj = Vec::new();
+
+        let mut output: Vec<OpCodeContainer> = Vec::new();
 
-        let mut output = Vec::new();
+        // Opcodes are numbered like enum variants, from 0 or from an explicit
+        // `= N`, and have to fit both the op field and the u8 they decode from
+        let op_limit = ::std::cmp::min(1u32 << layout.op.size, 256);
+        let mut next_number = 0;
 
         // Parse opcodes and arguments
         while !input.is_empty() {
             // match something like: `Move: AB = Register, Register`
             // as well as: `MoveK: ABx = Register, Constant`
+            // or with an explicit number: `MoveK = 40: ABx = Register, Constant`
             // valid values for RHS are:
             // Register, Constant, RegisterConstant, Integer, Bool, UpValue
             let current_name: Ident = input.parse()?;
+            if output.iter().any(|x| x.0 == current_name) {
+                return Err(Error::new(current_name.span(),
+                                      format!("`{}` is declared twice", current_name)));
+            }
+            let (number, number_span) = if input.peek(Token![=]) {
+                input.parse::<Token![=]>()?;
+                let literal = input.parse::<LitInt>()?;
+                (literal.base10_parse::<u32>()?, literal.span())
+            } else {
+                (next_number, current_name.span())
+            };
+            if number >= op_limit {
+                return Err(Error::new(number_span, format!(
+                    "opcode {} does not fit in the {} bits of `op`, the limit is {}",
+                    number, layout.op.size, op_limit - 1)));
+            }
+            if let Some(taken) = output.iter().find(|x| u32::from(x.3) == number) {
+                return Err(Error::new(number_span, format!(
+                    "opcode {} is already taken by `{}`", number, taken.0)));
+            }
+            next_number = number + 1;
+            let number = number as u8;
             input.parse::<Token![:]>()?;
             let format: Ident = input.parse()?;
             input.parse::<Token![=]>()?;
             let arg_types_punct = Punctuated::<Ident, Token![,]>::parse_separated_nonempty(input)?;
             let arg_types_span = arg_types_punct.span();
             let mut arg_types = arg_types_punct.into_iter();
+
+            // optional mode flags, like: `Eq: ABC = ... [test]`
+            let mut flags = Vec::new();
+            if input.peek(syn::token::Bracket) {
+                let content;
+                syn::bracketed!(content in input);
+                for flag in Punctuated::<Ident, Token![,]>::parse_terminated(&content)? {
+                    if !ALLOWED_FLAGS.contains(&flag.to_string().as_str()) {
+                        return Err(Error::new(flag.span(), INVALID_FLAG));
+                    }
+                    flags.push(flag);
+                }
+            }
             let arg_count = arg_types.len();
             let mut expected_arg_count = 0;
 
@@ -111,7 +328,7 @@ impl Parse for OpCodeParse {
                     let first = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
                     let second = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
                     let third = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
-                    let container = OpCodeContainer(current_name.clone(), OpCodeType::ABC(first, second, third));
+                    let container = OpCodeContainer(current_name.clone(), OpCodeType::ABC(first, second, third), flags.clone(), number);
                     abc.push(current_name);
                     output.push(container)
                 },
@@ -119,14 +336,14 @@ impl Parse for OpCodeParse {
                     expected_arg_count = 2;
                     let first = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
                     let second = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
-                    let container = OpCodeContainer(current_name.clone(), OpCodeType::AB(first, second));
+                    let container = OpCodeContainer(current_name.clone(), OpCodeType::AB(first, second), flags.clone(), number);
                     ab.push(current_name);
                     output.push(container)
                 },
                 "A" => {
                     expected_arg_count = 1;
                     let first = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
-                    let container = OpCodeContainer(current_name.clone(), OpCodeType::A(first));
+                    let container = OpCodeContainer(current_name.clone(), OpCodeType::A(first), flags.clone(), number);
                     a.push(current_name);
                     output.push(container)
                 },
@@ -134,7 +351,7 @@ impl Parse for OpCodeParse {
                     expected_arg_count = 2;
                     let first = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
                     let second = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
-                    let container = OpCodeContainer(current_name.clone(), OpCodeType::ABx(first, second));
+                    let container = OpCodeContainer(current_name.clone(), OpCodeType::ABx(first, second), flags.clone(), number);
                     abx.push(current_name);
                     output.push(container)
                 },
@@ -142,23 +359,34 @@ impl Parse for OpCodeParse {
                     expected_arg_count = 2;
                     let first = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
                     let second = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
-                    let container = OpCodeContainer(current_name.clone(), OpCodeType::AsBx(first, second));
+                    let container = OpCodeContainer(current_name.clone(), OpCodeType::AsBx(first, second), flags.clone(), number);
                     asbx.push(current_name);
                     output.push(container)
                 },
                 "Ax" => {
                     expected_arg_count = 1;
                     let first = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
-                    let container = OpCodeContainer(current_name.clone(), OpCodeType::Ax(first));
+                    let container = OpCodeContainer(current_name.clone(), OpCodeType::Ax(first), flags.clone(), number);
                     ax.push(current_name);
                     output.push(container)
                 },
+                "sJ" if layout.sj.is_none() => {
+                    return Err(Error::new(format.span(), "the layout has no `sJ` field"))
+                },
+                "sJ" => {
+                    expected_arg_count = 1;
+                    let first = get_arg(&mut arg_types, arg_count, expected_arg_count, arg_types_span)?;
+                    let container = OpCodeContainer(current_name.clone(), OpCodeType::SJ(first), flags.clone(), number);
+                    sj.push(current_name);
+                    output.push(container)
+                },
                 _ => return Err(Error::new(format.span(), INVALID_FORMAT))
             }
             if let Some(arg) = arg_types.next() {
                 return Err(Error::new(arg.span(),
                                       format!(bad_count_rhs!(), expected_arg_count, arg_count)))
             }
+            check_operands(&output.last().unwrap().1, &format)?;
             input.parse::<Token![;]>()?;
         }
         Ok(OpCodeParse {
@@ -166,17 +394,295 @@ impl Parse for OpCodeParse {
             opcode_name: opcode_name,
             error_name: error_name,
             error_expr: error_expr,
+            operand_error: operand_error,
+            layout: layout,
             abc: abc,
             ab: ab,
             a: a,
             abx: abx,
             asbx: asbx,
             ax: ax,
+            sj: sj,
             list: output
         })
     }
 }
 
+// Builds the Argument for an operand of the given kind, read from `field`;
+// `Unused` operands are left out
+fn operand(kind: &Ident, field: &TokenStream2, layout: &Layout) -> Option<TokenStream2> {
+    let biased = field.to_string() == "b" || field.to_string() == "c";
+    Some(match kind.to_string().as_str() {
+        "Register" => quote! { Argument::Register(#field as u32) },
+        "Constant" => quote! { Argument::Constant(#field as u32) },
+        "RegisterConstant" if layout.k.is_some() => quote! {
+            if k { Argument::Constant(#field as u32) } else { Argument::Register(#field as u32) }
+        },
+        "RegisterConstant" => quote! { Argument::from_rk(#field as u32) },
+        // sB and sC are excess-K like sBx, the wider fields are unbiased on decoding
+        "SInteger" if biased => quote! { Argument::SInteger(#field as i32 - MAXARG_SC) },
+        "SInteger" => quote! { Argument::SInteger(#field as i32) },
+        "Integer" => quote! { Argument::Integer(#field as u32) },
+        "Bool" => quote! { Argument::Bool(#field != 0) },
+        "UpValue" => quote! { Argument::UpValue(#field as u32) },
+        "Unused" => return None,
+        _ => unreachable!(), // checked by get_arg
+    })
+}
+
+// Operand kinds of a format, paired with the field they are read from
+fn operand_fields(format: &OpCodeType) -> Vec<(&Ident, TokenStream2)> {
+    match format {
+        OpCodeType::ABC(x, y, z) => vec![(x, quote!(a)), (y, quote!(b)), (z, quote!(c))],
+        OpCodeType::AB(x, y) => vec![(x, quote!(a)), (y, quote!(b))],
+        OpCodeType::A(x) => vec![(x, quote!(a))],
+        OpCodeType::ABx(x, y) => vec![(x, quote!(a)), (y, quote!(bx))],
+        OpCodeType::AsBx(x, y) => vec![(x, quote!(a)), (y, quote!(sbx))],
+        OpCodeType::Ax(x) => vec![(x, quote!(ax))],
+        OpCodeType::SJ(x) => vec![(x, quote!(sj))],
+    }
+}
+
+// Rejects operand kinds that can not be read from their field: RK operands
+// need B or C, where the RK bit or the k flag is, signed ones need an
+// excess-K field, and sBx and sJ are always signed
+fn check_operands(format: &OpCodeType, format_name: &Ident) -> Result<()> {
+    for (kind, field) in operand_fields(format) {
+        let (kind_name, field_name) = (kind.to_string(), field.to_string());
+        let allowed = match field_name.as_str() {
+            "b" | "c" => true,
+            "sbx" | "sj" => kind_name == "SInteger" || kind_name == "Unused",
+            _ => kind_name != "SInteger" && kind_name != "RegisterConstant",
+        };
+        if !allowed {
+            return Err(Error::new(kind.span(), format!(
+                "`{}` does not fit the {} field of `{}`",
+                kind_name, field_name, format_name)));
+        }
+    }
+    Ok(())
+}
+
+// Variant of Instruction that holds the fields of a format
+fn format_variant(format: &OpCodeType) -> TokenStream2 {
+    match format {
+        OpCodeType::ABC(..) | OpCodeType::AB(..) | OpCodeType::A(..) => quote!(ABC),
+        OpCodeType::ABx(..) => quote!(ABx),
+        OpCodeType::AsBx(..) => quote!(AsBx),
+        OpCodeType::Ax(..) => quote!(Ax),
+        OpCodeType::SJ(..) => quote!(SJ),
+    }
+}
+
+// Match arm of Instruction::operands for a single opcode
+fn operands_arm(instruction_name: &Ident, opcode_name: &Ident,
+                container: &OpCodeContainer, layout: &Layout) -> TokenStream2 {
+    let OpCodeContainer(name, format, _, _) = container;
+    let variant = format_variant(format);
+    let mut fields = Vec::new();
+    let mut operands = Vec::new();
+    for (kind, field) in operand_fields(format) {
+        if let Some(arg) = operand(kind, &field, layout) {
+            fields.push(field);
+            operands.push(arg);
+        }
+    }
+    // the k flag picks between register and constant
+    let uses_k = operand_fields(format).iter().any(|(kind, _)| *kind == "RegisterConstant");
+    if uses_k && layout.k.is_some() {
+        fields.push(quote!(k));
+    }
+    quote! {
+        #instruction_name::#variant { instruction: #opcode_name::#name, #(#fields,)* .. }
+            => vec![#(#operands),*],
+    }
+}
+
+// Visitor method name of an opcode, GetTabUp becomes visit_get_tab_up and
+// a run of capitals stays together, so BXOr becomes visit_bx_or
+fn visit_method(name: &Ident) -> Ident {
+    let chars: Vec<char> = name.to_string().chars().collect();
+    let mut snake = String::from("visit");
+    for (i, &ch) in chars.iter().enumerate() {
+        if ch.is_uppercase() {
+            let after_lower = i > 0 && !chars[i - 1].is_uppercase();
+            let before_lower = chars.get(i + 1).is_some_and(|x| x.is_lowercase());
+            if i == 0 || after_lower || before_lower {
+                snake.push('_');
+            }
+        }
+        snake.extend(ch.to_lowercase());
+    }
+    format_ident!("{}", snake, span = name.span())
+}
+
+// Type and value of an operand as a visitor receives it; `Unused` operands
+// are left out like in `operand`
+fn typed_operand(kind: &Ident, field: &TokenStream2, layout: &Layout)
+        -> Option<(TokenStream2, TokenStream2)> {
+    let biased = field.to_string() == "b" || field.to_string() == "c";
+    Some(match kind.to_string().as_str() {
+        "Register" => (quote!(Reg), quote!(Reg(#field as u32))),
+        "Constant" => (quote!(Const), quote!(Const(#field as u32))),
+        "RegisterConstant" if layout.k.is_some() => (quote!(RK), quote! {
+            if k { RK::Constant(Const(#field as u32)) } else { RK::Register(Reg(#field as u32)) }
+        }),
+        "RegisterConstant" => (quote!(RK), quote!(RK::from_rk(#field as u32))),
+        "SInteger" if biased => (quote!(i32), quote!(#field as i32 - MAXARG_SC)),
+        "SInteger" => (quote!(i32), quote!(#field as i32)),
+        "Integer" => (quote!(u32), quote!(#field as u32)),
+        "Bool" => (quote!(bool), quote!(#field != 0)),
+        "UpValue" => (quote!(Upval), quote!(Upval(#field as u32))),
+        "Unused" => return None,
+        _ => unreachable!(), // checked by get_arg
+    })
+}
+
+// Method of the visitor trait for a single opcode and the arm of
+// Instruction::visit that calls it
+fn visitor_parts(instruction_name: &Ident, opcode_name: &Ident,
+                 container: &OpCodeContainer, layout: &Layout) -> (TokenStream2, TokenStream2) {
+    let OpCodeContainer(name, format, _, _) = container;
+    let variant = format_variant(format);
+    let method = visit_method(name);
+    let mut fields = Vec::new();
+    let mut params = Vec::new();
+    let mut values = Vec::new();
+    for (kind, field) in operand_fields(format) {
+        if let Some((ty, value)) = typed_operand(kind, &field, layout) {
+            params.push(quote!(#field: #ty));
+            fields.push(field);
+            values.push(value);
+        }
+    }
+    // the k flag is either read by RK operands or handed over as it is
+    let abc = variant.to_string() == "ABC";
+    let uses_rk = operand_fields(format).iter().any(|(kind, _)| *kind == "RegisterConstant");
+    if abc && layout.k.is_some() {
+        fields.push(quote!(k));
+        if !uses_rk {
+            params.push(quote!(k: bool));
+            values.push(quote!(k));
+        }
+    }
+    (quote! {
+        fn #method(&mut self, #(#params),*) -> Self::Output;
+    }, quote! {
+        #instruction_name::#variant { instruction: #opcode_name::#name, #(#fields,)* .. }
+            => visitor.#method(#(#values),*),
+    })
+}
+
+// Argument mode of an operand kind, like OpArgMask in lopcodes.h
+fn arg_mode(kind: Option<&Ident>) -> TokenStream2 {
+    match kind.map(|x| x.to_string()).as_deref() {
+        None | Some("Unused") => quote!(ArgMode::Unused),
+        Some("Register") | Some("SInteger") => quote!(ArgMode::Register),
+        Some("RegisterConstant") | Some("Constant") => quote!(ArgMode::RegisterConstant),
+        Some(_) => quote!(ArgMode::Used),
+    }
+}
+
+// Entry of the generated OPMODES table for a single opcode
+fn opmode(container: &OpCodeContainer) -> TokenStream2 {
+    let OpCodeContainer(_, format, flags, _) = container;
+    let (format_name, b, c) = match format {
+        OpCodeType::ABC(_, b, c) => (quote!(ABC), Some(b), Some(c)),
+        OpCodeType::AB(_, b) => (quote!(AB), Some(b), None),
+        OpCodeType::A(_) => (quote!(A), None, None),
+        OpCodeType::ABx(_, bx) => (quote!(ABx), Some(bx), None),
+        OpCodeType::AsBx(_, sbx) => (quote!(AsBx), Some(sbx), None),
+        // Ax and sJ span both B and C
+        OpCodeType::Ax(ax) => (quote!(Ax), Some(ax), Some(ax)),
+        OpCodeType::SJ(sj) => (quote!(SJ), Some(sj), Some(sj)),
+    };
+    let (b, c) = (arg_mode(b), arg_mode(c));
+    let flag = |name: &str| flags.iter().any(|x| x == name);
+    let (sets_a, test, uses_top, sets_top, extra_arg) = (
+        flag("sets_a"), flag("test"), flag("uses_top"), flag("sets_top"),
+        flag("extra_arg"),
+    );
+    quote! {
+        OpMode {
+            format: OpFormat::#format_name,
+            sets_a: #sets_a,
+            test: #test,
+            b: #b,
+            c: #c,
+            uses_top: #uses_top,
+            sets_top: #sets_top,
+            extra_arg: #extra_arg,
+        }
+    }
+}
+
+// Smallest unsigned type that holds a field of the given size
+fn field_type(field: Field) -> TokenStream2 {
+    match field.size {
+        0..=8 => quote!(u8),
+        9..=16 => quote!(u16),
+        _ => quote!(u32),
+    }
+}
+
+// SIZE_*, OFFSET_* and BITMASK_* of each field of the layout, with the
+// constants derived from them
+fn layout_consts(layout: &Layout) -> TokenStream2 {
+    let mut consts = Vec::new();
+    for (name, field) in layout.fields() {
+        let (size, offset, bitmask) = (format_ident!("SIZE_{}", name),
+                                       format_ident!("OFFSET_{}", name),
+                                       format_ident!("BITMASK_{}", name));
+        let (field_size, field_offset) = (field.size, field.offset);
+        consts.push(quote! {
+            pub const #size: u32 = #field_size;
+            pub const #offset: u32 = #field_offset;
+            pub const #bitmask: u32 = (1 << #size) - 1;
+        });
+    }
+    if layout.k.is_none() {
+        consts.push(quote! {
+            // Is constant: C & BITMASK_IS_RK == 1
+            // Register number: (n as u32) & ~BITMASK_IS_RK
+            pub const BITMASK_IS_RK: u32 = 1 << (SIZE_B - 1);
+
+            impl Argument {
+                pub fn from_rk(n: u32) -> Argument {
+                    if n & BITMASK_IS_RK == 0 {
+                        Argument::Register(n)
+                    } else {
+                        Argument::Constant(n & !BITMASK_IS_RK)
+                    }
+                }
+            }
+
+            impl RK {
+                pub fn from_rk(n: u32) -> RK {
+                    if n & BITMASK_IS_RK == 0 {
+                        RK::Register(Reg(n))
+                    } else {
+                        RK::Constant(Const(n & !BITMASK_IS_RK))
+                    }
+                }
+            }
+        });
+    }
+    if layout.sj.is_some() {
+        consts.push(quote! {
+            pub const MAXARG_SJ: i32 = (BITMASK_SJ >> 1) as i32;
+        });
+    }
+    quote! {
+        #(#consts)*
+
+        // sBx is stored in excess-K: the field holds sBx + MAXARG_SBX, so
+        // that a zero field is the most negative offset
+        pub const MAXARG_SBX: i32 = (BITMASK_BX >> 1) as i32;
+        // the same goes for signed B and C
+        pub const MAXARG_SC: i32 = (BITMASK_C >> 1) as i32;
+    }
+}
+
 #[proc_macro]
 pub fn bytecode(input: TokenStream) -> TokenStream {
     let OpCodeParse {
@@ -184,16 +690,21 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
         opcode_name: opcode_name,
         error_name: error_name,
         error_expr: error_expr,
+        operand_error: operand_error,
+        layout,
         abc: abc,
         ab: ab,
         a: a,
         abx: abx,
         asbx: asbx,
         ax: ax,
+        sj,
         list: opcode_list,
     } = parse_macro_input!(input as OpCodeParse);
 
-    let number = 0u8..255u8;
+    let number: Vec<_> = opcode_list.iter().map(|x| x.3).collect();
+    let discriminant: Vec<_> = number.iter().map(|&x| Literal::u8_unsuffixed(x)).collect();
+    let index = 0..opcode_list.len();
 
     let opcode_variant: Vec<_> = opcode_list
         .iter()
@@ -206,72 +717,177 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
         .collect();
 
     let opcode_name_repeat = ::std::iter::repeat(opcode_name.clone());
+    let opcode_name_mode = ::std::iter::repeat(opcode_name.clone());
 
-    let result = quote! {
-        pub type Word = u32;
+    let operands_arms: Vec<_> = opcode_list
+        .iter()
+        .map(|x| operands_arm(&instruction_name, &opcode_name, x, &layout))
+        .collect();
 
-        const SIZE_OP: u32 = 6;
+    let (visit_methods, visit_arms): (Vec<_>, Vec<_>) = opcode_list
+        .iter()
+        .map(|x| visitor_parts(&instruction_name, &opcode_name, x, &layout))
+        .unzip();
 
-        const SIZE_C: u32 = 9;
-        const SIZE_B: u32 = 9;
-        const SIZE_BX: u32 = SIZE_C + SIZE_B;
-        const SIZE_A: u32 = 8;
-        const SIZE_AX: u32 = SIZE_C + SIZE_B + SIZE_A;
+    let opmodes: Vec<_> = opcode_list.iter().map(opmode).collect();
+    let opcode_count = opcode_list.len();
 
-        const OFFSET_OP: u32 = 0;
-        const OFFSET_A: u32 = (OFFSET_OP + SIZE_OP);
-        const OFFSET_C: u32 = (OFFSET_A + SIZE_A);
-        const OFFSET_B: u32 = (OFFSET_C + SIZE_C);
+    let consts = layout_consts(&layout);
+    let (a_type, b_type, c_type) = (field_type(layout.a), field_type(layout.b),
+                                    field_type(layout.c));
 
-        const OFFSET_BX: u32 = OFFSET_C;
-        const OFFSET_AX: u32 = OFFSET_A;
+    // Parts that only exist in layouts with a k flag or an sJ field
+    let (k_field, k_decode, k_pattern, k_encode) = if layout.k.is_some() {
+        (quote!(k: bool,),
+         quote!(k: (instr >> OFFSET_K) & BITMASK_K != 0,),
+         quote!(k,),
+         quote!(| (k as Word) << OFFSET_K))
+    } else {
+        (quote!(), quote!(), quote!(), quote!())
+    };
+    let (sj_format, sj_variant, sj_encode) = if layout.sj.is_some() {
+        (quote!(SJ,),
+         quote! {
+             SJ {
+                 instruction: #opcode_name,
+                 sj: i32,
+             },
+         },
+         quote! {
+             #instruction_name::SJ { instruction, sj } => {
+                 (instruction as Word) << OFFSET_OP
+                     | pack_argument("sj", i64::from(sj), i64::from(MAXARG_SJ),
+                                     BITMASK_SJ, OFFSET_SJ)?
+             },
+         })
+    } else {
+        (quote!(), quote!(), quote!())
+    };
 
-        const BITMASK_OP: u32 = (1 << SIZE_OP) - 1;
-        const BITMASK_A: u32 = (1 << SIZE_A) - 1;
-        const BITMASK_AX: u32 = (1 << SIZE_AX) - 1;
-        const BITMASK_B: u32 = (1 << SIZE_B) - 1;
-        const BITMASK_BX: u32 = (1 << SIZE_BX) - 1;
-        const BITMASK_C: u32 = (1 << SIZE_C) - 1;
+    // Decoding arms for each format used by at least one opcode, a match arm
+    // without patterns would not compile
+    let abc_group: Vec<_> = abc.iter().chain(ab.iter()).chain(a.iter()).collect();
+    let mut decode_arms = Vec::new();
+    if !abc_group.is_empty() {
+        decode_arms.push(quote! {
+            #(| #opcode_name::#abc_group)* => #instruction_name::ABC {
+                instruction: _enum,
+                a: ((instr >> OFFSET_A) & BITMASK_A) as #a_type,
+                b: ((instr >> OFFSET_B) & BITMASK_B) as #b_type,
+                c: ((instr >> OFFSET_C) & BITMASK_C) as #c_type,
+                #k_decode
+            },
+        });
+    }
+    if !abx.is_empty() {
+        decode_arms.push(quote! {
+            #(| #opcode_name::#abx)* => #instruction_name::ABx {
+                instruction: _enum,
+                a: ((instr >> OFFSET_A) & BITMASK_A) as #a_type,
+                bx: ((instr >> OFFSET_BX) & BITMASK_BX) as u32,
+            },
+        });
+    }
+    if !asbx.is_empty() {
+        decode_arms.push(quote! {
+            #(| #opcode_name::#asbx)* => #instruction_name::AsBx {
+                instruction: _enum,
+                a: ((instr >> OFFSET_A) & BITMASK_A) as #a_type,
+                sbx: ((instr >> OFFSET_BX) & BITMASK_BX) as i32 - MAXARG_SBX,
+            },
+        });
+    }
+    if !ax.is_empty() {
+        decode_arms.push(quote! {
+            #(| #opcode_name::#ax)* => #instruction_name::Ax {
+                instruction: _enum,
+                ax: ((instr >> OFFSET_AX) & BITMASK_AX) as u32,
+            },
+        });
+    }
+    if !sj.is_empty() {
+        decode_arms.push(quote! {
+            #(| #opcode_name::#sj)* => #instruction_name::SJ {
+                instruction: _enum,
+                sj: ((instr >> OFFSET_SJ) & BITMASK_SJ) as i32 - MAXARG_SJ,
+            },
+        });
+    }
 
-        const BITMASK_IS_RK: u32 = 1 << (SIZE_B - 1);
+    let result = quote! {
+        pub type Word = u32;
 
-        // Is constant: C & BITMASK_IS_RK == 1
-        // Register number: (n as u32) & ~BITMASK_IS_RK
+        #consts
 
-        enum Argument {
+        // Typed operand of an instruction, RK operands are resolved to
+        // either a register or a constant index
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum Argument {
             Register(u32),
             Constant(u32),
-            RegisterConstant(u32),
             SInteger(i32),
             Integer(u32),
-            Bool(u32),
+            Bool(bool),
             UpValue(u32),
         }
 
-        impl ::std::fmt::Debug for Argument {
-            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        // Layout of the arguments of an instruction
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+        pub enum OpFormat {
+            ABC,
+            AB,
+            A,
+            ABx,
+            AsBx,
+            Ax,
+            #sj_format
+        }
+
+        // How an instruction uses its B or C argument, OpArgMask in lopcodes.h
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+        pub enum ArgMode {
+            Unused,           // OpArgN
+            Used,             // OpArgU
+            Register,         // OpArgR, a register or a jump offset
+            RegisterConstant, // OpArgK, a constant or register/constant
+        }
+
+        // Per-opcode metadata, luaP_opmodes in lopcodes.c
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+        pub struct OpMode {
+            pub format: OpFormat,
+            pub sets_a: bool,    // instruction writes to register A
+            pub test: bool,      // next instruction is always a jump
+            pub b: ArgMode,
+            pub c: ArgMode,
+            pub uses_top: bool,  // reads up to `top` when B is 0
+            pub sets_top: bool,  // leaves results up to `top` (B or C is 0)
+            pub extra_arg: bool, // can be followed by ExtraArg
+        }
+
+        // In the order the opcodes are declared, which is not their number
+        // when some are given explicitly
+        pub static OPMODES: [OpMode; #opcode_count] = [
+        #(
+            #opmodes,
+        )*
+        ];
+
+        impl #opcode_name {
+            pub fn mode(self) -> &'static OpMode {
                 match self {
-                    Argument::Register(n) => write!(f, "Register({})", n),
-                    Argument::Constant(n) => write!(f, "Constant({})", n),
-                    Argument::RegisterConstant(n) => {
-                        write!(f, "RegisterConstant(");
-                        if n & BITMASK_IS_RK == 0 {
-                            write!(f, "Register({}))", n);
-                        } else {
-                            write!(f, "Constant({}))", n & !BITMASK_IS_RK);
-                        }
-                        Ok(())
-                    },
-                    _ => unimplemented!()
+                #(
+                    #opcode_name_mode::#opcode_variant => &OPMODES[#index],
+                )*
                 }
             }
         }
 
         // Generate variants for opcode field names
-        #[derive(Debug, Eq, PartialEq)]
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
         pub enum #opcode_name {
         #(
-            #opcode_variant,
+            #opcode_variant = #discriminant,
         )*
         }
 
@@ -290,28 +906,30 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
         }
 
         // Structure to hold bytecode variant types
-        #[derive(Debug, PartialEq)]
+        #[derive(Debug, Clone, Copy, PartialEq)]
         pub enum #instruction_name {
             ABC {
                 instruction: #opcode_name,
-                a: u8, // 8
-                b: u16, // 9
-                c: u16, // 9
+                a: #a_type,
+                b: #b_type,
+                c: #c_type,
+                #k_field
             },
             ABx {
                 instruction: #opcode_name,
-                a: u8, // 8
-                bx: u32, // 18
+                a: #a_type,
+                bx: u32,
             },
             AsBx {
                 instruction: #opcode_name,
-                a: u8, // 8
-                sbx: i32, // 1 + 17
+                a: #a_type,
+                sbx: i32,
             },
             Ax {
                 instruction: #opcode_name,
-                ax: u32, // 26
+                ax: u32,
             },
+            #sj_variant
         }
 
         impl ::std::convert::TryFrom<Word> for #instruction_name {
@@ -321,40 +939,100 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
                 let opcode = (instr >> OFFSET_OP) & BITMASK_OP;
                 let _enum: #opcode_name = #opcode_name::try_from(opcode as u8)?;
                 Ok(match _enum {
+                    #(#decode_arms)*
+                })
+            }
+        }
+
+        impl #instruction_name {
+            // Operands in the order they are declared in bytecode!, empty if
+            // the variant does not match the format of the opcode
+            pub fn operands(&self) -> Vec<Argument> {
+                match *self {
                     #(
-                    | #opcode_name::#abc
+                    #operands_arms
                     )*
+                    _ => Vec::new(),
+                }
+            }
+        }
+
+        // Operands as a visitor receives them
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+        pub struct Reg(pub u32);
+
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+        pub struct Const(pub u32);
+
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+        pub struct Upval(pub u32);
+
+        #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+        pub enum RK {
+            Register(Reg),
+            Constant(Const),
+        }
+
+        // One method per opcode, taking its operands already typed. `Unused`
+        // operands are left out, and with a k flag that no RK operand reads
+        // the flag comes last.
+        pub trait InstructionVisitor {
+            type Output;
+            #(#visit_methods)*
+        }
+
+        impl #instruction_name {
+            // Calls the visitor method of the opcode, fails if the variant
+            // does not match the format of the opcode
+            pub fn visit<V: InstructionVisitor>(&self, visitor: &mut V) -> Result<V::Output> {
+                Ok(match *self {
                     #(
-                    | #opcode_name::#ab
+                    #visit_arms
                     )*
-                    #(
-                    | #opcode_name::#a
-                    )* => #instruction_name::ABC {
-                        instruction: _enum,
-                        a: ((instr >> OFFSET_A) & BITMASK_A) as u8,
-                        b: ((instr >> OFFSET_B) & BITMASK_B) as u16,
-                        c: ((instr >> OFFSET_C) & BITMASK_C) as u16,
+                    _ => return Err(#error_expr),
+                })
+            }
+        }
+
+        // Checks an argument fits in its field before shifting it into place,
+        // `bias` is added to the value first for excess-K fields
+        fn pack_argument(field: &'static str, value: i64, bias: i64,
+                         bitmask: u32, offset: u32) -> Result<Word> {
+            let biased = value + bias;
+            if biased < 0 || biased > i64::from(bitmask) {
+                return Err(#operand_error(field, value).into());
+            }
+            Ok((biased as Word) << offset)
+        }
+
+        impl ::std::convert::TryFrom<#instruction_name> for Word {
+            type Error = #error_name;
+
+            fn try_from(instr: #instruction_name) -> Result<Word> {
+                Ok(match instr {
+                    #instruction_name::ABC { instruction, a, b, c, #k_pattern } => {
+                        (instruction as Word) << OFFSET_OP
+                            | pack_argument("a", i64::from(a), 0, BITMASK_A, OFFSET_A)?
+                            | pack_argument("b", i64::from(b), 0, BITMASK_B, OFFSET_B)?
+                            | pack_argument("c", i64::from(c), 0, BITMASK_C, OFFSET_C)?
+                            #k_encode
                     },
-                    #(
-                    | #opcode_name::#abx
-                    )* => #instruction_name::ABx {
-                        instruction: _enum,
-                        a: ((instr >> OFFSET_A) & BITMASK_A) as u8,
-                        bx: ((instr >> OFFSET_BX) & BITMASK_BX) as u32,
+                    #instruction_name::ABx { instruction, a, bx } => {
+                        (instruction as Word) << OFFSET_OP
+                            | pack_argument("a", i64::from(a), 0, BITMASK_A, OFFSET_A)?
+                            | pack_argument("bx", i64::from(bx), 0, BITMASK_BX, OFFSET_BX)?
                     },
-                    #(
-                    | #opcode_name::#asbx
-                    )* => #instruction_name::AsBx {
-                        instruction: _enum,
-                        a: ((instr >> OFFSET_A) & BITMASK_A) as u8,
-                        sbx: ((instr >> OFFSET_BX) & BITMASK_BX) as i32,
+                    #instruction_name::AsBx { instruction, a, sbx } => {
+                        (instruction as Word) << OFFSET_OP
+                            | pack_argument("a", i64::from(a), 0, BITMASK_A, OFFSET_A)?
+                            | pack_argument("sbx", i64::from(sbx), i64::from(MAXARG_SBX),
+                                          BITMASK_BX, OFFSET_BX)?
                     },
-                    #(
-                    | #opcode_name::#ax
-                    )* => #instruction_name::Ax {
-                        instruction: _enum,
-                        ax: ((instr >> OFFSET_A) & BITMASK_AX) as u32,
-                    }
+                    #instruction_name::Ax { instruction, ax } => {
+                        (instruction as Word) << OFFSET_OP
+                            | pack_argument("ax", i64::from(ax), 0, BITMASK_AX, OFFSET_AX)?
+                    },
+                    #sj_encode
                 })
             }
         }
@@ -363,3 +1041,187 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
 
     result.into()
 }
+
+// Shift of the variant bits of a tag, makevariant in lobject.h
+const VARIANT_SHIFT: u32 = 4;
+
+// Error type and constructor of a tag enum, like
+// `#[tag(error = Error, invalid = ErrorKind::InvalidType)]`
+struct TagError {
+    error: Path,
+    invalid: Path,
+}
+
+impl Parse for TagError {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let key = input.parse::<Ident>()?;
+        if key != "error" {
+            return Err(Error::new(key.span(), "expected `error`"));
+        }
+        input.parse::<Token![=]>()?;
+        let error = input.parse::<Path>()?;
+        input.parse::<Token![,]>()?;
+        let key = input.parse::<Ident>()?;
+        if key != "invalid" {
+            return Err(Error::new(key.span(), "expected `invalid`"));
+        }
+        input.parse::<Token![=]>()?;
+        let invalid = input.parse::<Path>()?;
+        Ok(TagError { error, invalid })
+    }
+}
+
+// Sub-tag of a variant, like `#[tag(TNUMBER, 1)]` for `TNUMBER | (1 << 4)`
+struct SubTag {
+    base: Ident,
+    bits: LitInt,
+}
+
+impl Parse for SubTag {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let base = input.parse::<Ident>()?;
+        input.parse::<Token![,]>()?;
+        let bits = input.parse::<LitInt>()?;
+        Ok(SubTag { base, bits })
+    }
+}
+
+fn tag_attribute(attrs: &[syn::Attribute]) -> Option<&syn::Attribute> {
+    attrs.iter().find(|x| x.path.is_ident("tag"))
+}
+
+// Numbers each variant of a tag enum. Base tags are given like enum
+// discriminants or counted up from the previous base, sub-tags add variant
+// bits to a base declared before them.
+fn tag_values(data: &syn::DataEnum) -> Result<Vec<(Ident, u8, Ident)>> {
+    let mut values: Vec<(Ident, u8, Ident)> = Vec::new();
+    let mut bases: Vec<(Ident, u8)> = Vec::new();
+    let mut next = 0u32;
+    for variant in &data.variants {
+        if !variant.fields.is_empty() {
+            return Err(Error::new(variant.fields.span(), "tags can not hold fields"));
+        }
+        let mut sub_base = None;
+        let value = if let Some(attr) = tag_attribute(&variant.attrs) {
+            if let Some((_, ref discriminant)) = variant.discriminant {
+                return Err(Error::new(discriminant.span(),
+                                      "a sub-tag takes its value from `#[tag]`"));
+            }
+            let SubTag { base, bits } = attr.parse_args::<SubTag>()?;
+            let base_value = bases.iter().find(|x| x.0 == base).map(|x| u32::from(x.1))
+                .ok_or_else(|| Error::new(base.span(), "expected a base tag declared above"))?;
+            let bits_value = bits.base10_parse::<u32>()?;
+            if base_value >= 1 << VARIANT_SHIFT || bits_value >= 1 << (8 - VARIANT_SHIFT) {
+                return Err(Error::new(bits.span(), format!(
+                    "`{} | ({} << {})` does not fit in a u8 next to its base",
+                    base, bits_value, VARIANT_SHIFT)));
+            }
+            sub_base = Some(base);
+            base_value | bits_value << VARIANT_SHIFT
+        } else {
+            let value = match variant.discriminant {
+                Some((_, Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(ref x), .. }))) => {
+                    x.base10_parse::<u32>()?
+                }
+                Some((_, ref x)) => return Err(Error::new(x.span(), "expected an integer tag")),
+                None => next,
+            };
+            if value > u32::from(u8::MAX) {
+                return Err(Error::new(variant.ident.span(),
+                                      format!("tag {} does not fit in a u8", value)));
+            }
+            bases.push((variant.ident.clone(), value as u8));
+            next = value + 1;
+            value
+        };
+        // a sub-tag with no variant bits shares the value of its base
+        let shared = |x: &Ident| sub_base.as_ref() == Some(x);
+        if let Some(taken) = values.iter().find(|x| u32::from(x.1) == value && !shared(&x.0)) {
+            return Err(Error::new(variant.ident.span(), format!(
+                "tag {} is already taken by `{}`", value, taken.0)));
+        }
+        let base = sub_base.clone().unwrap_or_else(|| variant.ident.clone());
+        values.push((variant.ident.clone(), value as u8, base));
+    }
+    Ok(values)
+}
+
+// Derives conversions for an enum of u8 tags, like the type tags of lua.h
+// and their variants from lobject.h:
+//
+//     #[derive(TagEnum)]
+//     #[tag(error = Error, invalid = ErrorKind::InvalidType)]
+//     enum SyxType { TNIL, TBOOLEAN, TNUMBER = 3, #[tag(TNUMBER, 1)] TNUMINT }
+//
+// A byte shared by a base tag and its sub-tag without variant bits decodes
+// to the sub-tag.
+#[proc_macro_derive(TagEnum, attributes(tag))]
+pub fn tag_enum(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    tag_enum_impl(&input).unwrap_or_else(|x| x.to_compile_error()).into()
+}
+
+fn tag_enum_impl(input: &syn::DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let data = match input.data {
+        syn::Data::Enum(ref data) => data,
+        _ => return Err(Error::new(name.span(), "TagEnum only derives for enums")),
+    };
+    let TagError { error, invalid } = tag_attribute(&input.attrs)
+        .ok_or_else(|| Error::new(name.span(), "expected `#[tag(error = ..., invalid = ...)]`"))?
+        .parse_args::<TagError>()?;
+    let values = tag_values(data)?;
+
+    let variant: Vec<_> = values.iter().map(|x| &x.0).collect();
+    let variant_name: Vec<_> = variant.iter().map(|x| x.to_string()).collect();
+    let value: Vec<_> = values.iter().map(|x| Literal::u8_unsuffixed(x.1)).collect();
+    let base: Vec<_> = values.iter().map(|x| &x.2).collect();
+    // the last variant with a value is the sub-tag, if it has one
+    let (decode_variant, decode_value): (Vec<_>, Vec<_>) = values.iter().enumerate()
+        .filter(|&(i, x)| !values[i + 1..].iter().any(|y| y.1 == x.1))
+        .map(|(_, x)| (&x.0, Literal::u8_unsuffixed(x.1)))
+        .unzip();
+    let count = values.len();
+
+    Ok(quote! {
+        impl ::std::convert::TryFrom<u8> for #name {
+            type Error = #error;
+
+            fn try_from(value: u8) -> ::std::result::Result<#name, #error> {
+                match value {
+                    #(#decode_value => Ok(#name::#decode_variant),)*
+                    _ => Err(#invalid(value).into()),
+                }
+            }
+        }
+
+        impl From<#name> for u8 {
+            fn from(tag: #name) -> u8 {
+                match tag {
+                    #(#name::#variant => #value,)*
+                }
+            }
+        }
+
+        impl #name {
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    #(#name::#variant => #variant_name,)*
+                }
+            }
+
+            // Tag without its variant bits
+            pub fn base(&self) -> #name {
+                match *self {
+                    #(#name::#variant => #name::#base,)*
+                }
+            }
+
+            // Every variant, in the order they are declared
+            pub fn variants() -> ::std::slice::Iter<'static, #name> {
+                static VARIANTS: [#name; #count] = [#(#name::#variant),*];
+                VARIANTS.iter()
+            }
+        }
+    })
+}